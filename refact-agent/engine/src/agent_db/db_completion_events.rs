@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock as ARwLock;
+use serde_json::json;
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use ropey::Rope;
+use rusqlite::Connection;
+use similar::{ChangeTag, TextDiff};
+
+use crate::custom_error::ScratchError;
+use crate::global_context::GlobalContext;
+use crate::telemetry::telemetry_structs::SnippetTracker;
+
+
+const COMPLETION_STATS_DAYS: f64 = 30.0;
+const FIM_EXAMPLE_MAX_CHARS: usize = 8000;  // of prefix and of suffix, the rest of the file is too far away to matter
+
+
+#[derive(Debug, Clone, Default)]
+pub struct CompletionEvent {
+    pub model: String,
+    pub scratchpad: String,
+    pub language: String,
+    pub ab_arm: String,
+    pub latency_ms: i64,
+    pub rag_tokens: i64,
+    pub accepted: bool,
+    pub edit_distance: i64,
+}
+
+fn edit_distance(a: &str, b: &str) -> i64 {
+    TextDiff::from_chars(a, b).iter_all_changes()
+        .filter(|c| c.tag() != ChangeTag::Equal)
+        .map(|c| c.value().chars().count() as i64)
+        .sum()
+}
+
+// Called when the snippet tracker forgets the snippet: it's finished, or it wasn't accepted in time
+pub fn completion_event_record(
+    tx: &rusqlite::Transaction,
+    snip: &SnippetTracker,
+) -> Result<(), String> {
+    let accepted = snip.accepted_ts != 0;
+    let distance = if !accepted {
+        -1
+    } else if snip.corrected_by_user.is_empty() {
+        0
+    } else {
+        edit_distance(&snip.grey_text, &snip.corrected_by_user)
+    };
+    let language = PathBuf::from(&snip.inputs.cursor.file).extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    tx.execute(
+        "INSERT INTO completion_events (ce_snippet_id, ce_ts, ce_model, ce_scratchpad, ce_language, ce_ab_arm, ce_latency_ms, ce_rag_tokens,
+            ce_completion_chars, ce_accepted, ce_edit_distance)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        rusqlite::params![
+            snip.snippet_telemetry_id as i64, snip.created_ts as f64, snip.model, snip.scratchpad, language, snip.ab_arm,
+            snip.latency_ms, snip.rag_tokens, snip.grey_text.chars().count() as i64, accepted as i64, distance,
+        ],
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Accepted and finished snippets only: the middle is what the user kept after the follow-up edits
+pub fn fim_example_record(
+    tx: &rusqlite::Transaction,
+    snip: &SnippetTracker,
+) -> Result<(), String> {
+    if snip.accepted_ts == 0 || snip.finished_ts == 0 || snip.remaining_percentage < 0. {
+        return Ok(());
+    }
+    let source = match snip.inputs.sources.get(&snip.inputs.cursor.file) {
+        Some(source) => Rope::from_str(source),
+        None => return Ok(()),
+    };
+    let line = snip.inputs.cursor.line.max(0) as usize;
+    if line >= source.len_lines() {
+        return Ok(());
+    }
+    let line_len = source.line(line).len_chars();
+    let cursor_char = source.line_to_char(line) + (snip.inputs.cursor.character.max(0) as usize).min(line_len);
+    let prefix = source.slice(cursor_char.saturating_sub(FIM_EXAMPLE_MAX_CHARS)..cursor_char).to_string();
+    let suffix = source.slice(cursor_char..(cursor_char + FIM_EXAMPLE_MAX_CHARS).min(source.len_chars())).to_string();
+    let middle = if snip.corrected_by_user.is_empty() { &snip.grey_text } else { &snip.corrected_by_user };
+    if middle.trim().is_empty() {
+        return Ok(());
+    }
+    tx.execute(
+        "INSERT INTO fim_examples (fe_ts, fe_file_name, fe_model, fe_prefix, fe_suffix, fe_middle) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![snip.created_ts as f64, snip.inputs.cursor.file, snip.model, prefix, suffix, middle],
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub fn completion_events_since(conn: &Connection, since_ts: f64) -> Result<Vec<CompletionEvent>, String> {
+    let mut stmt = conn.prepare(
+        "SELECT ce_model, ce_scratchpad, ce_language, ce_ab_arm, ce_latency_ms, ce_rag_tokens, ce_accepted, ce_edit_distance
+         FROM completion_events WHERE ce_ts >= ?1"
+    ).map_err(|e| e.to_string())?;
+    let rows = stmt.query_map(rusqlite::params![since_ts], |row| {
+        Ok(CompletionEvent {
+            model: row.get(0)?,
+            scratchpad: row.get(1)?,
+            language: row.get(2)?,
+            ab_arm: row.get(3)?,
+            latency_ms: row.get(4)?,
+            rag_tokens: row.get(5)?,
+            accepted: row.get::<_, i64>(6)? != 0,
+            edit_distance: row.get(7)?,
+        })
+    }).map_err(|e| e.to_string())?;
+    rows.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+}
+
+fn percentile(sorted: &Vec<i64>, p: f64) -> i64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[idx]
+}
+
+fn summary(events: &Vec<&CompletionEvent>) -> serde_json::Value {
+    let accepted = events.iter().filter(|e| e.accepted).collect::<Vec<_>>();
+    let mut latencies = events.iter().map(|e| e.latency_ms).collect::<Vec<_>>();
+    latencies.sort();
+    let edit_distance_avg = if accepted.is_empty() {
+        0.0
+    } else {
+        accepted.iter().map(|e| e.edit_distance as f64).sum::<f64>() / accepted.len() as f64
+    };
+    json!({
+        "shown": events.len(),
+        "accepted": accepted.len(),
+        "acceptance_rate": if events.is_empty() { 0.0 } else { accepted.len() as f64 / events.len() as f64 },
+        "latency_ms_p50": percentile(&latencies, 0.5),
+        "latency_ms_p90": percentile(&latencies, 0.9),
+        "latency_ms_p99": percentile(&latencies, 0.99),
+        "edit_distance_avg": edit_distance_avg,
+    })
+}
+
+fn breakdown<F: Fn(&CompletionEvent) -> String>(events: &Vec<CompletionEvent>, key: F) -> serde_json::Value {
+    let mut groups: BTreeMap<String, Vec<&CompletionEvent>> = BTreeMap::new();
+    for e in events.iter() {
+        let k = key(e);
+        if !k.is_empty() {
+            groups.entry(k).or_default().push(e);
+        }
+    }
+    json!(groups.iter().map(|(k, v)| (k.clone(), summary(v))).collect::<serde_json::Map<_, _>>())
+}
+
+pub fn completion_stats(events: &Vec<CompletionEvent>) -> serde_json::Value {
+    json!({
+        "total": summary(&events.iter().collect()),
+        "by_model": breakdown(events, |e| e.model.clone()),
+        "by_scratchpad": breakdown(events, |e| e.scratchpad.clone()),
+        "by_language": breakdown(events, |e| e.language.clone()),
+        "by_rag": breakdown(events, |e| if e.rag_tokens > 0 { "with_rag".to_string() } else { "without_rag".to_string() }),
+        "ab_test": breakdown(events, |e| e.ab_arm.clone()),
+    })
+}
+
+// HTTP handler
+pub async fn handle_v1_completion_stats(
+    Extension(gcx): Extension<Arc<ARwLock<GlobalContext>>>,
+    _: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let ab_models = match crate::global_context::try_load_caps_quickly_if_not_present(gcx.clone(), 0).await {
+        Ok(caps) => caps.read().unwrap().code_completion_ab_test.clone(),
+        Err(_) => vec![],
+    };
+    let cdb = gcx.read().await.chore_db.clone();
+    let lite = cdb.lock().lite.clone();
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+    let events = completion_events_since(&lite.lock(), now - COMPLETION_STATS_DAYS * 86400.0)
+        .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let mut result = completion_stats(&events);
+    result["days"] = json!(COMPLETION_STATS_DAYS);
+    result["ab_test_models"] = json!(ab_models);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&result).unwrap()))
+        .unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_db::db_schema_20241102::create_tables_20241102;
+
+    #[test]
+    fn test_completion_stats() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables_20241102(&conn, false).unwrap();
+        let snip = |id: u64, model: &str, file: &str, ab_arm: &str, latency_ms: i64, accepted: bool, corrected: &str| {
+            let mut s = SnippetTracker {
+                snippet_telemetry_id: id,
+                model: model.to_string(),
+                grey_text: "return x + 1".to_string(),
+                corrected_by_user: corrected.to_string(),
+                created_ts: 1000,
+                accepted_ts: if accepted { 1001 } else { 0 },
+                scratchpad: "FIM-PSM".to_string(),
+                ab_arm: ab_arm.to_string(),
+                latency_ms,
+                ..Default::default()
+            };
+            s.inputs.cursor.file = file.to_string();
+            s
+        };
+        {
+            let tx = conn.transaction().unwrap();
+            completion_event_record(&tx, &snip(1, "starcoder", "/a.py", "a", 100, true, "return x + 2")).unwrap();
+            completion_event_record(&tx, &snip(2, "starcoder", "/a.py", "a", 300, false, "")).unwrap();
+            completion_event_record(&tx, &snip(3, "qwen", "/b.RS", "b", 200, true, "")).unwrap();
+            tx.commit().unwrap();
+        }
+        let events = completion_events_since(&conn, 0.0).unwrap();
+        let stats = completion_stats(&events);
+        assert_eq!(stats["total"]["shown"], 3);
+        assert_eq!(stats["total"]["latency_ms_p50"], 200);
+        assert_eq!(stats["by_model"]["starcoder"]["acceptance_rate"], 0.5);
+        assert_eq!(stats["by_model"]["starcoder"]["edit_distance_avg"], 2.0);
+        assert_eq!(stats["by_language"]["rs"]["accepted"], 1);
+        assert_eq!(stats["ab_test"]["b"]["acceptance_rate"], 1.0);
+        assert!(completion_events_since(&conn, 2000.0).unwrap().is_empty());
+    }
+}
@@ -8,6 +8,9 @@ pub fn create_tables_20241102(conn: &Connection, reset_memory: bool) -> Result<(
         conn.execute("DROP TABLE IF EXISTS chore_events", []).map_err(|e| e.to_string())?;
         conn.execute("DROP TABLE IF EXISTS cthreads", []).map_err(|e| e.to_string())?;
         conn.execute("DROP TABLE IF EXISTS cmessages", []).map_err(|e| e.to_string())?;
+        conn.execute("DROP TABLE IF EXISTS spend_log", []).map_err(|e| e.to_string())?;
+        conn.execute("DROP TABLE IF EXISTS completion_events", []).map_err(|e| e.to_string())?;
+        conn.execute("DROP TABLE IF EXISTS fim_examples", []).map_err(|e| e.to_string())?;
     }
     conn.execute(
         "CREATE TABLE IF NOT EXISTS pubsub_events (
@@ -91,6 +94,52 @@ pub fn create_tables_20241102(conn: &Connection, reset_memory: bool) -> Result<(
         )",
         [],
     ).map_err(|e| e.to_string())?;
+    // no foreign keys: the money is spent even if the cthread is deleted later
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS spend_log (
+            spend_ts REAL NOT NULL,
+            spend_day TEXT NOT NULL,                    -- local date YYYY-MM-DD, for daily budgets
+            spend_cthread_id TEXT NOT NULL,
+            spend_chore_id TEXT,                        -- NULL for cthreads outside of chores
+            spend_model TEXT NOT NULL,
+            spend_prompt_tokens INT NOT NULL,
+            spend_completion_tokens INT NOT NULL,
+            spend_usd REAL NOT NULL
+        )",
+        [],
+    ).map_err(|e| e.to_string())?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_spend_day ON spend_log (spend_day)", []).map_err(|e| e.to_string())?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_spend_cthread_id ON spend_log (spend_cthread_id)", []).map_err(|e| e.to_string())?;
+    // one row per code completion shown to the user, written when the snippet tracker is done with it
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS completion_events (
+            ce_snippet_id INT NOT NULL,
+            ce_ts REAL NOT NULL,
+            ce_model TEXT NOT NULL,
+            ce_scratchpad TEXT NOT NULL,
+            ce_language TEXT NOT NULL,                  -- file extension
+            ce_ab_arm TEXT NOT NULL,                    -- 'a' or 'b' if the A/B test picked the model, empty otherwise
+            ce_latency_ms INT NOT NULL,
+            ce_rag_tokens INT NOT NULL,
+            ce_completion_chars INT NOT NULL,
+            ce_accepted INT NOT NULL,
+            ce_edit_distance INT NOT NULL               -- chars the user changed in the accepted completion, -1 if not accepted
+        )",
+        [],
+    ).map_err(|e| e.to_string())?;
+    conn.execute("CREATE INDEX IF NOT EXISTS idx_ce_ts ON completion_events (ce_ts)", []).map_err(|e| e.to_string())?;
+    // accepted completions as the user left them, training data for /v1/sft-export
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS fim_examples (
+            fe_ts REAL NOT NULL,
+            fe_file_name TEXT NOT NULL,
+            fe_model TEXT NOT NULL,
+            fe_prefix TEXT NOT NULL,
+            fe_suffix TEXT NOT NULL,
+            fe_middle TEXT NOT NULL
+        )",
+        [],
+    ).map_err(|e| e.to_string())?;
     // Useful to speed up SELECT .. JOIN
     // conn.execute("CREATE INDEX IF NOT EXISTS idx_chore_event_belongs_to_chore_id ON chore_events (chore_event_belongs_to_chore_id)", []).map_err(|e| e.to_string())?;
     // conn.execute("CREATE INDEX IF NOT EXISTS idx_cthread_belongs_to_chore_event_id ON cthreads (cthread_belongs_to_chore_event_id)", []).map_err(|e| e.to_string())?;
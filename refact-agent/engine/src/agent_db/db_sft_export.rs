@@ -0,0 +1,329 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock as ARwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use rusqlite::Connection;
+
+use crate::agent_db::db_cmessage::cmessages_from_rows;
+use crate::agent_db::db_structs::CMessage;
+use crate::call_validation::ContextFile;
+use crate::custom_error::ScratchError;
+use crate::global_context::GlobalContext;
+use crate::privacy::{check_file_privacy, load_privacy_if_needed, FilePrivacyLevel, PrivacySettings};
+
+
+// Training data out of what's already stored locally:
+//   fim_*.jsonl  -- {"file_name", "prefix", "suffix", "middle"} from accepted completions (fim_examples table)
+//   chat_*.jsonl -- {"messages": [...]} from cthreads that ended with an assistant message, the latest alternative
+//                   of each message when the user edited and regenerated
+// Files blocked in privacy.yaml don't go into the dataset, a chat that has seen a blocked file is skipped as a whole.
+// Duplicates are removed, the holdout split depends on the example text only, so the examples stay on the same side
+// when exporting again later.
+
+
+#[derive(Deserialize)]
+pub struct SftExportPost {
+    #[serde(default)]
+    pub output_dir: String,  // subdirectory of cache_dir, default is sft_export
+    #[serde(default = "default_holdout_percent")]
+    pub holdout_percent: u32,
+}
+
+fn default_holdout_percent() -> u32 { 10 }
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SftSplit {
+    pub train: Vec<Value>,
+    pub holdout: Vec<Value>,
+    pub duplicates_n: usize,
+    pub blocked_n: usize,
+}
+
+fn is_holdout(example_text: &str, holdout_percent: u32) -> bool {
+    let digest = md5::compute(example_text);
+    (u16::from_be_bytes([digest[0], digest[1]]) as u32 % 100) < holdout_percent
+}
+
+fn dedup_and_split(examples: Vec<Value>, holdout_percent: u32, blocked_n: usize) -> SftSplit {
+    let mut split = SftSplit { blocked_n, ..Default::default() };
+    let mut seen = HashSet::new();
+    for example in examples {
+        let text = example.to_string();
+        if !seen.insert(md5::compute(&text).0) {
+            split.duplicates_n += 1;
+            continue;
+        }
+        if is_holdout(&text, holdout_percent) {
+            split.holdout.push(example);
+        } else {
+            split.train.push(example);
+        }
+    }
+    split
+}
+
+fn is_blocked(privacy: &Arc<PrivacySettings>, file_name: &str) -> bool {
+    check_file_privacy(privacy.clone(), Path::new(file_name), &FilePrivacyLevel::OnlySendToServersIControl).is_err()
+}
+
+pub fn fim_examples_split(conn: &Connection, privacy: &Arc<PrivacySettings>, holdout_percent: u32) -> Result<SftSplit, String> {
+    let mut stmt = conn.prepare(
+        "SELECT fe_file_name, fe_prefix, fe_suffix, fe_middle FROM fim_examples ORDER BY fe_ts"
+    ).map_err(|e| e.to_string())?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+    }).map_err(|e| e.to_string())?;
+    let mut examples = vec![];
+    let mut blocked_n = 0;
+    for row in rows {
+        let (file_name, prefix, suffix, middle) = row.map_err(|e| e.to_string())?;
+        if is_blocked(privacy, &file_name) {
+            blocked_n += 1;
+            continue;
+        }
+        examples.push(json!({"file_name": file_name, "prefix": prefix, "suffix": suffix, "middle": middle}));
+    }
+    Ok(dedup_and_split(examples, holdout_percent, blocked_n))
+}
+
+// Walks back from the last message of the latest alternative, each message knows the alternative of the one before it
+fn latest_branch(cmessages: Vec<CMessage>) -> Vec<CMessage> {
+    let max_alt = match cmessages.iter().map(|m| m.cmessage_alt).max() {
+        Some(alt) => alt,
+        None => return vec![],
+    };
+    let mut by_key: HashMap<(i32, i32), CMessage> = cmessages.into_iter().map(|m| ((m.cmessage_alt, m.cmessage_num), m)).collect();
+    let last_num = by_key.keys().filter(|(alt, _)| *alt == max_alt).map(|(_, num)| *num).max().unwrap_or(0);
+    let mut branch = vec![];
+    let mut key = (max_alt, last_num);
+    while let Some(m) = by_key.remove(&key) {
+        key = (m.cmessage_prev_alt, m.cmessage_num - 1);
+        branch.push(m);
+    }
+    branch.reverse();
+    branch
+}
+
+// Strings in tool results and diffs that might be paths, nested json is looked into. Catching more than
+// there is only means more chats are skipped.
+fn path_like_strings(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) => match serde_json::from_str::<Value>(s) {
+            Ok(nested @ (Value::Array(_) | Value::Object(_))) => path_like_strings(&nested, out),
+            _ => out.extend(
+                s.split(|c: char| c.is_whitespace() || c == ',')
+                    .map(|w| w.trim_matches(|c: char| "'\"`()[]{}<>:;".contains(c)))
+                    .filter(|w| w.contains('/') || w.contains('\\'))
+                    .map(|w| w.to_string())
+            ),
+        },
+        Value::Array(items) => items.iter().for_each(|x| path_like_strings(x, out)),
+        Value::Object(fields) => fields.values().for_each(|x| path_like_strings(x, out)),
+        _ => {},
+    }
+}
+
+fn string_args(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) => out.extend(s.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty())),
+        Value::Array(items) => items.iter().for_each(|x| string_args(x, out)),
+        Value::Object(fields) => fields.values().for_each(|x| string_args(x, out)),
+        _ => {},
+    }
+}
+
+// Files attached as context, and everything that looks like a path in tool calls, tool results and diffs
+fn files_mentioned(message: &Value) -> Vec<String> {
+    let mut files = vec![];
+    if message["role"] == "context_file" {
+        let context_files: Vec<ContextFile> = match &message["content"] {
+            Value::String(s) => serde_json::from_str(s).unwrap_or_default(),
+            other => serde_json::from_value(other.clone()).unwrap_or_default(),
+        };
+        files.extend(context_files.into_iter().map(|f| f.file_name));
+    } else if message["role"] == "tool" || message["role"] == "diff" {
+        path_like_strings(&message["content"], &mut files);
+    }
+    for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+        let args = &tool_call["function"]["arguments"];
+        match args.as_str().map(serde_json::from_str::<Value>) {
+            Some(Ok(parsed)) => string_args(&parsed, &mut files),
+            _ => string_args(args, &mut files),
+        }
+    }
+    files
+}
+
+// Only under cache_dir, the request comes over HTTP and shouldn't be able to write anywhere else
+fn export_dir(cache_dir: &Path, output_dir: &str) -> Result<PathBuf, String> {
+    if output_dir.is_empty() {
+        return Ok(cache_dir.join("sft_export"));
+    }
+    let relative = Path::new(output_dir);
+    if !relative.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        return Err(format!("output_dir {:?} should be a relative path inside the cache directory, without ..", output_dir));
+    }
+    Ok(cache_dir.join(relative))
+}
+
+pub fn chat_examples_split(conn: &Connection, privacy: &Arc<PrivacySettings>, holdout_percent: u32) -> Result<SftSplit, String> {
+    let cthread_ids = {
+        let mut stmt = conn.prepare("SELECT cthread_id FROM cthreads WHERE cthread_error = '' ORDER BY cthread_created_ts")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0)).map_err(|e| e.to_string())?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())?
+    };
+    let mut examples = vec![];
+    let mut blocked_n = 0;
+    for cthread_id in cthread_ids {
+        let mut stmt = conn.prepare("SELECT * FROM cmessages WHERE cmessage_belongs_to_cthread_id = ?1")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt.query(rusqlite::params![cthread_id]).map_err(|e| e.to_string())?;
+        let messages = latest_branch(cmessages_from_rows(rows)).into_iter()
+            .filter_map(|m| serde_json::from_str::<Value>(&m.cmessage_json).ok())
+            .collect::<Vec<_>>();
+        if messages.last().map(|m| m["role"] != "assistant").unwrap_or(true) {
+            continue;
+        }
+        if messages.iter().flat_map(files_mentioned).any(|f| is_blocked(privacy, &f)) {
+            blocked_n += 1;
+            continue;
+        }
+        let messages = messages.into_iter().map(|m| {
+            let mut clean = json!({"role": m["role"], "content": m["content"]});
+            for field in ["tool_calls", "tool_call_id"] {
+                if !m[field].is_null() {
+                    clean[field] = m[field].clone();
+                }
+            }
+            clean
+        }).collect::<Vec<_>>();
+        examples.push(json!({"messages": messages}));
+    }
+    Ok(dedup_and_split(examples, holdout_percent, blocked_n))
+}
+
+async fn write_jsonl(path: &PathBuf, examples: &Vec<Value>) -> Result<(), String> {
+    let text = examples.iter().map(|x| x.to_string() + "\n").collect::<String>();
+    tokio::fs::write(path, text).await.map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+// HTTP handler
+pub async fn handle_v1_sft_export(
+    Extension(gcx): Extension<Arc<ARwLock<GlobalContext>>>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let post = serde_json::from_slice::<SftExportPost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("JSON problem: {}", e))
+    )?;
+    if post.holdout_percent > 100 {
+        return Err(ScratchError::new(StatusCode::BAD_REQUEST, "holdout_percent should be 0..100".to_string()));
+    }
+    let output_dir = export_dir(&gcx.read().await.cache_dir, &post.output_dir)
+        .map_err(|e| ScratchError::new(StatusCode::BAD_REQUEST, e))?;
+    let privacy = load_privacy_if_needed(gcx.clone()).await;
+    let cdb = gcx.read().await.chore_db.clone();
+    let lite = cdb.lock().lite.clone();
+    let internal_error = |e: String| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e);
+    let (fim, chat) = {
+        let conn = lite.lock();
+        (
+            fim_examples_split(&conn, &privacy, post.holdout_percent).map_err(internal_error)?,
+            chat_examples_split(&conn, &privacy, post.holdout_percent).map_err(internal_error)?,
+        )
+    };
+    tokio::fs::create_dir_all(&output_dir).await.map_err(|e| internal_error(format!("cannot create {}: {}", output_dir.display(), e)))?;
+    let mut result = json!({"output_dir": output_dir});
+    for (name, split) in [("fim", &fim), ("chat", &chat)] {
+        write_jsonl(&output_dir.join(format!("{}_train.jsonl", name)), &split.train).await.map_err(internal_error)?;
+        write_jsonl(&output_dir.join(format!("{}_holdout.jsonl", name)), &split.holdout).await.map_err(internal_error)?;
+        result[name] = json!({
+            "train": split.train.len(),
+            "holdout": split.holdout.len(),
+            "duplicates": split.duplicates_n,
+            "blocked": split.blocked_n,
+        });
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&result).unwrap()))
+        .unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_db::db_schema_20241102::create_tables_20241102;
+    use crate::privacy::FilePrivacySettings;
+
+    #[test]
+    fn test_sft_export() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables_20241102(&conn, false).unwrap();
+        conn.execute_batch(
+            "INSERT INTO fim_examples VALUES (1, '/p/a.py', 'm', 'def f(', '):', 'x');
+             INSERT INTO fim_examples VALUES (2, '/p/a.py', 'm', 'def f(', '):', 'x');
+             INSERT INTO fim_examples VALUES (3, '/p/secret/b.py', 'm', 'def g(', '):', 'y');
+             INSERT INTO cthreads (cthread_id, cthread_title, cthread_toolset, cthread_model, cthread_temperature,
+                cthread_error, cthread_anything_new, cthread_created_ts, cthread_updated_ts, cthread_archived_ts, cthread_locked_by, cthread_locked_ts)
+                VALUES ('thread1', '', 'agent', 'gpt-4o', 0, '', 0, 0, 0, 0, '', 0);
+             INSERT INTO cmessages VALUES ('thread1', 0, 0, 0, '', 0, 0, '{\"role\":\"user\",\"content\":\"hi\"}');
+             INSERT INTO cmessages VALUES ('thread1', 0, 1, 0, '', 0, 0, '{\"role\":\"assistant\",\"content\":\"bad answer\",\"usage\":{}}');
+             INSERT INTO cmessages VALUES ('thread1', 1, 1, 0, '', 0, 0, '{\"role\":\"assistant\",\"content\":\"good answer\",\"usage\":{}}');"
+        ).unwrap();
+        let privacy = Arc::new(PrivacySettings {
+            privacy_rules: FilePrivacySettings {
+                only_send_to_servers_I_control: vec![],
+                blocked: vec!["*/secret/*".to_string()],
+            },
+            loaded_ts: 0,
+        });
+
+        let fim = fim_examples_split(&conn, &privacy, 0).unwrap();
+        assert_eq!((fim.train.len(), fim.duplicates_n, fim.blocked_n), (1, 1, 1));
+        assert_eq!(fim.train[0]["middle"], "x");
+        let fim_all_holdout = fim_examples_split(&conn, &privacy, 100).unwrap();
+        assert_eq!((fim_all_holdout.train.len(), fim_all_holdout.holdout.len()), (0, 1));
+
+        let chat = chat_examples_split(&conn, &privacy, 0).unwrap();
+        assert_eq!(chat.train, vec![json!({"messages": [
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": "good answer"},
+        ]})]);
+
+        // the thread read a blocked file with a tool, nothing attached as context_file
+        conn.execute_batch(
+            "INSERT INTO cthreads (cthread_id, cthread_title, cthread_toolset, cthread_model, cthread_temperature,
+                cthread_error, cthread_anything_new, cthread_created_ts, cthread_updated_ts, cthread_archived_ts, cthread_locked_by, cthread_locked_ts)
+                VALUES ('thread2', '', 'agent', 'gpt-4o', 0, '', 0, 1, 0, 0, '', 0);
+             INSERT INTO cmessages VALUES ('thread2', 0, 0, 0, '', 0, 0, '{\"role\":\"user\",\"content\":\"what is in there\"}');
+             INSERT INTO cmessages VALUES ('thread2', 0, 1, 0, '', 0, 0, '{\"role\":\"assistant\",\"content\":\"\",\"tool_calls\":[{\"id\":\"c1\",\"type\":\"function\",\"function\":{\"name\":\"cat\",\"arguments\":\"{\\\"paths\\\":\\\"/p/b.py,/p/secret/key.py\\\"}\"}}]}');
+             INSERT INTO cmessages VALUES ('thread2', 0, 2, 0, '', 0, 0, '{\"role\":\"tool\",\"content\":\"ok\",\"tool_call_id\":\"c1\"}');
+             INSERT INTO cmessages VALUES ('thread2', 0, 3, 0, '', 0, 0, '{\"role\":\"assistant\",\"content\":\"it has a key\"}');"
+        ).unwrap();
+        let chat = chat_examples_split(&conn, &privacy, 0).unwrap();
+        assert_eq!((chat.train.len(), chat.blocked_n), (1, 1));
+    }
+
+    #[test]
+    fn test_files_mentioned_and_export_dir() {
+        let tool_result = json!({"role": "tool", "content": "File /p/secret/key.py:\n1 | KEY = 1\n"});
+        assert_eq!(files_mentioned(&tool_result), vec!["/p/secret/key.py"]);
+        let diff = json!({"role": "diff", "content": "[{\"file_name\": \"/p/secret/key.py\", \"lines_add\": \"x\"}]"});
+        assert!(files_mentioned(&diff).contains(&"/p/secret/key.py".to_string()));
+
+        let cache_dir = Path::new("/cache");
+        assert_eq!(export_dir(cache_dir, "").unwrap(), PathBuf::from("/cache/sft_export"));
+        assert_eq!(export_dir(cache_dir, "exports/today").unwrap(), PathBuf::from("/cache/exports/today"));
+        assert!(export_dir(cache_dir, "/etc").is_err());
+        assert!(export_dir(cache_dir, "../outside").is_err());
+        assert!(export_dir(cache_dir, "exports/../../outside").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::Arc;
+use tokio::sync::RwLock as ARwLock;
+use serde::Serialize;
+use serde_json::json;
+use axum::Extension;
+use axum::response::Result;
+use hyper::{Body, Response, StatusCode};
+use rusqlite::Connection;
+
+use crate::call_validation::ChatUsage;
+use crate::caps::Budgets;
+use crate::custom_error::ScratchError;
+use crate::global_context::GlobalContext;
+
+
+const SPEND_DAYS_TO_SHOW: usize = 30;
+const SPEND_TOP_N: usize = 50;
+
+
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct SpendTotal {
+    pub usd: f64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+}
+
+pub fn spend_day_now() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+pub fn spend_record(
+    tx: &rusqlite::Transaction,
+    cthread_id: &String,
+    model: &String,
+    usage: &ChatUsage,
+    usd: f64,
+) -> Result<(), String> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+    tx.execute(
+        "INSERT INTO spend_log (spend_ts, spend_day, spend_cthread_id, spend_chore_id, spend_model, spend_prompt_tokens, spend_completion_tokens, spend_usd)
+         VALUES (?1, ?2, ?3, (
+            SELECT chore_events.chore_event_belongs_to_chore_id FROM cthreads
+            JOIN chore_events ON chore_events.chore_event_id = cthreads.cthread_belongs_to_chore_event_id
+            WHERE cthreads.cthread_id = ?3
+         ), ?4, ?5, ?6, ?7)",
+        rusqlite::params![now, spend_day_now(), cthread_id, model, usage.prompt_tokens as i64, usage.completion_tokens as i64, usd],
+    ).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn spend_total_where(conn: &Connection, where_sql: &str, param: &String) -> Result<SpendTotal, String> {
+    conn.query_row(
+        &format!("SELECT COALESCE(SUM(spend_usd), 0), COALESCE(SUM(spend_prompt_tokens), 0), COALESCE(SUM(spend_completion_tokens), 0) FROM spend_log WHERE {}", where_sql),
+        rusqlite::params![param],
+        |row| Ok(SpendTotal { usd: row.get(0)?, prompt_tokens: row.get(1)?, completion_tokens: row.get(2)? }),
+    ).map_err(|e| e.to_string())
+}
+
+pub fn spend_for_cthread(conn: &Connection, cthread_id: &String) -> Result<SpendTotal, String> {
+    spend_total_where(conn, "spend_cthread_id = ?1", cthread_id)
+}
+
+pub fn spend_for_chore(conn: &Connection, chore_id: &String) -> Result<SpendTotal, String> {
+    spend_total_where(conn, "spend_chore_id = ?1", chore_id)
+}
+
+pub fn spend_for_day(conn: &Connection, day: &String) -> Result<SpendTotal, String> {
+    spend_total_where(conn, "spend_day = ?1", day)
+}
+
+// The reason if the cthread must not get any more turns, checked in the order chat, chore, day
+pub fn budget_exceeded(conn: &Connection, budgets: &Budgets, cthread_id: &String) -> Result<Option<String>, String> {
+    if budgets.per_chat > 0.0 {
+        let spent = spend_for_cthread(conn, cthread_id)?;
+        if spent.usd >= budgets.per_chat {
+            return Ok(Some(format!("budget per chat exceeded: this chat spent ${:.2} of ${:.2}", spent.usd, budgets.per_chat)));
+        }
+    }
+    if budgets.per_chore > 0.0 {
+        let chore_id: Option<String> = conn.query_row(
+            "SELECT chore_events.chore_event_belongs_to_chore_id FROM cthreads
+             JOIN chore_events ON chore_events.chore_event_id = cthreads.cthread_belongs_to_chore_event_id
+             WHERE cthreads.cthread_id = ?1",
+            rusqlite::params![cthread_id],
+            |row| row.get(0),
+        ).ok();
+        if let Some(chore_id) = chore_id {
+            let spent = spend_for_chore(conn, &chore_id)?;
+            if spent.usd >= budgets.per_chore {
+                return Ok(Some(format!("budget per chore exceeded: chore {} spent ${:.2} of ${:.2}", chore_id, spent.usd, budgets.per_chore)));
+            }
+        }
+    }
+    if budgets.per_day > 0.0 {
+        let day = spend_day_now();
+        let spent = spend_for_day(conn, &day)?;
+        if spent.usd >= budgets.per_day {
+            return Ok(Some(format!("budget per day exceeded: {} spent ${:.2} of ${:.2}", day, spent.usd, budgets.per_day)));
+        }
+    }
+    Ok(None)
+}
+
+// Spend of the interactive /v1/chat, chat_id takes the place of cthread_id. Autonomous threads record theirs in autonomy.rs
+pub async fn spend_record_chat_usage(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    chat_id: &String,
+    model: &String,
+    usage_json: &serde_json::Value,
+) {
+    if chat_id.is_empty() || usage_json.is_null() {
+        return;
+    }
+    let usage = match serde_json::from_value::<ChatUsage>(usage_json.clone()) {
+        Ok(usage) => usage,
+        Err(e) => {
+            tracing::warn!("cannot record spend of {}, usage {:?}: {}", chat_id, usage_json, e);
+            return;
+        }
+    };
+    let usd = match crate::global_context::try_load_caps_quickly_if_not_present(gcx.clone(), 0).await {
+        Ok(caps) => crate::caps::usage_cost_usd(&caps.read().unwrap(), model, &usage),
+        Err(_) => 0.0,
+    };
+    let cdb = gcx.read().await.chore_db.clone();
+    let lite = cdb.lock().lite.clone();
+    let mut conn = lite.lock();
+    let recorded = conn.transaction().map_err(|e| e.to_string()).and_then(|tx| {
+        spend_record(&tx, chat_id, model, &usage, usd)?;
+        tx.commit().map_err(|e| e.to_string())
+    });
+    if let Err(e) = recorded {
+        tracing::error!("cannot record spend of {}: {}", chat_id, e);
+    }
+}
+
+pub async fn budget_exceeded_for_chat(gcx: Arc<ARwLock<GlobalContext>>, budgets: &Budgets, chat_id: &String) -> Result<Option<String>, String> {
+    if chat_id.is_empty() {
+        return Ok(None);
+    }
+    let cdb = gcx.read().await.chore_db.clone();
+    let lite = cdb.lock().lite.clone();
+    let conn = lite.lock();
+    budget_exceeded(&conn, budgets, chat_id)
+}
+
+fn spend_grouped_by(conn: &Connection, column: &str, order_by: &str, limit: usize) -> Result<Vec<serde_json::Value>, String> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {column}, SUM(spend_usd) AS usd, SUM(spend_prompt_tokens), SUM(spend_completion_tokens) FROM spend_log
+         WHERE {column} IS NOT NULL GROUP BY {column} ORDER BY {order_by} DESC LIMIT ?1",
+    )).map_err(|e| e.to_string())?;
+    let rows = stmt.query_map(rusqlite::params![limit as i64], |row| {
+        Ok(json!({
+            column: row.get::<_, String>(0)?,
+            "usd": row.get::<_, f64>(1)?,
+            "prompt_tokens": row.get::<_, i64>(2)?,
+            "completion_tokens": row.get::<_, i64>(3)?,
+        }))
+    }).map_err(|e| e.to_string())?;
+    rows.collect::<Result<Vec<_>, _>>().map_err(|e| e.to_string())
+}
+
+// HTTP handler
+pub async fn handle_v1_spend(
+    Extension(gcx): Extension<Arc<ARwLock<GlobalContext>>>,
+    _: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let budgets = match crate::global_context::try_load_caps_quickly_if_not_present(gcx.clone(), 0).await {
+        Ok(caps) => caps.read().unwrap().budgets.clone(),
+        Err(_) => Budgets::default(),
+    };
+    let cdb = gcx.read().await.chore_db.clone();
+    let lite = cdb.lock().lite.clone();
+    let result = {
+        let conn = lite.lock();
+        let today = spend_day_now();
+        let internal_error = |e: String| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e);
+        json!({
+            "today": today,
+            "spent_today": spend_for_day(&conn, &today).map_err(internal_error)?,
+            "budgets": budgets,
+            "days": spend_grouped_by(&conn, "spend_day", "spend_day", SPEND_DAYS_TO_SHOW).map_err(internal_error)?,
+            "cthreads": spend_grouped_by(&conn, "spend_cthread_id", "usd", SPEND_TOP_N).map_err(internal_error)?,
+            "chores": spend_grouped_by(&conn, "spend_chore_id", "usd", SPEND_TOP_N).map_err(internal_error)?,
+        })
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&result).unwrap()))
+        .unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_db::db_schema_20241102::create_tables_20241102;
+
+    #[test]
+    fn test_budget_exceeded() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_tables_20241102(&conn, false).unwrap();
+        conn.execute_batch(
+            "INSERT INTO chores VALUES ('chore1', 'fix the build', 1, 0, 0);
+             INSERT INTO chore_events VALUES ('event1', 'chore1', 'started', 0, '', 'thread1');
+             INSERT INTO cthreads (cthread_id, cthread_belongs_to_chore_event_id, cthread_title, cthread_toolset, cthread_model, cthread_temperature,
+                cthread_error, cthread_anything_new, cthread_created_ts, cthread_updated_ts, cthread_archived_ts, cthread_locked_by, cthread_locked_ts)
+                VALUES ('thread1', 'event1', '', 'agent', 'gpt-4o', 0, '', 0, 0, 0, 0, '', 0);"
+        ).unwrap();
+        let usage = ChatUsage { prompt_tokens: 1000, completion_tokens: 100, ..Default::default() };
+        {
+            let tx = conn.transaction().unwrap();
+            spend_record(&tx, &"thread1".to_string(), &"gpt-4o".to_string(), &usage, 1.5).unwrap();
+            spend_record(&tx, &"thread2".to_string(), &"gpt-4o".to_string(), &usage, 1.0).unwrap();
+            tx.commit().unwrap();
+        }
+        assert_eq!(spend_for_chore(&conn, &"chore1".to_string()).unwrap().usd, 1.5);
+        assert_eq!(spend_for_day(&conn, &spend_day_now()).unwrap().prompt_tokens, 2000);
+
+        let thread1 = "thread1".to_string();
+        let thread2 = "thread2".to_string();
+        let no_limits = Budgets::default();
+        assert!(budget_exceeded(&conn, &no_limits, &thread1).unwrap().is_none());
+        let per_chat = Budgets { per_chat: 1.2, ..Default::default() };
+        assert!(budget_exceeded(&conn, &per_chat, &thread1).unwrap().unwrap().contains("per chat"));
+        assert!(budget_exceeded(&conn, &per_chat, &thread2).unwrap().is_none());
+        let per_chore = Budgets { per_chore: 1.5, ..Default::default() };
+        assert!(budget_exceeded(&conn, &per_chore, &thread1).unwrap().unwrap().contains("chore1"));
+        let per_day = Budgets { per_day: 2.5, ..Default::default() };
+        assert!(budget_exceeded(&conn, &per_day, &thread2).unwrap().unwrap().contains("$2.50 of $2.50"));
+    }
+}
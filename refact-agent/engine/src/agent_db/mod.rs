@@ -8,9 +8,12 @@ use crate::global_context::GlobalContext;
 
 pub mod db_chore;
 pub mod db_cmessage;
+pub mod db_completion_events;
 pub mod db_cthread;
 pub mod db_init;
 pub mod db_schema_20241102;
+pub mod db_sft_export;
+pub mod db_spend;
 pub mod db_structs;
 
 pub fn chore_pubub_push(
@@ -69,28 +69,13 @@ fn gather_used_tools(messages: &Vec<ChatMessage>) -> Vec<String> {
     tools
 }
 
-pub async fn compress_trajectory(
+// runs COMPRESSION_MESSAGE over the messages with the given model, returns the trajectory json without fencing
+pub async fn compress_messages(
     gcx: Arc<ARwLock<GlobalContext>>,
+    model_name: &String,
+    n_ctx: usize,
     messages: &Vec<ChatMessage>,
-) -> Result<(String, String), String> {
-    if messages.is_empty() {
-        return Err("The provided chat is empty".to_string());
-    }
-    let (model_name, n_ctx) = match try_load_caps_quickly_if_not_present(gcx.clone(), 0).await {
-        Ok(caps) => {
-            let caps_locked = caps.read().unwrap();
-            let model_name = caps_locked.code_chat_default_model.clone();
-            if let Some(model_rec) = caps_locked.code_completion_models.get(&strip_model_from_finetune(&model_name)) {
-                Ok((model_name, model_rec.n_ctx))
-            } else {
-                Err(format!(
-                    "Model '{}' not found. Server has these models: {:?}",
-                    model_name, caps_locked.code_completion_models.keys()
-                ))
-            }
-        },
-        Err(_) => Err("No caps available".to_string()),
-    }?;
+) -> Result<String, String> {
     let mut messages_compress = messages.clone();
     messages_compress.push(
         ChatMessage {
@@ -126,6 +111,7 @@ pub async fn compress_trajectory(
         None,
         None,
         None,
+        None,
     ).await.map_err(|e| format!("Error: {}", e))?;
 
     let content = new_messages
@@ -140,7 +126,32 @@ pub async fn compress_trajectory(
         .flatten()
         .flatten()
         .ok_or("No traj message was generated".to_string())?;
-    let trajectory = remove_fencing(&content);
+    Ok(remove_fencing(&content))
+}
+
+pub async fn compress_trajectory(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    messages: &Vec<ChatMessage>,
+) -> Result<(String, String), String> {
+    if messages.is_empty() {
+        return Err("The provided chat is empty".to_string());
+    }
+    let (model_name, n_ctx) = match try_load_caps_quickly_if_not_present(gcx.clone(), 0).await {
+        Ok(caps) => {
+            let caps_locked = caps.read().unwrap();
+            let model_name = caps_locked.code_chat_default_model.clone();
+            if let Some(model_rec) = caps_locked.code_completion_models.get(&strip_model_from_finetune(&model_name)) {
+                Ok((model_name, model_rec.n_ctx))
+            } else {
+                Err(format!(
+                    "Model '{}' not found. Server has these models: {:?}",
+                    model_name, caps_locked.code_completion_models.keys()
+                ))
+            }
+        },
+        Err(_) => Err("No caps available".to_string()),
+    }?;
+    let trajectory = compress_messages(gcx.clone(), &model_name, n_ctx, messages).await?;
     let goal = parse_goal(&trajectory)?;
 
     Ok((goal, trajectory))
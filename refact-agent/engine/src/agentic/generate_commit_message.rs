@@ -238,6 +238,7 @@ pub async fn generate_commit_message_by_diff(
         None,
         None,
         None,
+        None,
     )
         .await
         .map_err(|e| format!("Error: {}", e))?;
@@ -101,6 +101,7 @@ pub async fn generate_follow_up_message(
         None,
         None,
         None,
+        None,
     ).await?;
     let response = updated_messages
         .into_iter()
@@ -25,6 +25,8 @@ pub struct ParserError {
 
 pub trait AstLanguageParser: Send {
     fn parse(&mut self, code: &str, path: &PathBuf) -> Vec<AstSymbolInstanceArc>;
+    // Raw syntax tree, for callers that need more than symbols (ERROR nodes, block boundaries)
+    fn parse_tree(&mut self, code: &str) -> Option<tree_sitter::Tree>;
 }
 
 fn internal_error<E: Display>(err: E) -> ParserError {
@@ -8,7 +8,7 @@ use itertools::Itertools;
 
 use parking_lot::RwLock;
 use similar::DiffableStr;
-use tree_sitter::{Node, Parser, Range};
+use tree_sitter::{Node, Parser, Range, Tree};
 use tree_sitter_java::language;
 use uuid::Uuid;
 
@@ -794,6 +794,10 @@ impl JavaParser {
 }
 
 impl AstLanguageParser for JavaParser {
+    fn parse_tree(&mut self, code: &str) -> Option<Tree> {
+        self.parser.parse(code, None)
+    }
+
     fn parse(&mut self, code: &str, path: &PathBuf) -> Vec<AstSymbolInstanceArc> {
         let tree = self.parser.parse(code, None).unwrap();
         let symbols = self.parse_(&tree.root_node(), code, path);
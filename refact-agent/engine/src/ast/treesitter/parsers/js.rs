@@ -5,7 +5,7 @@ use std::sync::Arc;
 use parking_lot::RwLock;
 
 use similar::DiffableStr;
-use tree_sitter::{Node, Parser, Range};
+use tree_sitter::{Node, Parser, Range, Tree};
 use tree_sitter_javascript::language;
 use uuid::Uuid;
 
@@ -788,6 +788,10 @@ impl JSParser {
 }
 
 impl AstLanguageParser for JSParser {
+    fn parse_tree(&mut self, code: &str) -> Option<Tree> {
+        self.parser.parse(code, None)
+    }
+
     fn parse(&mut self, code: &str, path: &PathBuf) -> Vec<AstSymbolInstanceArc> {
         let tree = self.parser.parse(code, None).unwrap();
         let symbols = self.parse_(&tree.root_node(), code, path);
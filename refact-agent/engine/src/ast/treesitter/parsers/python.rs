@@ -7,7 +7,7 @@ use std::sync::Arc;
 use itertools::Itertools;
 use parking_lot::RwLock;
 use similar::DiffableStr;
-use tree_sitter::{Node, Parser, Point, Range};
+use tree_sitter::{Node, Parser, Point, Range, Tree};
 use tree_sitter_python::language;
 use uuid::Uuid;
 
@@ -942,6 +942,10 @@ impl SkeletonFormatter for PythonSkeletonFormatter {
 }
 
 impl AstLanguageParser for PythonParser {
+    fn parse_tree(&mut self, code: &str) -> Option<Tree> {
+        self.parser.parse(code, None)
+    }
+
     fn parse(&mut self, code: &str, path: &PathBuf) -> Vec<AstSymbolInstanceArc> {
         let tree = self.parser.parse(code, None).unwrap();
         let symbols = self.parse_(&tree.root_node(), code, path);
@@ -4,7 +4,7 @@ use std::sync::Arc;
 use parking_lot::RwLock;
 
 use similar::DiffableStr;
-use tree_sitter::{Node, Parser, Point, Range};
+use tree_sitter::{Node, Parser, Point, Range, Tree};
 use tree_sitter_rust::language;
 use uuid::Uuid;
 
@@ -1004,6 +1004,10 @@ impl RustParser {
 }
 
 impl AstLanguageParser for RustParser {
+    fn parse_tree(&mut self, code: &str) -> Option<Tree> {
+        self.parser.parse(code, None)
+    }
+
     fn parse(&mut self, code: &str, path: &PathBuf) -> Vec<AstSymbolInstanceArc> {
         let tree = self.parser.parse(code, None).unwrap();
         let parent_guid = get_guid();
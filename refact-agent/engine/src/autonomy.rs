@@ -3,16 +3,27 @@ use std::time::SystemTime;
 use tokio::sync::{RwLock as ARwLock, Mutex as AMutex};
 use indexmap::IndexSet;
 
-use crate::global_context::GlobalContext;
+use crate::global_context::{GlobalContext, try_load_caps_quickly_if_not_present};
 use crate::agent_db::db_structs::{CThread, CMessage};
 use crate::agent_db::chore_pubsub_sleeping_procedure;
 use crate::agent_db::db_cthread::CThreadSubscription;
+use crate::agent_db::db_spend::{budget_exceeded, spend_record};
+use crate::caps::{BudgetOnExceed, Budgets, usage_cost_usd};
 use crate::call_validation::{ChatContent, ChatMessage, ChatUsage};
 use crate::at_commands::at_commands::AtCommandsContext;
 use crate::subchat::subchat_single;
 
 const SLEEP_IF_NO_WORK_SEC: u64 = 10;
 const LOCK_TOO_OLD_SEC: f64 = 600.0;
+const OVER_BUDGET_RECHECK_SEC: u64 = 60;
+// cthread_error of a thread paused by the budget, the thread resumes by itself when the budget allows it again
+const PAUSED_ERROR_PREFIX: &str = "paused: ";
+
+enum JobLookup {
+    DontComeBack,
+    Busy,
+    OverBudget,  // paused, look again later, the budget for the day or a raised limit might allow it
+}
 
 
 pub async fn look_for_a_job(
@@ -24,6 +35,8 @@ pub async fn look_for_a_job(
     let cdb = gcx.read().await.chore_db.clone();
     let lite_arc = cdb.lock().lite.clone();
 
+    let mut over_budget_cthread_ids: IndexSet<String> = IndexSet::new();
+    let mut over_budget_checked_ts = std::time::Instant::now();
     let (mut might_work_on_cthread_id, mut last_pubsub_id) = {
         let lite = cdb.lock().lite.clone();
         // intentional unwrap(), it's better to crash quickly than continue with a non-functioning thread
@@ -56,15 +69,23 @@ pub async fn look_for_a_job(
         might_work_on_cthread_id.extend(updated_cthread_ids.into_iter());
         for deleted_id in deleted_cthread_ids {
             might_work_on_cthread_id.remove(&deleted_id);
+            over_budget_cthread_ids.remove(&deleted_id);
+        }
+        if over_budget_checked_ts.elapsed().as_secs() >= OVER_BUDGET_RECHECK_SEC {
+            might_work_on_cthread_id.extend(over_budget_cthread_ids.drain(..));
+            over_budget_checked_ts = std::time::Instant::now();
         }
 
         while let Some(cthread_id) = might_work_on_cthread_id.iter().next().cloned() {
             match look_if_the_job_for_me(gcx.clone(), &worker_name, &cthread_id).await {
-                Ok(lock_success) => {
-                    if lock_success {
-                        might_work_on_cthread_id.remove(&cthread_id);
-                    }
+                Ok(JobLookup::DontComeBack) => {
+                    might_work_on_cthread_id.remove(&cthread_id);
                 }
+                Ok(JobLookup::OverBudget) => {
+                    might_work_on_cthread_id.remove(&cthread_id);
+                    over_budget_cthread_ids.insert(cthread_id);
+                }
+                Ok(JobLookup::Busy) => {}
                 Err(e) => {
                     tracing::error!("{} cannot work on {}: {}", worker_name, cthread_id, e);
                     might_work_on_cthread_id.remove(&cthread_id);
@@ -79,10 +100,14 @@ async fn look_if_the_job_for_me(
     gcx: Arc<ARwLock<GlobalContext>>,
     worker_name: &String,
     cthread_id: &String,
-) -> Result<bool, String> {
+) -> Result<JobLookup, String> {
     let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
     let cdb = gcx.read().await.chore_db.clone();
     let lite_arc = cdb.lock().lite.clone();
+    let budgets = match try_load_caps_quickly_if_not_present(gcx.clone(), 0).await {
+        Ok(caps) => caps.read().unwrap().budgets.clone(),
+        Err(_) => Budgets::default(),
+    };
     let (cthread_rec, cmessages) = {
         let mut conn = lite_arc.lock();
         let tx = conn.transaction().map_err(|e| e.to_string())?;
@@ -105,7 +130,7 @@ async fn look_if_the_job_for_me(
         let busy = !cthread_rec.cthread_locked_by.is_empty() && cthread_rec.cthread_locked_ts + LOCK_TOO_OLD_SEC > now;
         if busy {
             tracing::info!("{} {} busy", worker_name, cthread_id);
-            return Ok(false);
+            return Ok(JobLookup::Busy);
         }
 
         let last_message_is_user = cmessages.last().map_or(false, |cmsg| {
@@ -114,13 +139,36 @@ async fn look_if_the_job_for_me(
         });
 
         tracing::info!("{} {} last_message_is_user={} cthread_rec.cthread_error={:?}", worker_name, cthread_id, last_message_is_user, cthread_rec.cthread_error);
-        if !last_message_is_user || !cthread_rec.cthread_error.is_empty() {
-            return Ok(true);  // true means don't come back to it again
+        let paused = cthread_rec.cthread_error.starts_with(PAUSED_ERROR_PREFIX);
+        if !last_message_is_user || (!cthread_rec.cthread_error.is_empty() && !paused) {
+            return Ok(JobLookup::DontComeBack);
         }
 
+        if let Some(reason) = budget_exceeded(&tx, &budgets, cthread_id)? {
+            tracing::warn!("{} {} {}, on_exceed={:?}", worker_name, cthread_id, reason, budgets.on_exceed);
+            let pause = budgets.on_exceed == BudgetOnExceed::Pause;
+            let cthread_error = if pause { format!("{}{}", PAUSED_ERROR_PREFIX, reason) } else { reason };
+            if cthread_rec.cthread_error != cthread_error {
+                cthread_rec.cthread_error = cthread_error;
+                cthread_set_and_push(&tx, &cthread_rec)?;
+                tx.commit().map_err(|e| e.to_string())?;
+                cdb.lock().chore_sleeping_point.notify_waiters();
+            }
+            return Ok(if pause { JobLookup::OverBudget } else { JobLookup::DontComeBack });
+        }
+
+        if paused {
+            // the budget was raised, or the day is over
+            tracing::info!("{} {} resumed after a budget pause", worker_name, cthread_id);
+            cthread_rec.cthread_error = String::new();
+        }
         cthread_rec.cthread_locked_by = worker_name.clone();
         cthread_rec.cthread_locked_ts = now;
-        crate::agent_db::db_cthread::cthread_set_lowlevel(&tx, &cthread_rec)?;
+        if paused {
+            cthread_set_and_push(&tx, &cthread_rec)?;
+        } else {
+            crate::agent_db::db_cthread::cthread_set_lowlevel(&tx, &cthread_rec)?;
+        }
         tx.commit().map_err(|e| e.to_string())?;
         (cthread_rec, cmessages)
     };
@@ -144,7 +192,7 @@ async fn look_if_the_job_for_me(
     tracing::info!("{} {} /autonomous work\n{}", worker_name, cthread_id, apply_json);
     crate::agent_db::db_cthread::cthread_apply_json(cdb, apply_json)?;
 
-    Ok(true)  // true means don't come back to it again
+    Ok(JobLookup::DontComeBack)
 }
 
 async fn do_the_job(
@@ -202,6 +250,7 @@ async fn do_the_job(
         false,
     ).await));
     let log_prefix = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let input_messages_n = messages.len();
     let chat_response_msgs = subchat_single(
         ccx.clone(),
         cthread_rec.cthread_model.as_str(),
@@ -217,9 +266,11 @@ async fn do_the_job(
         Some(&mut usage),
         Some(cthread_rec.cthread_id.clone()),
         Some(format!("{log_prefix}-chore-job")),
+        None,
     ).await.map_err(|e| format!("Error: {}", e))?;
 
     let choice0: Vec<ChatMessage> = chat_response_msgs[0].clone();
+    let caps_mb = try_load_caps_quickly_if_not_present(gcx.clone(), 0).await.ok();
     {
         let mut lite_locked = lite.lock();
         let tx = lite_locked.transaction().map_err(|e| e.to_string())?;
@@ -241,6 +292,11 @@ async fn do_the_job(
                 cmessage_json: serde_json::to_string(chat_message).map_err(|e| format!("{}", e))?,
             };
             crate::agent_db::db_cmessage::cmessage_set(&tx, cmessage);
+            // the answer comes together with the history, earlier turns are already paid for
+            if let Some(u) = chat_message.usage.as_ref().filter(|_| i >= input_messages_n) {
+                let usd = caps_mb.as_ref().map_or(0.0, |caps| usage_cost_usd(&caps.read().unwrap(), &cthread_rec.cthread_model, u));
+                spend_record(&tx, &cthread_rec.cthread_id, &cthread_rec.cthread_model, u, usd)?;
+            }
         }
         tx.commit().map_err(|e| e.to_string())?;
     }
@@ -248,6 +304,15 @@ async fn do_the_job(
     Ok(serde_json::json!({}))
 }
 
+fn cthread_set_and_push(tx: &rusqlite::Transaction, cthread_rec: &CThread) -> Result<(), String> {
+    crate::agent_db::db_cthread::cthread_set_lowlevel(tx, cthread_rec)?;
+    crate::agent_db::chore_pubub_push(tx, "cthread", "update", &serde_json::json!({
+        "cthread_id": cthread_rec.cthread_id,
+        "cthread_belongs_to_chore_event_id": cthread_rec.cthread_belongs_to_chore_event_id,
+    }));
+    Ok(())
+}
+
 pub async fn look_for_a_job_start_tasks(
     gcx: Arc<ARwLock<GlobalContext>>,
 ) -> Vec<tokio::task::JoinHandle<()>> {
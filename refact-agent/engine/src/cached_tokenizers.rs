@@ -5,6 +5,7 @@ use std::time::Duration;
 use tokio::sync::RwLock as ARwLock;
 use tokio::sync::Mutex as AMutex;
 use tokenizers::Tokenizer;
+use serde_json::Value;
 use reqwest::header::AUTHORIZATION;
 use reqwest::Response;
 use tracing::{error, info};
@@ -157,3 +158,113 @@ pub async fn cached_tokenizer(
     global_context.write().await.tokenizer_map.insert(model_name.clone(), arc.clone());
     Ok(arc)
 }
+
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatTemplateConfig {
+    pub chat_template: String,
+    pub tool_use_template: Option<String>,  // some models ship a separate template for requests with tools
+    pub bos_token: String,
+    pub eos_token: String,
+}
+
+// Reads chat_template, bos_token and eos_token from HuggingFace tokenizer_config.json
+pub fn parse_tokenizer_config(config: &Value) -> Option<ChatTemplateConfig> {
+    let special_token = |key: &str| -> String {
+        match config.get(key) {
+            Some(Value::String(s)) => s.clone(),
+            Some(v) => v.get("content").and_then(|c| c.as_str()).unwrap_or("").to_string(),
+            None => "".to_string(),
+        }
+    };
+    let (chat_template, tool_use_template) = match config.get("chat_template")? {
+        Value::String(template) => (template.clone(), None),
+        Value::Array(templates) => {
+            let by_name = |name: &str| templates.iter()
+                .find(|t| t.get("name").and_then(|n| n.as_str()) == Some(name))
+                .and_then(|t| t.get("template")).and_then(|t| t.as_str()).map(|t| t.to_string());
+            (by_name("default")?, by_name("tool_use"))
+        },
+        _ => return None,
+    };
+    Some(ChatTemplateConfig {
+        chat_template,
+        tool_use_template,
+        bos_token: special_token("bos_token"),
+        eos_token: special_token("eos_token"),
+    })
+}
+
+const TOKENIZER_CONFIG_MAX_AGE: Duration = Duration::from_secs(24 * 3600);
+
+// tokenizer_config.json lives next to tokenizer.json, None if the model doesn't have a chat template there
+pub async fn cached_chat_template(
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    global_context: Arc<ARwLock<GlobalContext>>,
+    model_name: String,
+) -> Option<ChatTemplateConfig> {
+    let model_name = strip_model_from_finetune(&model_name);
+    let (client2, cache_dir, api_key) = {
+        let cx_locked = global_context.read().await;
+        (cx_locked.http_client.clone(), cx_locked.cache_dir.clone(), cx_locked.cmdline.api_key.clone())
+    };
+    let http_path = {
+        let caps_locked = caps.read().unwrap();
+        let rewritten_model_name = caps_locked.tokenizer_rewrite_path.get(&model_name).unwrap_or(&model_name);
+        caps_locked.tokenizer_path_template.replace("$MODEL", rewritten_model_name)
+    };
+    let http_path = http_path.strip_suffix("tokenizer.json")?.to_string() + "tokenizer_config.json";
+    let to = std::path::PathBuf::from(cache_dir).join("tokenizers").join(model_name.clone()).join("tokenizer_config.json");
+    // unlike tokenizer.json, chat templates get fixed upstream, so an old copy is downloaded again
+    let is_fresh = tokio::fs::metadata(&to).await.ok()
+        .and_then(|m| m.modified().ok())
+        .and_then(|modified| modified.elapsed().ok())
+        .map(|age| age < TOKENIZER_CONFIG_MAX_AGE)
+        .unwrap_or(false);
+    if !is_fresh {
+        let tmp = to.with_extension("json.download");
+        let _ = tokio::fs::remove_file(&tmp).await;
+        let downloaded = match download_tokenizer_file(&client2, http_path.as_str(), api_key, &tmp).await {
+            Ok(()) => tokio::fs::rename(&tmp, &to).await.map_err(|e| format!("failed to move {}: {}", tmp.display(), e)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = downloaded {
+            error!("failed to download tokenizer_config.json for {}: {}", model_name, e);
+            if !to.exists() {
+                return None;
+            }
+            // keep using the old copy, and don't try again on every chat request
+            if let Ok(old_copy) = tokio::fs::read(&to).await {
+                let _ = tokio::fs::write(&to, old_copy).await;
+            }
+        }
+    }
+    let text = tokio::fs::read_to_string(&to).await.map_err(|e| error!("failed to read {}: {}", to.display(), e)).ok()?;
+    let config: Value = serde_json::from_str(&text).map_err(|e| error!("failed to parse {}: {}", to.display(), e)).ok()?;
+    parse_tokenizer_config(&config)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_tokenizer_config() {
+        let config = json!({
+            "bos_token": {"content": "<s>", "lstrip": false},
+            "eos_token": "</s>",
+            "chat_template": [
+                {"name": "default", "template": "{{ messages }}"},
+                {"name": "tool_use", "template": "{{ tools }}"}
+            ]
+        });
+        let parsed = parse_tokenizer_config(&config).unwrap();
+        assert_eq!(parsed.chat_template, "{{ messages }}");
+        assert_eq!(parsed.tool_use_template, Some("{{ tools }}".to_string()));
+        assert_eq!(parsed.bos_token, "<s>");
+        assert_eq!(parsed.eos_token, "</s>");
+        assert!(parse_tokenizer_config(&json!({"eos_token": "</s>"})).is_none());
+    }
+}
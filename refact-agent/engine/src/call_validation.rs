@@ -48,7 +48,9 @@ pub struct SamplingParameters {
     #[serde(default)]
     pub stop: Vec<String>,
     pub n: Option<usize>,
-    pub reasoning_effort: Option<ReasoningEffort>
+    pub reasoning_effort: Option<ReasoningEffort>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,  // openai style {"type": "json_schema", "json_schema": {...}}, see structured_output.rs
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -66,11 +68,62 @@ pub struct CodeCompletionPost {
     pub no_cache: bool,
     #[serde(default)]
     pub use_ast: bool,
-    #[allow(dead_code)]
     #[serde(default)]
     pub use_vecdb: bool,
     #[serde(default)]
     pub rag_tokens_n: usize,
+    #[serde(default)]
+    pub client: String,  // a newer request from the same client for the same file cancels the older one
+    #[serde(default)]
+    pub comment_to_code: bool,  // implement the instruction comment above the cursor using the chat model
+    #[serde(skip)]
+    pub ab_arm: String,  // "a" or "b" when code_completion_ab_test picked the model, set by the router
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CodeCompletionBatchPost {
+    pub sources: HashMap<String, String>,
+    pub cursors: Vec<CursorPosition>,
+    #[serde(default)]
+    pub multiline: bool,
+    #[serde(default)]
+    pub parameters: SamplingParameters,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub scratchpad: String,
+    #[serde(default)]
+    pub no_cache: bool,
+    #[serde(default)]
+    pub use_ast: bool,
+    #[serde(default)]
+    pub use_vecdb: bool,
+    #[serde(default)]
+    pub rag_tokens_n: usize,
+    #[serde(default)]
+    pub client: String,
+}
+
+impl CodeCompletionBatchPost {
+    pub fn post_for_cursor(&self, cursor: &CursorPosition) -> CodeCompletionPost {
+        let sources = self.sources.get(&cursor.file)
+            .map(|text| HashMap::from([(cursor.file.clone(), text.clone())]))
+            .unwrap_or_default();
+        CodeCompletionPost {
+            inputs: CodeCompletionInputs { sources, cursor: cursor.clone(), multiline: self.multiline },
+            parameters: self.parameters.clone(),
+            model: self.model.clone(),
+            scratchpad: self.scratchpad.clone(),
+            stream: false,
+            no_cache: self.no_cache,
+            use_ast: self.use_ast,
+            use_vecdb: self.use_vecdb,
+            rag_tokens_n: self.rag_tokens_n,
+            client: self.client.clone(),
+            comment_to_code: false,
+            ab_arm: "".to_string(),
+        }
+    }
 }
 
 pub fn code_completion_post_validate(code_completion_post: CodeCompletionPost) -> axum::response::Result<(), ScratchError> {
@@ -147,6 +200,12 @@ pub struct ChatUsage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,   // TODO: remove (can produce self-contradictory data when prompt+completion != total)
+    #[serde(default)]
+    pub cache_read_tokens: usize,      // part of prompt_tokens
+    #[serde(default)]
+    pub cache_creation_tokens: usize,  // part of prompt_tokens
+    #[serde(default)]
+    pub retries_n: usize,              // failed attempts before this answer, including switches to fallback models
 }
 
 #[derive(Debug, Serialize, Clone, Default)]
@@ -163,6 +222,10 @@ pub struct ChatMessage {
     pub usage: Option<ChatUsage>,
     #[serde(default, skip_serializing_if="Vec::is_empty")]
     pub checkpoints: Vec<Checkpoint>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub reasoning_content: Option<String>,  // thinking of a reasoning model, shown apart from the content
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub thinking_blocks: Option<Vec<serde_json::Value>>,  // opaque blocks with signatures, go back to the provider unchanged
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -199,6 +262,10 @@ pub struct ChatPost {
     #[serde(default)]
     pub checkpoints_enabled: bool,
     #[serde(default)]
+    pub response_format: Option<serde_json::Value>,
+    #[serde(default)]
+    pub history_summarization: bool,  // summarize older turns instead of dropping them, see chat_utils_summarize_history.rs
+    #[serde(default)]
     pub only_deterministic_messages: bool,  // means don't sample from the model
     #[serde(default)]
     pub subchat_tool_parameters: IndexMap<String, SubchatParameters>, // tool_name: {model, allowed_context, temperature}
@@ -338,7 +405,8 @@ mod tests {
                 top_p: None,
                 stop: vec![],
                 n: None,
-                reasoning_effort: None
+                reasoning_effort: None,
+                response_format: None,
             },
             model: "".to_string(),
             scratchpad: "".to_string(),
@@ -347,6 +415,9 @@ mod tests {
             use_ast: true,
             use_vecdb: true,
             rag_tokens_n: 0,
+            client: "".to_string(),
+            comment_to_code: false,
+            ab_arm: "".to_string(),
         };
         assert!(code_completion_post_validate(post).is_ok());
     }
@@ -369,7 +440,8 @@ mod tests {
                 top_p: None,
                 stop: vec![],
                 n: None,
-                reasoning_effort: None
+                reasoning_effort: None,
+                response_format: None,
             },
             model: "".to_string(),
             scratchpad: "".to_string(),
@@ -378,6 +450,9 @@ mod tests {
             use_ast: true,
             use_vecdb: true,
             rag_tokens_n: 0,
+            client: "".to_string(),
+            comment_to_code: false,
+            ab_arm: "".to_string(),
         };
         assert!(code_completion_post_validate(post).is_ok());
     }
@@ -400,7 +475,8 @@ mod tests {
                 top_p: None,
                 stop: vec![],
                 n: None,
-                reasoning_effort: None
+                reasoning_effort: None,
+                response_format: None,
             },
             model: "".to_string(),
             scratchpad: "".to_string(),
@@ -409,6 +485,9 @@ mod tests {
             use_ast: true,
             use_vecdb: true,
             rag_tokens_n: 0,
+            client: "".to_string(),
+            comment_to_code: false,
+            ab_arm: "".to_string(),
         };
         assert!(code_completion_post_validate(post).is_err());
     }
@@ -431,7 +510,8 @@ mod tests {
                 top_p: None,
                 stop: vec![],
                 n: None,
-                reasoning_effort: None
+                reasoning_effort: None,
+                response_format: None,
             },
             model: "".to_string(),
             scratchpad: "".to_string(),
@@ -440,6 +520,9 @@ mod tests {
             use_ast: true,
             use_vecdb: true,
             rag_tokens_n: 0,
+            client: "".to_string(),
+            comment_to_code: false,
+            ab_arm: "".to_string(),
         };
         assert!(code_completion_post_validate(post).is_err());
     }
@@ -12,6 +12,7 @@ use tokio::sync::RwLock as ARwLock;
 use url::Url;
 use tracing::{error, info, warn};
 
+use crate::call_validation::ChatUsage;
 use crate::custom_error::ScratchError;
 use crate::global_context::{try_load_caps_quickly_if_not_present, GlobalContext};
 use crate::known_models::KNOWN_MODELS;
@@ -39,6 +40,37 @@ pub struct ModelRecord {
     pub supports_clicks: bool,
     #[serde(default)]
     pub supports_agent: bool,
+    #[serde(default)]
+    pub provider: String,  // key in caps.providers, empty means the model uses the top level endpoints
+    #[serde(default)]
+    pub chat_template: String,  // jinja template for CHAT-JINJA, empty means take it from tokenizer_config.json
+    #[serde(default)]
+    pub repo_name_token: String,  // FIM-REPO special tokens, like <|repo_name|> and <|file_sep|> for qwen2.5 coder
+    #[serde(default)]
+    pub file_sep_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProviderRecord {
+    #[serde(default)]
+    pub chat_endpoint: String,
+    #[serde(default)]
+    pub completion_endpoint: String,
+    #[serde(default)]
+    pub embedding_endpoint: String,
+    #[serde(default)]
+    pub endpoint_style: String,  // "openai" if empty
+    #[serde(default)]
+    pub api_key: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,  // values can be "$ENV_VAR" like api_key
+    #[serde(default)]
+    pub models: Vec<String>,
+    // records for models that are not in known_models.json, they are served by this provider and need no "models" entry
+    #[serde(default)]
+    pub code_completion_models: IndexMap<String, ModelRecord>,
+    #[serde(default)]
+    pub code_chat_models: IndexMap<String, ModelRecord>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -74,6 +106,66 @@ fn default_endpoint_embeddings_style() -> String {
 
 fn default_support_metadata() -> bool { false }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    #[serde(default = "default_retry_max_retries")]
+    pub max_retries: usize,  // per model, then the next fallback model is tried
+    #[serde(default = "default_retry_backoff_initial_ms")]
+    pub backoff_initial_ms: u64,
+    #[serde(default = "default_retry_backoff_max_ms")]
+    pub backoff_max_ms: u64,  // also caps Retry-After from the server
+}
+
+fn default_retry_max_retries() -> usize { 2 }
+
+fn default_retry_backoff_initial_ms() -> u64 { 500 }
+
+fn default_retry_backoff_max_ms() -> u64 { 20_000 }
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: default_retry_max_retries(),
+            backoff_initial_ms: default_retry_backoff_initial_ms(),
+            backoff_max_ms: default_retry_backoff_max_ms(),
+        }
+    }
+}
+
+// USD per 1M tokens, cache_* fall back to the prompt price when not set
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ModelPrice {
+    #[serde(default)]
+    pub prompt: f64,
+    #[serde(default)]
+    pub generated: f64,
+    #[serde(default)]
+    pub cache_read: Option<f64>,
+    #[serde(default)]
+    pub cache_creation: Option<f64>,
+}
+
+// USD, zero means no limit
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Budgets {
+    #[serde(default)]
+    pub per_chat: f64,
+    #[serde(default)]
+    pub per_chore: f64,
+    #[serde(default)]
+    pub per_day: f64,
+    #[serde(default)]
+    pub on_exceed: BudgetOnExceed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetOnExceed {
+    #[default]
+    Pause,   // cthread_error is "paused: <reason>" until the budget allows it again, then the thread continues
+    Refuse,  // the reason goes into cthread_error, the thread is done
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CodeAssistantCaps {
     pub cloud_name: String,
@@ -129,6 +221,18 @@ pub struct CodeAssistantCaps {
     #[serde(default)]
     pub models_dict_patch: HashMap<String, ModelRecord>,
     #[serde(default)]
+    pub providers: IndexMap<String, ProviderRecord>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub fallback_models: HashMap<String, Vec<String>>,  // primary model -> ordered fallbacks, used on 429/5xx/timeouts
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    #[serde(default)]
+    pub budgets: Budgets,
+    #[serde(default)]
+    pub code_completion_ab_test: Vec<String>,  // two completion models, requests without a model go to either at random
+    #[serde(default)]
     #[serde(alias = "default_embeddings_model")]
     pub embedding_model: String,
     #[serde(default)]
@@ -199,12 +303,26 @@ fn load_caps_from_buf(
     if !r1.multiline_code_completion_default_model.is_empty() && !r1.running_models.contains(&r1.multiline_code_completion_default_model) {
         r1.running_models.push(r1.multiline_code_completion_default_model.clone());
     }
+    for model in r1.code_completion_ab_test.clone() {
+        if !r1.running_models.contains(&model) {
+            r1.running_models.push(model);
+        }
+    }
     if !r1.embedding_model.is_empty() && !r1.running_models.contains(&r1.embedding_model) {
         r1.running_models.push(r1.embedding_model.clone());
     }
+    let more_models: Vec<String> = r1.providers.values().flat_map(|p| p.models.iter().cloned())
+        .chain(r1.fallback_models.values().flat_map(|v| v.iter().cloned()))
+        .collect();
+    for m in more_models {
+        if !r1.running_models.contains(&m) {
+            r1.running_models.push(m);
+        }
+    }
 
     _inherit_r1_from_r0(&mut r1, &r0);
     apply_models_dict_patch(&mut r1);
+    apply_providers(&mut r1, &caps_url)?;
     r1.endpoint_template = relative_to_full_url(&caps_url, &r1.endpoint_template)?;
     r1.endpoint_chat_passthrough = relative_to_full_url(&caps_url, &r1.endpoint_chat_passthrough)?;
     if r1.endpoint_chat_passthrough.is_empty() {
@@ -249,24 +367,29 @@ macro_rules! get_api_key_macro {
     }};
 }
 
+// "$NAME" means the value of the environment variable NAME, anything else is taken as is
+pub fn resolve_env_var(value: &str) -> Result<String, String> {
+    match value.strip_prefix("$") {
+        Some(env_var_name) => std::env::var(env_var_name)
+            .map_err(|e| format!("tried to read env var {}, but failed: {}\nTry editing ~/.config/refact/bring-your-own-key.yaml", env_var_name, e)),
+        None => Ok(value.to_string()),
+    }
+}
+
 pub async fn get_api_key(
     gcx: Arc<ARwLock<GlobalContext>>,
     use_this_fall_back_to_default_if_empty: String,
 ) -> String {
     let gcx_locked = gcx.write().await;
     if use_this_fall_back_to_default_if_empty.is_empty() {
-        gcx_locked.cmdline.api_key.clone()
-    } else if use_this_fall_back_to_default_if_empty.starts_with("$") {
-        let env_var_name = &use_this_fall_back_to_default_if_empty[1..];
-        match std::env::var(env_var_name) {
-            Ok(env_value) => env_value,
-            Err(e) => {
-                error!("tried to read API key from env var {}, but failed: {}\nTry editing ~/.config/refact/bring-your-own-key.yaml", env_var_name, e);
-                gcx_locked.cmdline.api_key.clone()
-            }
+        return gcx_locked.cmdline.api_key.clone();
+    }
+    match resolve_env_var(&use_this_fall_back_to_default_if_empty) {
+        Ok(api_key) => api_key,
+        Err(e) => {
+            error!("API key: {}", e);
+            gcx_locked.cmdline.api_key.clone()
         }
-    } else {
-        use_this_fall_back_to_default_if_empty
     }
 }
 
@@ -417,6 +540,12 @@ fn apply_models_dict_patch(caps: &mut CodeAssistantCaps) {
         if rec_patched.supports_tools {
             rec.supports_tools = rec_patched.supports_tools;
         }
+        if !rec_patched.provider.is_empty() {
+            rec.provider = rec_patched.provider.clone();
+        }
+        if !rec_patched.chat_template.is_empty() {
+            rec.chat_template = rec_patched.chat_template.clone();
+        }
     }
 
     for (model, rec_patched) in caps.models_dict_patch.iter() {
@@ -429,6 +558,123 @@ fn apply_models_dict_patch(caps: &mut CodeAssistantCaps) {
     }
 }
 
+fn apply_providers(caps: &mut CodeAssistantCaps, caps_url: &String) -> Result<(), String> {
+    for (provider_name, provider) in caps.providers.iter_mut() {
+        provider.chat_endpoint = relative_to_full_url(caps_url, &provider.chat_endpoint)?;
+        provider.completion_endpoint = relative_to_full_url(caps_url, &provider.completion_endpoint)?;
+        provider.embedding_endpoint = relative_to_full_url(caps_url, &provider.embedding_endpoint)?;
+        for (model, rec) in provider.code_completion_models.iter() {
+            caps.code_completion_models.insert(model.clone(), ModelRecord { provider: provider_name.clone(), ..rec.clone() });
+        }
+        for (model, rec) in provider.code_chat_models.iter() {
+            caps.code_chat_models.insert(model.clone(), ModelRecord { provider: provider_name.clone(), ..rec.clone() });
+        }
+        for model in provider.models.iter() {
+            if let Some(rec) = caps.code_completion_models.get_mut(model) {
+                rec.provider = provider_name.clone();
+            }
+            if let Some(rec) = caps.code_chat_models.get_mut(model) {
+                rec.provider = provider_name.clone();
+            }
+        }
+    }
+    for rec in caps.code_completion_models.values().chain(caps.code_chat_models.values()) {
+        if !rec.provider.is_empty() && !caps.providers.contains_key(&rec.provider) {
+            return Err(format!("model refers to provider '{}', but there is no such provider in caps", rec.provider));
+        }
+    }
+    // embeddings have no ModelRecord, the provider simply overrides the top level embedding fields
+    let embedding_provider = caps.providers.values()
+        .find(|p| p.models.contains(&caps.embedding_model))
+        .cloned();
+    if let Some(provider) = embedding_provider {
+        if !provider.embedding_endpoint.is_empty() {
+            caps.endpoint_embeddings_template = provider.embedding_endpoint.clone();
+        }
+        if !provider.endpoint_style.is_empty() {
+            caps.endpoint_embeddings_style = provider.endpoint_style.clone();
+        }
+        caps.embedding_apikey = provider.api_key.clone();
+    }
+    Ok(())
+}
+
+pub fn model_provider(caps: &CodeAssistantCaps, model_name: &String) -> Option<ProviderRecord> {
+    let no_finetune = strip_model_from_finetune(model_name);
+    let rec = caps.code_chat_models.get(model_name)
+        .or_else(|| caps.code_completion_models.get(model_name))
+        .or_else(|| caps.code_chat_models.get(&no_finetune))
+        .or_else(|| caps.code_completion_models.get(&no_finetune))?;
+    caps.providers.get(&rec.provider).cloned()
+}
+
+// the provider of a chat model must have a chat endpoint, top level endpoints are often empty in a caps with providers
+pub fn provider_endpoint(provider: &ProviderRecord, is_chat: bool) -> String {
+    if is_chat { provider.chat_endpoint.clone() } else { provider.completion_endpoint.clone() }
+}
+
+fn model_record<'a>(models: &'a IndexMap<String, ModelRecord>, model_name: &String) -> Option<&'a ModelRecord> {
+    models.get(model_name).or_else(|| models.get(&strip_model_from_finetune(model_name)))
+}
+
+fn same_prompt_format(a: &ModelRecord, b: &ModelRecord) -> bool {
+    a.supports_scratchpads == b.supports_scratchpads
+        && a.default_scratchpad == b.default_scratchpad
+        && a.chat_template == b.chat_template
+        && a.repo_name_token == b.repo_name_token
+        && a.file_sep_token == b.file_sep_token
+}
+
+pub fn models_to_try(caps: &CodeAssistantCaps, model_name: &String) -> Vec<String> {
+    // the prompt is built once by the primary model's scratchpad, so a fallback must be of the same kind
+    // and take the same prompt: same scratchpads with the same FIM tokens, chat template and repo tokens
+    let models = if caps.code_chat_models.contains_key(model_name) { &caps.code_chat_models } else { &caps.code_completion_models };
+    let primary_rec = model_record(models, model_name);
+    let fallbacks = caps.fallback_models.get(model_name)
+        .or_else(|| caps.fallback_models.get(&strip_model_from_finetune(model_name)))
+        .cloned()
+        .unwrap_or_default();
+    let mut result = vec![model_name.clone()];
+    for m in fallbacks {
+        if result.contains(&m) {
+            continue;
+        }
+        let fallback_rec = match model_record(models, &m) {
+            Some(rec) => rec,
+            None => {
+                warn!("fallback model {:?} for {:?} is not a model of the same kind, skipped", m, model_name);
+                continue;
+            }
+        };
+        if primary_rec.map_or(false, |rec| !same_prompt_format(rec, fallback_rec)) {
+            warn!("fallback model {:?} for {:?} expects a different prompt format, skipped", m, model_name);
+            continue;
+        }
+        result.push(m);
+    }
+    result
+}
+
+pub fn usage_cost_usd(caps: &CodeAssistantCaps, model_name: &String, usage: &ChatUsage) -> f64 {
+    let price = match caps.prices.get(model_name).or_else(|| caps.prices.get(&strip_model_from_finetune(model_name))) {
+        Some(price) => price,
+        None => return 0.0,
+    };
+    let cached = usage.cache_read_tokens + usage.cache_creation_tokens;
+    let cost = price.prompt * usage.prompt_tokens.saturating_sub(cached) as f64
+        + price.cache_read.unwrap_or(price.prompt) * usage.cache_read_tokens as f64
+        + price.cache_creation.unwrap_or(price.prompt) * usage.cache_creation_tokens as f64
+        + price.generated * usage.completion_tokens as f64;
+    cost / 1_000_000.0
+}
+
+pub fn chat_endpoint_style(caps: &CodeAssistantCaps, model_name: &String) -> String {
+    if let Some(provider) = model_provider(caps, model_name) {
+        return if provider.endpoint_style.is_empty() { "openai".to_string() } else { provider.endpoint_style };
+    }
+    if !caps.chat_endpoint_style.is_empty() { caps.chat_endpoint_style.clone() } else { caps.endpoint_style.clone() }
+}
+
 fn _inherit_r1_from_r0(
     r1: &mut CodeAssistantCaps,
     r0: &ModelsOnly,
@@ -467,16 +713,25 @@ pub fn which_model_to_use<'a>(
     models: &'a IndexMap<String, ModelRecord>,
     user_wants_model: &str,
     default_model: &str,
+    fallback_models: &HashMap<String, Vec<String>>,
 ) -> Result<(String, &'a ModelRecord), String> {
     let mut take_this_one = default_model;
     if user_wants_model != "" {
         take_this_one = user_wants_model;
     }
     let no_finetune = strip_model_from_finetune(&take_this_one.to_string());
+    // a model without a record (not running, or its provider is gone) is replaced by the first fallback that has one
+    let fallback_mb = fallback_models.get(take_this_one).or_else(|| fallback_models.get(&no_finetune))
+        .and_then(|fallbacks| fallbacks.iter().find_map(|m| {
+            models.get(m).or_else(|| models.get(&strip_model_from_finetune(m))).map(|rec| (m, rec))
+        }));
     if let Some(model_rec) = models.get(&take_this_one.to_string()) {
         Ok((take_this_one.to_string(), model_rec))
     } else if let Some(model_rec) = models.get(&no_finetune) {
         Ok((take_this_one.to_string(), model_rec))
+    } else if let Some((fallback, model_rec)) = fallback_mb {
+        warn!("model {:?} not found, using its fallback {:?}", take_this_one, fallback);
+        Ok((fallback.clone(), model_rec))
     } else {
         Err(format!(
             "Model '{}' not found. Server has these models: {:?}",
@@ -557,9 +812,187 @@ running_models:   # all models mentioned in *_model are automatically running, b
   - gpt-4o-mini
   - gpt-4o
 
+# On 429/5xx/timeouts requests are retried with exponential backoff (honoring Retry-After), then fallback models are tried in order,
+# a fallback is skipped unless it takes the same prompt (same scratchpads, FIM tokens and chat template) as the primary model:
+# retry_policy:
+#   max_retries: 2
+#   backoff_initial_ms: 500
+#   backoff_max_ms: 20000
+# fallback_models:
+#   gpt-4o: [gpt-4o-mini]
+
+# Prices in USD per 1M tokens make the spend visible at /v1/spend, budgets (USD, 0 is no limit) stop autonomous
+# chats that cost too much: on_exceed "pause" sets cthread_error to "paused: <reason>" and the thread resumes when the budget allows it, "refuse"
+# stops it with the reason in cthread_error. Interactive chats get the reason as an error either way
+# prices:
+#   gpt-4o: {prompt: 2.5, generated: 10.0, cache_read: 1.25}
+#   gpt-4o-mini: {prompt: 0.15, generated: 0.6}
+# budgets:
+#   per_chat: 2.0
+#   per_chore: 10.0
+#   per_day: 20.0
+#   on_exceed: pause
+
+# Completions without an explicit model are split at random between two models, see the acceptance rate of each at /v1/completion-stats:
+# code_completion_ab_test: [bigcode/starcoder2-3b, qwen2.5/coder/1.5b/base]
+
+# To use several providers at once, give each one its own endpoints and key, and list the models it serves:
+# providers:
+#   llama_cpp:
+#     completion_endpoint: "http://localhost:8080/v1/completions"
+#     models: [bigcode/starcoder2-3b]
+#   anthropic:
+#     chat_endpoint: "https://api.anthropic.com/v1/messages"
+#     endpoint_style: "anthropic"
+#     api_key: "$ANTHROPIC_API_KEY"
+#     headers: {}                  # values can be "$ENV_VAR" too
+#     models: [claude-3-7-sonnet]
+#   openrouter:                     # models unknown to this binary need a record
+#     chat_endpoint: "https://openrouter.ai/api/v1/chat/completions"
+#     api_key: "$OPENROUTER_API_KEY"
+#     code_chat_models:
+#       deepseek/deepseek-chat: {n_ctx: 64000, supports_scratchpads: {PASSTHROUGH: {}}, default_scratchpad: PASSTHROUGH, supports_tools: true}
+
 # More examples https://github.com/smallcloudai/refact-lsp/tree/dev/bring_your_own_key
 
 # Refact sends basic telemetry (counters and errors), you can send it to a different address (a Refact self-hosting server is especially useful) or set to an empty string for no telemetry.
 # telemetry_basic_dest: <your-telemetry-address>             # default: https://www.smallcloud.ai/v1/telemetry-basic
 # telemetry_basic_retrieve_my_own: <your-telemetry-address>  # default: https://www.smallcloud.ai/v1/telemetry-retrieve-my-own-stats
-"#;
\ No newline at end of file
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_providers_route_per_model() {
+        let buf = r#"
+cloud_name: test
+chat_endpoint: "https://top-level.example.com/v1/chat/completions"
+chat_model: gpt-4o
+completion_model: bigcode/starcoder2-3b
+embedding_model: voyage-code-3
+providers:
+  llama_cpp:
+    completion_endpoint: "http://localhost:8080/v1/completions"
+    models: [bigcode/starcoder2-3b]
+  anthropic:
+    chat_endpoint: "https://api.anthropic.com/v1/messages"
+    endpoint_style: anthropic
+    api_key: "sk-ant"
+    headers: {"x-team": "abc"}
+    models: [claude-3-7-sonnet]
+  voyage:
+    embedding_endpoint: "https://api.voyageai.com/v1/embeddings"
+    api_key: "pa-123"
+    models: [voyage-code-3]
+"#.to_string();
+        let caps = load_caps_from_buf(&buf, &"/tmp/bring-your-own-key.yaml".to_string()).unwrap();
+        let caps_locked = caps.read().unwrap();
+        assert_eq!(caps_locked.code_chat_models["claude-3-7-sonnet"].provider, "anthropic");
+        assert_eq!(caps_locked.code_chat_models["gpt-4o"].provider, "");
+        assert_eq!(caps_locked.code_completion_models["bigcode/starcoder2-3b"].provider, "llama_cpp");
+
+        let anthropic = model_provider(&caps_locked, &"claude-3-7-sonnet".to_string()).unwrap();
+        assert_eq!(anthropic.endpoint_style, "anthropic");
+        assert_eq!(anthropic.headers["x-team"], "abc");
+        assert!(model_provider(&caps_locked, &"gpt-4o".to_string()).is_none());
+
+        assert_eq!(caps_locked.endpoint_embeddings_template, "https://api.voyageai.com/v1/embeddings");
+        assert_eq!(caps_locked.embedding_apikey, "pa-123");
+    }
+
+    #[test]
+    fn test_providers_without_top_level_endpoints() {
+        let buf = r#"
+cloud_name: test
+chat_model: claude-3-7-sonnet
+completion_model: bigcode/starcoder2-3b
+providers:
+  llama_cpp:
+    completion_endpoint: "http://localhost:8080/v1/completions"
+    models: [bigcode/starcoder2-3b]
+  anthropic:
+    chat_endpoint: "https://api.anthropic.com/v1/messages"
+    completion_endpoint: "https://wrong.example.com/v1/completions"
+    endpoint_style: anthropic
+    models: [claude-3-7-sonnet]
+"#.to_string();
+        let caps = load_caps_from_buf(&buf, &"/tmp/bring-your-own-key.yaml".to_string()).unwrap();
+        let caps_locked = caps.read().unwrap();
+        assert!(caps_locked.endpoint_chat_passthrough.is_empty());
+        let chat_model = "claude-3-7-sonnet".to_string();
+        let is_chat = caps_locked.code_chat_models.contains_key(&chat_model);
+        let anthropic = model_provider(&caps_locked, &chat_model).unwrap();
+        assert_eq!(provider_endpoint(&anthropic, is_chat), "https://api.anthropic.com/v1/messages");
+        let completion_model = "bigcode/starcoder2-3b".to_string();
+        let is_chat = caps_locked.code_chat_models.contains_key(&completion_model);
+        let llama_cpp = model_provider(&caps_locked, &completion_model).unwrap();
+        assert_eq!(provider_endpoint(&llama_cpp, is_chat), "http://localhost:8080/v1/completions");
+    }
+
+    #[test]
+    fn test_which_model_to_use_provider_records_and_fallbacks() {
+        let buf = r#"
+cloud_name: test
+chat_model: gpt-4o
+providers:
+  openrouter:
+    chat_endpoint: "https://openrouter.ai/api/v1/chat/completions"
+    headers: {"x-title": "$REFACT_TEST_NO_SUCH_VAR"}
+    code_chat_models:
+      deepseek/deepseek-chat: {n_ctx: 64000, supports_scratchpads: {PASSTHROUGH: {}}, default_scratchpad: PASSTHROUGH}
+fallback_models:
+  gpt-5-preview: [no-such-model, deepseek/deepseek-chat]
+"#.to_string();
+        let caps = load_caps_from_buf(&buf, &"/tmp/bring-your-own-key.yaml".to_string()).unwrap();
+        let caps_locked = caps.read().unwrap();
+        let (name, rec) = which_model_to_use(&caps_locked.code_chat_models, "deepseek/deepseek-chat", "gpt-4o", &caps_locked.fallback_models).unwrap();
+        assert_eq!((name.as_str(), rec.n_ctx, rec.provider.as_str()), ("deepseek/deepseek-chat", 64000, "openrouter"));
+        let (name, _) = which_model_to_use(&caps_locked.code_chat_models, "gpt-5-preview", "gpt-4o", &caps_locked.fallback_models).unwrap();
+        assert_eq!(name, "deepseek/deepseek-chat");
+        assert!(which_model_to_use(&caps_locked.code_chat_models, "no-such-model", "gpt-4o", &caps_locked.fallback_models).is_err());
+        assert_eq!(resolve_env_var("plain").unwrap(), "plain");
+        assert!(resolve_env_var(&caps_locked.providers["openrouter"].headers["x-title"]).is_err());
+    }
+
+    #[test]
+    fn test_models_to_try_same_prompt_format() {
+        let buf = r#"
+cloud_name: test
+completion_model: starcoder-a
+providers:
+  llama_cpp:
+    completion_endpoint: "http://localhost:8080/v1/completions"
+    code_completion_models:
+      starcoder-a: {n_ctx: 4096, supports_scratchpads: {FIM-PSM: {fim_prefix: "<fim_prefix>"}}, default_scratchpad: FIM-PSM}
+      starcoder-b: {n_ctx: 8192, supports_scratchpads: {FIM-PSM: {fim_prefix: "<fim_prefix>"}}, default_scratchpad: FIM-PSM}
+      qwen-coder: {n_ctx: 4096, supports_scratchpads: {FIM-PSM: {fim_prefix: "<|fim_prefix|>"}}, default_scratchpad: FIM-PSM}
+fallback_models:
+  starcoder-a: [qwen-coder, starcoder-b, no-such-model]
+"#.to_string();
+        let caps = load_caps_from_buf(&buf, &"/tmp/bring-your-own-key.yaml".to_string()).unwrap();
+        let caps_locked = caps.read().unwrap();
+        assert_eq!(models_to_try(&caps_locked, &"starcoder-a".to_string()), vec!["starcoder-a", "starcoder-b"]);
+    }
+
+    #[test]
+    fn test_usage_cost_usd() {
+        let buf = r#"
+cloud_name: test
+chat_model: gpt-4o
+prices:
+  gpt-4o: {prompt: 2.5, generated: 10.0, cache_read: 1.25}
+budgets:
+  per_day: 20.0
+"#.to_string();
+        let caps = load_caps_from_buf(&buf, &"/tmp/bring-your-own-key.yaml".to_string()).unwrap();
+        let caps_locked = caps.read().unwrap();
+        let usage = ChatUsage { prompt_tokens: 1_000_000, completion_tokens: 100_000, cache_read_tokens: 400_000, ..Default::default() };
+        let cost = usage_cost_usd(&caps_locked, &"gpt-4o".to_string(), &usage);
+        assert!((cost - (0.6 * 2.5 + 0.4 * 1.25 + 0.1 * 10.0)).abs() < 1e-9, "{}", cost);
+        assert_eq!(usage_cost_usd(&caps_locked, &"gpt-4o-mini".to_string(), &usage), 0.0);
+        assert_eq!(caps_locked.budgets.per_day, 20.0);
+        assert_eq!(caps_locked.budgets.on_exceed, BudgetOnExceed::Pause);
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex as StdMutex, OnceLock};
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest_eventsource::{Error as REError, Event, EventSource};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+
+// Record and replay of the upstream LLM traffic: chat, completions and embeddings, streamed or not.
+// Run once with --cassette-record session.jsonl against the real models, then with --cassette-replay session.jsonl
+// to get exactly the same answers offline, that makes a whole agent session usable as a regression test.
+//
+// The cassette is jsonl, one upstream request per line. The key is sha256 of the url and the request body without
+// "meta" (chat ids and such change from run to run). Identical requests get the recorded responses in the same order,
+// the last one repeats after that. Replay of a request that is not in the cassette is an error, never a network call.
+// Headers are not recorded, api keys don't end up in the file.
+
+static CASSETTE: OnceLock<Cassette> = OnceLock::new();
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CassetteEntry {
+    pub key: String,
+    pub url: String,
+    pub request: Value,
+    #[serde(default)]
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<String>,
+    #[serde(default)]
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,  // data of each server-sent event, for streamed requests
+}
+
+pub struct Cassette {
+    record_path: Option<PathBuf>,
+    record_lock: StdMutex<()>,
+    replay: StdMutex<HashMap<String, VecDeque<CassetteEntry>>>,
+}
+
+impl Cassette {
+    fn load(path: &PathBuf) -> Result<HashMap<String, VecDeque<CassetteEntry>>, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read cassette {}: {}", path.display(), e))?;
+        let mut entries: HashMap<String, VecDeque<CassetteEntry>> = HashMap::new();
+        for (line_n, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let entry: CassetteEntry = serde_json::from_str(line)
+                .map_err(|e| format!("cassette {} line {}: {}", path.display(), line_n + 1, e))?;
+            entries.entry(entry.key.clone()).or_default().push_back(entry);
+        }
+        Ok(entries)
+    }
+
+    fn is_replay(&self) -> bool {
+        self.record_path.is_none()
+    }
+
+    fn take(&self, key: &String, url: &str) -> Result<CassetteEntry, String> {
+        let mut replay = self.replay.lock().unwrap();
+        let queue = replay.get_mut(key).filter(|q| !q.is_empty()).ok_or_else(|| {
+            error!("cassette miss: {} key={}", url, key);
+            format!("cassette miss: request to {} with key {} was not recorded", url, key)
+        })?;
+        Ok(if queue.len() > 1 { queue.pop_front().unwrap() } else { queue[0].clone() })
+    }
+
+    fn record(&self, entry: &CassetteEntry) {
+        let path = match &self.record_path {
+            Some(path) => path,
+            None => return,
+        };
+        // one line at a time under the lock, concurrent requests don't interleave
+        let _lock = self.record_lock.lock().unwrap();
+        let written = std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|mut f| writeln!(f, "{}", serde_json::to_string(entry).unwrap()));
+        if let Err(e) = written {
+            error!("cannot write cassette {}: {}", path.display(), e);
+        }
+    }
+}
+
+pub fn cassette_init(record_path: &String, replay_path: &String) -> Result<(), String> {
+    let cassette = match (record_path.is_empty(), replay_path.is_empty()) {
+        (true, true) => return Ok(()),
+        (false, false) => return Err("--cassette-record and --cassette-replay can't be used together".to_string()),
+        (false, true) => {
+            let path = PathBuf::from(record_path);
+            std::fs::write(&path, "").map_err(|e| format!("cannot create cassette {}: {}", path.display(), e))?;
+            info!("recording upstream traffic to {}", path.display());
+            Cassette { record_path: Some(path), record_lock: StdMutex::new(()), replay: StdMutex::new(HashMap::new()) }
+        },
+        (true, false) => {
+            let path = PathBuf::from(replay_path);
+            let entries = Cassette::load(&path)?;
+            info!("replaying upstream traffic from {}, {} different requests", path.display(), entries.len());
+            Cassette { record_path: None, record_lock: StdMutex::new(()), replay: StdMutex::new(entries) }
+        },
+    };
+    CASSETTE.set(cassette).map_err(|_| "cassette is already initialized".to_string())
+}
+
+pub fn request_key(url: &str, request: &Value) -> String {
+    let mut request = request.clone();
+    if let Some(obj) = request.as_object_mut() {
+        obj.remove("meta");
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(url);
+    hasher.update("\n");
+    hasher.update(request.to_string());
+    format!("{:x}", hasher.finalize())
+}
+
+
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub retry_after: Option<String>,
+    pub text: String,
+}
+
+impl UpstreamResponse {
+    pub fn into_error(self, url: &str) -> UpstreamError {
+        let retry_after_txt = self.retry_after.as_ref().map(|v| format!(" retry-after={}", v)).unwrap_or_default();
+        UpstreamError {
+            status: Some(self.status),
+            message: format!("{} status={}{} text {}", url, self.status, retry_after_txt, self.text),
+            retry_after: self.retry_after,
+            transport: false,
+        }
+    }
+}
+
+// Failure of a non-streamed request: the server answered with an error status, or there was no answer at all
+// (transport), or anything else. Retries are decided from these fields, never from the message.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamError {
+    pub status: Option<u16>,
+    pub retry_after: Option<String>,
+    pub transport: bool,
+    pub message: String,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<String> for UpstreamError {
+    fn from(message: String) -> Self {
+        UpstreamError { message, ..Default::default() }
+    }
+}
+
+fn transport_error(message: String) -> UpstreamError {
+    UpstreamError { transport: true, message, ..Default::default() }
+}
+
+// Sends the request, or takes the answer from the cassette in replay mode
+pub async fn post_json(
+    client: &reqwest::Client,
+    url: &str,
+    headers: HeaderMap,
+    request: &Value,
+) -> Result<UpstreamResponse, UpstreamError> {
+    let cassette = CASSETTE.get();
+    let key = request_key(url, request);
+    if let Some(cassette) = cassette.filter(|c| c.is_replay()) {
+        let entry = cassette.take(&key, url)?;
+        return Ok(UpstreamResponse { status: entry.status, retry_after: entry.retry_after, text: entry.response });
+    }
+    let resp = client.post(url)
+        .headers(headers)
+        .body(request.to_string())
+        .send()
+        .await
+        .map_err(|e| transport_error(format!("{}", e)))?;
+    let status = resp.status().as_u16();
+    let retry_after = resp.headers().get(RETRY_AFTER).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+    let text = resp.text().await.map_err(|e| transport_error(format!("reading from socket {}: {}", url, e)))?;
+    if let Some(cassette) = cassette {
+        cassette.record(&CassetteEntry {
+            key,
+            url: url.to_string(),
+            request: request.clone(),
+            status,
+            retry_after: retry_after.clone(),
+            response: text.clone(),
+            chunks: vec![],
+        });
+    }
+    Ok(UpstreamResponse { status, retry_after, text })
+}
+
+// Opens a stream of server-sent events, or a stream of recorded events in replay mode
+pub fn post_streaming(
+    client: &reqwest::Client,
+    url: &str,
+    headers: HeaderMap,
+    request: &Value,
+) -> Result<UpstreamEvents, String> {
+    let cassette = CASSETTE.get();
+    let key = request_key(url, request);
+    if let Some(cassette) = cassette.filter(|c| c.is_replay()) {
+        let entry = cassette.take(&key, url)?;
+        let failure = (entry.status != 200).then(|| StreamError::Status {
+            status: entry.status,
+            retry_after: entry.retry_after,
+            text: entry.response,
+        });
+        return Ok(UpstreamEvents::Replay { chunks: entry.chunks.into(), failure, ended: false });
+    }
+    let builder = client.post(url)
+        .headers(headers)
+        .body(request.to_string());
+    let source = EventSource::new(builder).map_err(|e| format!("can't stream from {}: {}", url, e))?;
+    let recording = cassette.map(|_| CassetteEntry {
+        key,
+        url: url.to_string(),
+        request: request.clone(),
+        status: 200,
+        ..Default::default()
+    });
+    Ok(UpstreamEvents::Live { source, recording })
+}
+
+// A non-200 answer is read here, so the cassette gets its status and body the same way as for a non-streamed request
+pub enum StreamError {
+    Status { status: u16, retry_after: Option<String>, text: String },
+    Source(REError),
+}
+
+pub enum UpstreamEvents {
+    Live { source: EventSource, recording: Option<CassetteEntry> },
+    Replay { chunks: VecDeque<String>, failure: Option<StreamError>, ended: bool },
+}
+
+impl UpstreamEvents {
+    pub async fn next(&mut self) -> Option<Result<Event, StreamError>> {
+        match self {
+            UpstreamEvents::Live { source, recording } => {
+                let event = match source.next().await? {
+                    Ok(event) => event,
+                    Err(REError::InvalidStatusCode(status, resp)) => {
+                        let retry_after = resp.headers().get(RETRY_AFTER).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+                        let text = resp.text().await.unwrap_or_default();
+                        if let Some(recording) = recording {
+                            recording.status = status.as_u16();
+                            recording.retry_after = retry_after.clone();
+                            recording.response = text.clone();
+                        }
+                        return Some(Err(StreamError::Status { status: status.as_u16(), retry_after, text }));
+                    },
+                    Err(e) => return Some(Err(StreamError::Source(e))),
+                };
+                if let (Event::Message(message), Some(recording)) = (&event, recording) {
+                    recording.chunks.push(message.data.clone());
+                }
+                Some(Ok(event))
+            },
+            UpstreamEvents::Replay { chunks, failure, ended } => {
+                if let Some(failure) = failure.take() {
+                    *ended = true;
+                    return Some(Err(failure));
+                }
+                if let Some(data) = chunks.pop_front() {
+                    return Some(Ok(Event::Message(eventsource_stream::Event {
+                        event: "message".to_string(),
+                        data,
+                        id: String::new(),
+                        retry: None,
+                    })));
+                }
+                if *ended {
+                    return None;
+                }
+                // a live stream ends the same way when the server closes the connection
+                *ended = true;
+                Some(Err(StreamError::Source(REError::StreamEnded)))
+            },
+        }
+    }
+
+    pub fn close(&mut self) {
+        if let UpstreamEvents::Live { source, .. } = self {
+            source.close();
+        }
+    }
+}
+
+impl Drop for UpstreamEvents {
+    fn drop(&mut self) {
+        // the caller stops reading at [DONE] or on error, everything received by then is the recording
+        if let UpstreamEvents::Live { recording: Some(recording), .. } = self {
+            if let Some(cassette) = CASSETTE.get().filter(|_| !recording.chunks.is_empty() || recording.status != 200) {
+                cassette.record(recording);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request_key_ignores_meta() {
+        let a = request_key("http://x/v1/chat/completions", &json!({"model": "gpt-4o", "messages": [], "meta": {"chat_id": "1"}}));
+        let b = request_key("http://x/v1/chat/completions", &json!({"model": "gpt-4o", "messages": [], "meta": {"chat_id": "2"}}));
+        let c = request_key("http://x/v1/chat/completions", &json!({"model": "gpt-4o-mini", "messages": []}));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_replay_in_order_then_repeat_last() {
+        let entry = |response: &str| CassetteEntry { key: "k".to_string(), response: response.to_string(), status: 200, ..Default::default() };
+        let cassette = Cassette {
+            record_path: None,
+            record_lock: StdMutex::new(()),
+            replay: StdMutex::new(HashMap::from([("k".to_string(), VecDeque::from([entry("first"), entry("second")]))])),
+        };
+        let key = "k".to_string();
+        assert_eq!(cassette.take(&key, "url").unwrap().response, "first");
+        assert_eq!(cassette.take(&key, "url").unwrap().response, "second");
+        assert_eq!(cassette.take(&key, "url").unwrap().response, "second");
+        assert!(cassette.take(&"other".to_string(), "url").unwrap_err().contains("cassette miss"));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_stream_ends_like_a_live_one() {
+        let mut events = UpstreamEvents::Replay { chunks: VecDeque::from(["{\"a\": 1}".to_string(), "[DONE]".to_string()]), failure: None, ended: false };
+        match events.next().await {
+            Some(Ok(Event::Message(message))) => assert_eq!(message.data, "{\"a\": 1}"),
+            _ => panic!("expected a message"),
+        }
+        assert!(matches!(events.next().await, Some(Ok(Event::Message(_)))));
+        assert!(matches!(events.next().await, Some(Err(StreamError::Source(REError::StreamEnded)))));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replayed_stream_error_status() {
+        let failure = StreamError::Status { status: 429, retry_after: Some("3".to_string()), text: "{\"detail\": \"slow down\"}".to_string() };
+        let mut events = UpstreamEvents::Replay { chunks: VecDeque::new(), failure: Some(failure), ended: false };
+        match events.next().await {
+            Some(Err(StreamError::Status { status, retry_after, text })) => {
+                assert_eq!((status, retry_after.as_deref()), (429, Some("3")));
+                assert!(text.contains("slow down"));
+            },
+            _ => panic!("expected the recorded status"),
+        }
+        assert!(events.next().await.is_none());
+    }
+}
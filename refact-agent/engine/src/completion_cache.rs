@@ -1,13 +1,26 @@
 use crate::call_validation::CodeCompletionPost;
+use crate::global_context::GlobalContext;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock as StdRwLock;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use ropey::Rope;
-// use tracing::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock as ARwLock;
+use tracing::{info, warn};
 
 const CACHE_ENTRIES: usize = 500;
 const CACHE_KEY_CHARS: usize = 5000;  // max memory CACHE_KEY_CHARS * CACHE_ENTRIES = 2500000 = 2.5M
+const PERSIST_ENTRIES: usize = 200;
+const PERSIST_EACH_N_SECONDS: u64 = 60;
+const PERSIST_FILENAME: &str = "completion_cache.json";
+
+// One entry per completion, key is (text before cursor, mode + hash of text after cursor).
+// The user typing what the completion predicted is a "typed-ahead" hit: the text before cursor is the old key plus
+// the beginning of the completion, the answer is the rest of the completion. Edits below the cursor change the hash
+// and miss, edits above the cursor change the key and miss, so the entries invalidate themselves -- that's
+// also why it's safe to load them from disk after restart.
 
 
 // aggregate this struct in scratchpad to save cache
@@ -15,6 +28,7 @@ const CACHE_KEY_CHARS: usize = 5000;  // max memory CACHE_KEY_CHARS * CACHE_ENTR
 pub struct CompletionSaveToCache {
     pub cache_arc: Arc<StdRwLock<CompletionCache>>,
     pub cache_key: (String, String),
+    pub file_name: String,
     pub completion0_text: String,
     pub completion0_finish_reason: String,
     pub completion0_snippet_telemetry_id: Option<u64>,
@@ -29,6 +43,7 @@ impl CompletionSaveToCache {
         CompletionSaveToCache {
             cache_arc: cache_arc.clone(),
             cache_key: cache_key_from_post(post),
+            file_name: crate::files_correction::canonical_path(&post.inputs.cursor.file).to_string_lossy().to_string(),
             completion0_text: String::new(),
             completion0_finish_reason: String::new(),
             completion0_snippet_telemetry_id: None,
@@ -38,55 +53,177 @@ impl CompletionSaveToCache {
 }
 
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub key: (String, String),
+    pub file_name: String,
+    pub completion: String,
+    pub believe_chars: usize,  // typed-ahead hits only while fewer chars than that are typed
+    pub finish_reason: String,
+    pub model: String,
+    pub snippet_telemetry_id: Option<u64>,
+    #[serde(default)]
+    pub hits: usize,
+    #[serde(default)]
+    pub ts: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompletionCacheStats {
+    pub entries: usize,
+    pub hits: usize,
+    pub typed_ahead_hits: usize,
+    pub misses: usize,
+    pub invalidated: usize,
+    pub loaded_from_disk: usize,
+}
+
 #[derive(Debug)]
 pub struct CompletionCache {
-    pub map: HashMap<(String, String), serde_json::Value>,
-    pub in_added_order: Vec<(String, String)>,
+    pub map: HashMap<(String, String), CacheEntry>,
+    pub in_added_order: VecDeque<(String, String)>,
+    pub stats: CompletionCacheStats,
+    pub dirty: bool,
 }
 
 impl CompletionCache {
     pub fn new(
     ) -> Self {
-        Self { map: HashMap::new(), in_added_order: Vec::new() }
+        Self { map: HashMap::new(), in_added_order: VecDeque::new(), stats: CompletionCacheStats::default(), dirty: false }
+    }
+
+    fn _insert(&mut self, entry: CacheEntry) {
+        while self.in_added_order.len() >= CACHE_ENTRIES {
+            if let Some(old_key) = self.in_added_order.pop_front() {
+                self.map.remove(&old_key);
+            }
+        }
+        if !self.map.contains_key(&entry.key) {
+            self.in_added_order.push_back(entry.key.clone());
+        }
+        self.map.insert(entry.key.clone(), entry);
+        self.dirty = true;
+    }
+}
+
+fn _now() -> f64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+fn _truncate_key(key: &str) -> String {
+    let chars_n = key.chars().count();
+    if chars_n > CACHE_KEY_CHARS {
+        key.chars().skip(chars_n - CACHE_KEY_CHARS).collect()
+    } else {
+        key.to_string()
+    }
+}
+
+// How many bytes at the end of key were typed from the entry's completion, None if the key isn't the entry's key
+// plus some typing. Both keys are windows of the last CACHE_KEY_CHARS chars, typing shifts the window.
+fn _typed_ahead_bytes(entry: &CacheEntry, key: &str) -> Option<usize> {
+    let key_is_whole_text = key.chars().count() < CACHE_KEY_CHARS;
+    // char boundaries of the completion, the last one is the whole completion typed (the answer is empty then)
+    let boundaries = entry.completion.char_indices().map(|(i, _)| i).chain(std::iter::once(entry.completion.len()));
+    for typed_bytes in boundaries.skip(1).take(entry.believe_chars.saturating_sub(1)) {
+        if !key.ends_with(&entry.completion[..typed_bytes]) {
+            continue;
+        }
+        let head = &key[..key.len() - typed_bytes];
+        let matches = if key_is_whole_text { head == entry.key.0 } else { entry.key.0.ends_with(head) };
+        if matches {
+            return Some(typed_bytes);
+        }
     }
+    None
+}
+
+fn _entry_to_response(entry: &CacheEntry, typed_bytes: usize) -> serde_json::Value {
+    serde_json::json!(
+        {
+            "choices": [{
+                "index": 0,
+                "code_completion": entry.completion[typed_bytes..].to_string(),
+                "finish_reason": entry.finish_reason,
+            }],
+            "model": entry.model,
+            "cached": true,
+            "snippet_telemetry_id": entry.snippet_telemetry_id,
+        }
+    )
 }
 
 pub fn cache_get(
     cache: Arc<StdRwLock<CompletionCache>>,
     key: (String, String),
 ) -> Option<serde_json::Value> {
-    let cache_locked = cache.write().unwrap();
-    if let Some(value) = cache_locked.map.get(&key) {
-        return Some(value.clone());
+    let mut cache_locked = cache.write().unwrap();
+    let key = (_truncate_key(&key.0), key.1);
+    let found = if let Some(entry) = cache_locked.map.get(&key) {
+        Some((entry.key.clone(), 0))
+    } else {
+        cache_locked.map.values()
+            .filter(|e| e.key.1 == key.1)
+            .find_map(|e| _typed_ahead_bytes(e, &key.0).map(|typed_bytes| (e.key.clone(), typed_bytes)))
+    };
+    match found {
+        Some((entry_key, typed_bytes)) => {
+            if typed_bytes == 0 {
+                cache_locked.stats.hits += 1;
+            } else {
+                cache_locked.stats.typed_ahead_hits += 1;
+            }
+            cache_locked.dirty = true;
+            let entry = cache_locked.map.get_mut(&entry_key).unwrap();
+            entry.hits += 1;
+            Some(_entry_to_response(entry, typed_bytes))
+        }
+        None => {
+            cache_locked.stats.misses += 1;
+            None
+        }
     }
-    None
 }
 
 pub fn cache_put(
     cache: Arc<StdRwLock<CompletionCache>>,
-    new_key: (String, String),
-    value: serde_json::Value,
+    entry: CacheEntry,
 ) {
     let mut cache_locked = cache.write().unwrap();
-    while cache_locked.in_added_order.len() > CACHE_ENTRIES {
-        let old_key = cache_locked.in_added_order.remove(0);
-        cache_locked.map.remove(&old_key);
-    }
-    // info!("cache put: {:?} = {:?}", new_key, value);
-    let mut new_key_copy = new_key.clone();
-    let k0_chars = new_key_copy.0.chars();
-    if k0_chars.clone().count() > CACHE_KEY_CHARS {
-        new_key_copy.0 = k0_chars.clone().skip(k0_chars.count() - CACHE_KEY_CHARS).collect();
+    let mut entry = entry;
+    entry.key.0 = _truncate_key(&entry.key.0);
+    // info!("cache put: {:?} = {:?}", entry.key, entry.completion);
+    cache_locked._insert(entry);
+}
+
+// The text before cursor doesn't change when the file is deleted or rewritten from disk, but the completion is junk
+pub fn cache_invalidate_file(
+    cache: Arc<StdRwLock<CompletionCache>>,
+    file_name: &str,
+) {
+    let mut cache_locked = cache.write().unwrap();
+    let before = cache_locked.map.len();
+    cache_locked.map.retain(|_, e| e.file_name != file_name);
+    let invalidated = before - cache_locked.map.len();
+    if invalidated > 0 {
+        let cache_ref = &mut *cache_locked;
+        cache_ref.in_added_order.retain(|k| cache_ref.map.contains_key(k));
+        cache_ref.stats.invalidated += invalidated;
+        cache_ref.dirty = true;
     }
-    cache_locked.map.entry(new_key_copy.clone()).or_insert(value);
-    cache_locked.in_added_order.push(new_key_copy.clone());
+}
+
+pub fn cache_stats(cache: Arc<StdRwLock<CompletionCache>>) -> CompletionCacheStats {
+    let cache_locked = cache.read().unwrap();
+    let mut stats = cache_locked.stats.clone();
+    stats.entries = cache_locked.map.len();
+    stats
 }
 
 pub fn cache_key_from_post(
     post: &CodeCompletionPost,
 ) -> (String, String) {
-    // Change this function only together with the function below, it fills the cache ahead of cursor
-    // directly manupulating the cache key.
+    // Change this function only together with _typed_ahead_bytes(), it relies on the key being the text before cursor.
     let text_maybe = post.inputs.sources.get(&post.inputs.cursor.file);
     if let None = text_maybe {
         // Don't handle it there, validation should have caught it
@@ -123,17 +260,22 @@ pub fn cache_key_from_post(
     let mut key = "".to_string();
     key.push_str(&linesvec.join(""));
     key.push_str(&cursor_line.to_string());
-    let chars = key.chars();
+    key = _truncate_key(&key);
 
-    if chars.clone().count() > CACHE_KEY_CHARS {
-        key = chars.skip(key.len() - CACHE_KEY_CHARS).collect();
-    }
-    return (key, cache_part2_from_post(post));
+    let after_cursor = rope.slice(rope.line_to_char(post.inputs.cursor.line as usize) + cursor_line.len_chars()..).to_string();
+    return (key, cache_part2_from_post(post, &after_cursor.replace("\r", "")));
 }
 
 
-pub fn cache_part2_from_post(post: &CodeCompletionPost) -> String {
-    if post.inputs.multiline { "multiline".to_string() } else { "singleline".to_string() }
+pub fn cache_part2_from_post(post: &CodeCompletionPost, after_cursor: &str) -> String {
+    let mode = if post.comment_to_code {
+        "comment-to-code"
+    } else if post.inputs.multiline {
+        "multiline"
+    } else {
+        "singleline"
+    };
+    format!("{}/{:x}", mode, md5::compute(after_cursor))
 }
 
 
@@ -143,7 +285,7 @@ impl Drop for CompletionSaveToCache {
         if self.completion0_finish_reason.is_empty() { // error happened, no nothing happened (prompt only request)
             return;
         }
-        let mut believe_chars = self.completion0_text.len();
+        let mut believe_chars = self.completion0_text.chars().count();
         if self.completion0_finish_reason == "length" {
             // Model stopped because of max tokens, there is a continuation, so it's good for cache in the beginning, but don't believe it to the end.
             // For example CODECODECODECOMPLETION| with empty completion is obviously junk as cache.
@@ -152,24 +294,124 @@ impl Drop for CompletionSaveToCache {
         } else {
             believe_chars += 1;
         }
-        for char_num in 0..believe_chars {
-            let code_completion_ahead: String = self.completion0_text.chars().skip(char_num).collect();
-            let cache_key_ahead: (String, String) = (
-                self.cache_key.0.clone() + &self.completion0_text.chars().take(char_num).collect::<String>(),
-                self.cache_key.1.clone()
-            );
-            cache_put(self.cache_arc.clone(), cache_key_ahead, serde_json::json!(
-                {
-                    "choices": [{
-                        "index": 0,
-                        "code_completion": code_completion_ahead,
-                        "finish_reason": self.completion0_finish_reason,
-                    }],
-                    "model": self.model,
-                    "cached": true,
-                    "snippet_telemetry_id": self.completion0_snippet_telemetry_id,
-                }
-            ));
+        if believe_chars == 0 {
+            return;
+        }
+        cache_put(self.cache_arc.clone(), CacheEntry {
+            key: self.cache_key.clone(),
+            file_name: self.file_name.clone(),
+            completion: self.completion0_text.clone(),
+            believe_chars,
+            finish_reason: self.completion0_finish_reason.clone(),
+            model: self.model.clone(),
+            snippet_telemetry_id: self.completion0_snippet_telemetry_id,
+            hits: 0,
+            ts: _now(),
+        });
+    }
+}
+
+
+fn _hot_entries(cache: &CompletionCache) -> Vec<CacheEntry> {
+    let mut entries = cache.map.values().cloned().collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.hits.cmp(&a.hits).then(b.ts.total_cmp(&a.ts)));
+    entries.truncate(PERSIST_ENTRIES);
+    entries.reverse();  // load in added order, the hottest last to survive longer
+    entries
+}
+
+async fn _load_from_disk(cache_arc: Arc<StdRwLock<CompletionCache>>, path: &PathBuf) {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(_) => return,
+    };
+    match serde_json::from_str::<Vec<CacheEntry>>(&text) {
+        Ok(entries) => {
+            let mut cache_locked = cache_arc.write().unwrap();
+            cache_locked.stats.loaded_from_disk = entries.len();
+            for entry in entries {
+                cache_locked._insert(entry);
+            }
+            cache_locked.dirty = false;
+            info!("completion cache: loaded {} entries from {}", cache_locked.map.len(), path.display());
         }
+        Err(e) => warn!("completion cache: cannot parse {}: {}", path.display(), e),
+    }
+}
+
+pub async fn completion_cache_background_persist(gcx: Arc<ARwLock<GlobalContext>>) {
+    let (cache_arc, path) = {
+        let gcx_locked = gcx.read().await;
+        (gcx_locked.completions_cache.clone(), gcx_locked.cache_dir.join(PERSIST_FILENAME))
+    };
+    _load_from_disk(cache_arc.clone(), &path).await;
+    loop {
+        tokio::time::sleep(tokio::time::Duration::from_secs(PERSIST_EACH_N_SECONDS)).await;
+        let hot = {
+            let mut cache_locked = cache_arc.write().unwrap();
+            if !cache_locked.dirty {
+                continue;
+            }
+            cache_locked.dirty = false;
+            _hot_entries(&cache_locked)
+        };
+        let text = serde_json::to_string(&hot).unwrap();
+        if let Err(e) = tokio::fs::write(&path, text).await {
+            warn!("completion cache: cannot save {}: {}", path.display(), e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, part2: &str, completion: &str, believe_chars: usize) -> CacheEntry {
+        CacheEntry {
+            key: (key.to_string(), part2.to_string()),
+            file_name: "a.py".to_string(),
+            completion: completion.to_string(),
+            believe_chars,
+            finish_reason: "stop".to_string(),
+            model: "m".to_string(),
+            snippet_telemetry_id: None,
+            hits: 0,
+            ts: 0.0,
+        }
+    }
+
+    fn completion_of(value: Option<serde_json::Value>) -> Option<String> {
+        value.map(|v| v["choices"][0]["code_completion"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_typed_ahead() {
+        let cache = Arc::new(StdRwLock::new(CompletionCache::new()));
+        cache_put(cache.clone(), entry("def f(", "singleline/1", "x, y):", 7));
+        let get = |key: &str, part2: &str| completion_of(cache_get(cache.clone(), (key.to_string(), part2.to_string())));
+        assert_eq!(get("def f(", "singleline/1"), Some("x, y):".to_string()));
+        assert_eq!(get("def f(x,", "singleline/1"), Some(" y):".to_string()));
+        assert_eq!(get("def f(x, y):", "singleline/1"), Some("".to_string()));
+        // typed something else, or the text after cursor is different
+        assert_eq!(get("def f(z", "singleline/1"), None);
+        assert_eq!(get("def f(x,", "singleline/2"), None);
+        let stats = cache_stats(cache.clone());
+        assert_eq!((stats.hits, stats.typed_ahead_hits, stats.misses), (1, 2, 2));
+
+        cache_invalidate_file(cache.clone(), "a.py");
+        assert_eq!(get("def f(", "singleline/1"), None);
+    }
+
+    #[test]
+    fn test_typed_ahead_shifts_long_keys() {
+        let cache = Arc::new(StdRwLock::new(CompletionCache::new()));
+        let text = (0..CACHE_KEY_CHARS + 10).map(|i| if i % 50 == 0 { '\n' } else { 'a' }).collect::<String>() + "x = ";
+        cache_put(cache.clone(), entry(&text, "multiline/1", "compute()", 5));
+        let typed = _truncate_key(&(text.clone() + "comp"));
+        assert_eq!(completion_of(cache_get(cache.clone(), (typed, "multiline/1".to_string()))), Some("ute()".to_string()));
+        // believe_chars is 5: typed-ahead hits only while fewer chars than that are typed
+        let typed = _truncate_key(&(text + "compu"));
+        assert_eq!(completion_of(cache_get(cache.clone(), (typed, "multiline/1".to_string()))), None);
     }
 }
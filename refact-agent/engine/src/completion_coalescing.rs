@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hyper::StatusCode;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::call_validation::CodeCompletionPost;
+use crate::custom_error::ScratchError;
+
+
+// At most one completion in flight per (client, file). Editors send a request per keystroke, on a slow
+// GPU the old ones queue behind http_client_slowdown producing answers nobody will look at. A newer request
+// for the same file cancels the older one (the upstream request is dropped together with its future),
+// an identical request joins the one in flight instead of making another upstream call.
+
+pub type SharedCompletion = Shared<BoxFuture<'static, Result<String, ScratchError>>>;
+
+struct InFlight {
+    id: u64,
+    request_key: String,
+    cancel: Arc<Notify>,
+    fut: SharedCompletion,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CompletionsInFlightStats {
+    pub cancelled_n: usize,
+    pub coalesced_n: usize,
+}
+
+#[derive(Default)]
+pub struct CompletionsInFlight {
+    per_file: HashMap<(String, String), InFlight>,
+    next_id: u64,
+    pub stats: CompletionsInFlightStats,
+}
+
+pub fn request_key_from_post(post: &CodeCompletionPost) -> String {
+    let request = serde_json::json!({
+        "inputs": post.inputs,
+        "parameters": post.parameters,
+        "model": post.model,
+        "scratchpad": post.scratchpad,
+        "use_ast": post.use_ast,
+        "use_vecdb": post.use_vecdb,
+        "rag_tokens_n": post.rag_tokens_n,
+        "comment_to_code": post.comment_to_code,
+    });
+    format!("{:x}", md5::compute(request.to_string()))
+}
+
+struct ForgetWhenDone {
+    in_flight: Arc<StdMutex<CompletionsInFlight>>,
+    slot: (String, String),
+    id: u64,
+}
+
+impl Drop for ForgetWhenDone {
+    fn drop(&mut self) {
+        let mut in_flight_locked = self.in_flight.lock().unwrap();
+        if in_flight_locked.per_file.get(&self.slot).map(|x| x.id == self.id).unwrap_or(false) {
+            in_flight_locked.per_file.remove(&self.slot);
+        }
+    }
+}
+
+pub async fn run_coalesced<F>(
+    in_flight: Arc<StdMutex<CompletionsInFlight>>,
+    client: &str,
+    file: &str,
+    request_key: String,
+    work: F,
+) -> Result<String, ScratchError>
+where
+    F: Future<Output = Result<String, ScratchError>> + Send + 'static,
+{
+    let slot = (client.to_string(), file.to_string());
+    let (fut, _forget_when_done) = {
+        let mut in_flight_locked = in_flight.lock().unwrap();
+        let same_request = in_flight_locked.per_file.get(&slot).filter(|x| x.request_key == request_key).map(|x| x.fut.clone());
+        if let Some(fut) = same_request {
+            in_flight_locked.stats.coalesced_n += 1;
+            info!("completion for {} joins the identical request in flight", crate::nicer_logs::last_n_chars(&file.to_string(), 30));
+            (fut, None)
+        } else {
+            if let Some(older) = in_flight_locked.per_file.remove(&slot) {
+                // notify_one() keeps a permit, works even if the older request isn't waiting yet
+                older.cancel.notify_one();
+                in_flight_locked.stats.cancelled_n += 1;
+            }
+            let cancel = Arc::new(Notify::new());
+            let cancel_clone = cancel.clone();
+            let fut = async move {
+                tokio::select! {
+                    result = work => result,
+                    _ = cancel_clone.notified() => Err(ScratchError::new_but_skip_telemetry(
+                        StatusCode::CONFLICT,
+                        "cancelled, a newer completion request for the same file arrived".to_string(),
+                    )),
+                }
+            }.boxed().shared();
+            in_flight_locked.next_id += 1;
+            let id = in_flight_locked.next_id;
+            in_flight_locked.per_file.insert(slot.clone(), InFlight { id, request_key, cancel, fut: fut.clone() });
+            (fut, Some(ForgetWhenDone { in_flight: in_flight.clone(), slot, id }))
+        }
+    };
+    fut.await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_coalesce_and_cancel() {
+        let in_flight = Arc::new(StdMutex::new(CompletionsInFlight::default()));
+        let upstream_calls = Arc::new(AtomicUsize::new(0));
+        let slow_work = |answer: &str| {
+            let answer = answer.to_string();
+            let upstream_calls = upstream_calls.clone();
+            async move {
+                upstream_calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                Ok::<String, ScratchError>(answer)
+            }
+        };
+
+        let a1 = run_coalesced(in_flight.clone(), "lsp", "a.py", "key1".to_string(), slow_work("one"));
+        let a2 = run_coalesced(in_flight.clone(), "lsp", "a.py", "key1".to_string(), slow_work("two"));
+        let (r1, r2) = tokio::join!(a1, a2);
+        assert_eq!((r1.unwrap(), r2.unwrap()), ("one".to_string(), "one".to_string()));
+        assert_eq!(upstream_calls.load(Ordering::SeqCst), 1);
+        assert!(in_flight.lock().unwrap().per_file.is_empty());
+
+        let older = tokio::spawn(run_coalesced(in_flight.clone(), "lsp", "a.py", "key1".to_string(), slow_work("old")));
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        let newer = run_coalesced(in_flight.clone(), "lsp", "a.py", "key2".to_string(), slow_work("new")).await;
+        assert_eq!(newer.unwrap(), "new");
+        assert_eq!(older.await.unwrap().unwrap_err().status_code, StatusCode::CONFLICT);
+        assert_eq!(in_flight.lock().unwrap().stats.cancelled_n, 1);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use similar::TextDiff;
+
+
+// Recent edits per file, taken from the full texts on_did_change gets, to show the model what the user is doing
+// right now (NEXT_EDIT scratchpad). Typing produces an edit per keystroke, so an edit that lands on the lines
+// the previous edit produced is merged into it: typing "foo" gives one edit, not three, and typing then deleting
+// the same thing gives nothing at all.
+
+const MAX_EDITS_PER_FILE: usize = 10;
+const MAX_FILES: usize = 20;
+const MAX_EDIT_LINES: usize = 50;      // bigger hunks are reformats or reloads from disk, not something to follow
+const COALESCE_SECONDS: f64 = 30.0;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentEdit {
+    pub ts: f64,
+    pub line1: usize,          // 0-based, in the current text of the file
+    pub removed: Vec<String>,
+    pub added: Vec<String>,
+}
+
+impl RecentEdit {
+    fn new_range(&self) -> (usize, usize) {
+        (self.line1, self.line1 + self.added.len())
+    }
+
+    // Unified-diff style hunk with 1-based line numbers, how the edit goes into the prompt
+    pub fn to_hunk(&self) -> String {
+        let mut hunk = format!("@@ -{},{} +{},{} @@\n", self.line1 + 1, self.removed.len(), self.line1 + 1, self.added.len());
+        for line in self.removed.iter() {
+            hunk.push_str(&format!("-{}\n", line));
+        }
+        for line in self.added.iter() {
+            hunk.push_str(&format!("+{}\n", line));
+        }
+        hunk
+    }
+}
+
+#[derive(Default)]
+pub struct EditHistory {
+    pub per_file: HashMap<PathBuf, VecDeque<RecentEdit>>,
+}
+
+fn lines_of(text: &str) -> Vec<String> {
+    text.lines().map(|l| l.trim_end_matches('\r').to_string()).collect()
+}
+
+// Diff of two texts of the same file as hunks top to bottom, line numbers are in the new text. Whole-file diff
+// is the expensive part, it runs before taking the EditHistory lock.
+pub fn diff_hunks(old_text: &str, new_text: &str, ts: f64) -> Vec<RecentEdit> {
+    if old_text == new_text {
+        return vec![];
+    }
+    let old_lines = lines_of(old_text);
+    let new_lines = lines_of(new_text);
+    let old_refs = old_lines.iter().map(|l| l.as_str()).collect::<Vec<_>>();
+    let new_refs = new_lines.iter().map(|l| l.as_str()).collect::<Vec<_>>();
+    let diff = TextDiff::from_slices(&old_refs, &new_refs);
+    let mut hunks = vec![];
+    for group in diff.grouped_ops(0) {
+        let (first, last) = match (group.first(), group.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue,
+        };
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+        hunks.push(RecentEdit {
+            ts,
+            line1: new_range.start,
+            removed: old_lines[old_range].to_vec(),
+            added: new_lines[new_range].to_vec(),
+        });
+    }
+    hunks
+}
+
+impl EditHistory {
+    pub fn record(&mut self, path: &PathBuf, old_text: &str, new_text: &str, ts: f64) {
+        self.record_hunks(path, diff_hunks(old_text, new_text, ts), ts);
+    }
+
+    pub fn record_hunks(&mut self, path: &PathBuf, hunks: Vec<RecentEdit>, ts: f64) {
+        if hunks.is_empty() {
+            return;
+        }
+        let edits = self.per_file.entry(path.clone()).or_default();
+        // hunks come top to bottom, the line numbers are in the new text already
+        for hunk in hunks {
+            let hunk_old_end = hunk.line1 + hunk.removed.len();
+            let shift = hunk.added.len() as i64 - hunk.removed.len() as i64;
+            if hunk.removed.len().max(hunk.added.len()) > MAX_EDIT_LINES {
+                // not worth showing, but the lines below still move, and the edits it overwrote are gone
+                edits.retain(|e| e.line1 + e.added.len() <= hunk.line1 || e.line1 >= hunk_old_end);
+                for edit in edits.iter_mut() {
+                    if edit.line1 >= hunk_old_end {
+                        edit.line1 = (edit.line1 as i64 + shift).max(0) as usize;
+                    }
+                }
+                continue;
+            }
+            let merged = match edits.back_mut() {
+                Some(prev) if ts - prev.ts < COALESCE_SECONDS
+                    && hunk.line1 >= prev.new_range().0 && hunk_old_end <= prev.new_range().1 => {
+                    let from = hunk.line1 - prev.line1;
+                    let to = hunk_old_end - prev.line1;
+                    prev.added.splice(from..to, hunk.added.iter().cloned());
+                    prev.ts = ts;
+                    true
+                },
+                _ => false,
+            };
+            // older edits below this one move down or up
+            for edit in edits.iter_mut().rev().skip(if merged { 1 } else { 0 }) {
+                if edit.line1 >= hunk_old_end {
+                    edit.line1 = (edit.line1 as i64 + shift).max(0) as usize;
+                }
+            }
+            if !merged {
+                edits.push_back(hunk);
+            }
+        }
+        edits.retain(|e| e.removed != e.added);
+        while edits.len() > MAX_EDITS_PER_FILE {
+            edits.pop_front();
+        }
+        if edits.is_empty() {
+            self.per_file.remove(path);
+        }
+        if self.per_file.len() > MAX_FILES {
+            let oldest = self.per_file.iter()
+                .min_by(|a, b| a.1.back().map(|e| e.ts).unwrap_or(0.0).total_cmp(&b.1.back().map(|e| e.ts).unwrap_or(0.0)))
+                .map(|(p, _)| p.clone());
+            if let Some(oldest) = oldest {
+                self.per_file.remove(&oldest);
+            }
+        }
+    }
+
+    // The newest edits across all files, oldest first, at most n
+    pub fn recent(&self, n: usize) -> Vec<(PathBuf, RecentEdit)> {
+        let mut all = self.per_file.iter()
+            .flat_map(|(path, edits)| edits.iter().map(move |e| (path.clone(), e.clone())))
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| a.1.ts.total_cmp(&b.1.ts));
+        all.split_off(all.len().saturating_sub(n))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typing_is_coalesced() {
+        let path = PathBuf::from("/a.py");
+        let mut history = EditHistory::default();
+        history.record(&path, "def f(x):\n    return x\n", "def f(xy):\n    return x\n", 1.0);
+        history.record(&path, "def f(xy):\n    return x\n", "def f(xyz):\n    return x\n", 2.0);
+        let edits = history.per_file.get(&path).unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].removed, vec!["def f(x):"]);
+        assert_eq!(edits[0].added, vec!["def f(xyz):"]);
+        assert_eq!(edits[0].to_hunk(), "@@ -1,1 +1,1 @@\n-def f(x):\n+def f(xyz):\n");
+
+        // typing it back is no edit at all
+        history.record(&path, "def f(xyz):\n    return x\n", "def f(x):\n    return x\n", 3.0);
+        assert!(history.per_file.get(&path).is_none());
+    }
+
+    #[test]
+    fn test_edits_elsewhere_shift_line_numbers() {
+        let path = PathBuf::from("/a.py");
+        let mut history = EditHistory::default();
+        history.record(&path, "a\nb\nc\n", "a\nb\nC\n", 1.0);
+        history.record(&path, "a\nb\nC\n", "import os\na\nb\nC\n", 2.0);
+        let edits = history.recent(10);
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].1.line1, 3);
+        assert_eq!(edits[0].1.added, vec!["C"]);
+        assert_eq!(edits[1].1.line1, 0);
+        assert_eq!(edits[1].1.removed, Vec::<String>::new());
+
+        // too big to show, and it rewrote the lines both edits were on
+        let big = (0..100).map(|i| format!("line {}\n", i)).collect::<String>();
+        history.record(&path, "import os\na\nb\nC\n", &big, 3.0);
+        assert_eq!(history.recent(10).len(), 0);
+    }
+
+    #[test]
+    fn test_big_hunk_is_skipped_but_shifts_line_numbers() {
+        let path = PathBuf::from("/a.py");
+        let mut history = EditHistory::default();
+        history.record(&path, "a\nb\nc\n", "a\nb\nC\n", 1.0);
+        let pasted = (0..100).map(|i| format!("line {}\n", i)).collect::<String>();
+        history.record(&path, "a\nb\nC\n", &format!("{}a\nb\nC\n", pasted), 2.0);
+        let edits = history.recent(10);
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].1.line1, 102);
+        assert_eq!(edits[0].1.added, vec!["C"]);
+
+        // a reload that rewrites the edited lines forgets the edit
+        let reloaded = (0..200).map(|i| format!("other {}\n", i)).collect::<String>();
+        history.record(&path, &format!("{}a\nb\nC\n", pasted), &reloaded, 3.0);
+        assert!(history.recent(10).is_empty());
+    }
+}
@@ -16,6 +16,7 @@ use tracing::info;
 use crate::git::operations::git_ls_files;
 use crate::global_context::GlobalContext;
 use crate::telemetry;
+use crate::edit_history::EditHistory;
 use crate::file_filter::{is_valid_file, SOURCE_FILE_EXTENSIONS};
 use crate::ast::ast_indexer_thread::ast_indexer_enqueue_files;
 use crate::privacy::{check_file_privacy, load_privacy_if_needed, PrivacySettings, FilePrivacyLevel};
@@ -143,6 +144,7 @@ pub struct DocumentsState {
     pub cache_correction: Arc<HashMap<String, HashSet<String>>>,  // map dir3/file.ext -> to /dir1/dir2/dir3/file.ext
     pub cache_shortened: Arc<HashSet<String>>,
     pub fs_watcher: Arc<ARwLock<RecommendedWatcher>>,
+    pub edit_history: Arc<StdMutex<EditHistory>>,
 }
 
 async fn mem_overwrite_or_create_document(
@@ -178,6 +180,7 @@ impl DocumentsState {
             cache_correction: Arc::new(HashMap::<String, HashSet<String>>::new()),
             cache_shortened: Arc::new(HashSet::<String>::new()),
             fs_watcher: Arc::new(ARwLock::new(watcher)),
+            edit_history: Arc::new(StdMutex::new(EditHistory::default())),
         }
     }
 }
@@ -663,6 +666,22 @@ pub async fn on_did_change(
     text: &String,
 ) {
     let t0 = Instant::now();
+    let (old_text, edit_history) = {
+        let gcx_locked = gcx.read().await;
+        let old_text = match gcx_locked.documents_state.memory_document_map.get(path) {
+            Some(doc) => doc.read().await.doc_text.as_ref().map(|t| t.to_string()),
+            None => None,
+        };
+        (old_text, gcx_locked.documents_state.edit_history.clone())
+    };
+    // edits go into prompts as they are, nothing from files the model is not supposed to see
+    let recordable = is_valid_file(path, false, false).is_ok()
+        && check_file_privacy(load_privacy_if_needed(gcx.clone()).await, path, &FilePrivacyLevel::OnlySendToServersIControl).is_ok();
+    if let (Some(old_text), true) = (old_text, recordable) {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+        let hunks = crate::edit_history::diff_hunks(&old_text, text, now);
+        edit_history.lock().unwrap().record_hunks(path, hunks, now);
+    }
     let (doc_arc, dirty_arc, mark_dirty) = {
         let mut doc = Document::new(path);
         doc.update_text(text);
@@ -704,11 +723,12 @@ pub async fn on_did_delete(gcx: Arc<ARwLock<GlobalContext>>, path: &PathBuf)
 {
     info!("on_did_delete {}", crate::nicer_logs::last_n_chars(&path.to_string_lossy().to_string(), 30));
 
-    let (vec_db_module, ast_service, dirty_arc) = {
+    let (vec_db_module, ast_service, dirty_arc, completions_cache) = {
         let mut cx = gcx.write().await;
         cx.documents_state.memory_document_map.remove(path);
-        (cx.vec_db.clone(), cx.ast_service.clone(), cx.documents_state.cache_dirty.clone())
+        (cx.vec_db.clone(), cx.ast_service.clone(), cx.documents_state.cache_dirty.clone(), cx.completions_cache.clone())
     };
+    crate::completion_cache::cache_invalidate_file(completions_cache, &path.to_string_lossy());
 
     let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
     (*dirty_arc.lock().await) = now;
@@ -0,0 +1,651 @@
+use std::collections::HashMap;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::USER_AGENT;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderValue;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::call_validation::{ReasoningEffort, SamplingParameters};
+use crate::cassette::{UpstreamError, UpstreamEvents};
+
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_DEFAULT_MAX_TOKENS: usize = 4096;
+// the api allows 4 breakpoints: one goes to the system prompt, these go to the history
+const CACHE_BREAKPOINTS_IN_MESSAGES: usize = 2;
+
+
+pub async fn forward_to_anthropic_endpoint(
+    save_url: &mut String,
+    bearer: String,
+    model_name: &str,
+    prompt: &str,
+    client: &reqwest::Client,
+    extra_headers: &HeaderMap,
+    endpoint_chat_passthrough: &String,
+    sampling_parameters: &SamplingParameters,
+    is_metadata_supported: bool,
+) -> Result<Value, UpstreamError> {
+    save_url.clone_from(endpoint_chat_passthrough);
+    let data = anthropic_request_data(model_name, prompt, sampling_parameters, false)?;
+    let resp = crate::cassette::post_json(client, endpoint_chat_passthrough, anthropic_headers(&bearer, is_metadata_supported, extra_headers), &data).await?;
+    if resp.status != 200 && resp.status != 400 {
+        return Err(resp.into_error(endpoint_chat_passthrough));
+    }
+    let status_code = resp.status;
+    let response_txt = resp.text;
+    if status_code != 200 {
+        info!("forward_to_anthropic_endpoint: {} {}\n{}", endpoint_chat_passthrough, status_code, response_txt);
+    }
+    let parsed_json: Value = match serde_json::from_str(&response_txt) {
+        Ok(json) => json,
+        Err(e) => return Err(format!("Failed to parse JSON response: {}\n{}", e, response_txt).into()),
+    };
+    Ok(anthropic_response_to_openai(&parsed_json, model_name))
+}
+
+pub async fn forward_to_anthropic_endpoint_streaming(
+    save_url: &mut String,
+    bearer: String,
+    model_name: &str,
+    prompt: &str,
+    client: &reqwest::Client,
+    extra_headers: &HeaderMap,
+    endpoint_chat_passthrough: &String,
+    sampling_parameters: &SamplingParameters,
+    is_metadata_supported: bool,
+) -> Result<UpstreamEvents, String> {
+    save_url.clone_from(endpoint_chat_passthrough);
+    let data = anthropic_request_data(model_name, prompt, sampling_parameters, true)?;
+    crate::cassette::post_streaming(client, endpoint_chat_passthrough, anthropic_headers(&bearer, is_metadata_supported, extra_headers), &data)
+}
+
+fn anthropic_headers(bearer: &String, is_metadata_supported: bool, extra_headers: &HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap());
+    headers.insert("anthropic-version", HeaderValue::from_str(ANTHROPIC_VERSION).unwrap());
+    if !bearer.is_empty() {
+        headers.insert("x-api-key", HeaderValue::from_str(bearer.as_str()).unwrap());
+    }
+    if is_metadata_supported {
+        headers.insert(USER_AGENT, HeaderValue::from_str(format!("refact-lsp {}", crate::version::build_info::PKG_VERSION).as_str()).unwrap());
+    }
+    headers.extend(extra_headers.clone());
+    headers
+}
+
+fn anthropic_request_data(
+    model_name: &str,
+    prompt: &str,
+    sampling_parameters: &SamplingParameters,
+    stream: bool,
+) -> Result<Value, String> {
+    // the messages api has no raw completions, so only the passthrough scratchpad can talk to it
+    let messages_str = prompt.strip_prefix("PASSTHROUGH ")
+        .ok_or("anthropic endpoint style works only with chat models that support passthrough".to_string())?;
+    let big_json: Value = serde_json::from_str(messages_str).map_err(|e| e.to_string())?;
+    let openai_messages = big_json.get("messages").and_then(|m| m.as_array()).cloned().unwrap_or_default();
+    let (system, mut messages) = convert_messages_to_anthropic(&openai_messages);
+    let thinking_budget = sampling_parameters.reasoning_effort.as_ref().map(thinking_budget_tokens);
+    if thinking_budget.is_none() {
+        // without thinking turned on the api doesn't accept thinking blocks in the history, an assistant turn that
+        // had nothing else is dropped, the user turns around it are merged to keep the roles alternating
+        let mut without_thinking = vec![];
+        for msg in messages.iter() {
+            let blocks = msg["content"].as_array().cloned().unwrap_or_default()
+                .into_iter().filter(|b| !is_thinking_block(b)).collect::<Vec<_>>();
+            push_anthropic_blocks(&mut without_thinking, msg["role"].as_str().unwrap_or_default(), blocks);
+        }
+        messages = without_thinking;
+    }
+
+    let mut max_tokens = if sampling_parameters.max_new_tokens > 0 { sampling_parameters.max_new_tokens } else { ANTHROPIC_DEFAULT_MAX_TOKENS };
+    if let Some(budget) = thinking_budget {
+        // the thinking budget is a part of max_tokens
+        max_tokens = max_tokens.max(budget + ANTHROPIC_DEFAULT_MAX_TOKENS);
+    }
+    let mut data = json!({
+        "model": model_name,
+        "max_tokens": max_tokens,
+        "stream": stream,
+        "messages": messages,
+    });
+    if !system.is_empty() {
+        data["system"] = json!(system);
+    }
+    if let Some(budget) = thinking_budget {
+        // temperature is not allowed together with thinking
+        data["thinking"] = json!({"type": "enabled", "budget_tokens": budget});
+    } else if let Some(temperature) = sampling_parameters.temperature {
+        data["temperature"] = json!(temperature);
+    }
+    if !sampling_parameters.stop.is_empty() {
+        data["stop_sequences"] = json!(sampling_parameters.stop);
+    }
+    if let Some(tools) = big_json.get("tools").and_then(|t| t.as_array()).filter(|t| !t.is_empty()) {
+        data["tools"] = json!(convert_tools_to_anthropic(tools));
+        if let Some(tool_choice) = big_json.get("tool_choice").and_then(|t| t.as_str()).and_then(convert_tool_choice_to_anthropic) {
+            // forcing a tool call is not compatible with thinking
+            if thinking_budget.is_none() || tool_choice["type"] != "any" {
+                data["tool_choice"] = tool_choice;
+            }
+        }
+    }
+    add_cache_breakpoints(&mut data);
+    Ok(data)
+}
+
+fn thinking_budget_tokens(reasoning_effort: &ReasoningEffort) -> usize {
+    match reasoning_effort {
+        ReasoningEffort::Low => 1024,  // the minimum the api accepts
+        ReasoningEffort::Medium => 4096,
+        ReasoningEffort::High => 16384,
+    }
+}
+
+fn is_thinking_block(block: &Value) -> bool {
+    matches!(block.get("type").and_then(|t| t.as_str()), Some("thinking") | Some("redacted_thinking"))
+}
+
+fn content_to_anthropic_blocks(content: &Value) -> Vec<Value> {
+    let mut blocks = vec![];
+    match content {
+        Value::String(text) if !text.is_empty() => {
+            blocks.push(json!({"type": "text", "text": text}));
+        },
+        Value::Array(parts) => {
+            for part in parts {
+                match part.get("type").and_then(|t| t.as_str()).unwrap_or_default() {
+                    "text" => {
+                        let text = part.get("text").and_then(|t| t.as_str()).unwrap_or_default();
+                        if !text.is_empty() {
+                            blocks.push(json!({"type": "text", "text": text}));
+                        }
+                    },
+                    "image_url" => {
+                        let url = part.get("image_url").and_then(|i| i.get("url")).and_then(|u| u.as_str()).unwrap_or_default();
+                        match url.strip_prefix("data:").and_then(|rest| rest.split_once(";base64,")) {
+                            Some((media_type, data)) => blocks.push(json!({
+                                "type": "image",
+                                "source": {"type": "base64", "media_type": media_type, "data": data},
+                            })),
+                            None => blocks.push(json!({"type": "image", "source": {"type": "url", "url": url}})),
+                        }
+                    },
+                    other => tracing::warn!("anthropic: skipping content part of unknown type {:?}", other),
+                }
+            }
+        },
+        _ => {},
+    }
+    blocks
+}
+
+fn push_anthropic_blocks(messages: &mut Vec<Value>, role: &str, blocks: Vec<Value>) {
+    if blocks.is_empty() {
+        return;
+    }
+    // roles must alternate, tool results and the user text that follows them become one user turn
+    if let Some(last) = messages.last_mut() {
+        if last["role"] == role {
+            last["content"].as_array_mut().unwrap().extend(blocks);
+            return;
+        }
+    }
+    messages.push(json!({"role": role, "content": blocks}));
+}
+
+pub fn convert_messages_to_anthropic(openai_messages: &Vec<Value>) -> (Vec<Value>, Vec<Value>) {
+    let mut system = vec![];
+    let mut messages = vec![];
+    for msg in openai_messages {
+        let role = msg.get("role").and_then(|r| r.as_str()).unwrap_or_default();
+        let content = msg.get("content").unwrap_or(&Value::Null);
+        match role {
+            "system" => system.extend(content_to_anthropic_blocks(content)),
+            "user" => push_anthropic_blocks(&mut messages, "user", content_to_anthropic_blocks(content)),
+            "assistant" => {
+                // thinking goes first and unchanged, the signatures are checked when the model continues after tool calls
+                let mut blocks = msg.get("thinking_blocks").and_then(|t| t.as_array()).cloned().unwrap_or_default();
+                blocks.retain(is_thinking_block);
+                blocks.extend(content_to_anthropic_blocks(content));
+                for tool_call in msg.get("tool_calls").and_then(|t| t.as_array()).cloned().unwrap_or_default() {
+                    let arguments = tool_call.get("function").and_then(|f| f.get("arguments")).and_then(|a| a.as_str()).unwrap_or_default();
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": tool_call.get("id").cloned().unwrap_or_default(),
+                        "name": tool_call.get("function").and_then(|f| f.get("name")).cloned().unwrap_or_default(),
+                        "input": serde_json::from_str::<Value>(arguments).ok().filter(|a| a.is_object()).unwrap_or(json!({})),
+                    }));
+                }
+                push_anthropic_blocks(&mut messages, "assistant", blocks);
+            },
+            "tool" => {
+                let mut result_blocks = content_to_anthropic_blocks(content);
+                if result_blocks.is_empty() {
+                    result_blocks.push(json!({"type": "text", "text": "(empty)"}));
+                }
+                push_anthropic_blocks(&mut messages, "user", vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": msg.get("tool_call_id").cloned().unwrap_or_default(),
+                    "content": result_blocks,
+                })]);
+            },
+            _ => tracing::warn!("anthropic: skipping message with unknown role {:?}", role),
+        }
+    }
+    (system, messages)
+}
+
+fn convert_tools_to_anthropic(openai_tools: &Vec<Value>) -> Vec<Value> {
+    openai_tools.iter().filter_map(|tool| {
+        let function = tool.get("function")?;
+        Some(json!({
+            "name": function.get("name")?,
+            "description": function.get("description").cloned().unwrap_or(json!("")),
+            "input_schema": function.get("parameters").cloned().unwrap_or(json!({"type": "object", "properties": {}})),
+        }))
+    }).collect()
+}
+
+fn convert_tool_choice_to_anthropic(tool_choice: &str) -> Option<Value> {
+    match tool_choice {
+        "auto" => Some(json!({"type": "auto"})),
+        "required" | "any" => Some(json!({"type": "any"})),
+        "none" => Some(json!({"type": "none"})),
+        _ => None,
+    }
+}
+
+fn add_cache_breakpoints(data: &mut Value) {
+    // system prompt (and tools that go before it) don't change between the turns, the history only grows:
+    // the previous user turn reads what the last request has written, the last one writes for the next request
+    if let Some(last_system_block) = data.get_mut("system").and_then(|s| s.as_array_mut()).and_then(|s| s.last_mut()) {
+        last_system_block["cache_control"] = json!({"type": "ephemeral"});
+    } else if let Some(last_tool) = data.get_mut("tools").and_then(|t| t.as_array_mut()).and_then(|t| t.last_mut()) {
+        last_tool["cache_control"] = json!({"type": "ephemeral"});
+    }
+    if let Some(messages) = data.get_mut("messages").and_then(|m| m.as_array_mut()) {
+        for msg in messages.iter_mut().rev().filter(|m| m["role"] == "user").take(CACHE_BREAKPOINTS_IN_MESSAGES) {
+            if let Some(last_block) = msg.get_mut("content").and_then(|c| c.as_array_mut()).and_then(|c| c.last_mut()) {
+                last_block["cache_control"] = json!({"type": "ephemeral"});
+            }
+        }
+    }
+}
+
+fn anthropic_finish_reason(stop_reason: &Value) -> Value {
+    match stop_reason.as_str() {
+        Some("end_turn") | Some("stop_sequence") => json!("stop"),
+        Some("tool_use") => json!("tool_calls"),
+        Some("max_tokens") => json!("length"),
+        Some(other) => json!(other),
+        None => Value::Null,
+    }
+}
+
+fn anthropic_usage_to_openai(usage: &Value) -> Value {
+    let get = |field: &str| usage.get(field).and_then(|v| v.as_u64()).unwrap_or(0);
+    let cache_read_tokens = get("cache_read_input_tokens");
+    let cache_creation_tokens = get("cache_creation_input_tokens");
+    // input_tokens doesn't include the cached part, openai's prompt_tokens does
+    let prompt_tokens = get("input_tokens") + cache_read_tokens + cache_creation_tokens;
+    let completion_tokens = get("output_tokens");
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+        "cache_read_tokens": cache_read_tokens,
+        "cache_creation_tokens": cache_creation_tokens,
+    })
+}
+
+pub fn anthropic_response_to_openai(resp: &Value, model_name: &str) -> Value {
+    if resp.get("type").and_then(|t| t.as_str()) == Some("error") {
+        return json!({"error": resp.get("error").cloned().unwrap_or_default()});
+    }
+    let mut text = String::new();
+    let mut reasoning = String::new();
+    let mut thinking_blocks = vec![];
+    let mut tool_calls = vec![];
+    for block in resp.get("content").and_then(|c| c.as_array()).cloned().unwrap_or_default() {
+        match block.get("type").and_then(|t| t.as_str()).unwrap_or_default() {
+            "text" => text.push_str(block.get("text").and_then(|t| t.as_str()).unwrap_or_default()),
+            "thinking" => {
+                reasoning.push_str(block.get("thinking").and_then(|t| t.as_str()).unwrap_or_default());
+                thinking_blocks.push(block);
+            },
+            "redacted_thinking" => thinking_blocks.push(block),
+            "tool_use" => tool_calls.push(json!({
+                "id": block.get("id").cloned().unwrap_or_default(),
+                "type": "function",
+                "function": {
+                    "name": block.get("name").cloned().unwrap_or_default(),
+                    "arguments": block.get("input").map(|i| i.to_string()).unwrap_or("{}".to_string()),
+                },
+            })),
+            _ => {},
+        }
+    }
+    let mut message = json!({
+        "role": "assistant",
+        "content": text,
+        "tool_calls": if tool_calls.is_empty() { Value::Null } else { json!(tool_calls) },
+    });
+    if !thinking_blocks.is_empty() {
+        message["reasoning_content"] = json!(reasoning);
+        message["thinking_blocks"] = json!(thinking_blocks);
+    }
+    json!({
+        "id": resp.get("id").cloned().unwrap_or_default(),
+        "object": "chat.completion",
+        "model": resp.get("model").and_then(|m| m.as_str()).unwrap_or(model_name),
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": anthropic_finish_reason(resp.get("stop_reason").unwrap_or(&Value::Null)),
+        }],
+        "usage": anthropic_usage_to_openai(resp.get("usage").unwrap_or(&json!({}))),
+    })
+}
+
+/// Turns anthropic SSE events into openai-style chat.completion.chunk values, the rest of restream works with those.
+/// Thinking streams as delta.reasoning_content, a complete block with its signature arrives as delta.thinking_blocks
+/// once the signature is known, the client appends those to the message.
+#[derive(Default)]
+pub struct AnthropicStreamConverter {
+    model: String,
+    message_id: String,
+    usage: serde_json::Map<String, Value>,
+    block_to_tool_call_index: HashMap<u64, usize>,
+    block_to_thinking: HashMap<u64, String>,
+}
+
+impl AnthropicStreamConverter {
+    pub fn new(model_name: &str) -> Self {
+        AnthropicStreamConverter {
+            model: model_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Value) -> Value {
+        json!({
+            "id": self.message_id,
+            "object": "chat.completion.chunk",
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        })
+    }
+
+    fn merge_usage(&mut self, usage: Option<&Value>) {
+        if let Some(Value::Object(usage)) = usage {
+            for (k, v) in usage {
+                if !v.is_null() {
+                    self.usage.insert(k.clone(), v.clone());
+                }
+            }
+        }
+    }
+
+    pub fn is_message_stop(event: &Value) -> bool {
+        event.get("type").and_then(|t| t.as_str()) == Some("message_stop")
+    }
+
+    pub fn convert(&mut self, event: &Value) -> Result<Vec<Value>, String> {
+        let event_type = event.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+        match event_type {
+            "message_start" => {
+                let message = event.get("message").unwrap_or(&Value::Null);
+                if let Some(model) = message.get("model").and_then(|m| m.as_str()) {
+                    self.model = model.to_string();
+                }
+                self.message_id = message.get("id").and_then(|i| i.as_str()).unwrap_or_default().to_string();
+                self.merge_usage(message.get("usage"));
+                Ok(vec![self.chunk(json!({"role": "assistant", "content": ""}), Value::Null)])
+            },
+            "content_block_start" => {
+                let block = event.get("content_block").unwrap_or(&Value::Null);
+                let block_index = event.get("index").and_then(|i| i.as_u64()).unwrap_or_default();
+                match block.get("type").and_then(|t| t.as_str()).unwrap_or_default() {
+                    "tool_use" => {
+                        let tool_call_index = self.block_to_tool_call_index.len();
+                        self.block_to_tool_call_index.insert(block_index, tool_call_index);
+                        Ok(vec![self.chunk(json!({"tool_calls": [{
+                            "index": tool_call_index,
+                            "id": block.get("id").cloned().unwrap_or_default(),
+                            "type": "function",
+                            "function": {"name": block.get("name").cloned().unwrap_or_default(), "arguments": ""},
+                        }]}), Value::Null)])
+                    },
+                    "text" => {
+                        let text = block.get("text").and_then(|t| t.as_str()).unwrap_or_default();
+                        if text.is_empty() {
+                            Ok(vec![])
+                        } else {
+                            Ok(vec![self.chunk(json!({"content": text}), Value::Null)])
+                        }
+                    },
+                    "thinking" => {
+                        let thinking = block.get("thinking").and_then(|t| t.as_str()).unwrap_or_default();
+                        self.block_to_thinking.insert(block_index, thinking.to_string());
+                        if thinking.is_empty() {
+                            Ok(vec![])
+                        } else {
+                            Ok(vec![self.chunk(json!({"reasoning_content": thinking}), Value::Null)])
+                        }
+                    },
+                    "redacted_thinking" => Ok(vec![self.chunk(json!({"thinking_blocks": [block]}), Value::Null)]),
+                    _ => Ok(vec![]),
+                }
+            },
+            "content_block_delta" => {
+                let delta = event.get("delta").unwrap_or(&Value::Null);
+                let block_index = event.get("index").and_then(|i| i.as_u64()).unwrap_or_default();
+                match delta.get("type").and_then(|t| t.as_str()).unwrap_or_default() {
+                    "text_delta" => Ok(vec![self.chunk(json!({"content": delta.get("text").cloned().unwrap_or_default()}), Value::Null)]),
+                    "thinking_delta" => {
+                        let thinking = delta.get("thinking").and_then(|t| t.as_str()).unwrap_or_default();
+                        self.block_to_thinking.entry(block_index).or_default().push_str(thinking);
+                        Ok(vec![self.chunk(json!({"reasoning_content": thinking}), Value::Null)])
+                    },
+                    "signature_delta" => {
+                        let thinking = self.block_to_thinking.remove(&block_index).unwrap_or_default();
+                        Ok(vec![self.chunk(json!({"thinking_blocks": [{
+                            "type": "thinking",
+                            "thinking": thinking,
+                            "signature": delta.get("signature").cloned().unwrap_or_default(),
+                        }]}), Value::Null)])
+                    },
+                    "input_json_delta" => {
+                        let tool_call_index = self.block_to_tool_call_index.get(&block_index)
+                            .ok_or(format!("anthropic: input_json_delta for unknown content block {}", block_index))?;
+                        Ok(vec![self.chunk(json!({"tool_calls": [{
+                            "index": tool_call_index,
+                            "function": {"arguments": delta.get("partial_json").cloned().unwrap_or_default()},
+                        }]}), Value::Null)])
+                    },
+                    _ => Ok(vec![]),
+                }
+            },
+            "message_delta" => {
+                self.merge_usage(event.get("usage"));
+                let finish_reason = anthropic_finish_reason(event.get("delta").and_then(|d| d.get("stop_reason")).unwrap_or(&Value::Null));
+                Ok(vec![
+                    self.chunk(json!({}), finish_reason),
+                    json!({
+                        "id": self.message_id,
+                        "object": "chat.completion.chunk",
+                        "model": self.model,
+                        "choices": [],
+                        "usage": anthropic_usage_to_openai(&Value::Object(self.usage.clone())),
+                    }),
+                ])
+            },
+            "error" => Err(format!("{}", event.get("error").unwrap_or(event))),
+            _ => Ok(vec![]),  // ping, content_block_stop, message_stop
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_messages_to_anthropic() {
+        let openai_messages = vec![
+            json!({"role": "system", "content": "You are a helpful assistant."}),
+            json!({"role": "user", "content": "What's in main.rs?"}),
+            json!({"role": "assistant", "content": "Let me look.", "tool_calls": [
+                {"id": "toolu_1", "type": "function", "function": {"name": "cat", "arguments": "{\"paths\": \"main.rs\"}"}},
+                {"id": "toolu_2", "type": "function", "function": {"name": "tree", "arguments": ""}},
+            ]}),
+            json!({"role": "tool", "tool_call_id": "toolu_1", "content": "fn main() {}"}),
+            json!({"role": "tool", "tool_call_id": "toolu_2", "content": ""}),
+            json!({"role": "user", "content": [{"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}}]}),
+        ];
+        let (system, messages) = convert_messages_to_anthropic(&openai_messages);
+        assert_eq!(system, vec![json!({"type": "text", "text": "You are a helpful assistant."})]);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][1], json!({"type": "tool_use", "id": "toolu_1", "name": "cat", "input": {"paths": "main.rs"}}));
+        assert_eq!(messages[1]["content"][2]["input"], json!({}));
+        // both tool results and the image that follows them are in the same user turn
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"][0]["type"], "tool_result");
+        assert_eq!(messages[2]["content"][0]["tool_use_id"], "toolu_1");
+        assert_eq!(messages[2]["content"][1]["content"][0]["text"], "(empty)");
+        assert_eq!(messages[2]["content"][2], json!({"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "AAAA"}}));
+    }
+
+    #[test]
+    fn test_request_has_cache_breakpoints() {
+        let big_json = json!({
+            "messages": [
+                {"role": "system", "content": "system prompt"},
+                {"role": "user", "content": "first question"},
+                {"role": "assistant", "content": "first answer"},
+                {"role": "user", "content": "second question"},
+                {"role": "assistant", "content": "second answer"},
+                {"role": "user", "content": "third question"},
+            ],
+            "tools": [{"type": "function", "function": {"name": "cat", "description": "Read files", "parameters": {"type": "object", "properties": {}}, "agentic": false}}],
+            "tool_choice": "auto",
+        });
+        let prompt = format!("PASSTHROUGH {}", big_json);
+        let sampling_parameters = SamplingParameters { max_new_tokens: 0, temperature: Some(0.2), ..Default::default() };
+        let data = anthropic_request_data("claude-3-7-sonnet", &prompt, &sampling_parameters, true).unwrap();
+        assert_eq!(data["max_tokens"], ANTHROPIC_DEFAULT_MAX_TOKENS);
+        assert_eq!(data["system"][0]["cache_control"], json!({"type": "ephemeral"}));
+        assert_eq!(data["tools"][0], json!({"name": "cat", "description": "Read files", "input_schema": {"type": "object", "properties": {}}}));
+        assert_eq!(data["tool_choice"], json!({"type": "auto"}));
+        let cached = data["messages"].as_array().unwrap().iter()
+            .map(|m| m["content"][0].get("cache_control").is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(cached, vec![false, false, true, false, true]);
+        assert!(anthropic_request_data("claude-3-7-sonnet", "raw prompt", &sampling_parameters, true).is_err());
+    }
+
+    #[test]
+    fn test_anthropic_response_to_openai() {
+        let resp = json!({
+            "id": "msg_1", "type": "message", "role": "assistant", "model": "claude-3-7-sonnet",
+            "content": [{"type": "text", "text": "Looking"}, {"type": "tool_use", "id": "toolu_1", "name": "cat", "input": {"paths": "a.rs"}}],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 10, "output_tokens": 5, "cache_read_input_tokens": 100, "cache_creation_input_tokens": 20},
+        });
+        let openai = anthropic_response_to_openai(&resp, "claude");
+        assert_eq!(openai["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(openai["choices"][0]["message"]["content"], "Looking");
+        assert_eq!(openai["choices"][0]["message"]["tool_calls"][0]["function"]["arguments"], "{\"paths\":\"a.rs\"}");
+        assert_eq!(openai["usage"]["prompt_tokens"], 130);
+        assert_eq!(openai["usage"]["cache_read_tokens"], 100);
+        assert_eq!(openai["usage"]["cache_creation_tokens"], 20);
+        let usage: crate::call_validation::ChatUsage = serde_json::from_value(openai["usage"].clone()).unwrap();
+        assert_eq!(usage.cache_read_tokens, 100);
+    }
+
+    #[test]
+    fn test_stream_converter() {
+        let events = vec![
+            json!({"type": "message_start", "message": {"id": "msg_1", "model": "claude-3-7-sonnet", "usage": {"input_tokens": 7, "output_tokens": 1, "cache_read_input_tokens": 50}}}),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Hi"}}),
+            json!({"type": "ping"}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "tool_use", "id": "toolu_1", "name": "cat", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "{\"paths\":"}}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use"}, "usage": {"output_tokens": 15}}),
+        ];
+        let mut converter = AnthropicStreamConverter::new("claude");
+        let chunks = events.iter().flat_map(|e| converter.convert(e).unwrap()).collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 6);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "Hi");
+        assert_eq!(chunks[2]["choices"][0]["delta"]["tool_calls"][0]["id"], "toolu_1");
+        assert_eq!(chunks[3]["choices"][0]["delta"]["tool_calls"][0]["function"]["arguments"], "{\"paths\":");
+        assert_eq!(chunks[4]["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(chunks[5]["usage"], json!({"prompt_tokens": 57, "completion_tokens": 15, "total_tokens": 72, "cache_read_tokens": 50, "cache_creation_tokens": 0}));
+        assert!(converter.convert(&json!({"type": "error", "error": {"type": "overloaded_error"}})).is_err());
+        assert!(AnthropicStreamConverter::is_message_stop(&json!({"type": "message_stop"})));
+    }
+
+    #[test]
+    fn test_thinking_round_trip() {
+        let events = vec![
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "thinking", "thinking": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "Need to "}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "thinking_delta", "thinking": "read a.rs"}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "signature_delta", "signature": "sig1"}}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "redacted_thinking", "data": "opaque"}}),
+        ];
+        let mut converter = AnthropicStreamConverter::new("claude");
+        let deltas = events.iter().flat_map(|e| converter.convert(e).unwrap()).map(|c| c["choices"][0]["delta"].clone()).collect::<Vec<_>>();
+        assert_eq!(deltas[0], json!({"reasoning_content": "Need to "}));
+        assert_eq!(deltas[2]["thinking_blocks"][0], json!({"type": "thinking", "thinking": "Need to read a.rs", "signature": "sig1"}));
+        assert_eq!(deltas[3]["thinking_blocks"][0]["data"], "opaque");
+
+        let resp = json!({
+            "content": [{"type": "thinking", "thinking": "Need to read a.rs", "signature": "sig1"}, {"type": "text", "text": "Reading"}],
+            "stop_reason": "end_turn",
+        });
+        let message = anthropic_response_to_openai(&resp, "claude")["choices"][0]["message"].clone();
+        assert_eq!(message["reasoning_content"], "Need to read a.rs");
+        assert_eq!(message["content"], "Reading");
+
+        let big_json = json!({"messages": [
+            {"role": "user", "content": "show a.rs"},
+            {"role": "assistant", "content": "Reading", "reasoning_content": "Need to read a.rs", "thinking_blocks": message["thinking_blocks"]},
+            {"role": "user", "content": "thanks"},
+        ]});
+        let prompt = format!("PASSTHROUGH {}", big_json);
+        let thinking_on = SamplingParameters { temperature: Some(0.2), reasoning_effort: Some(ReasoningEffort::Low), ..Default::default() };
+        let data = anthropic_request_data("claude-3-7-sonnet", &prompt, &thinking_on, false).unwrap();
+        assert_eq!(data["thinking"], json!({"type": "enabled", "budget_tokens": 1024}));
+        assert!(data.get("temperature").is_none());
+        assert!(data["max_tokens"].as_u64().unwrap() > 1024);
+        assert_eq!(data["messages"][1]["content"][0], json!({"type": "thinking", "thinking": "Need to read a.rs", "signature": "sig1"}));
+        assert_eq!(data["messages"][1]["content"][1]["text"], "Reading");
+        let thinking_off = SamplingParameters::default();
+        let data = anthropic_request_data("claude-3-7-sonnet", &prompt, &thinking_off, false).unwrap();
+        assert_eq!(data["messages"][1]["content"], json!([{"type": "text", "text": "Reading"}]));
+    }
+
+    #[test]
+    fn test_thinking_only_turn_is_dropped_without_thinking() {
+        let big_json = json!({"messages": [
+            {"role": "user", "content": "show a.rs"},
+            {"role": "assistant", "content": "", "thinking_blocks": [{"type": "thinking", "thinking": "hmm", "signature": "sig1"}]},
+            {"role": "user", "content": "are you there?"},
+        ]});
+        let prompt = format!("PASSTHROUGH {}", big_json);
+        let data = anthropic_request_data("claude-3-7-sonnet", &prompt, &SamplingParameters::default(), false).unwrap();
+        let messages = data["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[0]["content"][0]["text"], "show a.rs");
+        assert_eq!(messages[0]["content"][1]["text"], "are you there?");
+    }
+}
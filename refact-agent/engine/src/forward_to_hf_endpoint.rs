@@ -2,12 +2,12 @@ use reqwest::header::AUTHORIZATION;
 use reqwest::header::CONTENT_TYPE;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
-use reqwest_eventsource::EventSource;
 use serde_json::json;
 #[cfg(feature="vecdb")]
 use tokio::sync::Mutex as AMutex;
 
 use crate::call_validation::{ChatMeta, SamplingParameters};
+use crate::cassette::{UpstreamError, UpstreamEvents};
 
 // Idea: use USER_AGENT
 // let user_agent = format!("{NAME}/{VERSION}; rust/unknown; ide/{ide:?}");
@@ -19,10 +19,11 @@ pub async fn forward_to_hf_style_endpoint(
     model_name: &str,
     prompt: &str,
     client: &reqwest::Client,
+    extra_headers: &HeaderMap,
     endpoint_template: &String,
     sampling_parameters: &SamplingParameters,
     meta: Option<ChatMeta>
-) -> Result<serde_json::Value, String> {
+) -> Result<serde_json::Value, UpstreamError> {
     let url = endpoint_template.replace("$MODEL", model_name);
     save_url.clone_from(&&url);
     let mut headers = HeaderMap::new();
@@ -30,9 +31,13 @@ pub async fn forward_to_hf_style_endpoint(
     if !bearer.is_empty() {
         headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", bearer).as_str()).unwrap());
     }
+    headers.extend(extra_headers.clone());
     let params_string = serde_json::to_string(sampling_parameters).unwrap();
     let mut params_json = serde_json::from_str::<serde_json::Value>(&params_string).unwrap();
     params_json["return_full_text"] = serde_json::Value::Bool(false);
+    if let Some(params_obj) = params_json.as_object_mut() {
+        params_obj.remove("response_format");  // no native support, the answer is validated by the caller
+    }
 
     let mut data = json!({
         "inputs": prompt,
@@ -42,22 +47,13 @@ pub async fn forward_to_hf_style_endpoint(
         data["meta"] = serde_json::to_value(meta).unwrap();
     }
     
-    let req = client.post(&url)
-        .headers(headers)
-        .body(data.to_string())
-        .send()
-        .await;
-    let resp = req.map_err(|e| format!("{}", e))?;
-    let status_code = resp.status().as_u16();
-    let response_txt = resp.text().await.map_err(|e|
-        format!("reading from socket {}: {}", url, e)
-    )?;
-    if status_code != 200 {
-        return Err(format!("{} status={} text {}", url, status_code, response_txt));
+    let resp = crate::cassette::post_json(client, &url, headers, &data).await?;
+    if resp.status != 200 {
+        return Err(resp.into_error(&url));
     }
-    Ok(match serde_json::from_str(&response_txt) {
+    Ok(match serde_json::from_str(&resp.text) {
         Ok(json) => json,
-        Err(e) => return Err(format!("{}: {}", url, e)),
+        Err(e) => return Err(format!("{}: {}", url, e).into()),
     })
 }
 
@@ -68,10 +64,11 @@ pub async fn forward_to_hf_style_endpoint_streaming(
     model_name: &str,
     prompt: &str,
     client: &reqwest::Client,
+    extra_headers: &HeaderMap,
     endpoint_template: &String,
     sampling_parameters: &SamplingParameters,
     meta: Option<ChatMeta>
-) -> Result<EventSource, String> {
+) -> Result<UpstreamEvents, String> {
     let url = endpoint_template.replace("$MODEL", model_name);
     save_url.clone_from(&&url);
     let mut headers = HeaderMap::new();
@@ -79,9 +76,13 @@ pub async fn forward_to_hf_style_endpoint_streaming(
     if !bearer.is_empty() {
         headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", bearer).as_str()).unwrap());
     }
+    headers.extend(extra_headers.clone());
     let params_string = serde_json::to_string(sampling_parameters).unwrap();
     let mut params_json = serde_json::from_str::<serde_json::Value>(&params_string).unwrap();
     params_json["return_full_text"] = serde_json::Value::Bool(false);
+    if let Some(params_obj) = params_json.as_object_mut() {
+        params_obj.remove("response_format");  // no native support, the answer is validated by the caller
+    }
 
     let mut data = json!({
         "inputs": prompt,
@@ -92,13 +93,7 @@ pub async fn forward_to_hf_style_endpoint_streaming(
         data["meta"] = serde_json::to_value(meta).unwrap();
     }
 
-    let builder = client.post(&url)
-        .headers(headers)
-        .body(data.to_string());
-    let event_source: EventSource = EventSource::new(builder).map_err(|e|
-        format!("can't stream from {}: {}", url, e)
-    )?;
-    Ok(event_source)
+    crate::cassette::post_streaming(client, &url, headers, &data)
 }
 
 #[cfg(feature="vecdb")]
@@ -132,31 +127,25 @@ pub async fn get_embedding_hf_style(
     let payload = EmbeddingsPayloadHF { inputs: text, options: EmbeddingsPayloadHFOptions::new() };
     let url = endpoint_template.clone().replace("$MODEL", &model_name);
 
-    let maybe_response = client.lock().await
-        .post(&url)
-        .bearer_auth(api_key.clone())
-        .json(&payload)
-        .send()
-        .await;
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap());
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", api_key).as_str()).map_err(|e| format!("bad api key: {}", e))?);
+    let maybe_response = crate::cassette::post_json(&*client.lock().await, &url, headers, &serde_json::to_value(&payload).unwrap()).await;
 
     match maybe_response {
         Ok(response) => {
-            let status = response.status().clone();
-            if status.is_success() {
-                match response.json::<Vec<Vec<f32>>>().await {
+            if (200..300).contains(&response.status) {
+                match serde_json::from_str::<Vec<Vec<f32>>>(&response.text) {
                     Ok(embedding) =>
                         Ok(embedding),
                     Err(err) => Err(format!("Failed to parse the response: {:?}", err)),
                 }
+            } else if response.text.is_empty() {
+                Err(format!("Failed to get a response: {:?}", response.status))
             } else {
-                let body = response.text().await.unwrap().clone();
-                if body.is_empty() {
-                    Err(format!("Failed to get a response: {:?}", status))
-                } else {
-                    Err(format!("Failed to get a response: {:?}", body))
-                }
+                Err(format!("Failed to get a response: {:?}", response.text))
             }
         }
-        Err(err) => Err(format!("Failed to send a request: {:?}", err)),
+        Err(err) => Err(format!("Failed to send a request: {}", err)),
     }
 }
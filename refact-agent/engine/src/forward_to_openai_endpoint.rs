@@ -3,13 +3,13 @@ use reqwest::header::CONTENT_TYPE;
 use reqwest::header::USER_AGENT;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
-use reqwest_eventsource::EventSource;
 use serde_json::json;
 #[cfg(feature="vecdb")]
 use tokio::sync::Mutex as AMutex;
 use tracing::info;
 
 use crate::call_validation::{ChatMeta, SamplingParameters};
+use crate::cassette::{UpstreamError, UpstreamEvents};
 
 
 pub async fn forward_to_openai_style_endpoint(
@@ -18,12 +18,13 @@ pub async fn forward_to_openai_style_endpoint(
     model_name: &str,
     prompt: &str,
     client: &reqwest::Client,
+    extra_headers: &HeaderMap,
     endpoint_template: &String,
     endpoint_chat_passthrough: &String,
     sampling_parameters: &SamplingParameters,
     is_metadata_supported: bool,
     meta: Option<ChatMeta>
-) -> Result<serde_json::Value, String> {
+) -> Result<serde_json::Value, UpstreamError> {
     let is_passthrough = prompt.starts_with("PASSTHROUGH ");
     let url = if !is_passthrough { endpoint_template.replace("$MODEL", model_name) } else { endpoint_chat_passthrough.clone() };
     save_url.clone_from(&&url);
@@ -32,35 +33,19 @@ pub async fn forward_to_openai_style_endpoint(
     if !bearer.is_empty() {
         headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", bearer).as_str()).unwrap());
     }
+    headers.extend(extra_headers.clone());
     if is_metadata_supported {
         headers.insert(USER_AGENT, HeaderValue::from_str(format!("refact-lsp {}", crate::version::build_info::PKG_VERSION).as_str()).unwrap());
     }
-    let mut data = json!({
-        "model": model_name,
-        "stream": false,
-    });
-    if !sampling_parameters.stop.is_empty() {  // openai does not like empty stop
-        data["stop"] = serde_json::Value::from(sampling_parameters.stop.clone());
-    };
-    if let Some(n) = sampling_parameters.n {
-        data["n"] = serde_json::Value::from(n);
-    }
-    if model_name != "o1-mini" {
-        data["temperature"] = serde_json::Value::from(sampling_parameters.temperature);
-        data["max_completion_tokens"] = serde_json::Value::from(sampling_parameters.max_new_tokens);
-    } else {
-        data["max_completion_tokens"] = serde_json::Value::from(sampling_parameters.max_new_tokens);
-    }
-    if let Some(n) = sampling_parameters.n {
-        if n > 1 {
-            data["n"] = serde_json::Value::from(n);
-        }
-    }
+    let mut data = non_streaming_body(model_name, sampling_parameters);
     info!("NOT STREAMING TEMP {}", sampling_parameters.temperature
         .map(|x| x.to_string())
         .unwrap_or("None".to_string()));
     if is_passthrough {
         passthrough_messages_to_json(&mut data, prompt, model_name);
+        if let Some(response_format) = &sampling_parameters.response_format {
+            data["response_format"] = response_format.clone();
+        }
     } else {
         data["prompt"] = serde_json::Value::String(prompt.to_string());
         data["echo"] = serde_json::Value::Bool(false);
@@ -70,43 +55,90 @@ pub async fn forward_to_openai_style_endpoint(
     }
     
     // When cancelling requests, coroutine ususally gets aborted here on the following line.
-    let req = client.post(&url)
-        .headers(headers)
-        .body(data.to_string())
-        .send()
-        .await;
-    let resp = req.map_err(|e| format!("{}", e))?;
-    let status_code = resp.status().as_u16();
-    let response_txt = resp.text().await.map_err(|e|
-        format!("reading from socket {}: {}", url, e)
-    )?;
+    let resp = crate::cassette::post_json(client, &url, headers, &data).await?;
     // 400 "client error" is likely a json that we rather accept here, pick up error details as we analyse json fields at the level
     // higher, the most often 400 is no such model.
-    if status_code != 200 && status_code != 400 {
-        return Err(format!("{} status={} text {}", url, status_code, response_txt));
+    if resp.status != 200 && resp.status != 400 {
+        return Err(resp.into_error(&url));
     }
+    let status_code = resp.status;
+    let response_txt = resp.text;
     if status_code != 200 {
         info!("forward_to_openai_style_endpoint: {} {}\n{}", url, status_code, response_txt);
     }
     let parsed_json: serde_json::Value = match serde_json::from_str(&response_txt) {
         Ok(json) => json,
-        Err(e) => return Err(format!("Failed to parse JSON response: {}\n{}", e, response_txt)),
+        Err(e) => return Err(format!("Failed to parse JSON response: {}\n{}", e, response_txt).into()),
     };
     Ok(parsed_json)
 }
 
+fn non_streaming_body(model_name: &str, sampling_parameters: &SamplingParameters) -> serde_json::Value {
+    let mut data = json!({
+        "model": model_name,
+        "stream": false,
+    });
+    if !sampling_parameters.stop.is_empty() {  // openai does not like empty stop
+        data["stop"] = serde_json::Value::from(sampling_parameters.stop.clone());
+    };
+    if model_name != "o1-mini" {
+        data["temperature"] = serde_json::Value::from(sampling_parameters.temperature);
+    }
+    data["max_completion_tokens"] = serde_json::Value::from(sampling_parameters.max_new_tokens);
+    if let Some(n) = sampling_parameters.n {
+        if n > 1 {
+            data["n"] = serde_json::Value::from(n);
+        }
+    }
+    data
+}
+
+// Completions API takes an array of prompts, choices come back together, choice["index"] is prompt_idx * n + choice_idx
+pub async fn forward_to_openai_style_endpoint_batch(
+    save_url: &mut String,
+    bearer: String,
+    model_name: &str,
+    prompts: &Vec<String>,
+    client: &reqwest::Client,
+    extra_headers: &HeaderMap,
+    endpoint_template: &String,
+    sampling_parameters: &SamplingParameters,
+    is_metadata_supported: bool,
+) -> Result<serde_json::Value, UpstreamError> {
+    let url = endpoint_template.replace("$MODEL", model_name);
+    save_url.clone_from(&&url);
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap());
+    if !bearer.is_empty() {
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", bearer).as_str()).unwrap());
+    }
+    headers.extend(extra_headers.clone());
+    if is_metadata_supported {
+        headers.insert(USER_AGENT, HeaderValue::from_str(format!("refact-lsp {}", crate::version::build_info::PKG_VERSION).as_str()).unwrap());
+    }
+    let mut data = non_streaming_body(model_name, sampling_parameters);
+    data["prompt"] = json!(prompts);
+    data["echo"] = serde_json::Value::Bool(false);
+    let resp = crate::cassette::post_json(client, &url, headers, &data).await?;
+    if resp.status != 200 {
+        return Err(resp.into_error(&url));
+    }
+    serde_json::from_str(&resp.text).map_err(|e| format!("Failed to parse JSON response: {}\n{}", e, resp.text).into())
+}
+
 pub async fn forward_to_openai_style_endpoint_streaming(
     save_url: &mut String,
     bearer: String,
     model_name: &str,
     prompt: &str,
     client: &reqwest::Client,
+    extra_headers: &HeaderMap,
     endpoint_template: &String,
     endpoint_chat_passthrough: &String,
     sampling_parameters: &SamplingParameters,
     is_metadata_supported: bool,
     meta: Option<ChatMeta>
-) -> Result<EventSource, String> {
+) -> Result<UpstreamEvents, String> {
     let is_passthrough = prompt.starts_with("PASSTHROUGH ");
     let url = if !is_passthrough { endpoint_template.replace("$MODEL", model_name) } else { endpoint_chat_passthrough.clone() };
     save_url.clone_from(&&url);
@@ -115,6 +147,7 @@ pub async fn forward_to_openai_style_endpoint_streaming(
     if !bearer.is_empty() {
         headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", bearer).as_str()).unwrap());
     }
+    headers.extend(extra_headers.clone());
     if is_metadata_supported {
         headers.insert(USER_AGENT, HeaderValue::from_str(format!("refact-lsp {}", crate::version::build_info::PKG_VERSION).as_str()).unwrap());
     }
@@ -137,19 +170,16 @@ pub async fn forward_to_openai_style_endpoint_streaming(
         .unwrap_or("None".to_string()));
     if is_passthrough {
         passthrough_messages_to_json(&mut data, prompt, model_name);
+        if let Some(response_format) = &sampling_parameters.response_format {
+            data["response_format"] = response_format.clone();
+        }
     } else {
         data["prompt"] = serde_json::Value::String(prompt.to_string());
     }
     if let Some(meta) = meta {
         data["meta"] = json!(meta);
     }
-    let builder = client.post(&url)
-        .headers(headers)
-        .body(data.to_string());
-    let event_source: EventSource = EventSource::new(builder).map_err(|e|
-        format!("can't stream from {}: {}", url, e)
-    )?;
-    Ok(event_source)
+    crate::cassette::post_streaming(client, &url, headers, &data)
 }
 
 fn passthrough_messages_to_json(
@@ -162,6 +192,12 @@ fn passthrough_messages_to_json(
     let big_json: serde_json::Value = serde_json::from_str(&messages_str).unwrap();
 
     data["messages"] = big_json["messages"].clone();
+    // deepseek and others reject their own reasoning_content in the input, litellm wants thinking_blocks back to talk to anthropic
+    if let Some(messages) = data["messages"].as_array_mut() {
+        for msg in messages.iter_mut().filter_map(|m| m.as_object_mut()) {
+            msg.remove("reasoning_content");
+        }
+    }
     if let Some(tools) = big_json.get("tools") {
         if model_name != "o1-mini" {
             data["tools"] = tools.clone();
@@ -204,24 +240,21 @@ pub async fn get_embedding_openai_style(
         model: model_name.clone(),
     };
     let url = endpoint_template.clone();
-    let api_key_clone = api_key.clone();
-    let response = client.lock().await
-        .post(&url)
-        .bearer_auth(api_key_clone.clone())
-        .json(&payload)
-        .send()
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap());
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(format!("Bearer {}", api_key).as_str()).map_err(|e| format!("bad api key: {}", e))?);
+    let response = crate::cassette::post_json(&*client.lock().await, &url, headers, &serde_json::to_value(&payload).unwrap())
         .await
-        .map_err(|e| format!("Failed to send a request: {:?}", e))?;
+        .map_err(|e| format!("Failed to send a request: {}", e))?;
 
-    if !response.status().is_success() {
-        if response.status().as_u16() != 503 {
-            info!("get_embedding_openai_style: {:?}", response);
+    if !(200..300).contains(&response.status) {
+        if response.status != 503 {
+            info!("get_embedding_openai_style: status={} {}", response.status, response.text);
         }
-        return Err(format!("get_embedding_openai_style: bad status: {:?}", response.status()));
+        return Err(format!("get_embedding_openai_style: bad status: {}", response.status));
     }
 
-    let json = response.json::<serde_json::Value>()
-        .await
+    let json = serde_json::from_str::<serde_json::Value>(&response.text)
         .map_err(|err| format!("get_embedding_openai_style: failed to parse the response: {:?}", err))?;
 
     // info!("get_embedding_openai_style: {:?}", json);
@@ -18,6 +18,7 @@ use tracing::{error, info};
 use crate::ast::ast_indexer_thread::AstIndexService;
 use crate::caps::CodeAssistantCaps;
 use crate::completion_cache::CompletionCache;
+use crate::completion_coalescing::CompletionsInFlight;
 use crate::custom_error::ScratchError;
 use crate::files_in_workspace::DocumentsState;
 use crate::integrations::docker::docker_ssh_tunnel_utils::SshTunnel;
@@ -100,6 +101,11 @@ pub struct CommandLine {
     pub variables_yaml: String,
     #[structopt(long, default_value="", help="Specify the secrets.yaml, disabling the global one")]
     pub secrets_yaml: String,
+
+    #[structopt(long, default_value="", help="Record all requests to the model endpoints (chat, completion, embeddings) and the responses into this jsonl file.")]
+    pub cassette_record: String,
+    #[structopt(long, default_value="", help="Answer requests to the model endpoints from a file made by --cassette-record, without network. A request that wasn't recorded is an error, useful for offline regression tests.")]
+    pub cassette_replay: String,
 }
 
 impl CommandLine {
@@ -154,6 +160,7 @@ pub struct GlobalContext {
     pub tokenizer_map: HashMap< String, Arc<StdRwLock<Tokenizer>>>,
     pub tokenizer_download_lock: Arc<AMutex<bool>>,
     pub completions_cache: Arc<StdRwLock<CompletionCache>>,
+    pub completions_in_flight: Arc<StdMutex<CompletionsInFlight>>,
     pub telemetry: Arc<StdRwLock<telemetry_structs::Storage>>,
     #[cfg(feature="vecdb")]
     pub vec_db: Arc<AMutex<Option<crate::vecdb::vdb_highlev::VecDb>>>,
@@ -164,6 +171,7 @@ pub struct GlobalContext {
     pub ask_shutdown_sender: Arc<StdMutex<std::sync::mpsc::Sender<String>>>,
     pub documents_state: DocumentsState,
     pub at_commands_preview_cache: Arc<AMutex<AtCommandsPreviewCache>>,
+    pub history_summary_cache: Arc<AMutex<crate::scratchpads::chat_utils_summarize_history::HistorySummaryCache>>,
     pub privacy_settings: Arc<PrivacySettings>,
     pub indexing_everywhere: Arc<crate::files_blocklist::IndexingEverywhere>,
     pub integration_sessions: HashMap<String, Arc<AMutex<Box<dyn IntegrationSession>>>>,
@@ -371,6 +379,7 @@ pub async fn create_global_context(
         tokenizer_map: HashMap::new(),
         tokenizer_download_lock: Arc::new(AMutex::<bool>::new(false)),
         completions_cache: Arc::new(StdRwLock::new(CompletionCache::new())),
+        completions_in_flight: Arc::new(StdMutex::new(CompletionsInFlight::default())),
         telemetry: Arc::new(StdRwLock::new(telemetry_structs::Storage::new())),
         #[cfg(feature="vecdb")]
         vec_db: Arc::new(AMutex::new(None)),
@@ -381,6 +390,7 @@ pub async fn create_global_context(
         ask_shutdown_sender: Arc::new(StdMutex::new(ask_shutdown_sender)),
         documents_state: DocumentsState::new(workspace_dirs).await,
         at_commands_preview_cache: Arc::new(AMutex::new(AtCommandsPreviewCache::new())),
+        history_summary_cache: Arc::new(AMutex::new(HashMap::new())),
         privacy_settings: Arc::new(PrivacySettings::default()),
         indexing_everywhere: Arc::new(crate::files_blocklist::IndexingEverywhere::default()),
         integration_sessions: HashMap::new(),
@@ -12,7 +12,7 @@ use tower_http::cors::CorsLayer;
 use crate::{telemetry_get, telemetry_post};
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
-use crate::http::routers::v1::code_completion::{handle_v1_code_completion_web, handle_v1_code_completion_prompt};
+use crate::http::routers::v1::code_completion::{handle_v1_code_completion_web, handle_v1_code_completion_batch, handle_v1_code_completion_prompt};
 use crate::http::routers::v1::code_lens::handle_v1_code_lens;
 use crate::http::routers::v1::ast::{handle_v1_ast_file_dump, handle_v1_ast_file_symbols, handle_v1_ast_status};
 use crate::http::routers::v1::at_commands::{handle_v1_command_completion, handle_v1_command_preview, handle_v1_at_command_execute};
@@ -47,6 +47,9 @@ use crate::http::routers::v1::v1_integrations::{handle_v1_integration_get, handl
 use crate::agent_db::db_cthread::{handle_db_v1_cthread_update, handle_db_v1_cthreads_sub};
 use crate::agent_db::db_cmessage::{handle_db_v1_cmessages_update, handle_db_v1_cmessages_sub};
 use crate::agent_db::db_chore::{handle_db_v1_chore_update, handle_db_v1_chore_event_update, handle_db_v1_chores_sub};
+use crate::agent_db::db_spend::handle_v1_spend;
+use crate::agent_db::db_completion_events::handle_v1_completion_stats;
+use crate::agent_db::db_sft_export::handle_v1_sft_export;
 use crate::http::routers::v1::file_edit_tools::handle_v1_file_edit_tool_dry_run;
 use crate::http::routers::v1::handlers_memdb::{handle_mem_sub, handle_mem_upd};
 use crate::http::utils::telemetry_wrapper;
@@ -88,6 +91,7 @@ pub fn make_v1_router() -> Router {
         .route("/graceful-shutdown", telemetry_get!(handle_v1_graceful_shutdown))
 
         .route("/code-completion", telemetry_post!(handle_v1_code_completion_web))
+        .route("/code-completion-batch", telemetry_post!(handle_v1_code_completion_batch))
         .route("/code-lens", telemetry_post!(handle_v1_code_lens))
 
         .route("/chat", telemetry_post!(handle_v1_chat))
@@ -150,6 +154,9 @@ pub fn make_v1_router() -> Router {
 
         // experimental
         .route("/get-dashboard-plots", telemetry_get!(get_dashboard_plots))
+        .route("/spend", telemetry_get!(handle_v1_spend))
+        .route("/completion-stats", telemetry_get!(handle_v1_completion_stats))
+        .route("/sft-export", telemetry_post!(handle_v1_sft_export))
 
         .route("/code-completion-prompt", telemetry_post!(handle_v1_code_completion_prompt))
         .route("/commit-message-from-diff", telemetry_post!(handle_v1_commit_message_from_diff))
@@ -174,6 +174,7 @@ pub async fn handle_v1_command_preview(
                 &caps_locked.code_chat_models,
                 &post.model,
                 &caps_locked.code_chat_default_model,
+                &caps_locked.fallback_models,
             );
         match tmp {
             Ok(x) => (x.0, x.1.clone()),
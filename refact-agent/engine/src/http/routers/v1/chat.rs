@@ -67,6 +67,7 @@ pub async fn lookup_chat_scratchpad(
             &caps_locked.code_chat_models,
             &chat_post.model,
             &caps_locked.code_chat_default_model,
+            &caps_locked.fallback_models,
         )?;
     let (sname, patch) = crate::caps::which_scratchpad_to_use(
         &recommended_model_record.supports_scratchpads,
@@ -147,6 +148,12 @@ async fn _chat(
     }
 
     let caps = crate::global_context::try_load_caps_quickly_if_not_present(gcx.clone(), 0).await?;
+    // interactive chats are not paused like autonomous ones, the user gets the reason and can send again later
+    let budgets = caps.read().unwrap().budgets.clone();
+    if let Some(reason) = crate::agent_db::db_spend::budget_exceeded_for_chat(gcx.clone(), &budgets, &chat_post.meta.chat_id).await
+        .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, e))? {
+        return Err(ScratchError::new(StatusCode::TOO_MANY_REQUESTS, reason));
+    }
     let (model_name, scratchpad_name, scratchpad_patch, n_ctx, supports_tools, supports_multimodality, supports_clicks) = lookup_chat_scratchpad(
         caps.clone(),
         &chat_post,
@@ -161,6 +168,23 @@ async fn _chat(
     }
     chat_post.parameters.n = chat_post.n;
     chat_post.parameters.temperature = Some(chat_post.parameters.temperature.unwrap_or(chat_post.temperature.unwrap_or(0.0)));
+    if chat_post.parameters.response_format.is_none() {
+        chat_post.parameters.response_format = chat_post.response_format.clone();
+    }
+    if let Some(response_format) = &chat_post.parameters.response_format {
+        crate::structured_output::check_response_format(response_format)
+            .map_err(|e| ScratchError::new(StatusCode::BAD_REQUEST, e))?;
+        let native_support = crate::structured_output::has_native_support(&caps.read().unwrap(), &model_name, &scratchpad_name);
+        if chat_post.stream != Some(false) && !native_support {
+            // a streamed answer cannot be taken back to re-ask
+            return Err(ScratchError::new(StatusCode::BAD_REQUEST, format!(
+                "model '{}' has no native response_format support, use stream=false to get the answer validated against the schema", model_name
+            )));
+        }
+        if !native_support {
+            messages.extend(crate::structured_output::format_instruction_message(response_format));
+        }
+    }
     chat_post.model = model_name.clone();
 
     // extra validation to catch {"query": "Frog", "scope": "workspace"}{"query": "Toad", "scope": "workspace"}
@@ -243,66 +267,97 @@ async fn _chat(
 
 
     // chat_post.stream = Some(false);  // for debugging 400 errors that are hard to debug with streaming (because "data: " is not present and the error message is ignored by the library)
-    let mut scratchpad = crate::scratchpads::create_chat_scratchpad(
-        gcx.clone(),
-        caps,
-        model_name.clone(),
-        &mut chat_post,
-        &messages,
-        true,
-        &scratchpad_name,
-        &scratchpad_patch,
-        allow_at,
-        supports_tools,
-        supports_clicks,
-    ).await.map_err(|e|
-        ScratchError::new(StatusCode::BAD_REQUEST, e)
-    )?;
-    // if !chat_post.chat_id.is_empty() {
-    //     let cache_dir = {
-    //         let gcx_locked = gcx.read().await;
-    //         gcx_locked.cache_dir.clone()
-    //     };
-    //     let notes_dir_path = cache_dir.join("chats");
-    //     let _ = std::fs::create_dir_all(&notes_dir_path);
-    //     let notes_path = notes_dir_path.join(format!("chat{}_{}.json",
-    //         chrono::Local::now().format("%Y%m%d"),
-    //         chat_post.chat_id,
-    //     ));
-    //     let _ = std::fs::write(&notes_path, serde_json::to_string_pretty(&chat_post.messages).unwrap());
-    // }
-    let mut ccx = AtCommandsContext::new(
-        gcx.clone(),
-        n_ctx,
-        CHAT_TOP_N,
-        false,
-        messages.clone(),
-        chat_post.meta.chat_id.clone(),
-        should_execute_remotely,
-    ).await;
-    ccx.subchat_tool_parameters = chat_post.subchat_tool_parameters.clone();
-    ccx.postprocess_parameters = chat_post.postprocess_parameters.clone();
-    let ccx_arc = Arc::new(AMutex::new(ccx));
+    let mut structured_attempt_n = 0;
+    let mut rejected_usage = serde_json::Value::Null;
+    loop {
+        let mut scratchpad = crate::scratchpads::create_chat_scratchpad(
+            gcx.clone(),
+            caps.clone(),
+            model_name.clone(),
+            &mut chat_post,
+            &messages,
+            true,
+            &scratchpad_name,
+            &scratchpad_patch,
+            allow_at,
+            supports_tools,
+            supports_clicks,
+        ).await.map_err(|e|
+            ScratchError::new(StatusCode::BAD_REQUEST, e)
+        )?;
+        // if !chat_post.chat_id.is_empty() {
+        //     let cache_dir = {
+        //         let gcx_locked = gcx.read().await;
+        //         gcx_locked.cache_dir.clone()
+        //     };
+        //     let notes_dir_path = cache_dir.join("chats");
+        //     let _ = std::fs::create_dir_all(&notes_dir_path);
+        //     let notes_path = notes_dir_path.join(format!("chat{}_{}.json",
+        //         chrono::Local::now().format("%Y%m%d"),
+        //         chat_post.chat_id,
+        //     ));
+        //     let _ = std::fs::write(&notes_path, serde_json::to_string_pretty(&chat_post.messages).unwrap());
+        // }
+        let mut ccx = AtCommandsContext::new(
+            gcx.clone(),
+            n_ctx,
+            CHAT_TOP_N,
+            false,
+            messages.clone(),
+            chat_post.meta.chat_id.clone(),
+            should_execute_remotely,
+        ).await;
+        ccx.subchat_tool_parameters = chat_post.subchat_tool_parameters.clone();
+        ccx.postprocess_parameters = chat_post.postprocess_parameters.clone();
+        let ccx_arc = Arc::new(AMutex::new(ccx));
 
-    if chat_post.stream == Some(false) {
-        crate::restream::scratchpad_interaction_not_stream(
+        if chat_post.stream != Some(false) {
+            return crate::restream::scratchpad_interaction_stream(
+                ccx_arc.clone(),
+                scratchpad,
+                "chat-stream".to_string(),
+                model_name,
+                chat_post.parameters.clone(),
+                chat_post.only_deterministic_messages,
+                meta
+            ).await;
+        }
+        let response = crate::restream::scratchpad_interaction_not_stream(
             ccx_arc.clone(),
             &mut scratchpad,
             "chat".to_string(),
-            model_name,
+            model_name.clone(),
             &mut chat_post.parameters,
             chat_post.only_deterministic_messages,
-            meta
-        ).await
-    } else {
-        crate::restream::scratchpad_interaction_stream(
-            ccx_arc.clone(),
-            scratchpad,
-            "chat-stream".to_string(),
-            model_name,
-            chat_post.parameters.clone(),
-            chat_post.only_deterministic_messages,
-            meta
-        ).await
+            meta.clone()
+        ).await?;
+        let response_format = match chat_post.parameters.response_format.clone() {
+            Some(response_format) if !chat_post.only_deterministic_messages => response_format,
+            _ => return Ok(response),
+        };
+
+        // validate the answer against response_format, re-ask the model if it doesn't match
+        let (parts, body) = response.into_parts();
+        let body_bytes = hyper::body::to_bytes(body).await
+            .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("cannot read the model response: {}", e)))?;
+        let mut response_json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap_or_default();
+        let (bad_answer, problems) = match crate::structured_output::first_invalid_choice_in_response(&response_format, &response_json) {
+            Some(x) => x,
+            None if structured_attempt_n == 0 => return Ok(Response::from_parts(parts, Body::from(body_bytes))),
+            None => {
+                crate::structured_output::add_usage(&mut response_json["usage"], &rejected_usage);
+                return Ok(Response::from_parts(parts, Body::from(serde_json::to_string_pretty(&response_json).unwrap())));
+            },
+        };
+        crate::structured_output::add_usage(&mut rejected_usage, &response_json["usage"]);
+        structured_attempt_n += 1;
+        tracing::warn!("response_format attempt {} failed: {}", structured_attempt_n, problems);
+        if structured_attempt_n >= crate::structured_output::STRUCTURED_OUTPUT_ATTEMPTS {
+            return Err(ScratchError::new(StatusCode::UNPROCESSABLE_ENTITY, format!(
+                "the model failed to produce an answer matching response_format after {} attempts, last problem: {}", structured_attempt_n, problems
+            )));
+        }
+        messages.push(ChatMessage::new("assistant".to_string(), bad_answer));
+        messages.push(crate::structured_output::reask_message(&problems));
     }
 }
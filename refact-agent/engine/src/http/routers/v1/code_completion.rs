@@ -7,41 +7,57 @@ use axum::Extension;
 use axum::response::Result;
 use hyper::{Body, Response, StatusCode};
 use tracing::info;
-use crate::call_validation::{CodeCompletionPost, code_completion_post_validate};
+use crate::call_validation::{CodeCompletionBatchPost, CodeCompletionPost, code_completion_post_validate};
 use crate::caps;
 use crate::caps::CodeAssistantCaps;
 use crate::completion_cache;
+use crate::completion_coalescing;
 use crate::custom_error::ScratchError;
 use crate::global_context::GlobalContext;
 use crate::privacy::{check_file_privacy, load_privacy_if_needed};
 use crate::files_correction::canonical_path;
+use crate::scratchpad_abstract::ScratchpadAbstract;
 use crate::scratchpads;
 use crate::at_commands::at_commands::AtCommandsContext;
 
 
 const CODE_COMPLETION_TOP_N: usize = 5;
+const CODE_COMPLETION_BATCH_MAX: usize = 32;
 
 async fn _lookup_code_completion_scratchpad(
     caps: Arc<StdRwLock<CodeAssistantCaps>>,
     code_completion_post: &CodeCompletionPost,
     look_for_multiline_model: bool,
-) -> Result<(String, String, serde_json::Value, usize), String> {
+) -> Result<(String, String, serde_json::Value, usize, String), String> {
     let caps_locked = caps.read().unwrap();
 
-    let (model_name, modelrec) = if !look_for_multiline_model 
-        || caps_locked.multiline_code_completion_default_model.is_empty() {
-        caps::which_model_to_use(
-            &caps_locked.code_completion_models,
+    if code_completion_post.comment_to_code {
+        let (model_name, modelrec) = caps::which_model_to_use(
+            &caps_locked.code_chat_models,
             &code_completion_post.model,
-            &caps_locked.code_completion_default_model,
-        )?
+            &caps_locked.code_chat_default_model,
+            &caps_locked.fallback_models,
+        )?;
+        return Ok((model_name, "COMMENT-TO-CODE".to_string(), serde_json::json!({}), modelrec.n_ctx, String::new()));
+    }
+    let mut default_model = if !look_for_multiline_model
+        || caps_locked.multiline_code_completion_default_model.is_empty() {
+        &caps_locked.code_completion_default_model
     } else {
-        caps::which_model_to_use(
-            &caps_locked.code_completion_models,
-            &code_completion_post.model,
-            &caps_locked.multiline_code_completion_default_model,
-        )?
+        &caps_locked.multiline_code_completion_default_model
     };
+    let mut ab_arm = String::new();
+    if code_completion_post.model.is_empty() && caps_locked.code_completion_ab_test.len() == 2 {
+        let arm_b = rand::random::<bool>();
+        ab_arm = if arm_b { "b" } else { "a" }.to_string();
+        default_model = &caps_locked.code_completion_ab_test[arm_b as usize];
+    }
+    let (model_name, modelrec) = caps::which_model_to_use(
+        &caps_locked.code_completion_models,
+        &code_completion_post.model,
+        default_model,
+        &caps_locked.fallback_models,
+    )?;
     let (sname, patch) = caps::which_scratchpad_to_use(
         &modelrec.supports_scratchpads,
         &code_completion_post.scratchpad,
@@ -53,7 +69,7 @@ async fn _lookup_code_completion_scratchpad(
         // the model might be capable of a bigger context, but server (i.e. admin) tells us to use smaller (for example because latency)
         n_ctx = caps_completion_n_ctx;
     }
-    Ok((model_name, sname.clone(), patch.clone(), n_ctx))
+    Ok((model_name, sname.clone(), patch.clone(), n_ctx, ab_arm))
 }
 
 pub async fn handle_v1_code_completion(
@@ -77,7 +93,11 @@ pub async fn handle_v1_code_completion(
         let _ = crate::global_context::try_load_caps_quickly_if_not_present(gcx.clone(), 10).await;
         return Err(ScratchError::new(StatusCode::BAD_REQUEST, format!("{}", maybe.unwrap_err())))
     }
-    let (model_name, scratchpad_name, scratchpad_patch, n_ctx) = maybe.unwrap();
+    let (model_name, scratchpad_name, scratchpad_patch, n_ctx, ab_arm) = maybe.unwrap();
+    if code_completion_post.comment_to_code && code_completion_post.stream {
+        return Err(ScratchError::new(StatusCode::BAD_REQUEST, "comment_to_code doesn't support streaming".to_string()));
+    }
+    code_completion_post.ab_arm = ab_arm;
     if code_completion_post.parameters.max_new_tokens == 0 {
         code_completion_post.parameters.max_new_tokens = 50;
     }
@@ -89,11 +109,9 @@ pub async fn handle_v1_code_completion(
     }
     info!("chosen completion model: {}, scratchpad: {}", code_completion_post.model, code_completion_post.scratchpad);
     code_completion_post.parameters.temperature = Some(code_completion_post.parameters.temperature.unwrap_or(0.2));
-    let (cache_arc, tele_storage) = {
-        let gcx_locked = gcx.write().await;
-        (gcx_locked.completions_cache.clone(), gcx_locked.telemetry.clone())
-    };
-    if !code_completion_post.no_cache {
+    let cache_arc = gcx.read().await.completions_cache.clone();
+    // next edit depends on the edit history, not only on the text before the cursor, cache can't answer that
+    if !code_completion_post.no_cache && scratchpad_name != "NEXT_EDIT" {
         let cache_key = completion_cache::cache_key_from_post(&code_completion_post);
         let cached_maybe = completion_cache::cache_get(cache_arc.clone(), cache_key.clone());
         if let Some(cached_json_value) = cached_maybe {
@@ -106,21 +124,89 @@ pub async fn handle_v1_code_completion(
         }
     }
 
+    if code_completion_post.stream {
+        // a stream stops when the editor closes the connection, nothing to coalesce or cancel there
+        let (scratchpad, ccx) = _create_scratchpad_and_ccx(
+            gcx.clone(), caps, model_name.clone(), &code_completion_post, &scratchpad_name, &scratchpad_patch, n_ctx
+        ).await?;
+        return crate::restream::scratchpad_interaction_stream(ccx.clone(), scratchpad, "completion-stream".to_string(), model_name, code_completion_post.parameters.clone(), false, None).await;
+    }
+
+    let in_flight = gcx.read().await.completions_in_flight.clone();
+    let request_key = completion_coalescing::request_key_from_post(&code_completion_post);
+    let client = code_completion_post.client.clone();
+    // comment-to-code is a long generation from a code action, keystroke completions in the same file must not cancel it
+    let slot = if code_completion_post.comment_to_code {
+        format!("{}#comment_to_code", cpath.to_string_lossy())
+    } else {
+        cpath.to_string_lossy().to_string()
+    };
+    let post = code_completion_post.clone();
+    let gcx_clone = gcx.clone();
+    let txt = completion_coalescing::run_coalesced(in_flight, &client, &slot, request_key, async move {
+        let (mut scratchpad, ccx) = _create_scratchpad_and_ccx(
+            gcx_clone, caps, model_name.clone(), &post, &scratchpad_name, &scratchpad_patch, n_ctx
+        ).await?;
+        let mut parameters = post.parameters.clone();
+        let response = crate::restream::scratchpad_interaction_not_stream(ccx.clone(), &mut scratchpad, "completion".to_string(), model_name, &mut parameters, false, None).await?;
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.map_err(|e|
+            ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e))
+        )?;
+        Ok(String::from_utf8_lossy(&body_bytes).to_string())
+    }).await?;
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(txt))
+        .unwrap())
+}
+
+async fn _create_scratchpad_and_ccx(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    model_name: String,
+    code_completion_post: &CodeCompletionPost,
+    scratchpad_name: &str,
+    scratchpad_patch: &serde_json::Value,
+    n_ctx: usize,
+) -> Result<(Box<dyn ScratchpadAbstract>, Arc<AMutex<AtCommandsContext>>), ScratchError> {
+    let scratchpad = _create_scratchpad(gcx.clone(), caps, model_name, code_completion_post, scratchpad_name, scratchpad_patch).await?;
+    Ok((scratchpad, _create_ccx(gcx, n_ctx).await))
+}
+
+async fn _create_scratchpad(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    caps: Arc<StdRwLock<CodeAssistantCaps>>,
+    model_name: String,
+    code_completion_post: &CodeCompletionPost,
+    scratchpad_name: &str,
+    scratchpad_patch: &serde_json::Value,
+) -> Result<Box<dyn ScratchpadAbstract>, ScratchError> {
+    let (cache_arc, tele_storage) = {
+        let gcx_locked = gcx.read().await;
+        (gcx_locked.completions_cache.clone(), gcx_locked.telemetry.clone())
+    };
     let ast_service_opt = gcx.read().await.ast_service.clone();
-    let mut scratchpad = scratchpads::create_code_completion_scratchpad(
+    let scratchpad = scratchpads::create_code_completion_scratchpad(
         gcx.clone(),
         caps,
-        model_name.clone(),
-        &code_completion_post.clone(),
-        &scratchpad_name,
-        &scratchpad_patch,
-        cache_arc.clone(),
-        tele_storage.clone(),
+        model_name,
+        code_completion_post,
+        scratchpad_name,
+        scratchpad_patch,
+        cache_arc,
+        tele_storage,
         ast_service_opt
     ).await.map_err(|e|
         ScratchError::new(StatusCode::BAD_REQUEST, e)
     )?;
-    let ccx: Arc<AMutex<AtCommandsContext>> = Arc::new(AMutex::new(AtCommandsContext::new(
+    Ok(scratchpad)
+}
+
+async fn _create_ccx(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    n_ctx: usize,
+) -> Arc<AMutex<AtCommandsContext>> {
+    Arc::new(AMutex::new(AtCommandsContext::new(
         gcx.clone(),
         n_ctx,
         CODE_COMPLETION_TOP_N,
@@ -128,12 +214,7 @@ pub async fn handle_v1_code_completion(
         vec![],
         "".to_string(),
         false,
-    ).await));
-    if !code_completion_post.stream {
-        crate::restream::scratchpad_interaction_not_stream(ccx.clone(), &mut scratchpad, "completion".to_string(), model_name, &mut code_completion_post.parameters, false, None).await
-    } else {
-        crate::restream::scratchpad_interaction_stream(ccx.clone(), scratchpad, "completion-stream".to_string(), model_name, code_completion_post.parameters.clone(), false, None).await
-    }
+    ).await))
 }
 
 pub async fn handle_v1_code_completion_web(
@@ -146,6 +227,89 @@ pub async fn handle_v1_code_completion_web(
     handle_v1_code_completion(gcx.clone(), &mut code_completion_post).await
 }
 
+// Multi-cursor editing: one model and scratchpad for all the cursors, one ccx so the cursors in the same file share RAG,
+// one upstream request if the endpoint takes an array of prompts. Results come in the order of the cursors,
+// a cursor that failed gets {"error": ...} without failing the others.
+pub async fn handle_v1_code_completion_batch(
+    Extension(gcx): Extension<Arc<ARwLock<GlobalContext>>>,
+    body_bytes: hyper::body::Bytes,
+) -> Result<Response<Body>, ScratchError> {
+    let batch = serde_json::from_slice::<CodeCompletionBatchPost>(&body_bytes).map_err(|e|
+        ScratchError::new(StatusCode::BAD_REQUEST, format!("JSON problem: {}", e))
+    )?;
+    if batch.cursors.is_empty() || batch.cursors.len() > CODE_COMPLETION_BATCH_MAX {
+        return Err(ScratchError::new(StatusCode::BAD_REQUEST, format!("cursors should be 1..{}", CODE_COMPLETION_BATCH_MAX)));
+    }
+    let mut posts = batch.cursors.iter().map(|cursor| batch.post_for_cursor(cursor)).collect::<Vec<_>>();
+
+    let caps = crate::global_context::try_load_caps_quickly_if_not_present(gcx.clone(), 0).await?;
+    let (model_name, scratchpad_name, scratchpad_patch, n_ctx, ab_arm) = _lookup_code_completion_scratchpad(
+        caps.clone(),
+        &posts[0],
+        batch.multiline,
+    ).await.map_err(|e| ScratchError::new(StatusCode::BAD_REQUEST, e))?;
+    info!("chosen completion model: {}, scratchpad: {}, batch of {}", model_name, scratchpad_name, posts.len());
+
+    let privacy = load_privacy_if_needed(gcx.clone()).await;
+    let cache_arc = gcx.read().await.completions_cache.clone();
+    let mut results: Vec<serde_json::Value> = vec![serde_json::Value::Null; posts.len()];
+    let mut todo: Vec<usize> = vec![];
+    for (i, post) in posts.iter_mut().enumerate() {
+        post.ab_arm = ab_arm.clone();
+        post.model = model_name.clone();
+        post.scratchpad = scratchpad_name.clone();
+        if post.parameters.max_new_tokens == 0 {
+            post.parameters.max_new_tokens = 50;
+        }
+        post.parameters.temperature = Some(post.parameters.temperature.unwrap_or(0.2));
+        let valid = code_completion_post_validate(post.clone()).and_then(|_| {
+            check_file_privacy(privacy.clone(), &canonical_path(&post.inputs.cursor.file), &crate::privacy::FilePrivacyLevel::OnlySendToServersIControl)
+                .map_err(|e| ScratchError::new(StatusCode::UNPROCESSABLE_ENTITY, e))
+        });
+        if let Err(e) = valid {
+            results[i] = serde_json::json!({"error": e.message});
+            continue;
+        }
+        if !post.no_cache && scratchpad_name != "NEXT_EDIT" {
+            if let Some(cached_json_value) = completion_cache::cache_get(cache_arc.clone(), completion_cache::cache_key_from_post(post)) {
+                results[i] = cached_json_value;
+                continue;
+            }
+        }
+        todo.push(i);
+    }
+
+    let mut scratchpads: Vec<Box<dyn ScratchpadAbstract>> = vec![];
+    let mut scratchpad_of: Vec<usize> = vec![];
+    for i in todo {
+        match _create_scratchpad(gcx.clone(), caps.clone(), model_name.clone(), &posts[i], &scratchpad_name, &scratchpad_patch).await {
+            Ok(scratchpad) => {
+                scratchpads.push(scratchpad);
+                scratchpad_of.push(i);
+            },
+            Err(e) => results[i] = serde_json::json!({"error": e.message}),
+        }
+    }
+    if !scratchpads.is_empty() {
+        let ccx = _create_ccx(gcx.clone(), n_ctx).await;
+        let answers = crate::restream::scratchpad_interaction_not_stream_batch(
+            ccx, &mut scratchpads, "completion-batch".to_string(), model_name.clone(), &posts[scratchpad_of[0]].parameters,
+        ).await;
+        for (i, answer) in scratchpad_of.into_iter().zip(answers.into_iter()) {
+            results[i] = answer.unwrap_or_else(|e| serde_json::json!({"error": e.message}));
+        }
+    }
+
+    let results = results.into_iter().zip(batch.cursors.iter()).map(|(mut result, cursor)| {
+        result["cursor"] = serde_json::json!(cursor);
+        result
+    }).collect::<Vec<_>>();
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(&serde_json::json!({"results": results, "model": model_name})).unwrap()))
+        .unwrap())
+}
+
 pub async fn handle_v1_code_completion_prompt(
     Extension(gcx): Extension<Arc<ARwLock<GlobalContext>>>,
     body_bytes: hyper::body::Bytes,
@@ -165,38 +329,11 @@ pub async fn handle_v1_code_completion_prompt(
     if maybe.is_err() {
         return Err(ScratchError::new(StatusCode::BAD_REQUEST, format!("{}", maybe.unwrap_err())))
     }
-    let (model_name, scratchpad_name, scratchpad_patch, n_ctx) = maybe.unwrap();
+    let (model_name, scratchpad_name, scratchpad_patch, n_ctx, _ab_arm) = maybe.unwrap();
 
-    // don't need cache, but go along
-    let (cache_arc, tele_storage) = {
-        let cx_locked = gcx.write().await;
-        (cx_locked.completions_cache.clone(), cx_locked.telemetry.clone())
-    };
-
-    let ast_service_opt = gcx.read().await.ast_service.clone();
-    let mut scratchpad = scratchpads::create_code_completion_scratchpad(
-        gcx.clone(),
-        caps,
-        model_name.clone(),
-        &post,
-        &scratchpad_name,
-        &scratchpad_patch,
-        cache_arc.clone(),
-        tele_storage.clone(),
-        ast_service_opt
-    ).await.map_err(|e|
-        ScratchError::new(StatusCode::BAD_REQUEST, e)
-    )?;
-
-    let ccx: Arc<AMutex<AtCommandsContext>> = Arc::new(AMutex::new(AtCommandsContext::new(
-        gcx.clone(),
-        n_ctx,
-        CODE_COMPLETION_TOP_N,
-        true,
-        vec![],
-        "".to_string(),
-        false,
-    ).await));
+    let (mut scratchpad, ccx) = _create_scratchpad_and_ccx(
+        gcx.clone(), caps, model_name.clone(), &post, &scratchpad_name, &scratchpad_patch, n_ctx
+    ).await?;
     let prompt = scratchpad.prompt(ccx.clone(), &mut post.parameters).await.map_err(|e|
         ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Prompt: {}", e))
     )?;
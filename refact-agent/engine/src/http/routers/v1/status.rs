@@ -4,6 +4,8 @@ use hyper::{Body, Response, StatusCode};
 use serde::Serialize;
 
 use crate::ast::ast_structs::AstStatus;
+use crate::completion_cache::{cache_stats, CompletionCacheStats};
+use crate::completion_coalescing::CompletionsInFlightStats;
 use crate::custom_error::ScratchError;
 use crate::global_context::SharedGlobalContext;
 
@@ -15,15 +17,23 @@ struct RagStatus {
     vecdb: Option<crate::vecdb::vdb_structs::VecDbStatus>,
     vecdb_alive: String,
     vec_db_error: String,
+    completion_cache: CompletionCacheStats,
+    completion_requests: CompletionsInFlightStats,
 }
 
 pub async fn handle_v1_rag_status(
     Extension(gcx): Extension<SharedGlobalContext>,
     _: hyper::body::Bytes,
 ) -> Result<Response<Body>, ScratchError> {
-    let (vec_db_module, vec_db_error, ast_module) = {
+    let (vec_db_module, vec_db_error, ast_module, completions_cache, completion_requests) = {
         let gcx_locked = gcx.write().await;
-        (gcx_locked.vec_db.clone(), gcx_locked.vec_db_error.clone(), gcx_locked.ast_service.clone())
+        (
+            gcx_locked.vec_db.clone(),
+            gcx_locked.vec_db_error.clone(),
+            gcx_locked.ast_service.clone(),
+            gcx_locked.completions_cache.clone(),
+            gcx_locked.completions_in_flight.lock().unwrap().stats.clone(),
+        )
     };
 
     #[cfg(feature="vecdb")]
@@ -52,6 +62,8 @@ pub async fn handle_v1_rag_status(
         vecdb: maybe_vecdb_status,
         vecdb_alive: vecdb_message,
         vec_db_error,
+        completion_cache: cache_stats(completions_cache),
+        completion_requests,
     };
 
     let json_string = serde_json::to_string_pretty(&status).map_err(|e| {
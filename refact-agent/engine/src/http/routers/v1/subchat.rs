@@ -10,6 +10,7 @@ use crate::at_commands::at_commands::AtCommandsContext;
 use crate::custom_error::ScratchError;
 use crate::global_context::GlobalContext;
 use crate::http::routers::v1::chat::deserialize_messages_from_post;
+use crate::structured_output::check_response_format;
 
 
 #[derive(Deserialize)]
@@ -74,6 +75,8 @@ struct SubChatSinglePost {
     temperature: Option<f32>,
     #[serde(default = "default_n")]
     n: usize,
+    #[serde(default)]
+    response_format: Option<serde_json::Value>,
 }
 
 fn default_n() -> usize { 1 }
@@ -84,6 +87,9 @@ pub async fn handle_v1_subchat_single(
 ) -> axum::response::Result<Response<Body>, ScratchError> {
     let post = serde_json::from_slice::<SubChatSinglePost>(&body_bytes)
         .map_err(|e| ScratchError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("JSON problem: {}", e)))?;
+    if let Some(response_format) = &post.response_format {
+        check_response_format(response_format).map_err(|e| ScratchError::new(StatusCode::BAD_REQUEST, e))?;
+    }
     let messages = deserialize_messages_from_post(&post.messages)?;
 
     let top_n = 7;
@@ -107,6 +113,7 @@ pub async fn handle_v1_subchat_single(
         None,
         None,
         None,
+        post.response_format,
     ).await.map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)))?;
 
     let new_messages = new_messages.into_iter()
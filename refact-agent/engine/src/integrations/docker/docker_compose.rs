@@ -0,0 +1,359 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::collections::HashMap;
+use tokio::process::Command;
+use tokio::sync::{Mutex as AMutex, RwLock as ARwLock};
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ChatContent, ChatMessage, ContextEnum};
+use crate::files_correction::get_active_project_path;
+use crate::global_context::GlobalContext;
+use crate::integrations::docker::integr_docker::{SettingsDocker, ToolDocker};
+use crate::integrations::integr_abstract::{IntegrationCommon, IntegrationConfirmation};
+use crate::postprocessing::pp_command_output::output_mini_postprocessing;
+use crate::tools::tools_description::{Tool, ToolDesc, ToolParam, MatchConfirmDeny, MatchConfirmDenyResult};
+use crate::tools::tools_execute::{command_should_be_confirmed_by_user, command_should_be_denied};
+
+
+pub const COMPOSE_FILE_NAMES: &[&str] = &["compose.yaml", "compose.yml", "docker-compose.yaml", "docker-compose.yml"];
+// NOTE: no `stop`, `rm`, `kill` or `pause`, the docker deny_default rules block them anyway, use `down` to stop the stack
+const COMPOSE_SUBCOMMANDS_ALLOWED: &[&str] = &["up", "down", "ps", "logs", "exec", "restart", "start", "build", "config", "images", "top"];
+const COMPOSE_OPTIONS_WITH_VALUE: &[&str] = &["-p", "-f", "--project-name", "--file", "--env-file"];
+
+#[derive(Clone, Default)]
+pub struct ToolDockerCompose {
+    pub common: IntegrationCommon,
+    pub settings_docker: SettingsDocker,
+    pub config_path: String,
+}
+
+impl ToolDockerCompose {
+    pub fn new_from_docker(docker: &ToolDocker) -> Self {
+        ToolDockerCompose {
+            common: docker.common.clone(),
+            settings_docker: docker.settings_docker.clone(),
+            config_path: docker.config_path.clone(),
+        }
+    }
+}
+
+pub fn find_compose_file(dir: &Path) -> Option<PathBuf> {
+    COMPOSE_FILE_NAMES.iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+#[async_trait]
+impl Tool for ToolDockerCompose {
+    fn as_any(&self) -> &dyn std::any::Any { self }
+
+    async fn tool_execute(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        tool_call_id: &String,
+        args: &HashMap<String, Value>,
+    ) -> Result<(bool, Vec<ContextEnum>), String> {
+        let (command, project_dir_maybe) = parse_args(args)?;
+        let gcx = ccx.lock().await.global_context.clone();
+
+        let project_dir = match project_dir_maybe {
+            Some(dir) => dir,
+            None => get_active_project_path(gcx.clone()).await
+                .ok_or("No active project, pass `project_dir` explicitly".to_string())?,
+        };
+        let compose_file = if !self.settings_docker.compose_file.is_empty() {
+            project_dir.join(&self.settings_docker.compose_file)
+        } else {
+            find_compose_file(&project_dir).ok_or(format!(
+                "No compose file found in {:?}, looked for {}", project_dir, COMPOSE_FILE_NAMES.join(", ")))?
+        };
+
+        let output = self.compose_execute(&command, &compose_file, &project_dir, gcx.clone()).await?;
+
+        Ok((false, vec![
+            ContextEnum::ChatMessage(ChatMessage {
+                role: "tool".to_string(),
+                content: ChatContent::SimpleText(output),
+                tool_calls: None,
+                tool_call_id: tool_call_id.clone(),
+                ..Default::default()
+            }),
+        ]))
+    }
+
+    async fn match_against_confirm_deny(
+        &self,
+        _ccx: Arc<AMutex<AtCommandsContext>>,
+        args: &HashMap<String, Value>
+    ) -> Result<MatchConfirmDeny, String> {
+        let command_to_match = self.command_to_match_against_confirm_deny(&args).map_err(|e| {
+            format!("Error getting tool command to match: {}", e)
+        })?;
+        if let Some(rules) = &self.confirm_deny_rules() {
+            let (is_denied, deny_rule) = command_should_be_denied(&command_to_match, &rules.deny);
+            if is_denied {
+                return Ok(MatchConfirmDeny {
+                    result: MatchConfirmDenyResult::DENY,
+                    command: command_to_match.clone(),
+                    rule: deny_rule.clone(),
+                });
+            }
+            let (needs_confirmation, confirmation_rule) = command_should_be_confirmed_by_user(&command_to_match, &rules.ask_user);
+            if needs_confirmation {
+                return Ok(MatchConfirmDeny {
+                    result: MatchConfirmDenyResult::CONFIRMATION,
+                    command: command_to_match.clone(),
+                    rule: confirmation_rule.clone(),
+                });
+            }
+        }
+        // NOTE: removing volumes or images loses data, always ask even if the config has no rules for it
+        let command_args = split_command(&parse_args(args)?.0)?;
+        if command_is_destructive(&command_args) {
+            return Ok(MatchConfirmDeny {
+                result: MatchConfirmDenyResult::CONFIRMATION,
+                command: command_to_match.clone(),
+                rule: "destructive compose operation".to_string(),
+            });
+        }
+        Ok(MatchConfirmDeny {
+            result: MatchConfirmDenyResult::PASS,
+            command: command_to_match.clone(),
+            rule: "".to_string(),
+        })
+    }
+
+    fn command_to_match_against_confirm_deny(
+        &self,
+        args: &HashMap<String, Value>,
+    ) -> Result<String, String> {
+        let (command, _) = parse_args(args)?;
+        let mut command_args = split_command(&command)?;
+        command_args.insert(0, "docker compose".to_string());
+        Ok(command_args.join(" "))
+    }
+
+    fn tool_name(&self) -> String {
+        "docker_compose".to_string()
+    }
+
+    fn tool_description(&self) -> ToolDesc {
+        ToolDesc {
+            name: "docker_compose".to_string(),
+            agentic: true,
+            experimental: true,
+            description: "Access to docker compose for multi-service stacks defined in compose.yaml, in a non-interactive way. Services are started detached, logs are not followed, exec runs without a TTY.".to_string(),
+            parameters: vec![
+                ToolParam {
+                    name: "command".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Examples: 'ps', 'up', 'up web', 'logs web', 'logs --tail 500 db', 'exec web ls /app', 'restart worker', 'down'".to_string(),
+                },
+                ToolParam {
+                    name: "project_dir".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Directory with the compose file, leave empty to use the active project.".to_string(),
+                },
+            ],
+            parameters_required: vec!["command".to_string()],
+        }
+    }
+
+    fn confirm_deny_rules(&self) -> Option<IntegrationConfirmation> {
+        Some(self.common.confirmation.clone())
+    }
+
+    fn has_config_path(&self) -> Option<String> {
+        Some(self.config_path.clone())
+    }
+}
+
+impl ToolDockerCompose {
+    async fn compose_execute(&self, command: &str, compose_file: &PathBuf, project_dir: &PathBuf, gcx: Arc<ARwLock<GlobalContext>>) -> Result<String, String>
+    {
+        let command_args = compose_command_make_non_blocking(split_command(command)?, &self.settings_docker.compose_logs_tail)?;
+
+        let docker = ToolDocker {
+            common: self.common.clone(),
+            settings_docker: self.settings_docker.clone(),
+            config_path: self.config_path.clone(),
+        };
+        let docker_host = docker.get_docker_host(gcx.clone()).await?;
+        let mut command_process = Command::new(&self.settings_docker.docker_cli_path);
+        if !docker_host.is_empty() {
+            command_process.arg("-H").arg(&docker_host);
+        }
+        command_process
+            .arg("compose")
+            .arg("-f").arg(compose_file)
+            .args(&command_args)
+            .current_dir(project_dir)
+            .stdin(std::process::Stdio::null());
+
+        tracing::info!("DOCKER COMPOSE: {:?} {:?}", compose_file, command_args);
+        let t0 = tokio::time::Instant::now();
+        let output = tokio::time::timeout(tokio::time::Duration::from_secs(self.settings_docker.compose_timeout), command_process.output())
+            .await
+            .map_err(|_| format!("Command `docker compose {}` timed out after {} seconds", command_args.join(" "), self.settings_docker.compose_timeout))?
+            .map_err(|e| e.to_string())?;
+        let duration = t0.elapsed();
+
+        let filter = &self.settings_docker.compose_output_filter;
+        let stdout = output_mini_postprocessing(filter, &String::from_utf8_lossy(&output.stdout));
+        let stderr = output_mini_postprocessing(filter, &String::from_utf8_lossy(&output.stderr));
+
+        let mut out = crate::integrations::integr_cmdline::format_output(&stdout, &stderr);
+        let exit_code = output.status.code().unwrap_or_default();
+        out.push_str(&format!("The command was running {:.3}s, finished with exit code {exit_code}\n", duration.as_secs_f64()));
+        Ok(out)
+    }
+}
+
+fn parse_args(args: &HashMap<String, Value>) -> Result<(String, Option<PathBuf>), String> {
+    let command = match args.get("command") {
+        Some(Value::String(s)) => s.to_string(),
+        Some(v) => return Err(format!("argument `command` is not a string: {:?}", v)),
+        None => return Err("Missing argument `command`".to_string()),
+    };
+    let project_dir = match args.get("project_dir") {
+        Some(Value::String(s)) if !s.is_empty() => {
+            let project_dir = crate::files_correction::canonical_path(s);
+            if !project_dir.exists() {
+                return Err(format!("Project dir {:?} doesn't exist", s));
+            }
+            Some(project_dir)
+        },
+        Some(Value::String(_)) | None => None,
+        Some(v) => return Err(format!("argument `project_dir` is not a string: {:?}", v)),
+    };
+    Ok((command, project_dir))
+}
+
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut parsed_args = shell_words::split(command).map_err(|e| e.to_string())?;
+    if parsed_args.first().map(|s| s.as_str()) == Some("docker") {
+        parsed_args.remove(0);
+    }
+    if parsed_args.first().map(|s| s.as_str()) == Some("compose") || parsed_args.first().map(|s| s.as_str()) == Some("docker-compose") {
+        parsed_args.remove(0);
+    }
+    if parsed_args.is_empty() {
+        return Err("Parsed command is empty".to_string());
+    }
+    Ok(parsed_args)
+}
+
+fn subcommand(command_args: &Vec<String>) -> &str {
+    subcommand_position(command_args).map(|pos| command_args[pos].as_str()).unwrap_or("")
+}
+
+fn subcommand_position(command_args: &Vec<String>) -> Option<usize> {
+    let mut skip_value = false;
+    for (i, arg) in command_args.iter().enumerate() {
+        if skip_value {
+            skip_value = false;
+            continue;
+        }
+        if COMPOSE_OPTIONS_WITH_VALUE.contains(&arg.as_str()) {
+            skip_value = true;
+            continue;
+        }
+        if !arg.starts_with('-') {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn command_is_destructive(command_args: &Vec<String>) -> bool {
+    match subcommand(command_args) {
+        "down" => command_args.iter().any(|arg| arg == "-v" || arg == "--volumes" || arg.starts_with("--rmi")),
+        _ => false,
+    }
+}
+
+fn compose_command_make_non_blocking(mut command_args: Vec<String>, logs_tail: &str) -> Result<Vec<String>, String> {
+    let sub = subcommand(&command_args).to_string();
+    if !COMPOSE_SUBCOMMANDS_ALLOWED.contains(&sub.as_str()) {
+        return Err(format!("Subcommand {:?} is not supported, use one of: {}", sub, COMPOSE_SUBCOMMANDS_ALLOWED.join(", ")));
+    }
+    let sub_pos = subcommand_position(&command_args).unwrap();
+    match sub.as_str() {
+        "up" => {
+            if command_args.iter().any(|arg| arg == "--attach" || arg == "--abort-on-container-exit" || arg == "--watch") {
+                return Err("`up` can only run detached, remove --attach, --abort-on-container-exit and --watch".to_string());
+            }
+            if !command_args.iter().any(|arg| arg == "-d" || arg == "--detach") {
+                command_args.insert(sub_pos + 1, "--detach".to_string());
+            }
+        },
+        "logs" => {
+            command_args.retain(|arg| arg != "-f" && arg != "--follow");
+            if !command_args.iter().any(|arg| arg == "-n" || arg.starts_with("--tail")) && !logs_tail.is_empty() {
+                command_args.insert(sub_pos + 1, format!("--tail={}", logs_tail));
+            }
+        },
+        "exec" => {
+            if command_args.iter().any(|arg| arg == "-i" || arg == "--interactive" || arg == "-it" || arg == "-ti") {
+                return Err("`exec` can't be interactive, run a single command instead of a shell".to_string());
+            }
+            if !command_args.iter().any(|arg| arg == "-T" || arg == "--no-TTY" || arg == "--no-tty") {
+                command_args.insert(sub_pos + 1, "-T".to_string());
+            }
+        },
+        _ => {},
+    }
+    Ok(command_args)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &str) -> Vec<String> {
+        split_command(command).unwrap()
+    }
+
+    #[test]
+    fn test_split_command_strips_prefix() {
+        assert_eq!(args("docker compose ps"), vec!["ps"]);
+        assert_eq!(args("docker-compose logs web"), vec!["logs", "web"]);
+        assert_eq!(args("exec web ls /app"), vec!["exec", "web", "ls", "/app"]);
+        assert!(split_command("docker compose").is_err());
+    }
+
+    #[test]
+    fn test_make_non_blocking() {
+        assert_eq!(compose_command_make_non_blocking(args("up web"), "200").unwrap(), vec!["up", "--detach", "web"]);
+        assert_eq!(compose_command_make_non_blocking(args("up -d"), "200").unwrap(), vec!["up", "-d"]);
+        assert_eq!(compose_command_make_non_blocking(args("logs -f web"), "200").unwrap(), vec!["logs", "--tail=200", "web"]);
+        assert_eq!(compose_command_make_non_blocking(args("logs --tail 10 web"), "200").unwrap(), vec!["logs", "--tail", "10", "web"]);
+        assert_eq!(compose_command_make_non_blocking(args("exec web ls"), "200").unwrap(), vec!["exec", "-T", "web", "ls"]);
+        assert!(compose_command_make_non_blocking(args("exec -it web bash"), "200").is_err());
+        assert!(compose_command_make_non_blocking(args("up --attach web"), "200").is_err());
+        assert!(compose_command_make_non_blocking(args("watch"), "200").is_err());
+        assert!(compose_command_make_non_blocking(args("stop web"), "200").is_err());
+        assert_eq!(compose_command_make_non_blocking(args("-p up up web"), "200").unwrap(), vec!["-p", "up", "up", "--detach", "web"]);
+    }
+
+    #[test]
+    fn test_subcommand_skips_option_values() {
+        assert_eq!(subcommand(&args("-p proj up")), "up");
+        assert_eq!(subcommand(&args("--project-name proj --env-file .env.test logs web")), "logs");
+        assert_eq!(subcommand(&args("-f other.yml --file=x.yml ps")), "ps");
+        assert_eq!(subcommand(&args("--project-name=proj down -v")), "down");
+        assert_eq!(subcommand(&args("-p proj")), "");
+        assert!(command_is_destructive(&args("-p proj down -v")));
+    }
+
+    #[test]
+    fn test_command_is_destructive() {
+        assert!(command_is_destructive(&args("down -v")));
+        assert!(command_is_destructive(&args("down --rmi all")));
+        assert!(!command_is_destructive(&args("down")));
+        assert!(!command_is_destructive(&args("logs web")));
+    }
+}
@@ -12,6 +12,8 @@ use crate::global_context::GlobalContext;
 use crate::integrations::integr_abstract::{IntegrationTrait, IntegrationCommon, IntegrationConfirmation};
 use crate::tools::tools_description::Tool;
 use crate::integrations::docker::docker_ssh_tunnel_utils::{SshConfig, forward_remote_docker_if_needed};
+use crate::integrations::docker::docker_compose::ToolDockerCompose;
+use crate::postprocessing::pp_command_output::CmdlineOutputFilter;
 use crate::integrations::utils::{serialize_num_to_str, deserialize_str_to_num};
 
 #[derive(Clone, Serialize, Deserialize, Default, Debug)]
@@ -25,6 +27,29 @@ pub struct SettingsDocker {
     #[serde(serialize_with = "serialize_num_to_str", deserialize_with = "deserialize_str_to_num")]
     pub ssh_port: u16,
     pub ssh_identity_file: String,
+    #[serde(default)]
+    pub compose_file: String,
+    #[serde(default = "default_compose_logs_tail")]
+    pub compose_logs_tail: String,
+    #[serde(default = "default_compose_timeout", serialize_with = "serialize_num_to_str", deserialize_with = "deserialize_str_to_num")]
+    pub compose_timeout: u64,
+    #[serde(default = "default_compose_output_filter")]
+    pub compose_output_filter: CmdlineOutputFilter,
+}
+
+fn default_compose_logs_tail() -> String {
+    "200".to_string()
+}
+
+fn default_compose_timeout() -> u64 {
+    120
+}
+
+fn default_compose_output_filter() -> CmdlineOutputFilter {
+    CmdlineOutputFilter {
+        valuable_top_or_bottom: "bottom".to_string(),
+        ..Default::default()
+    }
 }
 
 impl SettingsDocker {
@@ -70,11 +95,14 @@ impl IntegrationTrait for ToolDocker {
     }
 
     async fn integr_tools(&self, _integr_name: &str) -> Vec<Box<dyn crate::tools::tools_description::Tool + Send>> {
-        vec![Box::new(ToolDocker {
-            common: self.common.clone(),
-            settings_docker: self.settings_docker.clone(),
-            config_path: self.config_path.clone(),
-        })]
+        vec![
+            Box::new(ToolDocker {
+                common: self.common.clone(),
+                settings_docker: self.settings_docker.clone(),
+                config_path: self.config_path.clone(),
+            }),
+            Box::new(ToolDockerCompose::new_from_docker(self)),
+        ]
     }
 
     fn integr_schema(&self) -> &str
@@ -310,11 +338,32 @@ fields:
     f_desc: "Path to the SSH identity file to connect to remote Docker."
     f_label: "SSH Identity File"
     f_extra: true
+  compose_file:
+    f_type: string_long
+    f_desc: "Compose file relative to the project, leave empty to detect compose.yaml, compose.yml, docker-compose.yaml or docker-compose.yml."
+    f_label: "Compose File"
+    f_extra: true
+  compose_logs_tail:
+    f_type: string_short
+    f_desc: "How many last lines of each service log to fetch when the model doesn't specify --tail."
+    f_default: "200"
+    f_label: "Compose Logs Tail"
+    f_extra: true
+  compose_timeout:
+    f_type: string_short
+    f_desc: "Timeout in seconds for docker compose commands, `up` may need to pull and build images."
+    f_default: "120"
+    f_label: "Compose Timeout"
+    f_extra: true
+  compose_output_filter:
+    f_type: "output_filter"
+    f_desc: "Service logs can be long, this section allows to set limits, prioritize top or bottom, or use regexp to show the model the relevant part."
+    f_extra: true
 available:
   on_your_laptop_possible: true
   when_isolated_possible: false
 confirmation:
-  ask_user_default: []
+  ask_user_default: ["docker compose down*"]
   deny_default: ["docker* rm *", "docker* rmi *", "docker* pause *", "docker* stop *", "docker* kill *"]
 smartlinks:
   - sl_label: "Test"
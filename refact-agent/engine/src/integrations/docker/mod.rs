@@ -8,6 +8,7 @@ use crate::integrations::docker::integr_docker::ToolDocker;
 use crate::integrations::docker::integr_isolation::{SettingsIsolation, IntegrationIsolation};
 
 pub mod integr_docker;
+pub mod docker_compose;
 pub mod integr_isolation;
 pub mod docker_ssh_tunnel_utils;
 pub mod docker_container_manager;
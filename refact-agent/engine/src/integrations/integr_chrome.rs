@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::future::Future;
 use std::time::Duration;
 use serde_json::Value;
+use indexmap::IndexMap;
 use tokio::sync::{Mutex as AMutex, RwLock as ARwLock};
 use async_trait::async_trait;
 
@@ -17,6 +18,7 @@ use crate::postprocessing::pp_command_output::{CmdlineOutputFilter, output_mini_
 use crate::tools::tools_description::{Tool, ToolDesc, ToolParam};
 use crate::integrations::integr_abstract::{IntegrationTrait, IntegrationCommon, IntegrationConfirmation};
 use crate::integrations::docker::docker_container_manager::get_container_name;
+use crate::integrations::process_io_utils::first_n_chars;
 
 use tokio::time::sleep;
 use chrono::DateTime;
@@ -94,6 +96,33 @@ impl std::fmt::Display for DeviceType {
 }
 
 const MAX_CACHED_LOG_LINES: usize = 1000;
+const MAX_CACHED_NETWORK_REQUESTS: usize = 500;
+const MAX_NETWORK_BODY_CHARS: usize = 2000;
+const MAX_QUERY_SELECTOR_ELEMENTS: usize = 20;
+
+#[derive(Clone, Debug, Default)]
+struct NetworkRequest {
+    method: String,
+    url: String,
+    resource_type: String,
+    status: Option<u32>,
+    mime_type: String,
+    started_ts: f64,
+    finished_ts: Option<f64>,
+    encoded_data_length: Option<f64>,
+    error: Option<String>,
+    response_body: Option<String>,
+}
+
+impl NetworkRequest {
+    fn is_failed(&self) -> bool {
+        self.error.is_some() || self.status.map_or(false, |status| status >= 400)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished_ts.is_some()
+    }
+}
 
 #[derive(Clone)]
 pub struct ChromeTab {
@@ -102,6 +131,8 @@ pub struct ChromeTab {
     tab_id: String,
     screenshot_scale_factor: f64,
     tab_log: Arc<Mutex<Vec<String>>>,
+    console_log: Arc<Mutex<Vec<String>>>,
+    network_log: Arc<Mutex<IndexMap<String, NetworkRequest>>>,
 }
 
 impl ChromeTab {
@@ -112,6 +143,8 @@ impl ChromeTab {
             tab_id: tab_id.clone(),
             screenshot_scale_factor: 1.0,
             tab_log: Arc::new(Mutex::new(Vec::new())),
+            console_log: Arc::new(Mutex::new(Vec::new())),
+            network_log: Arc::new(Mutex::new(IndexMap::new())),
         }
     }
     pub fn state_string(&self) -> String {
@@ -213,6 +246,7 @@ impl Tool for ToolChrome {
                 .clone()
         };
 
+        let inside_container = gcx.read().await.cmdline.inside_container;
         let mut mutlimodal_els = vec![];
         for command in commands_str.lines().map(|s| s.trim()).collect::<Vec<&str>>() {
             let parsed_command = match parse_single_command(&command.to_string()) {
@@ -222,7 +256,7 @@ impl Tool for ToolChrome {
                     break
                 }
             };
-            match chrome_command_exec(&parsed_command, command_session.clone(), &self.settings_chrome, gcx.clone(), &chat_id).await {
+            match chrome_command_exec(&parsed_command, command_session.clone(), &self.settings_chrome, inside_container, &chat_id).await {
                 Ok((execute_log, command_multimodal_els)) => {
                     tool_log.extend(execute_log);
                     mutlimodal_els.extend(command_multimodal_els);
@@ -262,6 +296,10 @@ impl Tool for ToolChrome {
             "press_key <tab_id> <KeyName> [<Alt|Ctrl|Meta|Shift>,...]",
             "type_text_at <tab_id> <text>",
             "tab_log <tab_id>",
+            "console_log <tab_id>",
+            "network_log <tab_id> [failed]",
+            "query_selector <tab_id> <element_selector>",
+            "wait_for_selector <tab_id> <element_selector> [<1-30>]",
             "eval <tab_id> <expression>",
             "styles <tab_id> <element_selector> <property_filter>",
             "wait_for <tab_id> <1-5>",
@@ -580,18 +618,81 @@ async fn session_open_tab(
             let tab = Arc::new(AMutex::new(ChromeTab::new(headless_tab, device, tab_id)));
             let tab_lock = tab.lock().await;
             let tab_log = Arc::clone(&tab_lock.tab_log);
+            let console_log = Arc::clone(&tab_lock.console_log);
+            let network_log = Arc::clone(&tab_lock.network_log);
+            let network_log_for_bodies = Arc::clone(&tab_lock.network_log);
             tab_lock.headless_tab.enable_log().map_err(|e| e.to_string())?;
+            tab_lock.headless_tab.enable_runtime().map_err(|e| e.to_string())?;
+            // NOTE: also enables the network domain, bodies can only be fetched once loading is finished
+            tab_lock.headless_tab.register_response_handling("refact_network_log", Box::new(move |params, fetch_body| {
+                if !is_xhr_or_fetch(&format!("{:?}", params.Type)) {
+                    return;
+                }
+                let response_body = match fetch_body() {
+                    Ok(body) if body.base64_encoded => "<binary>".to_string(),
+                    Ok(body) => first_n_chars(&body.body, MAX_NETWORK_BODY_CHARS),
+                    Err(e) => format!("<failed to fetch body: {}>", e),
+                };
+                if let Some(request) = network_log_for_bodies.lock().unwrap().get_mut(&params.request_id) {
+                    request.response_body = Some(response_body);
+                }
+            })).map_err(|e| e.to_string())?;
             tab_lock.headless_tab.add_event_listener(Arc::new(move |event: &Event| {
-                if let Event::LogEntryAdded(e) = event {
-                    let formatted_ts = {
-                        let dt = DateTime::from_timestamp(e.params.entry.timestamp as i64, 0).unwrap();
-                        dt.format("%Y-%m-%d %H:%M:%S").to_string()
-                    };
-                    let mut tab_log_lock = tab_log.lock().unwrap();
-                    tab_log_lock.push(format!("{} [{:?}]: {}", formatted_ts, e.params.entry.level, e.params.entry.text));
-                    if tab_log_lock.len() > MAX_CACHED_LOG_LINES {
-                        tab_log_lock.remove(0);
-                    }
+                match event {
+                    Event::LogEntryAdded(e) => {
+                        let formatted_ts = {
+                            let dt = DateTime::from_timestamp(e.params.entry.timestamp as i64, 0).unwrap();
+                            dt.format("%Y-%m-%d %H:%M:%S").to_string()
+                        };
+                        push_log_line(&tab_log, format!("{} [{:?}]: {}", formatted_ts, e.params.entry.level, e.params.entry.text));
+                    },
+                    Event::RuntimeConsoleAPICalled(e) => {
+                        let args = e.params.args.iter()
+                            .map(|arg| format_console_value(arg.value.as_ref(), arg.description.as_deref()))
+                            .collect::<Vec<_>>();
+                        push_log_line(&console_log, format!("[{}] {}", format!("{:?}", e.params.Type).to_lowercase(), args.join(" ")));
+                    },
+                    Event::RuntimeExceptionThrown(e) => {
+                        let details = &e.params.exception_details;
+                        let description = details.exception.as_ref().and_then(|exception| exception.description.clone());
+                        push_log_line(&console_log, format!(
+                            "[exception] {} at {}:{}:{}\n{}",
+                            details.text, details.url.clone().unwrap_or_default(), details.line_number, details.column_number,
+                            description.unwrap_or_default(),
+                        ));
+                    },
+                    Event::NetworkRequestWillBeSent(e) => {
+                        let mut network_log_lock = network_log.lock().unwrap();
+                        network_log_lock.insert(e.params.request_id.clone(), NetworkRequest {
+                            method: e.params.request.method.clone(),
+                            url: e.params.request.url.clone(),
+                            resource_type: e.params.Type.as_ref().map(|t| format!("{:?}", t).to_lowercase()).unwrap_or_default(),
+                            started_ts: e.params.timestamp as f64,
+                            ..Default::default()
+                        });
+                        if network_log_lock.len() > MAX_CACHED_NETWORK_REQUESTS {
+                            network_log_lock.shift_remove_index(0);
+                        }
+                    },
+                    Event::NetworkResponseReceived(e) => {
+                        if let Some(request) = network_log.lock().unwrap().get_mut(&e.params.request_id) {
+                            request.status = Some(e.params.response.status as u32);
+                            request.mime_type = e.params.response.mime_type.clone();
+                        }
+                    },
+                    Event::NetworkLoadingFinished(e) => {
+                        if let Some(request) = network_log.lock().unwrap().get_mut(&e.params.request_id) {
+                            request.finished_ts = Some(e.params.timestamp as f64);
+                            request.encoded_data_length = Some(e.params.encoded_data_length as f64);
+                        }
+                    },
+                    Event::NetworkLoadingFailed(e) => {
+                        if let Some(request) = network_log.lock().unwrap().get_mut(&e.params.request_id) {
+                            request.finished_ts = Some(e.params.timestamp as f64);
+                            request.error = Some(e.params.error_text.clone());
+                        }
+                    },
+                    _ => {},
                 }
             })).map_err(|e| e.to_string())?;
             chrome_session.tabs.insert(tab_id.clone(), tab.clone());
@@ -623,6 +724,10 @@ enum Command {
     TypeTextAt(TypeTextAtArgs),
     PressKey(PressKeyArgs),
     TabLog(TabArgs),
+    ConsoleLog(TabArgs),
+    NetworkLog(NetworkLogArgs),
+    QuerySelector(TabElementArgs),
+    WaitForSelector(WaitForSelectorArgs),
     Eval(EvalArgs),
     Styles(StylesArgs),
     WaitFor(WaitForArgs),
@@ -632,7 +737,7 @@ async fn chrome_command_exec(
     cmd: &Command,
     chrome_session: Arc<AMutex<Box<dyn IntegrationSession>>>,
     settings_chrome: &SettingsChrome,
-    gcx: Arc<ARwLock<GlobalContext>>,
+    inside_container: bool,
     chat_id: &str,
 ) -> Result<(Vec<String>, Vec<MultimodalElement>), String> {
     let mut tool_log = vec![];
@@ -654,11 +759,8 @@ async fn chrome_command_exec(
                 session_get_tab_arc(chrome_session, &args.tab_id).await?
             };
             let mut url = args.uri.clone();
-            if settings_chrome.chrome_path.starts_with("container://") {
-                let is_inside_container = gcx.read().await.cmdline.inside_container;
-                if is_inside_container {
-                    url = replace_host_with_container_if_needed(&url, chat_id);
-                }
+            if settings_chrome.chrome_path.starts_with("container://") && inside_container {
+                url = replace_host_with_container_if_needed(&url, chat_id);
             }
             let log = {
                 let tab_lock = tab.lock().await;
@@ -893,6 +995,116 @@ async fn chrome_command_exec(
             let filtered_log = output_mini_postprocessing(&filter, tab_log.as_str());
             tool_log.push(filtered_log.clone());
         },
+        Command::ConsoleLog(args) => {
+            let tab = {
+                let mut chrome_session_locked = chrome_session.lock().await;
+                let chrome_session = chrome_session_locked.as_any_mut().downcast_mut::<ChromeSession>().ok_or("Failed to downcast to ChromeSession")?;
+                session_get_tab_arc(chrome_session, &args.tab_id).await?
+            };
+            let (console_log, state) = {
+                let tab_lock = tab.lock().await;
+                let mut console_log_lock = tab_lock.console_log.lock().unwrap();
+                let console_log = console_log_lock.join("\n");
+                console_log_lock.clear();
+                (console_log, tab_lock.state_string())
+            };
+            let filter = CmdlineOutputFilter {
+                limit_lines: 100,
+                limit_chars: 10000,
+                valuable_top_or_bottom: "bottom".to_string(),
+                grep: r"^\[(error|exception|assert)\]".to_string(),
+                grep_context_lines: 2,
+                remove_from_output: "".to_string(),
+            };
+            if console_log.is_empty() {
+                tool_log.push(format!("No console messages since the last console_log at {}", state));
+            } else {
+                tool_log.push(format!("Console messages at {}:\n{}", state, output_mini_postprocessing(&filter, &console_log)));
+            }
+        },
+        Command::NetworkLog(args) => {
+            let tab = {
+                let mut chrome_session_locked = chrome_session.lock().await;
+                let chrome_session = chrome_session_locked.as_any_mut().downcast_mut::<ChromeSession>().ok_or("Failed to downcast to ChromeSession")?;
+                session_get_tab_arc(chrome_session, &args.tab_id).await?
+            };
+            let (requests, state) = {
+                let tab_lock = tab.lock().await;
+                let mut network_log_lock = tab_lock.network_log.lock().unwrap();
+                let requests = network_log_lock.values().cloned().collect::<Vec<_>>();
+                // pending requests stay, they'll be reported when finished
+                network_log_lock.retain(|_, request| !request.is_finished());
+                (requests, tab_lock.state_string())
+            };
+            let lines = requests.iter()
+                .filter(|request| !args.only_failed || request.is_failed())
+                .map(format_network_request)
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                tool_log.push(format!("No {}network requests since the last network_log at {}", if args.only_failed { "failed " } else { "" }, state));
+            } else {
+                let filter = CmdlineOutputFilter {
+                    limit_lines: 200,
+                    limit_chars: 20000,
+                    valuable_top_or_bottom: "bottom".to_string(),
+                    grep: r"^\S+ \S+ (FAILED|[45]\d\d)".to_string(),
+                    grep_context_lines: 3,
+                    remove_from_output: "".to_string(),
+                };
+                tool_log.push(format!("Network requests at {}:\n{}", state, output_mini_postprocessing(&filter, &lines.join("\n"))));
+            }
+        },
+        Command::QuerySelector(args) => {
+            let tab = {
+                let mut chrome_session_locked = chrome_session.lock().await;
+                let chrome_session = chrome_session_locked.as_any_mut().downcast_mut::<ChromeSession>().ok_or("Failed to downcast to ChromeSession")?;
+                session_get_tab_arc(chrome_session, &args.tab_id).await?
+            };
+            let log = {
+                let tab_lock = tab.lock().await;
+                match {
+                    let elements = tab_lock.headless_tab.find_elements(&args.selector).map_err(|e| e.to_string())?;
+                    let mut elements_log = vec![];
+                    for (i, element) in elements.iter().take(MAX_QUERY_SELECTOR_ELEMENTS).enumerate() {
+                        let attributes = element.get_attributes().map_err(|e| e.to_string())?.unwrap_or_default();
+                        let text = element.get_inner_text().unwrap_or_default();
+                        elements_log.push(format_element(i, &element.tag_name, &attributes, &text));
+                    }
+                    if elements.len() > MAX_QUERY_SELECTOR_ELEMENTS {
+                        elements_log.push(format!("...shown {} of {} elements, use a more specific selector to see others", MAX_QUERY_SELECTOR_ELEMENTS, elements.len()));
+                    }
+                    Ok::<String, String>(elements_log.join("\n"))
+                } {
+                    Ok(elements_str) => {
+                        format!("query_selector `{}` at {}:\n{}", args.selector, tab_lock.state_string(), elements_str)
+                    },
+                    Err(e) => {
+                        format!("query_selector `{}` failed at {}: {}", args.selector, tab_lock.state_string(), e.to_string())
+                    },
+                }
+            };
+            tool_log.push(log);
+        },
+        Command::WaitForSelector(args) => {
+            let tab = {
+                let mut chrome_session_locked = chrome_session.lock().await;
+                let chrome_session = chrome_session_locked.as_any_mut().downcast_mut::<ChromeSession>().ok_or("Failed to downcast to ChromeSession")?;
+                session_get_tab_arc(chrome_session, &args.tab_id).await?
+            };
+            let log = {
+                let tab_lock = tab.lock().await;
+                let t0 = std::time::Instant::now();
+                match tab_lock.headless_tab.wait_for_element_with_custom_timeout(&args.selector, Duration::from_secs(args.seconds)) {
+                    Ok(_) => {
+                        format!("wait_for_selector `{}` appeared after {:.1}s at {}", args.selector, t0.elapsed().as_secs_f64(), tab_lock.state_string())
+                    },
+                    Err(e) => {
+                        format!("wait_for_selector `{}` failed after {}s at {}: {}", args.selector, args.seconds, tab_lock.state_string(), e.to_string())
+                    },
+                }
+            };
+            tool_log.push(log);
+        },
         Command::Eval(args) => {
             let tab = {
                 let mut chrome_session_locked = chrome_session.lock().await;
@@ -1032,6 +1244,19 @@ struct WaitForArgs {
     seconds: f64,
 }
 
+#[derive(Debug)]
+struct NetworkLogArgs {
+    tab_id: String,
+    only_failed: bool,
+}
+
+#[derive(Debug)]
+struct WaitForSelectorArgs {
+    tab_id: String,
+    selector: String,
+    seconds: u64,
+}
+
 fn parse_single_command(command: &String) -> Result<Command, String> {
     let args = shell_words::split(&command).map_err(|e| e.to_string())?;
     if args.is_empty() {
@@ -1211,6 +1436,75 @@ fn parse_single_command(command: &String) -> Result<Command, String> {
                 }
             }
         },
+        "console_log" => {
+            match parsed_args.as_slice() {
+                [tab_id] => {
+                    Ok(Command::ConsoleLog(TabArgs {
+                        tab_id: tab_id.clone(),
+                    }))
+                },
+                _ => {
+                    Err("Missing one or several arguments `tab_id`".to_string())
+                }
+            }
+        },
+        "network_log" => {
+            match parsed_args.as_slice() {
+                [tab_id] => {
+                    Ok(Command::NetworkLog(NetworkLogArgs {
+                        tab_id: tab_id.clone(),
+                        only_failed: false,
+                    }))
+                },
+                [tab_id, filter] if filter == "failed" || filter == "all" => {
+                    Ok(Command::NetworkLog(NetworkLogArgs {
+                        tab_id: tab_id.clone(),
+                        only_failed: filter == "failed",
+                    }))
+                },
+                _ => {
+                    Err("Expected arguments `tab_id` and optionally `failed`".to_string())
+                }
+            }
+        },
+        "query_selector" => {
+            match parsed_args.as_slice() {
+                [tab_id, selector] => {
+                    Ok(Command::QuerySelector(TabElementArgs {
+                        selector: selector.clone(),
+                        tab_id: tab_id.clone(),
+                    }))
+                },
+                _ => {
+                    Err("Missing one or several arguments `tab_id`, `selector`".to_string())
+                }
+            }
+        },
+        "wait_for_selector" => {
+            match parsed_args.as_slice() {
+                [tab_id, selector] => {
+                    Ok(Command::WaitForSelector(WaitForSelectorArgs {
+                        selector: selector.clone(),
+                        tab_id: tab_id.clone(),
+                        seconds: 5,
+                    }))
+                },
+                [tab_id, selector, seconds_str] => {
+                    let seconds = seconds_str.parse::<u64>().map_err(|e| format!("Failed to parse seconds: {}", e))?;
+                    if seconds < 1 || seconds > 30 {
+                        return Err("`seconds` should be integer in interval [1, 30]".to_string());
+                    }
+                    Ok(Command::WaitForSelector(WaitForSelectorArgs {
+                        selector: selector.clone(),
+                        tab_id: tab_id.clone(),
+                        seconds,
+                    }))
+                },
+                _ => {
+                    Err("Missing one or several arguments `tab_id`, `selector`".to_string())
+                }
+            }
+        },
         "eval" => {
             match parsed_args.as_slice() {
                 [tab_id, expression] => {
@@ -1256,6 +1550,68 @@ fn parse_single_command(command: &String) -> Result<Command, String> {
     }
 }
 
+fn push_log_line(log: &Arc<Mutex<Vec<String>>>, line: String) {
+    let mut log_lock = log.lock().unwrap();
+    log_lock.push(line);
+    if log_lock.len() > MAX_CACHED_LOG_LINES {
+        log_lock.remove(0);
+    }
+}
+
+fn is_xhr_or_fetch(resource_type: &str) -> bool {
+    let resource_type = resource_type.to_lowercase();
+    resource_type == "xhr" || resource_type == "fetch"
+}
+
+fn format_console_value(value: Option<&Value>, description: Option<&str>) -> String {
+    match (value, description) {
+        (Some(Value::String(s)), _) => s.clone(),
+        (Some(value), _) => value.to_string(),
+        (None, Some(description)) => description.to_string(),
+        (None, None) => "undefined".to_string(),
+    }
+}
+
+fn format_network_request(request: &NetworkRequest) -> String {
+    let status = match (&request.error, request.status) {
+        (Some(error), _) => format!("FAILED {}", error),
+        (None, Some(status)) => status.to_string(),
+        (None, None) => "pending".to_string(),
+    };
+    let mut line = format!("{} {} {} ({}", request.method, request.url, status, request.resource_type);
+    if !request.mime_type.is_empty() {
+        line.push_str(&format!(", {}", request.mime_type));
+    }
+    if let Some(finished_ts) = request.finished_ts {
+        line.push_str(&format!(", {:.0}ms", (finished_ts - request.started_ts) * 1000.0));
+    }
+    if let Some(encoded_data_length) = request.encoded_data_length {
+        line.push_str(&format!(", {:.0} bytes", encoded_data_length));
+    }
+    line.push(')');
+    if let Some(body) = &request.response_body {
+        line = format!("{}\n  body: {}", line, body.replace('\n', "\n  "));
+    }
+    line
+}
+
+fn format_element(index: usize, tag_name: &str, attributes: &Vec<String>, text: &str) -> String {
+    // attributes come flat from the devtools protocol: [name1, value1, name2, value2, ...]
+    let attributes_str = attributes.chunks(2)
+        .map(|pair| match pair {
+            [name, value] => format!(" {}={:?}", name, value),
+            [name] => format!(" {}", name),
+            _ => "".to_string(),
+        })
+        .collect::<String>();
+    let text = text.trim();
+    if text.is_empty() {
+        format!("[{}] <{}{}>", index, tag_name.to_lowercase(), attributes_str)
+    } else {
+        format!("[{}] <{}{}> {}", index, tag_name.to_lowercase(), attributes_str, first_n_chars(&text.replace('\n', " "), 300))
+    }
+}
+
 fn replace_host_with_container_if_needed(url: &str, chat_id: &str) -> String {
     if let Ok(mut parsed_url) = url::Url::parse(url) {
         if let Some(host) = parsed_url.host_str() {
@@ -1357,3 +1713,136 @@ docker:
           content: |
             🔧 Your job is to modify chrome config in the current file to connect through websockets to the container, use docker tool to inspect the container if needed. Current config file: %CURRENT_CONFIG%.
 "#;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_new_commands() {
+        assert!(matches!(parse_single_command(&"console_log 1".to_string()), Ok(Command::ConsoleLog(_))));
+        match parse_single_command(&"network_log 1 failed".to_string()) {
+            Ok(Command::NetworkLog(args)) => assert!(args.only_failed),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_single_command(&"network_log 1 whatever".to_string()).is_err());
+        assert!(matches!(parse_single_command(&"query_selector 1 \"ul > li.item\"".to_string()), Ok(Command::QuerySelector(_))));
+        match parse_single_command(&"wait_for_selector 1 #app 10".to_string()) {
+            Ok(Command::WaitForSelector(args)) => assert_eq!(args.seconds, 10),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_single_command(&"wait_for_selector 1 #app 100".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_format_network_request() {
+        let request = NetworkRequest {
+            method: "GET".to_string(),
+            url: "http://127.0.0.1:8080/api/items".to_string(),
+            resource_type: "fetch".to_string(),
+            status: Some(404),
+            mime_type: "application/json".to_string(),
+            started_ts: 10.0,
+            finished_ts: Some(10.25),
+            encoded_data_length: Some(120.0),
+            error: None,
+            response_body: Some("{\"detail\": \"not found\"}".to_string()),
+        };
+        assert!(request.is_failed());
+        assert_eq!(
+            format_network_request(&request),
+            "GET http://127.0.0.1:8080/api/items 404 (fetch, application/json, 250ms, 120 bytes)\n  body: {\"detail\": \"not found\"}"
+        );
+        let failed = NetworkRequest {
+            method: "POST".to_string(),
+            url: "http://127.0.0.1:9/".to_string(),
+            resource_type: "xhr".to_string(),
+            error: Some("net::ERR_CONNECTION_REFUSED".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(format_network_request(&failed), "POST http://127.0.0.1:9/ FAILED net::ERR_CONNECTION_REFUSED (xhr)");
+    }
+
+    #[test]
+    fn test_format_console_and_elements() {
+        assert_eq!(format_console_value(Some(&Value::String("hello".to_string())), None), "hello");
+        assert_eq!(format_console_value(Some(&serde_json::json!(42)), None), "42");
+        assert_eq!(format_console_value(None, Some("Error: boom")), "Error: boom");
+        let attributes = vec!["class".to_string(), "item".to_string(), "data-id".to_string(), "7".to_string()];
+        assert_eq!(format_element(0, "LI", &attributes, " First\nitem "), "[0] <li class=\"item\" data-id=\"7\"> First item");
+        assert!(is_xhr_or_fetch("Fetch"));
+        assert!(!is_xhr_or_fetch("Document"));
+    }
+
+    async fn serve_test_page() -> std::net::SocketAddr {
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|req: hyper::Request<hyper::Body>| async move {
+                let response = match req.uri().path() {
+                    "/" => hyper::Response::builder()
+                        .header("Content-Type", "text/html")
+                        .body(hyper::Body::from("<html><body><ul><li class=\"item\">First</li><li class=\"item\">Second</li></ul>\
+                            <script>console.log('page ready', 42); fetch('/api/missing');\
+                            setTimeout(() => document.body.insertAdjacentHTML('beforeend', '<div id=\"late\">Late</div>'), 500);</script></body></html>")),
+                    _ => hyper::Response::builder()
+                        .status(404)
+                        .header("Content-Type", "application/json")
+                        .body(hyper::Body::from("{\"detail\": \"not found\"}")),
+                };
+                Ok::<_, std::convert::Infallible>(response.unwrap())
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs Chrome or Chromium installed, run with `cargo test -- --ignored`"]
+    async fn test_navigate_and_screenshot_local_page() {
+        let chrome_path = headless_chrome::browser::default_executable().unwrap();
+        let addr = serve_test_page().await;
+        let browser = Browser::new(LaunchOptions {
+            path: Some(chrome_path),
+            headless: true,
+            sandbox: false,
+            ..Default::default()
+        }).unwrap();
+        let session: Box<dyn IntegrationSession> = Box::new(ChromeSession { browser, tabs: HashMap::new() });
+        let session = Arc::new(AMutex::new(session));
+        let settings = SettingsChrome::default();
+        let run = |command: String| {
+            let session = session.clone();
+            let settings = settings.clone();
+            async move {
+                let cmd = parse_single_command(&command).unwrap();
+                chrome_command_exec(&cmd, session, &settings, false, "test-chat").await.unwrap()
+            }
+        };
+
+        let (log, _) = run("open_tab 1 desktop".to_string()).await;
+        assert!(log[0].starts_with("Opened a new tab"), "{:?}", log);
+        let (log, _) = run(format!("navigate_to 1 http://{}/", addr)).await;
+        assert!(log[0].starts_with("navigate_to successful"), "{:?}", log);
+        let (log, _) = run("query_selector 1 li.item".to_string()).await;
+        assert!(log[0].contains("[1] <li class=\"item\"> Second"), "{:?}", log);
+        let (log, _) = run("wait_for 1 1".to_string()).await;
+        assert!(!log.is_empty());
+        let (log, _) = run("wait_for_selector 1 #late 5".to_string()).await;
+        assert!(log[0].starts_with("wait_for_selector `#late` appeared"), "{:?}", log);
+        let (log, _) = run("wait_for_selector 1 #never 1".to_string()).await;
+        assert!(log[0].starts_with("wait_for_selector `#never` failed"), "{:?}", log);
+        let (log, _) = run("console_log 1".to_string()).await;
+        assert!(log[0].contains("[log] page ready 42"), "{:?}", log);
+        let (log, _) = run("console_log 1".to_string()).await;
+        assert!(log[0].starts_with("No console messages since the last console_log"), "{:?}", log);
+        let (log, _) = run("network_log 1 failed".to_string()).await;
+        assert!(log[0].contains("/api/missing 404"), "{:?}", log);
+        let (log, images) = run("screenshot 1".to_string()).await;
+        assert!(log[0].starts_with("Made a screenshot"), "{:?}", log);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].m_type, "image/jpeg");
+        assert!(!images[0].m_content.is_empty());
+    }
+}
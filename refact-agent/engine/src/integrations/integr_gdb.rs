@@ -0,0 +1,646 @@
+use std::any::Any;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use std::fmt::Debug;
+use std::future::Future;
+use serde_json::Value;
+use tokio::io::BufReader;
+use tokio::sync::{Mutex as AMutex, RwLock as ARwLock};
+use tokio::process::{Command, Child, ChildStdin, ChildStdout, ChildStderr};
+use tokio::time::{Duration, Instant};
+use async_trait::async_trait;
+use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ContextEnum, ChatMessage, ChatContent, ChatUsage};
+use crate::files_correction::get_active_project_path;
+use crate::integrations::sessions::{IntegrationSession, get_session_hashmap_key};
+use crate::global_context::GlobalContext;
+use crate::integrations::integr_abstract::{IntegrationCommon, IntegrationConfirmation, IntegrationTrait};
+use crate::tools::tools_description::{Tool, ToolDesc, ToolParam};
+use crate::integrations::process_io_utils::{first_n_chars, last_n_chars, last_n_lines, write_to_stdin_and_flush, blocking_read_until_token_or_timeout};
+
+
+const SESSION_TIMEOUT_AFTER_INACTIVITY: Duration = Duration::from_secs(30 * 60);
+const GDB_MI_TOKEN: &str = "(gdb)";
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SettingsGdb {
+    pub gdb_path: String,
+}
+
+#[derive(Default)]
+pub struct ToolGdb {
+    pub common:  IntegrationCommon,
+    pub settings_gdb: SettingsGdb,
+    pub config_path: String,
+}
+
+pub struct GdbSession {
+    process: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: BufReader<ChildStderr>,
+    last_usage_ts: u64,
+}
+
+impl Drop for GdbSession {
+    fn drop(&mut self) {
+        self.process.start_kill().map_err(|e| error!("Failed to kill process: {}", e)).ok();
+    }
+}
+
+impl IntegrationSession for GdbSession
+{
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn is_expired(&self) -> bool {
+        let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        self.last_usage_ts + SESSION_TIMEOUT_AFTER_INACTIVITY.as_secs() < current_time
+    }
+
+    fn try_stop(&mut self, _self_arc: Arc<AMutex<Box<dyn IntegrationSession>>>) -> Box<dyn Future<Output = String> + Send> {
+        Box::new(async { "".to_string() })
+    }
+}
+
+#[async_trait]
+impl IntegrationTrait for ToolGdb {
+    fn as_any(&self) -> &dyn Any { self }
+
+    async fn integr_settings_apply(&mut self, _gcx: Arc<ARwLock<GlobalContext>>, config_path: String, value: &serde_json::Value) -> Result<(), serde_json::Error> {
+        self.settings_gdb = serde_json::from_value(value.clone())?;
+        self.common = serde_json::from_value(value.clone())?;
+        self.config_path = config_path;
+        Ok(())
+    }
+
+    fn integr_settings_as_json(&self) -> Value {
+        serde_json::to_value(&self.settings_gdb).unwrap_or_default()
+    }
+
+    fn integr_common(&self) -> IntegrationCommon {
+        self.common.clone()
+    }
+
+    async fn integr_tools(&self, _integr_name: &str) -> Vec<Box<dyn crate::tools::tools_description::Tool + Send>> {
+        vec![Box::new(ToolGdb {
+            common: self.common.clone(),
+            settings_gdb: self.settings_gdb.clone(),
+            config_path: self.config_path.clone(),
+        })]
+    }
+
+    fn integr_schema(&self) -> &str { GDB_INTEGRATION_SCHEMA }
+}
+
+#[async_trait]
+impl Tool for ToolGdb {
+    fn as_any(&self) -> &dyn Any { self }
+
+    async fn tool_execute(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        tool_call_id: &String,
+        args: &HashMap<String, Value>,
+    ) -> Result<(bool, Vec<ContextEnum>), String> {
+        let (command, workdir_maybe) = parse_args(args)?;
+        let command_args = split_command(&command)?;
+
+        let (gcx, chat_id) = {
+            let ccx_lock = ccx.lock().await;
+            (ccx_lock.global_context.clone(), ccx_lock.chat_id.clone())
+        };
+
+        let session_hashmap_key = get_session_hashmap_key("gdb", &chat_id);
+        let mut gdb_command = self.settings_gdb.gdb_path.clone();
+        if gdb_command.is_empty() {
+            gdb_command = "gdb".to_string();
+        }
+        if command_args[0] == "gdb" {
+            let output = start_gdb_session(&gdb_command, &command_args, &session_hashmap_key, &workdir_maybe, gcx.clone(), 10).await?;
+            return Ok(tool_answer(output, tool_call_id));
+        }
+
+        let command_session = {
+            let gcx_locked = gcx.read().await;
+            gcx_locked.integration_sessions.get(&session_hashmap_key)
+                .ok_or("There is no active gdb session in this chat, you can open it by running gdb(\"gdb ./path/to/binary\") or gdb(\"gdb --pid 1234\")")?
+                .clone()
+        };
+
+        let mut command_session_locked = command_session.lock().await;
+        let gdb_session = command_session_locked.as_any_mut().downcast_mut::<GdbSession>()
+            .ok_or("Failed to downcast to GdbSession")?;
+
+        let output = match command_args[0].as_str() {
+            "kill" => {
+                let mut gcx_locked = gcx.write().await;
+                gcx_locked.integration_sessions.remove(&session_hashmap_key);
+                "Gdb session has been killed".to_string()
+            },
+            "wait" => {
+                if command_args.len() < 2 {
+                    return Err("Argument `n_seconds` in `wait n_seconds` is missing".to_string());
+                }
+                let timeout_seconds = command_args[1].parse::<u64>().map_err(|_| "Argument `n_seconds` in `wait n_seconds` is not a number".to_string())?;
+                interact_with_gdb("", gdb_session, &session_hashmap_key, gcx.clone(), timeout_seconds).await?
+            },
+            "break" | "b" | "tbreak" if command_args.len() == 2 => {
+                let location = resolve_breakpoint_location(gcx.clone(), &command_args[1]).await;
+                let break_command = format!("{} {}", command_args[0], location);
+                interact_with_gdb(&break_command, gdb_session, &session_hashmap_key, gcx.clone(), 10).await?
+            },
+            _ => { interact_with_gdb(&command, gdb_session, &session_hashmap_key, gcx.clone(), 10).await? }
+        };
+        Ok(tool_answer(output, tool_call_id))
+    }
+
+    fn command_to_match_against_confirm_deny(
+        &self,
+        args: &HashMap<String, Value>,
+    ) -> Result<String, String> {
+        let (command, _) = parse_args(args)?;
+        let command_args = split_command(&command)?;
+        Ok(command_args.join(" "))
+    }
+
+    fn tool_description(&self) -> ToolDesc {
+        ToolDesc {
+            name: "gdb".to_string(),
+            agentic: true,
+            experimental: false,
+            description: "Native debugger (gdb) for Rust, C and C++ programs, use it to set breakpoints, step through code and inspect variables. This tool executes only one command at a time. Start with gdb ./path/to/binary or gdb --pid PID".to_string(),
+            parameters: vec![
+                ToolParam {
+                    name: "command".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Examples: 'gdb ./target/debug/app --flag', 'gdb --pid 1234', 'break src/main.rs:42', 'break my_module::my_function', 'run', 'next', 'step', 'finish', 'continue', 'print variable_name', 'bt', 'kill'".to_string(),
+                },
+                ToolParam {
+                    name: "workdir".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Working directory for the command, needed to start a gdb session from a relative path.".to_string(),
+                },
+            ],
+            parameters_required: vec!["command".to_string()],
+        }
+    }
+
+    fn tool_depends_on(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn usage(&mut self) -> &mut Option<ChatUsage> {
+        static mut DEFAULT_USAGE: Option<ChatUsage> = None;
+        #[allow(static_mut_refs)]
+        unsafe { &mut DEFAULT_USAGE }
+    }
+
+    fn confirm_deny_rules(&self) -> Option<IntegrationConfirmation> {
+        Some(self.integr_common().confirmation)
+    }
+
+    fn has_config_path(&self) -> Option<String> {
+        Some(self.config_path.clone())
+    }
+}
+
+fn parse_args(args: &HashMap<String, Value>) -> Result<(String, Option<PathBuf>), String> {
+    let command = match args.get("command") {
+        Some(Value::String(s)) => s.to_string(),
+        Some(v) => return Err(format!("argument `command` is not a string: {:?}", v)),
+        None => return Err("Missing argument `command`".to_string()),
+    };
+    let workdir_maybe = match args.get("workdir") {
+        Some(Value::String(s)) => {
+            if s.is_empty() {
+                None
+            } else {
+                let workdir = crate::files_correction::canonical_path(s);
+                if !workdir.exists() {
+                    return Err("Workdir doesn't exist".to_string());
+                } else {
+                    Some(workdir)
+                }
+            }
+        },
+        Some(v) => return Err(format!("argument `workdir` is not a string: {:?}", v)),
+        None => None
+    };
+    Ok((command, workdir_maybe))
+}
+
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let parsed_args = shell_words::split(command).map_err(|e| e.to_string())?;
+    if parsed_args.is_empty() {
+        return Err("Parsed command is empty".to_string());
+    }
+
+    Ok(parsed_args)
+}
+
+async fn resolve_breakpoint_location(gcx: Arc<ARwLock<GlobalContext>>, location: &str) -> String {
+    // file:line, *address and plain line numbers are understood by gdb as is
+    if location.starts_with('*') || location.parse::<usize>().is_ok() {
+        return location.to_string();
+    }
+    if let Some((_, line)) = location.rsplit_once(':') {
+        if line.parse::<usize>().is_ok() {
+            return location.to_string();
+        }
+    }
+    let ast_service_opt = gcx.read().await.ast_service.clone();
+    let ast_index = match ast_service_opt {
+        Some(ast_service) => ast_service.lock().await.ast_index.clone(),
+        None => return location.to_string(),
+    };
+    let defs = crate::ast::ast_db::definitions(ast_index, &location.replace(".", "::")).await;
+    match defs.first() {
+        Some(def) if defs.len() == 1 => {
+            info!("gdb breakpoint {:?} resolved to {}:{}", location, def.cpath, def.full_line1());
+            format!("{}:{}", def.cpath, def.full_line1())
+        },
+        _ => location.to_string(),
+    }
+}
+
+async fn start_gdb_session(
+    gdb_command: &String,
+    command_args: &Vec<String>,
+    session_hashmap_key: &String,
+    workdir_maybe: &Option<PathBuf>,
+    gcx: Arc<ARwLock<GlobalContext>>,
+    timeout_seconds: u64,
+) -> Result<String, String> {
+    let mut gdb_args = vec!["--interpreter=mi".to_string(), "--quiet".to_string()];
+    match command_args.get(1).map(|s| s.as_str()) {
+        Some("--pid") | Some("-p") => {
+            let pid = command_args.get(2).ok_or("Usage: gdb --pid PID")?;
+            pid.parse::<u32>().map_err(|_| format!("PID {:?} is not a number", pid))?;
+            gdb_args.extend(vec!["-p".to_string(), pid.clone()]);
+        },
+        Some(_) => {
+            gdb_args.push("--args".to_string());
+            gdb_args.extend(command_args[1..].iter().cloned());
+        },
+        None => return Err("Usage: gdb ./path/to/binary [args...] or gdb --pid PID. To use a different gdb, set the path to gdb binary in the integration settings.".to_string()),
+    }
+
+    info!("Starting gdb session with command: {} {:?}", gdb_command, gdb_args);
+    let mut process_command = Command::new(gdb_command);
+    process_command.args(&gdb_args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    if let Some(workdir) = workdir_maybe {
+        process_command.current_dir(workdir);
+    } else if let Some(project_path) = get_active_project_path(gcx.clone()).await {
+        process_command.current_dir(project_path);
+    } else {
+        tracing::warn!("no working directory, using whatever directory this binary is run :/");
+    }
+
+    let mut process = process_command.spawn().map_err(|e| {
+        error!("Failed to start gdb process: {}", e);
+        e.to_string()
+    })?;
+
+    let stdin = process.stdin.take().ok_or("Failed to open stdin for gdb process")?;
+    let stdout = BufReader::new(process.stdout.take().ok_or("Failed to open stdout for gdb process")?);
+    let stderr = BufReader::new(process.stderr.take().ok_or("Failed to open stderr for gdb process")?);
+    let mut gdb_session = GdbSession {process, stdin, stdout, stderr, last_usage_ts: 0};
+
+    let (startup, _) = send_command_and_get_output(
+        &mut gdb_session, "", session_hashmap_key, gcx.clone(), timeout_seconds * 1000, true).await?;
+    for setting in ["-gdb-set pagination off", "-gdb-set confirm off", "-gdb-set print pretty on"] {
+        send_command_and_get_output(&mut gdb_session, setting, session_hashmap_key, gcx.clone(), 2000, false).await?;
+    }
+    let output = format!("{}{}", startup.console, format_error("gdb error", &startup.errors.join("\n")));
+    let output = if gdb_args.contains(&"-p".to_string()) {
+        output + &interact_with_gdb("", &mut gdb_session, session_hashmap_key, gcx.clone(), timeout_seconds).await?
+    } else {
+        output + "The program is loaded but not running, set breakpoints and use `run` to start it."
+    };
+    gdb_session.last_usage_ts = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let command_session: Box<dyn IntegrationSession> = Box::new(gdb_session);
+    {
+        let mut gcx_locked = gcx.write().await;
+        gcx_locked.integration_sessions.insert(
+            session_hashmap_key.clone(), Arc::new(AMutex::new(command_session))
+        );
+    }
+    Ok(output)
+}
+
+async fn interact_with_gdb(
+    input_command: &str,
+    gdb_session: &mut GdbSession,
+    session_hashmap_key: &String,
+    gcx: Arc<ARwLock<GlobalContext>>,
+    timeout_seconds: u64,
+) -> Result<String, String> {
+    if !input_command.is_empty() {
+        let (prev_output, prev_error, _) = blocking_read_until_token_or_timeout(
+            &mut gdb_session.stdout, &mut gdb_session.stderr, 100, GDB_MI_TOKEN).await?;
+        let prev = parse_mi_output(&prev_output);
+        if !prev.console.is_empty() || !prev.target.is_empty() || !prev.stopped.is_empty() || !prev_error.is_empty() {
+            return Err(format!("There is leftover output from previous commands, run gdb tool again with \"wait n_seconds\" to wait for it or \"kill\" command to kill the session.\nstdout:\n{}{}\nstderr:\n{}", prev.target, prev.console, prev_error));
+        }
+    }
+
+    let mi_command = if input_command.is_empty() {
+        "".to_string()
+    } else {
+        format!("-interpreter-exec console {}", mi_quote(input_command))
+    };
+    let (main, error_main) = send_command_and_get_output(
+        gdb_session, &mi_command, session_hashmap_key, gcx.clone(), timeout_seconds * 1000, true).await?;
+    let (frame, _) = send_command_and_get_output(
+        gdb_session, "-interpreter-exec console \"frame\"", session_hashmap_key, gcx.clone(), 2000, false).await?;
+    let (backtrace, error_backtrace) = send_command_and_get_output(
+        gdb_session, "-interpreter-exec console \"bt 10\"", session_hashmap_key, gcx.clone(), 5000, false).await?;
+    let (locals, error_locals) = send_command_and_get_output(
+        gdb_session, "-interpreter-exec console \"info locals\"", session_hashmap_key, gcx.clone(), 5000, false).await?;
+
+    gdb_session.last_usage_ts = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    Ok(format_all_output(&main, &error_main, &frame, &backtrace, &error_backtrace, &locals, &error_locals))
+}
+
+async fn send_command_and_get_output(
+    gdb_session: &mut GdbSession,
+    input_command: &str,
+    session_hashmap_key: &str,
+    gcx: Arc<ARwLock<GlobalContext>>,
+    timeout_ms: u64,
+    ask_for_continuation_if_timeout: bool,
+) -> Result<(MiOutput, String), String> {
+    if !input_command.is_empty() {
+        write_to_stdin_and_flush(&mut gdb_session.stdin, input_command).await?;
+    }
+    let start_time = Instant::now();
+    let mut raw_output = String::new();
+    let mut error = String::new();
+    let mut have_the_token;
+    loop {
+        let time_left_ms = timeout_ms.saturating_sub(start_time.elapsed().as_millis() as u64).max(1);
+        let (output, stderr, token) = blocking_read_until_token_or_timeout(
+            &mut gdb_session.stdout, &mut gdb_session.stderr, time_left_ms, GDB_MI_TOKEN).await?;
+        raw_output.push_str(&output);
+        error.push_str(&stderr);
+        have_the_token = token;
+        // execution commands answer ^running and print the prompt right away, *stopped comes later
+        let parsed = parse_mi_output(&raw_output);
+        let still_running = parsed.running && parsed.stopped.is_empty();
+        if !have_the_token || !still_running || start_time.elapsed().as_millis() as u64 >= timeout_ms {
+            if still_running {
+                have_the_token = false;
+            }
+            break;
+        }
+    }
+
+    let exit_status = gdb_session.process.try_wait().map_err(|e| e.to_string())?;
+    if let Some(exit_status) = exit_status {
+        gcx.write().await.integration_sessions.remove(session_hashmap_key);
+        return Err(format!("Gdb process exited with status: {:?}", exit_status));
+    }
+
+    let mut parsed = parse_mi_output(&raw_output);
+    if !have_the_token {
+        let mut timeout_error = format!("Command {} timed out after {} seconds.", input_command, timeout_ms / 1000);
+        if ask_for_continuation_if_timeout {
+            timeout_error = timeout_error + " The program is probably still running. Call gdb tool again with \"wait n_seconds\" command to wait for n seconds for it to stop, or \"kill\" command to forcedly stop the session.";
+            return Err(format!("{}\n{}{}", timeout_error, parsed.target, last_n_chars(&parsed.console, 2000)));
+        }
+        parsed.errors.push(timeout_error);
+    }
+    error.push_str(&parsed.errors.join("\n"));
+
+    Ok((parsed, error))
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct MiOutput {
+    console: String,
+    target: String,
+    errors: Vec<String>,
+    stopped: Vec<String>,
+    running: bool,
+}
+
+fn parse_mi_output(raw: &str) -> MiOutput {
+    let mut result = MiOutput::default();
+    for line in raw.lines() {
+        let line = line.trim_end();
+        // result and async records may be prefixed with a numeric token
+        let record = line.trim_start_matches(|c: char| c.is_ascii_digit());
+        if let Some(s) = record.strip_prefix('~') {
+            result.console.push_str(&unescape_mi_cstring(s));
+        } else if let Some(s) = record.strip_prefix('@') {
+            result.target.push_str(&unescape_mi_cstring(s));
+        } else if record.starts_with("^error") {
+            result.errors.push(mi_field(record, "msg").unwrap_or_else(|| record.to_string()));
+        } else if record.starts_with("^running") || record.starts_with("*running") {
+            result.running = true;
+        } else if record.starts_with("*stopped") {
+            result.stopped.push(describe_stop(record));
+        } else if record.starts_with('&') || record.starts_with('=') || record.starts_with('^') || record == GDB_MI_TOKEN || record.is_empty() {
+            // log stream, notifications, ^done and prompts aren't interesting for the model
+        } else {
+            // the debugged program shares the terminal with gdb, its output comes in raw
+            result.target.push_str(line);
+            result.target.push('\n');
+        }
+    }
+    result
+}
+
+fn describe_stop(record: &str) -> String {
+    let reason = mi_field(record, "reason").unwrap_or("unknown".to_string());
+    let mut description = format!("stopped, reason: {}", reason);
+    if let Some(exit_code) = mi_field(record, "exit-code") {
+        description.push_str(&format!(", exit code {}", exit_code));
+    }
+    if let Some(signal) = mi_field(record, "signal-name") {
+        description.push_str(&format!(", signal {}", signal));
+    }
+    if let Some(func) = mi_field(record, "func") {
+        description.push_str(&format!(", in {}", func));
+    }
+    if let (Some(file), Some(line)) = (mi_field(record, "fullname").or(mi_field(record, "file")), mi_field(record, "line")) {
+        description.push_str(&format!(" at {}:{}", file, line));
+    }
+    description
+}
+
+fn mi_field(record: &str, name: &str) -> Option<String> {
+    let pattern = format!("{}=\"", name);
+    let mut search_from = 0;
+    while let Some(pos) = record[search_from..].find(&pattern) {
+        let start = search_from + pos;
+        let is_key_start = start == 0 || matches!(record.as_bytes()[start - 1], b',' | b'{' | b'[');
+        if is_key_start {
+            let value_start = start + pattern.len() - 1;
+            let mut escaped = false;
+            for (i, c) in record[value_start + 1..].char_indices() {
+                match c {
+                    '\\' if !escaped => escaped = true,
+                    '"' if !escaped => return Some(unescape_mi_cstring(&record[value_start..value_start + 1 + i + 1])),
+                    _ => escaped = false,
+                }
+            }
+            return None;
+        }
+        search_from = start + pattern.len();
+    }
+    None
+}
+
+fn unescape_mi_cstring(s: &str) -> String {
+    let s = s.trim();
+    let s = s.strip_prefix('"').unwrap_or(s);
+    let s = s.strip_suffix('"').unwrap_or(s);
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => {},
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+fn mi_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn tool_answer(output: String, tool_call_id: &String) -> (bool, Vec<ContextEnum>)
+{
+    (false, vec![
+        ContextEnum::ChatMessage(ChatMessage {
+            role: "tool".to_string(),
+            content: ChatContent::SimpleText(output),
+            tool_calls: None,
+            tool_call_id: tool_call_id.clone(),
+            ..Default::default()
+        })
+    ])
+}
+
+fn format_all_output(main: &MiOutput, error_main: &str, frame: &MiOutput, backtrace: &MiOutput, error_backtrace: &str, locals: &MiOutput, error_locals: &str) -> String
+{
+    let stopped = if main.stopped.is_empty() { "".to_string() } else { format!("{}\n", main.stopped.join("\n")) };
+    format!(
+        "Program output:\n{}\nCommand output:\n{}\n{}{}\nCurrent code section:\n{}\nStack trace:\n{}{}\nLocal variables:\n{}{}",
+        last_n_chars(&main.target, 5000),
+        last_n_chars(&main.console, 5000),
+        format_error("Command error", &last_n_chars(error_main, 5000)),
+        stopped,
+        frame.console,
+        last_n_lines(&backtrace.console, 10),
+        format_error("bt error", error_backtrace),
+        first_n_chars(&locals.console, 1000),
+        format_error("locals error", error_locals),
+    )
+}
+
+fn format_error(error_title: &str, error: &str) -> String
+{
+    if !error.is_empty() {
+        format!("{}:\n{}\n", error_title, error)
+    } else {
+        "".to_string()
+    }
+}
+
+const GDB_INTEGRATION_SCHEMA: &str = r#"
+fields:
+  gdb_path:
+    f_type: string_long
+    f_desc: "Path to the gdb binary, or any other debugger that speaks GDB/MI such as lldb-mi. Leave empty to use the default 'gdb' command."
+    f_placeholder: "/usr/bin/gdb"
+    f_label: "Debugger Path"
+description: |
+  The GDB integration allows interaction with the native debugger for Rust, C and C++ programs.
+  It can start a binary or attach to a running process, set breakpoints by file:line or by symbol name, step through code, inspect locals and print backtraces.
+available:
+  on_your_laptop_possible: true
+  when_isolated_possible: true
+confirmation:
+  ask_user_default: ["gdb --pid *", "gdb -p *"]
+  deny_default: []
+smartlinks:
+  - sl_label: "Test"
+    sl_chat:
+      - role: "user"
+        content: |
+          🔧 The gdb tool should be visible now. To test the tool, build a simple program with debug info, start a debugging session for it, set a breakpoint, run to it and inspect some variables.
+          If it doesn't work or the tool isn't available, go through the usual plan in the system prompt.
+    sl_enable_only_with_tool: true
+"#;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mi_output() {
+        let raw = concat!(
+            "=thread-group-added,id=\"i1\"\n",
+            "~\"Breakpoint 1 at 0x1149: file main.c, line 5.\\n\"\n",
+            "^done\n",
+            "(gdb) \n",
+            "hello from the program\n",
+            "*stopped,reason=\"breakpoint-hit\",frame={addr=\"0x1149\",func=\"main\",file=\"main.c\",fullname=\"/tmp/main.c\",line=\"5\"}\n",
+            "&\"warning: something\\n\"\n",
+            "^error,msg=\"No symbol \\\"xyz\\\" in current context.\"\n",
+        );
+        let parsed = parse_mi_output(raw);
+        assert_eq!(parsed.console, "Breakpoint 1 at 0x1149: file main.c, line 5.\n");
+        assert_eq!(parsed.target, "hello from the program\n");
+        assert_eq!(parsed.errors, vec!["No symbol \"xyz\" in current context.".to_string()]);
+        assert_eq!(parsed.stopped, vec!["stopped, reason: breakpoint-hit, in main at /tmp/main.c:5".to_string()]);
+        assert!(!parsed.running);
+    }
+
+    #[test]
+    fn test_mi_field_and_running() {
+        let parsed = parse_mi_output("^running\n*running,thread-id=\"all\"\n(gdb)\n");
+        assert!(parsed.running);
+        assert!(parsed.stopped.is_empty());
+        let record = "*stopped,reason=\"exited\",exit-code=\"01\"";
+        assert_eq!(mi_field(record, "exit-code"), Some("01".to_string()));
+        assert_eq!(mi_field(record, "code"), None);
+        assert_eq!(describe_stop(record), "stopped, reason: exited, exit code 01");
+    }
+
+    #[test]
+    fn test_mi_quote_roundtrip() {
+        let command = "print \"a\\b\"";
+        assert_eq!(unescape_mi_cstring(&mi_quote(command)), command);
+    }
+}
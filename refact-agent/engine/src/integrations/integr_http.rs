@@ -0,0 +1,718 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{Mutex as AMutex, RwLock as ARwLock};
+use async_trait::async_trait;
+use url::Url;
+
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ChatContent, ChatMessage, ContextEnum};
+use crate::files_correction::canonical_path;
+use crate::global_context::GlobalContext;
+use crate::integrations::integr_abstract::{IntegrationCommon, IntegrationConfirmation, IntegrationTrait};
+use crate::integrations::utils::{serialize_num_to_str, deserialize_str_to_num};
+use crate::postprocessing::pp_command_output::{CmdlineOutputFilter, output_mini_postprocessing};
+use crate::tools::tools_description::{Tool, ToolDesc, ToolParam};
+
+
+const HTTP_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+const HTTP_SHOWN_HEADERS: &[&str] = &["content-type", "content-length", "location", "retry-after", "www-authenticate"];
+const MAX_OPENAPI_TOOLS: usize = 30;
+const PATH_PARAM_ENCODE_SET: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SettingsHttp {
+    pub base_url: String,
+    #[serde(default)]
+    pub allowed_hosts: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "default_timeout", serialize_with = "serialize_num_to_str", deserialize_with = "deserialize_str_to_num")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub openapi_spec: String,
+    #[serde(default = "default_output_filter")]
+    pub output_filter: CmdlineOutputFilter,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_output_filter() -> CmdlineOutputFilter {
+    CmdlineOutputFilter {
+        limit_lines: 300,
+        limit_chars: 10000,
+        valuable_top_or_bottom: "top".to_string(),
+        grep: "".to_string(),
+        grep_context_lines: 0,
+        remove_from_output: "".to_string(),
+    }
+}
+
+impl SettingsHttp {
+    fn allowed_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.allowed_hosts.split(',').map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty()).collect();
+        if let Ok(base_url) = Url::parse(&self.base_url) {
+            if let Some(host) = base_url.host_str() {
+                hosts.push(host_with_port(host, base_url.port_or_known_default()));
+            }
+        }
+        hosts
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpenApiParam {
+    name: String,
+    location: String,  // "path", "query" or "header"
+    required: bool,
+    param_type: String,
+    description: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpenApiOperation {
+    tool_name: String,
+    method: String,
+    path: String,
+    summary: String,
+    params: Vec<OpenApiParam>,
+    has_body: bool,
+    body_required: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct HttpRequest {
+    method: String,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ToolHttp {
+    pub common: IntegrationCommon,
+    pub settings_http: SettingsHttp,
+    pub config_path: String,
+    pub openapi_operations: Vec<OpenApiOperation>,
+}
+
+pub struct ToolHttpOperation {
+    pub common: IntegrationCommon,
+    pub settings_http: SettingsHttp,
+    pub config_path: String,
+    pub operation: OpenApiOperation,
+}
+
+#[async_trait]
+impl IntegrationTrait for ToolHttp {
+    fn as_any(&self) -> &dyn std::any::Any { self }
+
+    async fn integr_settings_apply(&mut self, gcx: Arc<ARwLock<GlobalContext>>, config_path: String, value: &serde_json::Value) -> Result<(), serde_json::Error> {
+        self.settings_http = serde_json::from_value(value.clone())?;
+        self.common = serde_json::from_value(value.clone())?;
+        self.config_path = config_path;
+        self.openapi_operations = vec![];
+        if !self.settings_http.openapi_spec.is_empty() {
+            match load_openapi_spec(gcx.clone(), &self.settings_http.openapi_spec, self.settings_http.timeout).await
+                .and_then(|spec| parse_openapi_operations(&spec))
+            {
+                Ok(operations) => {
+                    if operations.len() > MAX_OPENAPI_TOOLS {
+                        tracing::warn!("openapi spec {} has {} operations, only the first {} become tools", self.settings_http.openapi_spec, operations.len(), MAX_OPENAPI_TOOLS);
+                    }
+                    self.openapi_operations = operations.into_iter().take(MAX_OPENAPI_TOOLS).collect();
+                },
+                Err(e) => {
+                    tracing::warn!("cannot load openapi spec {}: {}", self.settings_http.openapi_spec, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn integr_settings_as_json(&self) -> Value {
+        serde_json::to_value(&self.settings_http).unwrap()
+    }
+
+    fn integr_common(&self) -> IntegrationCommon {
+        self.common.clone()
+    }
+
+    async fn integr_tools(&self, _integr_name: &str) -> Vec<Box<dyn crate::tools::tools_description::Tool + Send>> {
+        let mut tools: Vec<Box<dyn crate::tools::tools_description::Tool + Send>> = vec![Box::new(ToolHttp {
+            common: self.common.clone(),
+            settings_http: self.settings_http.clone(),
+            config_path: self.config_path.clone(),
+            openapi_operations: self.openapi_operations.clone(),
+        })];
+        for operation in self.openapi_operations.iter() {
+            tools.push(Box::new(ToolHttpOperation {
+                common: self.common.clone(),
+                settings_http: self.settings_http.clone(),
+                config_path: self.config_path.clone(),
+                operation: operation.clone(),
+            }));
+        }
+        tools
+    }
+
+    fn integr_schema(&self) -> &str
+    {
+        HTTP_INTEGRATION_SCHEMA
+    }
+}
+
+#[async_trait]
+impl Tool for ToolHttp {
+    fn as_any(&self) -> &dyn std::any::Any { self }
+
+    async fn tool_execute(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        tool_call_id: &String,
+        args: &HashMap<String, Value>,
+    ) -> Result<(bool, Vec<ContextEnum>), String> {
+        let request = request_from_args(args, &self.settings_http)?;
+        let gcx = ccx.lock().await.global_context.clone();
+        let output = http_execute(gcx, &request, &self.settings_http).await?;
+        Ok((false, vec![tool_answer(output, tool_call_id)]))
+    }
+
+    fn command_to_match_against_confirm_deny(
+        &self,
+        args: &HashMap<String, Value>,
+    ) -> Result<String, String> {
+        let request = request_from_args(args, &self.settings_http)?;
+        Ok(format!("{} {}", request.method, request.url))
+    }
+
+    fn tool_name(&self) -> String {
+        "http".to_string()
+    }
+
+    fn tool_description(&self) -> ToolDesc {
+        let mut description = format!(
+            "Send an HTTP request to the API at {}, allowed hosts: {}. The response status, main headers and the pretty-printed body are returned. GET requests run right away, POST, PUT, PATCH and DELETE need the user's confirmation.",
+            self.settings_http.base_url, self.settings_http.allowed_hosts().join(", "),
+        );
+        if !self.openapi_operations.is_empty() {
+            description.push_str(" Prefer the typed tools generated from the OpenAPI spec: ");
+            description.push_str(&self.openapi_operations.iter().map(|op| op.tool_name.clone()).collect::<Vec<_>>().join(", "));
+        }
+        ToolDesc {
+            name: "http".to_string(),
+            agentic: true,
+            experimental: false,
+            description,
+            parameters: vec![
+                ToolParam {
+                    name: "method".to_string(),
+                    param_type: "string".to_string(),
+                    description: format!("One of: {}", HTTP_METHODS.join(", ")),
+                },
+                ToolParam {
+                    name: "path".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Path relative to the base URL, with the query string if needed, for example '/api/users?limit=10'. A full URL works too if its host is allowed.".to_string(),
+                },
+                ToolParam {
+                    name: "body".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Request body, JSON is sent with Content-Type: application/json.".to_string(),
+                },
+            ],
+            parameters_required: vec!["method".to_string(), "path".to_string()],
+        }
+    }
+
+    fn confirm_deny_rules(&self) -> Option<IntegrationConfirmation> {
+        Some(self.integr_common().confirmation)
+    }
+
+    fn has_config_path(&self) -> Option<String> {
+        Some(self.config_path.clone())
+    }
+}
+
+#[async_trait]
+impl Tool for ToolHttpOperation {
+    fn as_any(&self) -> &dyn std::any::Any { self }
+
+    async fn tool_execute(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        tool_call_id: &String,
+        args: &HashMap<String, Value>,
+    ) -> Result<(bool, Vec<ContextEnum>), String> {
+        let request = request_from_operation_args(&self.operation, args, &self.settings_http)?;
+        let gcx = ccx.lock().await.global_context.clone();
+        let output = http_execute(gcx, &request, &self.settings_http).await?;
+        Ok((false, vec![tool_answer(output, tool_call_id)]))
+    }
+
+    fn command_to_match_against_confirm_deny(
+        &self,
+        args: &HashMap<String, Value>,
+    ) -> Result<String, String> {
+        let request = request_from_operation_args(&self.operation, args, &self.settings_http)?;
+        Ok(format!("{} {}", request.method, request.url))
+    }
+
+    fn tool_name(&self) -> String {
+        self.operation.tool_name.clone()
+    }
+
+    fn tool_description(&self) -> ToolDesc {
+        let mut parameters = vec![];
+        let mut parameters_required = vec![];
+        for param in self.operation.params.iter() {
+            parameters.push(ToolParam {
+                name: param.name.clone(),
+                param_type: param.param_type.clone(),
+                description: format!("{} parameter. {}", param.location, param.description).trim().to_string(),
+            });
+            if param.required {
+                parameters_required.push(param.name.clone());
+            }
+        }
+        if self.operation.has_body {
+            parameters.push(ToolParam {
+                name: "body".to_string(),
+                param_type: "string".to_string(),
+                description: "JSON request body.".to_string(),
+            });
+            if self.operation.body_required {
+                parameters_required.push("body".to_string());
+            }
+        }
+        ToolDesc {
+            name: self.operation.tool_name.clone(),
+            agentic: true,
+            experimental: false,
+            description: format!("{} {} {}", self.operation.method, self.operation.path, self.operation.summary).trim().to_string(),
+            parameters,
+            parameters_required,
+        }
+    }
+
+    fn confirm_deny_rules(&self) -> Option<IntegrationConfirmation> {
+        Some(self.common.confirmation.clone())
+    }
+
+    fn has_config_path(&self) -> Option<String> {
+        Some(self.config_path.clone())
+    }
+}
+
+fn tool_answer(content: String, tool_call_id: &String) -> ContextEnum {
+    ContextEnum::ChatMessage(ChatMessage {
+        role: "tool".to_string(),
+        content: ChatContent::SimpleText(content),
+        tool_calls: None,
+        tool_call_id: tool_call_id.clone(),
+        ..Default::default()
+    })
+}
+
+fn host_with_port(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{}:{}", host.to_lowercase(), port),
+        None => host.to_lowercase(),
+    }
+}
+
+fn is_host_allowed(url: &Url, allowed_hosts: &Vec<String>) -> bool {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return false;
+    }
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    let host_port = host_with_port(&host, url.port_or_known_default());
+    allowed_hosts.iter().any(|allowed| *allowed == host || *allowed == host_port)
+}
+
+fn parse_string_arg(args: &HashMap<String, Value>, name: &str, required: bool) -> Result<Option<String>, String> {
+    match args.get(name) {
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(Value::Null) | None if !required => Ok(None),
+        Some(v) if name == "body" => Ok(Some(v.to_string())),
+        Some(v) => Err(format!("argument `{}` is not a string: {:?}", name, v)),
+        None => Err(format!("Missing argument `{}`", name)),
+    }
+}
+
+fn request_from_args(args: &HashMap<String, Value>, settings: &SettingsHttp) -> Result<HttpRequest, String> {
+    let method = parse_string_arg(args, "method", true)?.unwrap_or_default();
+    let path = parse_string_arg(args, "path", true)?.unwrap_or_default();
+    let body = parse_string_arg(args, "body", false)?.filter(|b| !b.is_empty());
+    prepare_http_request(&method, &path, vec![], body, settings)
+}
+
+fn request_from_operation_args(operation: &OpenApiOperation, args: &HashMap<String, Value>, settings: &SettingsHttp) -> Result<HttpRequest, String> {
+    let mut path = operation.path.clone();
+    let mut query = vec![];
+    let mut headers = vec![];
+    for param in operation.params.iter() {
+        let value = match args.get(&param.name) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Null) | None => {
+                if param.required {
+                    return Err(format!("Missing argument `{}`", param.name));
+                }
+                continue;
+            },
+            Some(v) => v.to_string(),
+        };
+        match param.location.as_str() {
+            "path" => {
+                let encoded = percent_encoding::utf8_percent_encode(&value, PATH_PARAM_ENCODE_SET).to_string();
+                path = path.replace(&format!("{{{}}}", param.name), &encoded);
+            },
+            "header" => headers.push((param.name.clone(), value)),
+            _ => query.push((param.name.clone(), value)),
+        }
+    }
+    let body = if operation.has_body {
+        parse_string_arg(args, "body", operation.body_required)?.filter(|b| !b.is_empty())
+    } else {
+        None
+    };
+    let mut request = prepare_http_request(&operation.method, &path, headers, body, settings)?;
+    if !query.is_empty() {
+        request.url.query_pairs_mut().extend_pairs(query.iter());
+    }
+    Ok(request)
+}
+
+fn prepare_http_request(
+    method: &str,
+    path: &str,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    settings: &SettingsHttp,
+) -> Result<HttpRequest, String> {
+    let method = method.trim().to_uppercase();
+    if !HTTP_METHODS.contains(&method.as_str()) {
+        return Err(format!("Method {:?} is not supported, use one of: {}", method, HTTP_METHODS.join(", ")));
+    }
+    let path = path.trim();
+    let url_str = if path.starts_with("http://") || path.starts_with("https://") {
+        path.to_string()
+    } else {
+        if settings.base_url.is_empty() {
+            return Err("base_url is not configured, pass a full URL or set base_url in the integration settings".to_string());
+        }
+        // NOTE: not Url::join, it drops the last segment of the base path if it has no trailing slash
+        format!("{}/{}", settings.base_url.trim_end_matches('/'), path.trim_start_matches('/'))
+    };
+    let url = Url::parse(&url_str).map_err(|e| format!("Cannot parse URL {:?}: {}", url_str, e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("Scheme {:?} is not supported, use http or https", url.scheme()));
+    }
+    let allowed_hosts = settings.allowed_hosts();
+    if !is_host_allowed(&url, &allowed_hosts) {
+        let host_port = host_with_port(url.host_str().unwrap_or_default(), url.port_or_known_default());
+        return Err(format!("Host {:?} is not allowed, allowed hosts: {}", host_port, allowed_hosts.join(", ")));
+    }
+    if body.is_some() && (method == "GET" || method == "HEAD") {
+        return Err(format!("{} requests can't have a body", method));
+    }
+    // headers from the settings usually carry credentials, the model doesn't get to replace them
+    if let Some((k, _)) = headers.iter().find(|(k, _)| settings.headers.keys().any(|sk| sk.eq_ignore_ascii_case(k))) {
+        return Err(format!("Header {:?} is set in the integration settings and can't be overridden", k));
+    }
+    Ok(HttpRequest { method, url, headers, body })
+}
+
+async fn http_execute(gcx: Arc<ARwLock<GlobalContext>>, request: &HttpRequest, settings: &SettingsHttp) -> Result<String, String> {
+    let insecure = gcx.read().await.cmdline.insecure;
+    // not the shared client: a redirect must not take the request (and the headers from the settings) to a host
+    // that is not allowed, so every hop goes through the same check as the original URL
+    let allowed_hosts = settings.allowed_hosts();
+    let redirect_policy = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= 10 {
+            attempt.error("too many redirects")
+        } else if is_host_allowed(attempt.url(), &allowed_hosts) {
+            attempt.follow()
+        } else {
+            let err = format!("redirect to {} is not allowed", attempt.url());
+            attempt.error(err)
+        }
+    });
+    let http_client = reqwest::Client::builder()
+        .redirect(redirect_policy)
+        .danger_accept_invalid_certs(insecure)
+        .build()
+        .map_err(|e| format!("Cannot create HTTP client: {}", e))?;
+    let method = reqwest::Method::from_bytes(request.method.as_bytes()).map_err(|e| e.to_string())?;
+    let timeout = if settings.timeout > 0 { settings.timeout } else { default_timeout() };
+    let mut req = http_client.request(method, request.url.clone())
+        .timeout(std::time::Duration::from_secs(timeout));
+    let mut has_content_type = false;
+    // settings go last, prepare_http_request already rejects model headers with the same name
+    let model_headers = request.headers.iter()
+        .filter(|(k, _)| !settings.headers.keys().any(|sk| sk.eq_ignore_ascii_case(k)))
+        .map(|(k, v)| (k, v));
+    for (k, v) in model_headers.chain(settings.headers.iter()) {
+        has_content_type |= k.eq_ignore_ascii_case("content-type");
+        req = req.header(k.as_str(), v.as_str());
+    }
+    if let Some(body) = &request.body {
+        if !has_content_type && serde_json::from_str::<Value>(body).is_ok() {
+            req = req.header("Content-Type", "application/json");
+        }
+        req = req.body(body.clone());
+    }
+
+    tracing::info!("HTTP: {} {}", request.method, request.url);
+    let t0 = tokio::time::Instant::now();
+    let response = req.send().await.map_err(|e| format!("{} {} failed: {}", request.method, request.url, e))?;
+    let status = response.status();
+    let mut out = format!("HTTP {}, {} {}, {:.3}s\n", status, request.method, request.url, t0.elapsed().as_secs_f64());
+    for name in HTTP_SHOWN_HEADERS {
+        if let Some(value) = response.headers().get(*name).and_then(|v| v.to_str().ok()) {
+            out.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+    let content_type = response.headers().get("content-type").and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+    let bytes = response.bytes().await.map_err(|e| format!("Failed to read the response body: {}", e))?;
+    let body = format_body(&bytes, &content_type);
+    out.push('\n');
+    out.push_str(&output_mini_postprocessing(&settings.output_filter, &body));
+    Ok(out)
+}
+
+fn format_body(bytes: &[u8], content_type: &str) -> String {
+    if bytes.is_empty() {
+        return "(empty body)".to_string();
+    }
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return format!("({} bytes of binary {})", bytes.len(), if content_type.is_empty() { "data" } else { content_type }),
+    };
+    if content_type.contains("json") || text.trim_start().starts_with('{') || text.trim_start().starts_with('[') {
+        if let Ok(value) = serde_json::from_str::<Value>(text) {
+            return serde_json::to_string_pretty(&value).unwrap_or(text.to_string());
+        }
+    }
+    text.to_string()
+}
+
+async fn load_openapi_spec(gcx: Arc<ARwLock<GlobalContext>>, spec_location: &str, timeout: u64) -> Result<Value, String> {
+    let spec_text = if spec_location.starts_with("http://") || spec_location.starts_with("https://") {
+        let http_client = gcx.read().await.http_client.clone();
+        http_client.get(spec_location)
+            .timeout(std::time::Duration::from_secs(timeout.max(1)))
+            .send().await.map_err(|e| e.to_string())?
+            .error_for_status().map_err(|e| e.to_string())?
+            .text().await.map_err(|e| e.to_string())?
+    } else {
+        tokio::fs::read_to_string(canonical_path(spec_location)).await.map_err(|e| e.to_string())?
+    };
+    // yaml is a superset of json, one parser covers both
+    let spec_yaml: serde_yaml::Value = serde_yaml::from_str(&spec_text).map_err(|e| e.to_string())?;
+    serde_json::to_value(spec_yaml).map_err(|e| e.to_string())
+}
+
+fn resolve_openapi_ref<'a>(spec: &'a Value, value: &'a Value) -> &'a Value {
+    match value.get("$ref").and_then(|r| r.as_str()).and_then(|r| r.strip_prefix("#")) {
+        Some(pointer) => spec.pointer(pointer).unwrap_or(value),
+        None => value,
+    }
+}
+
+fn openapi_tool_name(operation_id: &str) -> String {
+    let mut name = String::from("http_");
+    for c in operation_id.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    name.trim_end_matches('_').chars().take(64).collect()
+}
+
+fn parse_openapi_operations(spec: &Value) -> Result<Vec<OpenApiOperation>, String> {
+    let paths = spec.get("paths").and_then(|p| p.as_object()).ok_or("OpenAPI spec has no `paths`".to_string())?;
+    let mut operations = vec![];
+    for (path, path_item) in paths.iter() {
+        let path_item = resolve_openapi_ref(spec, path_item);
+        let common_params = path_item.get("parameters").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+        for method in ["get", "post", "put", "patch", "delete"] {
+            let op = match path_item.get(method) {
+                Some(op) => op,
+                None => continue,
+            };
+            let operation_id = op.get("operationId").and_then(|id| id.as_str()).map(|id| id.to_string())
+                .unwrap_or_else(|| format!("{}_{}", method, path));
+            let mut params: Vec<OpenApiParam> = vec![];
+            let op_params = op.get("parameters").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+            for param in common_params.iter().chain(op_params.iter()) {
+                let param = resolve_openapi_ref(spec, param);
+                let name = param.get("name").and_then(|n| n.as_str()).unwrap_or_default().to_string();
+                let location = param.get("in").and_then(|n| n.as_str()).unwrap_or_default().to_string();
+                if name.is_empty() || !["path", "query", "header"].contains(&location.as_str()) || name == "body" {
+                    continue;
+                }
+                let param_type = param.get("schema").map(|s| resolve_openapi_ref(spec, s))
+                    .and_then(|s| s.get("type")).and_then(|t| t.as_str())
+                    .filter(|t| ["string", "integer", "number", "boolean"].contains(t))
+                    .unwrap_or("string").to_string();
+                // operation level parameters override the path level ones
+                params.retain(|p| !(p.name == name && p.location == location));
+                params.push(OpenApiParam {
+                    required: location == "path" || param.get("required").and_then(|r| r.as_bool()).unwrap_or(false),
+                    description: param.get("description").and_then(|d| d.as_str()).unwrap_or_default().to_string(),
+                    name,
+                    location,
+                    param_type,
+                });
+            }
+            let request_body = op.get("requestBody").map(|b| resolve_openapi_ref(spec, b));
+            let summary = op.get("summary").or(op.get("description")).and_then(|s| s.as_str()).unwrap_or_default();
+            operations.push(OpenApiOperation {
+                tool_name: openapi_tool_name(&operation_id),
+                method: method.to_uppercase(),
+                path: path.clone(),
+                summary: summary.lines().next().unwrap_or_default().to_string(),
+                params,
+                has_body: request_body.is_some(),
+                body_required: request_body.and_then(|b| b.get("required")).and_then(|r| r.as_bool()).unwrap_or(false),
+            });
+        }
+    }
+    Ok(operations)
+}
+
+pub const HTTP_INTEGRATION_SCHEMA: &str = r#"
+fields:
+  base_url:
+    f_type: string_long
+    f_desc: "The API the model talks to, relative paths are resolved against it. Its host is always allowed."
+    f_placeholder: "http://localhost:8080"
+  allowed_hosts:
+    f_type: string_long
+    f_desc: "Comma-separated additional hosts the model can send requests to, as `host` (any port) or `host:port`."
+    f_placeholder: "localhost, api.staging.example.com"
+  headers:
+    f_type: string_to_string_map
+    f_desc: "Headers sent with every request. If you don't want to send your token to the AI model that helps you to configure the agent, put it into secrets.yaml and write `Bearer $MY_SECRET_VARIABLE` as the value."
+    smartlinks:
+      - sl_label: "Open secrets.yaml"
+        sl_goto: "EDITOR:secrets.yaml"
+  timeout:
+    f_type: string_short
+    f_desc: "Timeout in seconds for a single request."
+    f_default: "30"
+  openapi_spec:
+    f_type: string_long
+    f_desc: "Path or URL of an OpenAPI spec (json or yaml), each operation becomes a separate typed tool."
+    f_placeholder: "http://localhost:8080/openapi.json"
+    f_extra: true
+  output_filter:
+    f_type: "output_filter"
+    f_desc: "Responses can be long. This section allows to set limits, prioritize top or bottom, or use regexp to show the model the relevant part."
+    f_extra: true
+description: |
+  The HTTP integration lets the model call your API, for example a service you develop running on localhost.
+  Only the base URL host and the allowed hosts can be reached. GET requests run right away, requests that
+  change something (POST, PUT, PATCH, DELETE) ask for a confirmation. JSON responses are pretty-printed.
+available:
+  on_your_laptop_possible: true
+  when_isolated_possible: true
+confirmation:
+  ask_user_default: ["POST *", "PUT *", "PATCH *", "DELETE *"]
+  deny_default: []
+smartlinks:
+  - sl_label: "Test"
+    sl_chat:
+      - role: "user"
+        content: |
+          🔧 The http tool should be visible now. To test the tool, send a GET request to the base URL or to a health endpoint if you can guess one,
+          briefly describe the response, express satisfaction and relief if it works, and change nothing. If it doesn't work or the tool isn't available, go through the usual plan in the system prompt.
+    sl_enable_only_with_tool: true
+"#;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SettingsHttp {
+        SettingsHttp {
+            base_url: "http://localhost:8080/api".to_string(),
+            allowed_hosts: "staging.example.com, 127.0.0.1:9000".to_string(),
+            timeout: 30,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_prepare_http_request() {
+        let request = prepare_http_request("get", "/users?limit=10", vec![], None, &settings()).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.url.as_str(), "http://localhost:8080/api/users?limit=10");
+        assert!(prepare_http_request("GET", "https://staging.example.com/health", vec![], None, &settings()).is_ok());
+        assert!(prepare_http_request("GET", "http://127.0.0.1:9000/", vec![], None, &settings()).is_ok());
+        assert!(prepare_http_request("GET", "http://127.0.0.1:9001/", vec![], None, &settings()).is_err());
+        assert!(prepare_http_request("GET", "http://localhost:8081/", vec![], None, &settings()).is_err());
+        assert!(prepare_http_request("GET", "https://evil.example.com/", vec![], None, &settings()).is_err());
+        assert!(prepare_http_request("TRACE", "/users", vec![], None, &settings()).is_err());
+        assert!(prepare_http_request("GET", "/users", vec![], Some("{}".to_string()), &settings()).is_err());
+
+        let mut with_auth = settings();
+        with_auth.headers.insert("Authorization".to_string(), "Bearer secret".to_string());
+        let model_auth = vec![("authorization".to_string(), "Bearer other".to_string())];
+        assert!(prepare_http_request("GET", "/users", model_auth, None, &with_auth).is_err());
+        let model_other = vec![("X-Request-Id".to_string(), "1".to_string())];
+        assert!(prepare_http_request("GET", "/users", model_other, None, &with_auth).is_ok());
+
+        // redirects are checked against the same list
+        let allowed_hosts = settings().allowed_hosts();
+        assert!(is_host_allowed(&Url::parse("https://staging.example.com/login").unwrap(), &allowed_hosts));
+        assert!(!is_host_allowed(&Url::parse("https://evil.example.com/login").unwrap(), &allowed_hosts));
+        assert!(!is_host_allowed(&Url::parse("ftp://staging.example.com/").unwrap(), &allowed_hosts));
+    }
+
+    #[test]
+    fn test_openapi_operations() {
+        let spec = serde_json::json!({
+            "openapi": "3.0.0",
+            "paths": {
+                "/users/{id}": {
+                    "parameters": [{"name": "id", "in": "path", "schema": {"type": "integer"}}],
+                    "get": {"operationId": "getUser", "summary": "Get a user", "parameters": [{"$ref": "#/components/parameters/Verbose"}]},
+                    "delete": {"operationId": "deleteUser"}
+                },
+                "/users": {
+                    "post": {"summary": "Create a user", "requestBody": {"required": true}}
+                }
+            },
+            "components": {"parameters": {"Verbose": {"name": "verbose", "in": "query", "schema": {"type": "boolean"}}}}
+        });
+        let operations = parse_openapi_operations(&spec).unwrap();
+        let get_user = operations.iter().find(|op| op.tool_name == "http_getuser").unwrap();
+        assert_eq!(get_user.params.len(), 2);
+        assert_eq!(get_user.params[0].param_type, "integer");
+        assert!(get_user.params[0].required);
+        assert_eq!(get_user.params[1].name, "verbose");
+        let create_user = operations.iter().find(|op| op.method == "POST").unwrap();
+        assert_eq!(create_user.tool_name, "http_post_users");
+        assert!(create_user.has_body && create_user.body_required);
+
+        let args = HashMap::from([("id".to_string(), serde_json::json!(42)), ("verbose".to_string(), serde_json::json!(true))]);
+        let request = request_from_operation_args(get_user, &args, &settings()).unwrap();
+        assert_eq!(request.url.as_str(), "http://localhost:8080/api/users/42?verbose=true");
+    }
+
+    #[test]
+    fn test_format_body() {
+        assert_eq!(format_body(br#"{"a":1,"b":[true]}"#, "application/json"), "{\n  \"a\": 1,\n  \"b\": [\n    true\n  ]\n}");
+        assert_eq!(format_body(b"plain text", "text/plain"), "plain text");
+        assert_eq!(format_body(b"", "text/plain"), "(empty body)");
+        assert_eq!(format_body(&[0xff, 0xfe, 0x00], "image/png"), "(3 bytes of binary image/png)");
+    }
+}
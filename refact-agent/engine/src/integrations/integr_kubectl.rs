@@ -0,0 +1,471 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::process::Stdio;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::process::Command;
+use tokio::sync::{Mutex as AMutex, RwLock as ARwLock};
+use async_trait::async_trait;
+
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ChatContent, ChatMessage, ContextEnum};
+use crate::global_context::GlobalContext;
+use crate::integrations::integr_abstract::{IntegrationCommon, IntegrationConfirmation, IntegrationTrait};
+use crate::integrations::utils::{serialize_num_to_str, deserialize_str_to_num};
+use crate::postprocessing::pp_command_output::{CmdlineOutputFilter, output_mini_postprocessing};
+use crate::tools::tools_description::{Tool, ToolDesc, ToolParam, MatchConfirmDeny, MatchConfirmDenyResult};
+use crate::tools::tools_execute::{command_should_be_confirmed_by_user, command_should_be_denied};
+
+
+const KUBECTL_READ_SUBCOMMANDS: &[&str] = &["get", "describe", "logs", "events", "top", "explain", "api-resources"];
+const KUBECTL_MUTATING_SUBCOMMANDS: &[&str] = &["apply", "delete", "rollout", "scale"];
+const KUBECTL_ROLLOUT_READ: &[&str] = &["status", "history"];
+const KUBECTL_ROLLOUT_MUTATING: &[&str] = &["restart", "undo"];
+// these flags would escape the configured cluster, namespace or identity
+const KUBECTL_FORBIDDEN_FLAGS: &[&str] = &[
+    "--context", "--kubeconfig", "--cluster", "--user", "--token", "--server", "-s", "--as", "--as-group", "--as-uid",
+    "--certificate-authority", "--client-certificate", "--client-key", "--insecure-skip-tls-verify",
+];
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SettingsKubectl {
+    pub kubectl_binary_path: String,
+    #[serde(default)]
+    pub kubeconfig: String,
+    pub context: String,
+    pub namespaces: String,
+    #[serde(default = "default_timeout", serialize_with = "serialize_num_to_str", deserialize_with = "deserialize_str_to_num")]
+    pub timeout: u64,
+    #[serde(default = "default_logs_tail")]
+    pub logs_tail: String,
+    #[serde(default)]
+    pub output_filter: CmdlineOutputFilter,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_logs_tail() -> String {
+    "200".to_string()
+}
+
+impl SettingsKubectl {
+    fn allowed_namespaces(&self) -> Vec<String> {
+        self.namespaces.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+    }
+}
+
+#[derive(Default)]
+pub struct ToolKubectl {
+    pub common: IntegrationCommon,
+    pub settings_kubectl: SettingsKubectl,
+    pub config_path: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct KubectlCommand {
+    args: Vec<String>,
+    is_mutating: bool,
+    is_logs: bool,
+}
+
+#[async_trait]
+impl IntegrationTrait for ToolKubectl {
+    fn as_any(&self) -> &dyn std::any::Any { self }
+
+    async fn integr_settings_apply(&mut self, _gcx: Arc<ARwLock<GlobalContext>>, config_path: String, value: &serde_json::Value) -> Result<(), serde_json::Error> {
+        self.settings_kubectl = serde_json::from_value(value.clone())?;
+        self.common = serde_json::from_value(value.clone())?;
+        self.config_path = config_path;
+        Ok(())
+    }
+
+    fn integr_settings_as_json(&self) -> Value {
+        serde_json::to_value(&self.settings_kubectl).unwrap()
+    }
+
+    fn integr_common(&self) -> IntegrationCommon {
+        self.common.clone()
+    }
+
+    async fn integr_tools(&self, _integr_name: &str) -> Vec<Box<dyn crate::tools::tools_description::Tool + Send>> {
+        vec![Box::new(ToolKubectl {
+            common: self.common.clone(),
+            settings_kubectl: self.settings_kubectl.clone(),
+            config_path: self.config_path.clone(),
+        })]
+    }
+
+    fn integr_schema(&self) -> &str
+    {
+        KUBECTL_INTEGRATION_SCHEMA
+    }
+}
+
+#[async_trait]
+impl Tool for ToolKubectl {
+    fn as_any(&self) -> &dyn std::any::Any { self }
+
+    async fn tool_execute(
+        &mut self,
+        _ccx: Arc<AMutex<AtCommandsContext>>,
+        tool_call_id: &String,
+        args: &HashMap<String, Value>,
+    ) -> Result<(bool, Vec<ContextEnum>), String> {
+        let command = parse_command(args)?;
+        let kubectl_command = prepare_kubectl_command(&command, &self.settings_kubectl)?;
+        let output = self.kubectl_execute(&kubectl_command).await?;
+
+        Ok((false, vec![
+            ContextEnum::ChatMessage(ChatMessage {
+                role: "tool".to_string(),
+                content: ChatContent::SimpleText(output),
+                tool_calls: None,
+                tool_call_id: tool_call_id.clone(),
+                ..Default::default()
+            }),
+        ]))
+    }
+
+    async fn match_against_confirm_deny(
+        &self,
+        _ccx: Arc<AMutex<AtCommandsContext>>,
+        args: &HashMap<String, Value>
+    ) -> Result<MatchConfirmDeny, String> {
+        let command_to_match = self.command_to_match_against_confirm_deny(&args).map_err(|e| {
+            format!("Error getting tool command to match: {}", e)
+        })?;
+        let kubectl_command = prepare_kubectl_command(&parse_command(args)?, &self.settings_kubectl)?;
+        if let Some(rules) = &self.confirm_deny_rules() {
+            let (is_denied, deny_rule) = command_should_be_denied(&command_to_match, &rules.deny);
+            if is_denied {
+                return Ok(MatchConfirmDeny {
+                    result: MatchConfirmDenyResult::DENY,
+                    command: command_to_match.clone(),
+                    rule: deny_rule.clone(),
+                });
+            }
+            let (needs_confirmation, confirmation_rule) = command_should_be_confirmed_by_user(&command_to_match, &rules.ask_user);
+            if needs_confirmation {
+                return Ok(MatchConfirmDeny {
+                    result: MatchConfirmDenyResult::CONFIRMATION,
+                    command: command_to_match.clone(),
+                    rule: confirmation_rule.clone(),
+                });
+            }
+        }
+        // NOTE: changes to a cluster always need a human, whatever the config says
+        if kubectl_command.is_mutating {
+            return Ok(MatchConfirmDeny {
+                result: MatchConfirmDenyResult::CONFIRMATION,
+                command: command_to_match.clone(),
+                rule: "kubectl mutating command".to_string(),
+            });
+        }
+        Ok(MatchConfirmDeny {
+            result: MatchConfirmDenyResult::PASS,
+            command: command_to_match.clone(),
+            rule: "".to_string(),
+        })
+    }
+
+    fn command_to_match_against_confirm_deny(
+        &self,
+        args: &HashMap<String, Value>,
+    ) -> Result<String, String> {
+        let command = parse_command(args)?;
+        let kubectl_command = prepare_kubectl_command(&command, &self.settings_kubectl)?;
+        Ok(format!("kubectl {}", kubectl_command.args.join(" ")))
+    }
+
+    fn tool_description(&self) -> ToolDesc {
+        let namespaces = self.settings_kubectl.allowed_namespaces();
+        ToolDesc {
+            name: "kubectl".to_string(),
+            agentic: true,
+            experimental: false,
+            description: format!(
+                "Access to kubectl for the cluster context {:?}, namespaces allowed: {}. Read commands: get, describe, logs, events, top. Commands that change the cluster (apply, delete, rollout restart, scale) need the user's confirmation.",
+                self.settings_kubectl.context, namespaces.join(", ")
+            ),
+            parameters: vec![
+                ToolParam {
+                    name: "command".to_string(),
+                    param_type: "string".to_string(),
+                    description: "Examples: 'get pods -n staging', 'describe deployment api', 'logs deploy/api --since=10m', 'events --for pod/api-123', 'rollout restart deployment/api', 'apply -f k8s/api.yaml'. Without -n the first allowed namespace is used.".to_string(),
+                },
+            ],
+            parameters_required: vec!["command".to_string()],
+        }
+    }
+
+    fn confirm_deny_rules(&self) -> Option<IntegrationConfirmation> {
+        Some(self.integr_common().confirmation)
+    }
+
+    fn has_config_path(&self) -> Option<String> {
+        Some(self.config_path.clone())
+    }
+}
+
+impl ToolKubectl {
+    async fn kubectl_execute(&self, kubectl_command: &KubectlCommand) -> Result<String, String> {
+        let mut kubectl_binary = self.settings_kubectl.kubectl_binary_path.clone();
+        if kubectl_binary.is_empty() {
+            kubectl_binary = "kubectl".to_string();
+        }
+        let mut cmd = Command::new(&kubectl_binary);
+        if !self.settings_kubectl.kubeconfig.is_empty() {
+            cmd.env("KUBECONFIG", &self.settings_kubectl.kubeconfig);
+        }
+        cmd.args(&kubectl_command.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let timeout = if self.settings_kubectl.timeout > 0 { self.settings_kubectl.timeout } else { default_timeout() };
+        let t0 = tokio::time::Instant::now();
+        tracing::info!("KUBECTL: {:?}", kubectl_command.args);
+        let output = tokio::time::timeout(tokio::time::Duration::from_secs(timeout), cmd.output())
+            .await
+            .map_err(|_| format!("Command timed out after {} seconds", timeout))?
+            .map_err(|e| format!("Failed to execute {}: {}", kubectl_binary, e))?;
+        let duration = t0.elapsed();
+
+        let mut filter = self.settings_kubectl.output_filter.clone();
+        if kubectl_command.is_logs {
+            filter.valuable_top_or_bottom = "bottom".to_string();
+        }
+        let stdout = output_mini_postprocessing(&filter, &String::from_utf8_lossy(&output.stdout));
+        let stderr = output_mini_postprocessing(&filter, &String::from_utf8_lossy(&output.stderr));
+
+        let mut out = crate::integrations::integr_cmdline::format_output(&stdout, &stderr);
+        let exit_code = output.status.code().unwrap_or_default();
+        out.push_str(&format!("The command was running {:.3}s, finished with exit code {exit_code}\n", duration.as_secs_f64()));
+        Ok(out)
+    }
+}
+
+fn parse_command(args: &HashMap<String, Value>) -> Result<String, String> {
+    match args.get("command") {
+        Some(Value::String(s)) => Ok(s.to_string()),
+        Some(v) => Err(format!("argument `command` is not a string: {:?}", v)),
+        None => Err("Missing argument `command`".to_string()),
+    }
+}
+
+fn prepare_kubectl_command(command: &str, settings: &SettingsKubectl) -> Result<KubectlCommand, String> {
+    let mut args = shell_words::split(command).map_err(|e| e.to_string())?;
+    if args.first().map(|s| s.as_str()) == Some("kubectl") {
+        args.remove(0);
+    }
+    if args.is_empty() {
+        return Err("Parsed command is empty".to_string());
+    }
+    if settings.context.is_empty() {
+        return Err("kubectl context is not configured, set it in the integration settings".to_string());
+    }
+
+    for arg in args.iter() {
+        let flag = arg.split('=').next().unwrap_or("");
+        // short flags take the value attached too, -shttps://host is -s https://host
+        let short_flag_mb = KUBECTL_FORBIDDEN_FLAGS.iter()
+            .find(|f| !f.starts_with("--") && arg.starts_with(*f) && !arg.starts_with("--"));
+        if KUBECTL_FORBIDDEN_FLAGS.contains(&flag) || short_flag_mb.is_some() {
+            return Err(format!("Flag {} is not allowed, the context and credentials come from the integration settings", short_flag_mb.copied().unwrap_or(flag)));
+        }
+    }
+
+    let mut positional = args.iter().filter(|arg| !arg.starts_with('-'));
+    let subcommand = positional.next().cloned().unwrap_or_default();
+    let is_mutating = if KUBECTL_READ_SUBCOMMANDS.contains(&subcommand.as_str()) {
+        false
+    } else if subcommand == "rollout" {
+        let action = positional.next().cloned().unwrap_or_default();
+        if KUBECTL_ROLLOUT_READ.contains(&action.as_str()) {
+            false
+        } else if KUBECTL_ROLLOUT_MUTATING.contains(&action.as_str()) {
+            true
+        } else {
+            return Err(format!("`rollout {}` is not supported, use one of: {}", action, [KUBECTL_ROLLOUT_READ, KUBECTL_ROLLOUT_MUTATING].concat().join(", ")));
+        }
+    } else if KUBECTL_MUTATING_SUBCOMMANDS.contains(&subcommand.as_str()) {
+        true
+    } else {
+        return Err(format!("Subcommand {:?} is not supported, use one of: {}", subcommand, [KUBECTL_READ_SUBCOMMANDS, KUBECTL_MUTATING_SUBCOMMANDS].concat().join(", ")));
+    };
+    let is_logs = subcommand == "logs";
+
+    if is_logs {
+        if args.iter().any(|arg| arg == "-f" || arg == "--follow") {
+            return Err("`logs --follow` never returns, use --since or --tail instead".to_string());
+        }
+        if !args.iter().any(|arg| arg.starts_with("--tail") || arg.starts_with("--since")) && !settings.logs_tail.is_empty() {
+            args.push(format!("--tail={}", settings.logs_tail));
+        }
+    }
+    if subcommand == "get" && args.iter().any(|arg| arg == "-w" || arg.starts_with("--watch")) {
+        return Err("`get --watch` never returns, run get again later instead".to_string());
+    }
+
+    let allowed_namespaces = settings.allowed_namespaces();
+    let all_namespaces_allowed = allowed_namespaces.iter().any(|ns| ns == "*");
+    let mut all_namespaces = false;
+    for arg in args.iter() {
+        all_namespaces |= is_all_namespaces_flag(arg)?;
+    }
+    if all_namespaces && !all_namespaces_allowed {
+        return Err(format!("--all-namespaces is not allowed, namespaces allowed: {}", allowed_namespaces.join(", ")));
+    }
+    let mut namespaces_in_command = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "-n" || arg == "--namespace" {
+            let ns = args.get(i + 1).ok_or(format!("{} requires a value", arg))?;
+            namespaces_in_command.push(ns.clone());
+        } else if let Some(ns) = arg.strip_prefix("--namespace=").or(arg.strip_prefix("-n=")) {
+            namespaces_in_command.push(ns.to_string());
+        } else if let Some(ns) = arg.strip_prefix("-n").filter(|ns| !ns.is_empty() && !arg.starts_with("--")) {
+            // attached short form, -nkube-system
+            namespaces_in_command.push(ns.to_string());
+        }
+    }
+    for ns in namespaces_in_command.iter() {
+        if !all_namespaces_allowed && !allowed_namespaces.contains(ns) {
+            return Err(format!("Namespace {:?} is not allowed, namespaces allowed: {}", ns, allowed_namespaces.join(", ")));
+        }
+    }
+    let namespace_scoped = !["api-resources", "explain"].contains(&subcommand.as_str());
+    if namespaces_in_command.is_empty() && namespace_scoped && !all_namespaces {
+        let default_ns = allowed_namespaces.iter().find(|ns| *ns != "*")
+            .ok_or("No namespace given and no default namespace configured, pass -n NAMESPACE".to_string())?;
+        args.push(format!("--namespace={}", default_ns));
+    }
+
+    args.push(format!("--context={}", settings.context));
+    Ok(KubectlCommand { args, is_mutating, is_logs })
+}
+
+// kubectl takes --all-namespaces=VALUE with anything Go's strconv.ParseBool accepts
+fn is_all_namespaces_flag(arg: &str) -> Result<bool, String> {
+    if arg == "-A" || arg == "--all-namespaces" {
+        return Ok(true);
+    }
+    match arg.strip_prefix("--all-namespaces=") {
+        Some("1" | "t" | "T" | "true" | "TRUE" | "True") => Ok(true),
+        Some("0" | "f" | "F" | "false" | "FALSE" | "False") => Ok(false),
+        Some(value) => Err(format!("--all-namespaces={} is not a boolean", value)),
+        None => Ok(false),
+    }
+}
+
+pub const KUBECTL_INTEGRATION_SCHEMA: &str = r#"
+fields:
+  kubectl_binary_path:
+    f_type: string_long
+    f_desc: "Path to the kubectl binary, leave empty to use kubectl from PATH."
+    f_placeholder: "/usr/local/bin/kubectl"
+  kubeconfig:
+    f_type: string_long
+    f_desc: "Path to the kubeconfig file, leave empty to use the default ~/.kube/config or $KUBECONFIG."
+    f_placeholder: "~/.kube/config"
+    f_extra: true
+  context:
+    f_type: string_short
+    f_desc: "The kubeconfig context to use, the model can't switch to another one."
+    f_placeholder: "staging"
+  namespaces:
+    f_type: string_long
+    f_desc: "Comma-separated namespaces the model is allowed to access, the first one is the default. Use * to allow all namespaces."
+    f_placeholder: "staging, staging-workers"
+  timeout:
+    f_type: string_short
+    f_desc: "Timeout in seconds for a single kubectl call."
+    f_default: "30"
+  logs_tail:
+    f_type: string_short
+    f_desc: "How many last lines of logs to fetch when the model doesn't specify --tail or --since."
+    f_default: "200"
+    f_extra: true
+  output_filter:
+    f_type: "output_filter"
+    f_desc: "The output from kubectl can be long. This section allows to set limits, prioritize top or bottom, or use regexp to show the model the relevant part. Logs always prioritize the bottom."
+    f_extra: true
+description: |
+  The kubectl integration gives the model access to one Kubernetes context and a list of allowed namespaces.
+  Reading (get, describe, logs, events, top) works without asking, anything that changes the cluster
+  (apply, delete, rollout restart, scale) always asks for a confirmation.
+available:
+  on_your_laptop_possible: true
+  when_isolated_possible: true
+confirmation:
+  ask_user_default: ["kubectl get secret*", "kubectl describe secret*"]
+  deny_default: ["kubectl delete namespace*", "kubectl delete ns *"]
+smartlinks:
+  - sl_label: "Test"
+    sl_chat:
+      - role: "user"
+        content: |
+          🔧 The kubectl tool should be visible now. To test the tool, list the pods in the default namespace and briefly describe what's running,
+          express satisfaction and relief if it works, and change nothing. If it doesn't work or the tool isn't available, go through the usual plan in the system prompt.
+    sl_enable_only_with_tool: true
+"#;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SettingsKubectl {
+        SettingsKubectl {
+            context: "kind-test".to_string(),
+            namespaces: "staging, workers".to_string(),
+            logs_tail: "100".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_command_gets_default_namespace_and_context() {
+        let cmd = prepare_kubectl_command("kubectl get pods", &settings()).unwrap();
+        assert_eq!(cmd.args, vec!["get", "pods", "--namespace=staging", "--context=kind-test"]);
+        assert!(!cmd.is_mutating);
+        let cmd = prepare_kubectl_command("logs deploy/api -n workers", &settings()).unwrap();
+        assert_eq!(cmd.args, vec!["logs", "deploy/api", "-n", "workers", "--tail=100", "--context=kind-test"]);
+        assert!(cmd.is_logs);
+    }
+
+    #[test]
+    fn test_mutating_commands() {
+        assert!(prepare_kubectl_command("rollout restart deployment/api", &settings()).unwrap().is_mutating);
+        assert!(prepare_kubectl_command("delete pod api-123", &settings()).unwrap().is_mutating);
+        assert!(prepare_kubectl_command("apply -f k8s/api.yaml", &settings()).unwrap().is_mutating);
+        assert!(!prepare_kubectl_command("rollout status deployment/api", &settings()).unwrap().is_mutating);
+        assert!(prepare_kubectl_command("rollout pause deployment/api", &settings()).is_err());
+        assert!(prepare_kubectl_command("exec -it api-123 -- sh", &settings()).is_err());
+    }
+
+    #[test]
+    fn test_namespace_and_context_safety() {
+        assert!(prepare_kubectl_command("get pods -n kube-system", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods --namespace=kube-system", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods -A", &settings()).is_err());
+        for flag in ["--all-namespaces", "--all-namespaces=True", "--all-namespaces=1", "--all-namespaces=TRUE", "--all-namespaces=yes"] {
+            assert!(prepare_kubectl_command(&format!("get pods {}", flag), &settings()).is_err(), "{}", flag);
+        }
+        assert!(prepare_kubectl_command("get pods -nkube-system", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods --insecure-skip-tls-verify", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods --client-key=/tmp/key.pem", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods --certificate-authority /tmp/ca.pem", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods --context=prod", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods -shttps://evil.example.com", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods -s=https://evil.example.com", &settings()).is_err());
+        assert!(prepare_kubectl_command("get pods --as-uid=0", &settings()).is_err());
+        assert!(prepare_kubectl_command("logs api-123 --since=1h", &settings()).is_ok());
+        assert!(prepare_kubectl_command("logs -f api-123", &settings()).is_err());
+        let mut all = settings();
+        all.namespaces = "*".to_string();
+        assert!(prepare_kubectl_command("get pods -A", &all).is_ok());
+        assert!(prepare_kubectl_command("get pods", &all).is_err());
+    }
+}
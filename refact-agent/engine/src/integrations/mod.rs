@@ -12,6 +12,7 @@ pub mod integr_abstract;
 pub mod integr_github;
 pub mod integr_gitlab;
 pub mod integr_pdb;
+pub mod integr_gdb;
 pub mod integr_chrome;
 pub mod integr_postgres;
 pub mod integr_mysql;
@@ -19,6 +20,8 @@ pub mod integr_cmdline;
 pub mod integr_cmdline_service;
 pub mod integr_shell;
 pub mod integr_mcp;
+pub mod integr_kubectl;
+pub mod integr_http;
 
 pub mod process_io_utils;
 pub mod docker;
@@ -39,11 +42,14 @@ pub fn integration_from_name(n: &str) -> Result<Box<dyn IntegrationTrait + Send
         "github" => Ok(Box::new(integr_github::ToolGithub { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "gitlab" => Ok(Box::new(integr_gitlab::ToolGitlab { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "pdb" => Ok(Box::new(integr_pdb::ToolPdb { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
+        "gdb" => Ok(Box::new(integr_gdb::ToolGdb { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "chrome" => Ok(Box::new(integr_chrome::ToolChrome { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "postgres" => Ok(Box::new(integr_postgres::ToolPostgres { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "mysql" => Ok(Box::new(integr_mysql::ToolMysql { ..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "docker" => Ok(Box::new(docker::integr_docker::ToolDocker {..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         "shell" => Ok(Box::new(integr_shell::ToolShell {..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
+        "kubectl" => Ok(Box::new(integr_kubectl::ToolKubectl {..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
+        "http" => Ok(Box::new(integr_http::ToolHttp {..Default::default() }) as Box<dyn IntegrationTrait + Send + Sync>),
         cmdline if cmdline.starts_with("cmdline_") => {
             // let tool_name = cmdline.strip_prefix("cmdline_").unwrap();
             Ok(Box::new(integr_cmdline::ToolCmdline {..Default::default()}) as Box<dyn IntegrationTrait + Send + Sync>)
@@ -64,6 +70,7 @@ pub fn integrations_list(allow_experimental: bool) -> Vec<&'static str> {
         "github",
         "gitlab",
         "pdb",
+        "gdb",
         "chrome",
         "postgres",
         "mysql",
@@ -72,6 +79,8 @@ pub fn integrations_list(allow_experimental: bool) -> Vec<&'static str> {
         "mcp_TEMPLATE",
         "docker",
         "shell",
+        "kubectl",
+        "http",
     ];
     if allow_experimental {
         integrations.extend(vec![
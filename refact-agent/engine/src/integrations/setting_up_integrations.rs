@@ -258,10 +258,14 @@ pub fn read_integrations_d(
         if let serde_json::Value::Object(map) = &mut rec.config_unparsed {
             for (_key, value) in map.iter_mut() {
                 if let Some(str_value) = value.as_str() {
-                    let replaced_value = vars_for_replacements.iter().fold(str_value.to_string(), |acc, (var, replacement)| {
-                        acc.replace(&format!("${}", var), replacement)
-                    });
-                    *value = serde_json::Value::String(replaced_value);
+                    *value = serde_json::Value::String(replace_vars(str_value, &vars_for_replacements));
+                } else if let serde_json::Value::Object(nested_map) = value {
+                    // string_to_string_map fields, like headers or env, can hold secrets too
+                    for (_nested_key, nested_value) in nested_map.iter_mut() {
+                        if let Some(str_value) = nested_value.as_str() {
+                            *nested_value = serde_json::Value::String(replace_vars(str_value, &vars_for_replacements));
+                        }
+                    }
                 }
             }
         }
@@ -308,6 +312,12 @@ pub fn read_integrations_d(
     result
 }
 
+fn replace_vars(s: &str, vars_for_replacements: &HashMap<String, String>) -> String {
+    vars_for_replacements.iter().fold(s.to_string(), |acc, (var, replacement)| {
+        acc.replace(&format!("${}", var), replacement)
+    })
+}
+
 pub async fn get_vars_for_replacements(
     gcx: Arc<ARwLock<GlobalContext>>,
     error_log: &mut Vec<YamlError>,
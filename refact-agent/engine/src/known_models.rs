@@ -169,8 +169,12 @@ pub const KNOWN_MODELS: &str = r####"
                 "REPLACE_PASSTHROUGH": {
                     "context_format": "chat",
                     "rag_ratio": 0.5
+                },
+                "NEXT_EDIT": {
+                    "context_format": "chat"
                 }
             },
+            "default_scratchpad": "REPLACE_PASSTHROUGH",
             "similar_models": [
                 "gpt-4o-2024-05-13",
                 "gpt-4o-2024-08-06",
@@ -185,8 +189,12 @@ pub const KNOWN_MODELS: &str = r####"
                 "REPLACE_PASSTHROUGH": {
                     "context_format": "chat",
                     "rag_ratio": 0.5
+                },
+                "NEXT_EDIT": {
+                    "context_format": "chat"
                 }
             },
+            "default_scratchpad": "REPLACE_PASSTHROUGH",
             "similar_models": [
                 "claude-3-haiku",
                 "claude-3-5-haiku",
@@ -333,9 +341,19 @@ pub const KNOWN_MODELS: &str = r####"
                     "extra_stop_tokens": ["<|repo_name|>", "<|file_sep|>", "<|fim_pad|>"],
                     "context_format": "qwen2.5",
                     "rag_ratio": 0.5
+                },
+                "FIM-REPO": {
+                    "fim_prefix": "<|fim_prefix|>",
+                    "fim_suffix": "<|fim_suffix|>",
+                    "fim_middle": "<|fim_middle|>",
+                    "eot": "<|endoftext|>",
+                    "extra_stop_tokens": ["<|fim_pad|>"],
+                    "rag_ratio": 0.5
                 }
             },
             "default_scratchpad": "FIM-PSM",
+            "repo_name_token": "<|repo_name|>",
+            "file_sep_token": "<|file_sep|>",
             "similar_models": [
                 "qwen2.5/coder/1.5b/base",
                 "qwen2.5/coder/3b/base",
@@ -18,9 +18,11 @@ use crate::files_in_workspace;
 use crate::files_in_workspace::{on_did_change, on_did_delete};
 use crate::global_context::{CommandLine, GlobalContext};
 use crate::http::routers::v1::code_completion::handle_v1_code_completion;
+use crate::scratchpads::code_completion_comment_to_code::instruction_comment_above_cursor;
 use crate::telemetry::snippets_collection;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const COMMENT_TO_CODE_COMMAND: &str = "refact.commentToCode";
 
 
 #[derive(Debug, Deserialize)]
@@ -119,12 +121,16 @@ pub struct SuccessRes {
 }
 
 impl LspBackend {
+    async fn document_text(&self, uri: &Url) -> Result<String> {
+        let path = crate::files_correction::canonical_path(&uri.to_file_path().unwrap_or_default().display().to_string());
+        match self.gcx.read().await.documents_state.memory_document_map.get(&path) {
+            Some(doc) => Ok(doc.read().await.clone().get_text_or_read_from_disk(self.gcx.clone()).await.unwrap_or_default()),
+            None => Err(internal_error("document not found"))
+        }
+    }
+
     async fn flat_params_to_code_completion_post(&self, params: &CompletionParams1) -> Result<CodeCompletionPost> {
-        let path = crate::files_correction::canonical_path(&params.text_document_position.text_document.uri.to_file_path().unwrap_or_default().display().to_string());
-        let txt = match self.gcx.read().await.documents_state.memory_document_map.get(&path) {
-            Some(doc) => doc.read().await.clone().get_text_or_read_from_disk(self.gcx.clone()).await.unwrap_or_default(),
-            None => return Err(internal_error("document not found"))
-        };
+        let txt = self.document_text(&params.text_document_position.text_document.uri).await?;
         // url -> String method should be the same as in telemetry::snippets_collection::sources_changed
         let path_string = params.text_document_position.text_document.uri.to_file_path().unwrap_or_default().to_string_lossy().to_string();
         Ok(CodeCompletionPost {
@@ -143,7 +149,8 @@ impl LspBackend {
                 top_p: None,
                 stop: vec![],
                 n: None,
-                reasoning_effort: None
+                reasoning_effort: None,
+                response_format: None,
             },
             model: "".to_string(),
             scratchpad: "".to_string(),
@@ -152,13 +159,19 @@ impl LspBackend {
             use_ast: false,
             use_vecdb: false,
             rag_tokens_n: 0,
+            client: "lsp".to_string(),
+            comment_to_code: false,
+            ab_arm: "".to_string(),
         })
     }
 
     pub async fn get_completions(&self, params: CompletionParams1) -> Result<CompletionRes> {
         let mut post = self.flat_params_to_code_completion_post(&params).await?;
+        self.completion_res_from_post(&mut post).await
+    }
 
-        let res = handle_v1_code_completion(self.gcx.clone(), &mut post)
+    async fn completion_res_from_post(&self, post: &mut CodeCompletionPost) -> Result<CompletionRes> {
+        let res = handle_v1_code_completion(self.gcx.clone(), post)
             .await.map_err(|e| internal_error(e))?;
 
         let body_bytes = hyper::body::to_bytes(res.into_body()).await.map_err(|e| internal_error(e))?;
@@ -169,6 +182,28 @@ impl LspBackend {
         Ok(value)
     }
 
+    // Runs from the code action, the implementation goes into the document as a workspace edit
+    async fn comment_to_code(&self, position: TextDocumentPositionParams) -> Result<Option<serde_json::Value>> {
+        let params = CompletionParams1 {
+            text_document_position: position.clone(),
+            parameters: RequestParams { max_new_tokens: 0, temperature: 0.2 },
+            multiline: true,
+        };
+        let mut post = self.flat_params_to_code_completion_post(&params).await?;
+        post.comment_to_code = true;
+        let res = self.completion_res_from_post(&mut post).await?;
+        let code = res.choices.get(0).map(|x| x.code_completion.clone()).unwrap_or_default();
+        if code.is_empty() {
+            return Err(internal_error("comment-to-code returned no code"));
+        }
+        let edit = WorkspaceEdit::new(HashMap::from([(
+            position.text_document.uri.clone(),
+            vec![TextEdit::new(Range::new(position.position, position.position), code)],
+        )]));
+        self.client.apply_edit(edit).await?;
+        Ok(Some(serde_json::json!({"snippet_telemetry_id": res.snippet_telemetry_id})))
+    }
+
     pub async fn accept_snippet(&self, params: SnippetAcceptedParams) -> Result<SuccessRes> {
         let success = snippets_collection::snippet_accepted(self.gcx.clone(), params.snippet_telemetry_id).await;
         Ok(SuccessRes { success })
@@ -259,6 +294,11 @@ impl LanguageServer for LspBackend {
                     TextDocumentSyncKind::FULL,
                 )),
                 completion_provider: Some(completion_options),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![COMMENT_TO_CODE_COMMAND.to_string()],
+                    work_done_progress_options: WorkDoneProgressOptions { work_done_progress: Some(false) },
+                }),
                 workspace: Some(WorkspaceServerCapabilities {
                     workspace_folders: Some(WorkspaceFoldersServerCapabilities {
                         supported: Some(true),
@@ -337,6 +377,44 @@ impl LanguageServer for LspBackend {
         Ok(Some(CompletionResponse::Array(vec![])))
     }
 
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
+        // clients ask for code actions on any document, not only the ones they opened with us
+        let txt = match self.document_text(&uri).await {
+            Ok(txt) => txt,
+            Err(_) => return Ok(None),
+        };
+        let cursor = CursorPosition {
+            file: uri.to_file_path().unwrap_or_default().to_string_lossy().to_string(),
+            line: params.range.start.line as i32,
+            character: params.range.start.character as i32,
+        };
+        if instruction_comment_above_cursor(&txt, &PathBuf::from(&cursor.file), &cursor).is_none() {
+            return Ok(None);
+        }
+        let position = TextDocumentPositionParams { text_document: TextDocumentIdentifier { uri }, position: params.range.start };
+        Ok(Some(vec![CodeActionOrCommand::CodeAction(CodeAction {
+            title: "Refact: implement the comment".to_string(),
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            command: Some(Command::new(
+                "Refact: implement the comment".to_string(),
+                COMMENT_TO_CODE_COMMAND.to_string(),
+                Some(vec![serde_json::to_value(position).map_err(|e| internal_error(e))?]),
+            )),
+            ..Default::default()
+        })]))
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<serde_json::Value>> {
+        if params.command != COMMENT_TO_CODE_COMMAND {
+            return Err(Error::method_not_found());
+        }
+        let position = params.arguments.get(0).cloned()
+            .ok_or_else(|| internal_error("comment-to-code needs the document position argument"))
+            .and_then(|x| serde_json::from_value::<TextDocumentPositionParams>(x).map_err(|e| internal_error(e)))?;
+        self.comment_to_code(position).await
+    }
+
     async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
         for folder in params.event.added {
             info!("did_change_workspace_folders/add {}", folder.name);
@@ -31,6 +31,7 @@ mod yaml_configs;
 
 mod file_filter;
 mod files_in_workspace;
+mod edit_history;
 mod files_in_jsonl;
 mod files_blocklist;
 mod fuzzy_search;
@@ -47,6 +48,7 @@ mod at_commands;
 mod tools;
 mod postprocessing;
 mod completion_cache;
+mod completion_coalescing;
 mod cached_tokenizers;
 mod known_models;
 mod scratchpad_abstract;
@@ -56,7 +58,10 @@ mod scratchpads;
 mod fetch_embedding;
 mod forward_to_hf_endpoint;
 mod forward_to_openai_endpoint;
+mod forward_to_anthropic_endpoint;
+mod cassette;
 mod restream;
+mod structured_output;
 
 mod call_validation;
 mod agent_db;
@@ -121,6 +126,11 @@ async fn main() {
         tracing::error!("Panic occurred: {:?}\n{:?}", panic_info, backtrace);
     }));
 
+    if let Err(e) = cassette::cassette_init(&cmdline.cassette_record, &cmdline.cassette_replay) {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+
     match global_context::migrate_to_config_folder(&config_dir, &cache_dir).await {
         Ok(_) => {}
         Err(err) => {
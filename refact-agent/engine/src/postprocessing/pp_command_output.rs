@@ -3,7 +3,7 @@ use serde::Deserialize;
 use regex::Regex;
 
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct CmdlineOutputFilter {
     #[serde(default = "default_limit_lines")]
     pub limit_lines: usize,
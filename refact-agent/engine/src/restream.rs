@@ -5,6 +5,7 @@ use tokio::sync::mpsc;
 use async_stream::stream;
 use futures::StreamExt;
 use hyper::{Body, Response, StatusCode};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest_eventsource::Event;
 use reqwest_eventsource::Error as REError;
 use serde_json::{json, Value};
@@ -12,18 +13,19 @@ use tracing::info;
 
 use crate::call_validation::{ChatMeta, SamplingParameters};
 use crate::custom_error::ScratchError;
+use crate::cassette::{StreamError, UpstreamError};
 use crate::nicer_logs;
 use crate::scratchpad_abstract::{FinishReason, ScratchpadAbstract};
 use crate::telemetry::telemetry_structs;
 use crate::at_commands::at_commands::AtCommandsContext;
-use crate::caps::get_api_key;
+use crate::caps::{get_api_key, resolve_env_var};
 
 
 async fn _get_endpoint_and_stuff_from_model_name(
     gcx: Arc<ARwLock<crate::global_context::GlobalContext>>,
     caps: Arc<StdRwLock<crate::caps::CodeAssistantCaps>>,
     model_name: String,
-) -> (String, String, String, String)
+) -> (String, String, String, String, HeaderMap)
 {
     let (
         custom_apikey,
@@ -31,10 +33,13 @@ async fn _get_endpoint_and_stuff_from_model_name(
         custom_endpoint_style,
         mut endpoint_template,
         custom_endpoint_template,
-        endpoint_chat_passthrough
+        mut endpoint_chat_passthrough,
+        provider_mb,
+        is_chat,
     ) = {
         let caps_locked = caps.read().unwrap();
         let is_chat = caps_locked.code_chat_models.contains_key(&model_name);
+        let provider_mb = crate::caps::model_provider(&caps_locked, &model_name);
         if is_chat {
             (
                 caps_locked.chat_apikey.clone(),
@@ -43,6 +48,8 @@ async fn _get_endpoint_and_stuff_from_model_name(
                 caps_locked.endpoint_template.clone(),   // abstract
                 caps_locked.chat_endpoint.clone(),       // chat-specific
                 caps_locked.endpoint_chat_passthrough.clone(),
+                provider_mb,
+                is_chat,
             )
         } else {
             (
@@ -52,24 +59,128 @@ async fn _get_endpoint_and_stuff_from_model_name(
                 caps_locked.endpoint_template.clone(),          // abstract
                 caps_locked.completion_endpoint.clone(),        // completion-specific
                 "".to_string(),
+                provider_mb,
+                is_chat,
             )
         }
     };
-    let api_key = get_api_key(gcx, custom_apikey).await;
     if !custom_endpoint_style.is_empty() {
         endpoint_style = custom_endpoint_style;
     }
     if !custom_endpoint_template.is_empty() {
         endpoint_template = custom_endpoint_template;
     }
+    let mut extra_headers = HeaderMap::new();
+    let api_key = if let Some(provider) = provider_mb {
+        // the model is served by its own provider, top level endpoints don't apply
+        endpoint_style = if provider.endpoint_style.is_empty() { "openai".to_string() } else { provider.endpoint_style.clone() };
+        endpoint_template = crate::caps::provider_endpoint(&provider, is_chat);
+        if is_chat {
+            endpoint_chat_passthrough = endpoint_template.clone();
+        }
+        for (k, v) in provider.headers.iter() {
+            let v = match resolve_env_var(v) {
+                Ok(v) => v,
+                Err(e) => {
+                    tracing::error!("provider header {:?}: {}", k, e);
+                    continue;
+                }
+            };
+            match (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(&v)) {
+                (Ok(name), Ok(value)) => { extra_headers.insert(name, value); },
+                _ => tracing::warn!("provider header {:?} is not a valid http header, skipped", k),
+            }
+        }
+        get_api_key(gcx, provider.api_key.clone()).await
+    } else {
+        get_api_key(gcx, custom_apikey).await
+    };
     return (
         api_key,
         endpoint_template,
         endpoint_style,
         endpoint_chat_passthrough,
+        extra_headers,
     )
 }
 
+
+struct RetryState {
+    policy: crate::caps::RetryPolicy,
+    models: Vec<String>,
+    model_idx: usize,
+    attempt_n: usize,  // retries of the current model
+    retries_n: usize,  // all retries including switches to a fallback model
+}
+
+impl RetryState {
+    fn new(policy: crate::caps::RetryPolicy, models: Vec<String>) -> Self {
+        RetryState { policy, models, model_idx: 0, attempt_n: 0, retries_n: 0 }
+    }
+
+    fn current_model(&self) -> String {
+        self.models[self.model_idx].clone()
+    }
+
+    // None means give up, otherwise how long to sleep before the next attempt (possibly with another model)
+    fn next_attempt(&mut self, retry_after: Option<std::time::Duration>) -> Option<std::time::Duration> {
+        let max_delay = std::time::Duration::from_millis(self.policy.backoff_max_ms);
+        if self.attempt_n < self.policy.max_retries {
+            let backoff = std::time::Duration::from_millis(
+                self.policy.backoff_initial_ms.saturating_mul(1u64 << self.attempt_n.min(16))
+            );
+            self.attempt_n += 1;
+            self.retries_n += 1;
+            return Some(retry_after.unwrap_or(backoff).min(max_delay));
+        }
+        if self.model_idx + 1 < self.models.len() {
+            self.model_idx += 1;
+            self.attempt_n = 0;
+            self.retries_n += 1;
+            return Some(std::time::Duration::ZERO);
+        }
+        None
+    }
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    // 529 is anthropic "overloaded"
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    // only the delay-seconds form, http dates are rare in llm apis
+    value.trim().parse::<f64>().ok()
+        .filter(|secs| *secs >= 0.0)
+        .map(|secs| std::time::Duration::from_millis((secs * 1000.0) as u64))
+}
+
+fn retry_info_from_upstream_error(e: &UpstreamError) -> (bool, Option<std::time::Duration>) {
+    match e.status {
+        Some(status) => (is_retryable_status(status), e.retry_after.as_deref().and_then(parse_retry_after)),
+        None => (e.transport, None),
+    }
+}
+
+fn retry_info_from_stream_error(err: &StreamError) -> (bool, Option<std::time::Duration>) {
+    match err {
+        StreamError::Status { status, retry_after, .. } => {
+            (is_retryable_status(*status), retry_after.as_deref().and_then(parse_retry_after))
+        },
+        StreamError::Source(REError::Transport(_)) => (true, None),
+        _ => (false, None),
+    }
+}
+
+fn record_retries_in_usage(value: &mut serde_json::Value, retries_n: usize) {
+    if retries_n == 0 {
+        return;
+    }
+    if let Some(usage) = value.get_mut("usage").and_then(|u| u.as_object_mut()) {
+        usage.insert("retries_n".to_string(), json!(retries_n));
+    }
+}
+
 pub async fn scratchpad_interaction_not_stream_json(
     ccx: Arc<AMutex<AtCommandsContext>>,
     scratchpad: &mut Box<dyn ScratchpadAbstract>,
@@ -93,12 +204,10 @@ pub async fn scratchpad_interaction_not_stream_json(
             gcx_locked.http_client_slowdown.clone()
         )
     };
-    let (
-        bearer,
-        endpoint_template,
-        endpoint_style,
-        endpoint_chat_passthrough,
-    ) = _get_endpoint_and_stuff_from_model_name(gcx.clone(), caps.clone(), model_name.clone()).await;
+    let mut retry = {
+        let caps_locked = caps.read().unwrap();
+        RetryState::new(caps_locked.retry_policy.clone(), crate::caps::models_to_try(&caps_locked, &model_name))
+    };
 
     let mut save_url: String = String::new();
     let _ = slowdown_arc.acquire().await;
@@ -106,31 +215,74 @@ pub async fn scratchpad_interaction_not_stream_json(
     let mut model_says = if only_deterministic_messages {
         save_url = "only-det-messages".to_string();
         Ok(serde_json::Value::Object(serde_json::Map::new()))
-    } else if endpoint_style == "hf" {
-        crate::forward_to_hf_endpoint::forward_to_hf_style_endpoint(
-            &mut save_url,
-            bearer.clone(),
-            &model_name,
-            &prompt,
-            &client,
-            &endpoint_template,
-            &parameters,
-            meta
-        ).await
-    } else {
-        crate::forward_to_openai_endpoint::forward_to_openai_style_endpoint(
-            &mut save_url,
-            bearer.clone(),
-            &model_name,
-            &prompt,
-            &client,
-            &endpoint_template,
-            &endpoint_chat_passthrough,
-            &parameters,  // includes n
-            metadata_supported,
-            meta
-        ).await
-    }.map_err(|e| {
+    } else { loop {
+        let try_model = retry.current_model();
+        let (
+            bearer,
+            endpoint_template,
+            endpoint_style,
+            endpoint_chat_passthrough,
+            extra_headers,
+        ) = _get_endpoint_and_stuff_from_model_name(gcx.clone(), caps.clone(), try_model.clone()).await;
+        let model_says_maybe = if endpoint_style == "hf" {
+            crate::forward_to_hf_endpoint::forward_to_hf_style_endpoint(
+                &mut save_url,
+                bearer.clone(),
+                &try_model,
+                &prompt,
+                &client,
+                &extra_headers,
+                &endpoint_template,
+                &parameters,
+                meta.clone()
+            ).await
+        } else if endpoint_style == "anthropic" {
+            crate::forward_to_anthropic_endpoint::forward_to_anthropic_endpoint(
+                &mut save_url,
+                bearer.clone(),
+                &try_model,
+                &prompt,
+                &client,
+                &extra_headers,
+                &endpoint_chat_passthrough,
+                &parameters,
+                metadata_supported,
+            ).await
+        } else {
+            crate::forward_to_openai_endpoint::forward_to_openai_style_endpoint(
+                &mut save_url,
+                bearer.clone(),
+                &try_model,
+                &prompt,
+                &client,
+                &extra_headers,
+                &endpoint_template,
+                &endpoint_chat_passthrough,
+                &parameters,  // includes n
+                metadata_supported,
+                meta.clone()
+            ).await
+        };
+        let e = match model_says_maybe {
+            Ok(x) => break Ok(x),
+            Err(e) => e,
+        };
+        let (retryable, retry_after) = retry_info_from_upstream_error(&e);
+        let delay = if retryable { retry.next_attempt(retry_after) } else { None };
+        match delay {
+            Some(delay) => {
+                tracing::warn!("{} failed, retry #{} with {} in {:?}: {}", try_model, retry.retries_n, retry.current_model(), delay, e);
+                tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
+                    save_url.clone(),
+                    scope.clone(),
+                    false,
+                    format!("will retry: {}", e),
+                ));
+                tokio::time::sleep(delay).await;
+            },
+            None => break Err(e),
+        }
+    }}.map_err(|e| {
         tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
                 save_url.clone(),
                 scope.clone(),
@@ -139,6 +291,7 @@ pub async fn scratchpad_interaction_not_stream_json(
             ));
         ScratchError::new_but_skip_telemetry(StatusCode::INTERNAL_SERVER_ERROR, format!("forward_to_endpoint: {}", e))
     })?;
+    record_retries_in_usage(&mut model_says, retry.retries_n);
     tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
         save_url.clone(),
         scope.clone(),
@@ -147,6 +300,7 @@ pub async fn scratchpad_interaction_not_stream_json(
     ));
     info!("forward to endpoint {:.2}ms, url was {}", t2.elapsed().unwrap().as_millis() as f64, save_url);
     crate::global_context::look_for_piggyback_fields(gcx.clone(), &model_says).await;
+    normalize_reasoning_in_choices(&mut model_says, "message");
 
     let scratchpad_result: Result<serde_json::Value, String>;
     if only_deterministic_messages {
@@ -247,7 +401,13 @@ pub async fn scratchpad_interaction_not_stream_json(
             format!("scratchpad: {}", problem))
         );
     }
-    return Ok(scratchpad_result.unwrap());
+    let mut scratchpad_result = scratchpad_result.unwrap();
+    record_retries_in_usage(&mut scratchpad_result, retry.retries_n);
+    if scratchpad_result.is_object() && !only_deterministic_messages {
+        // a fallback model might have answered, the same as "model" in the last streamed chunk
+        scratchpad_result["model"] = json!(retry.current_model());
+    }
+    return Ok(scratchpad_result);
 }
 
 pub async fn scratchpad_interaction_not_stream(
@@ -272,9 +432,9 @@ pub async fn scratchpad_interaction_not_stream(
     let mut scratchpad_response_json = scratchpad_interaction_not_stream_json(
         ccx.clone(),
         scratchpad,
-        scope,
+        scope.clone(),
         prompt.as_str(),
-        model_name,
+        model_name.clone(),
         parameters,
         only_deterministic_messages,
         meta
@@ -282,6 +442,14 @@ pub async fn scratchpad_interaction_not_stream(
     scratchpad_response_json["created"] = json!(t2.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
 
     try_insert_usage(&mut scratchpad_response_json);
+    if scope.starts_with("chat") {
+        let (gcx, chat_id) = {
+            let ccx_locked = ccx.lock().await;
+            (ccx_locked.global_context.clone(), ccx_locked.chat_id.clone())
+        };
+        let answered_model = scratchpad_response_json["model"].as_str().map(|x| x.to_string()).unwrap_or(model_name.clone());
+        crate::agent_db::db_spend::spend_record_chat_usage(gcx, &chat_id, &answered_model, &scratchpad_response_json["usage"]).await;
+    }
 
     let txt = serde_json::to_string_pretty(&scratchpad_response_json).unwrap();
     // info!("handle_v1_code_completion return {}", txt);
@@ -292,6 +460,120 @@ pub async fn scratchpad_interaction_not_stream(
     return Ok(response);
 }
 
+fn split_batched_choices(model_says: &Value, prompts_n: usize) -> Result<Vec<(Vec<String>, Vec<FinishReason>)>, String> {
+    let choices = model_says.get("choices").and_then(|x| x.as_array())
+        .ok_or(format!("unrecognized batch response: {:?}", model_says))?;
+    if prompts_n == 0 || choices.is_empty() || choices.len() % prompts_n != 0 {
+        return Err(format!("batch response has {} choices for {} prompts", choices.len(), prompts_n));
+    }
+    let n = choices.len() / prompts_n;
+    let mut per_prompt = vec![(vec![String::new(); n], vec![FinishReason::None; n]); prompts_n];
+    for (i, choice) in choices.iter().enumerate() {
+        let index = choice.get("index").and_then(|x| x.as_u64()).map(|x| x as usize).unwrap_or(i);
+        if index >= choices.len() {
+            return Err(format!("batch response choice index {} is out of range", index));
+        }
+        let (texts, finish_reasons) = &mut per_prompt[index / n];
+        texts[index % n] = choice.get("text").and_then(|x| x.as_str()).unwrap_or_default().to_string();
+        finish_reasons[index % n] = FinishReason::from_json_val(choice.get("finish_reason").unwrap_or(&json!(""))).unwrap_or(FinishReason::None);
+    }
+    Ok(per_prompt)
+}
+
+// Several scratchpads of the same model: prompts are built one after another (so they can share the work kept in ccx),
+// then go upstream as one request if the endpoint takes an array of prompts, otherwise as separate requests
+pub async fn scratchpad_interaction_not_stream_batch(
+    ccx: Arc<AMutex<AtCommandsContext>>,
+    scratchpads: &mut Vec<Box<dyn ScratchpadAbstract>>,
+    scope: String,
+    model_name: String,
+    parameters: &SamplingParameters,
+) -> Vec<Result<Value, ScratchError>> {
+    let mut results: Vec<Option<Result<Value, ScratchError>>> = (0..scratchpads.len()).map(|_| None).collect();
+    let mut prompts: Vec<(usize, String, SamplingParameters)> = vec![];
+    for (i, scratchpad) in scratchpads.iter_mut().enumerate() {
+        let mut p = parameters.clone();
+        match scratchpad.prompt(ccx.clone(), &mut p).await {
+            Ok(prompt) => prompts.push((i, prompt, p)),
+            Err(e) => results[i] = Some(Err(ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Prompt: {}", e)))),
+        }
+    }
+
+    let t2 = std::time::SystemTime::now();
+    let gcx = ccx.lock().await.global_context.clone();
+    let (client, caps, tele_storage, slowdown_arc) = {
+        let gcx_locked = gcx.read().await;
+        (gcx_locked.http_client.clone(), gcx_locked.caps.clone(), gcx_locked.telemetry.clone(), gcx_locked.http_client_slowdown.clone())
+    };
+    let can_batch = prompts.len() > 1 && caps.is_some() && prompts.iter().all(|(_, prompt, _)| !prompt.starts_with("PASSTHROUGH "));
+    if can_batch {
+        let (bearer, endpoint_template, endpoint_style, _, extra_headers) =
+            _get_endpoint_and_stuff_from_model_name(gcx.clone(), caps.clone().unwrap(), model_name.clone()).await;
+        if endpoint_style != "hf" && endpoint_style != "anthropic" {
+            let mut save_url = String::new();
+            let prompt_texts = prompts.iter().map(|(_, prompt, _)| prompt.clone()).collect::<Vec<_>>();
+            let metadata_supported = crate::global_context::is_metadata_supported(gcx.clone()).await;
+            let model_says = {
+                let _ = slowdown_arc.acquire().await;
+                crate::forward_to_openai_endpoint::forward_to_openai_style_endpoint_batch(
+                    &mut save_url,
+                    bearer,
+                    &model_name,
+                    &prompt_texts,
+                    &client,
+                    &extra_headers,
+                    &endpoint_template,
+                    &prompts[0].2,  // the same model and scratchpad patch the parameters the same way
+                    metadata_supported,
+                ).await
+            };
+            match model_says.map_err(|e| e.to_string()).and_then(|x| split_batched_choices(&x, prompts.len())) {
+                Ok(per_prompt) => {
+                    tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
+                        save_url.clone(), scope.clone(), true, "".to_string(),
+                    ));
+                    info!("forward to endpoint batch of {} {:.2}ms, url was {}", prompts.len(), t2.elapsed().unwrap().as_millis() as f64, save_url);
+                    for ((i, _, _), (choices, finish_reasons)) in prompts.iter().zip(per_prompt.into_iter()) {
+                        results[*i] = Some(scratchpads[*i].response_n_choices(choices, finish_reasons)
+                            .map(|mut x| {
+                                x["created"] = json!(t2.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
+                                x
+                            })
+                            .map_err(|e| ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("scratchpad: {}", e))));
+                    }
+                    prompts.clear();
+                },
+                Err(e) => {
+                    tracing::warn!("batch request failed, sending the prompts one by one: {}", e);
+                    tele_storage.write().unwrap().tele_net.push(telemetry_structs::TelemetryNetwork::new(
+                        save_url.clone(), scope.clone(), false, format!("will send one by one: {}", e),
+                    ));
+                },
+            }
+        }
+    }
+
+    // one by one, with the usual retries
+    let mut todo = prompts.iter().map(|(i, _, _)| *i).collect::<Vec<_>>();
+    let mut answers = futures::future::join_all(
+        scratchpads.iter_mut().enumerate()
+            .filter(|(i, _)| todo.contains(i))
+            .zip(prompts.iter())
+            .map(|((_, scratchpad), (_, prompt, p))| {
+                let (ccx, scope, model_name) = (ccx.clone(), scope.clone(), model_name.clone());
+                async move {
+                    let mut answer = scratchpad_interaction_not_stream_json(ccx, scratchpad, scope, prompt, model_name, p, false, None).await?;
+                    answer["created"] = json!(t2.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
+                    Ok::<Value, ScratchError>(answer)
+                }
+            })
+    ).await;
+    for i in todo.drain(..).rev() {
+        results[i] = answers.pop();
+    }
+    results.into_iter().map(|x| x.unwrap_or_else(|| Err(ScratchError::new(StatusCode::INTERNAL_SERVER_ERROR, "no answer".to_string())))).collect()
+}
+
 pub async fn scratchpad_interaction_stream(
     ccx: Arc<AMutex<AtCommandsContext>>,
     mut scratchpad: Box<dyn ScratchpadAbstract>,
@@ -318,12 +600,10 @@ pub async fn scratchpad_interaction_stream(
                 gcx_locked.http_client_slowdown.clone()
             )
         };
-        let (
-            bearer,
-            endpoint_template,
-            endpoint_style,
-            endpoint_chat_passthrough,
-        ) = _get_endpoint_and_stuff_from_model_name(gcx.clone(), caps.clone(), model_name.clone()).await;
+        let mut retry = {
+            let caps_locked = caps.read().unwrap();
+            RetryState::new(caps_locked.retry_policy.clone(), crate::caps::models_to_try(&caps_locked, &model_name))
+        };
 
         let t0 = std::time::Instant::now();
         let mut prompt = String::new();
@@ -378,23 +658,32 @@ pub async fn scratchpad_interaction_stream(
 
         let mut save_url: String = String::new();
         let _ = slowdown_arc.acquire().await;
-        loop {
-            let value_maybe = my_scratchpad.response_spontaneous();
-            if let Ok(value) = value_maybe {
-                for el in value {
-                    let value_str = format!("data: {}\n\n", serde_json::to_string(&el).unwrap());
-                    info!("yield: {:?}", nicer_logs::first_n_chars(&value_str, 40));
-                    yield Result::<_, String>::Ok(value_str);
-                }
-            } else {
-                let err_str = value_maybe.unwrap_err();
-                tracing::error!("response_spontaneous error: {}", err_str);
-                let value_str = format!("data: {}\n\n", serde_json::to_string(&json!({"detail": err_str})).unwrap());
+        let value_maybe = my_scratchpad.response_spontaneous();
+        if let Ok(value) = value_maybe {
+            for el in value {
+                let value_str = format!("data: {}\n\n", serde_json::to_string(&el).unwrap());
+                info!("yield: {:?}", nicer_logs::first_n_chars(&value_str, 40));
                 yield Result::<_, String>::Ok(value_str);
             }
+        } else {
+            let err_str = value_maybe.unwrap_err();
+            tracing::error!("response_spontaneous error: {}", err_str);
+            let value_str = format!("data: {}\n\n", serde_json::to_string(&json!({"detail": err_str})).unwrap());
+            yield Result::<_, String>::Ok(value_str);
+        }
+        // each iteration is one attempt, the next one happens only if nothing was streamed from the previous one
+        'attempts: loop {
             if only_deterministic_messages {
                 break;
             }
+            model_name = retry.current_model();
+            let (
+                bearer,
+                endpoint_template,
+                endpoint_style,
+                endpoint_chat_passthrough,
+                extra_headers,
+            ) = _get_endpoint_and_stuff_from_model_name(gcx.clone(), caps.clone(), model_name.clone()).await;
             // info!("prompt: {:?}", prompt);
             let metadata_supported = crate::global_context::is_metadata_supported(gcx.clone()).await;
             let event_source_maybe = if endpoint_style == "hf" {
@@ -404,9 +693,22 @@ pub async fn scratchpad_interaction_stream(
                     &model_name,
                     prompt.as_str(),
                     &client,
+                    &extra_headers,
                     &endpoint_template,
                     &my_parameters,
-                    meta
+                    meta.clone()
+                ).await
+            } else if endpoint_style == "anthropic" {
+                crate::forward_to_anthropic_endpoint::forward_to_anthropic_endpoint_streaming(
+                    &mut save_url,
+                    bearer.clone(),
+                    &model_name,
+                    prompt.as_str(),
+                    &client,
+                    &extra_headers,
+                    &endpoint_chat_passthrough,
+                    &my_parameters,
+                    metadata_supported,
                 ).await
             } else {
                 crate::forward_to_openai_endpoint::forward_to_openai_style_endpoint_streaming(
@@ -415,11 +717,12 @@ pub async fn scratchpad_interaction_stream(
                     &model_name,
                     prompt.as_str(),
                     &client,
+                    &extra_headers,
                     &endpoint_template,
                     &endpoint_chat_passthrough,
                     &my_parameters,
                     metadata_supported,
-                    meta
+                    meta.clone()
                 ).await
             };
             let mut event_source = match event_source_maybe {
@@ -440,40 +743,63 @@ pub async fn scratchpad_interaction_stream(
             };
             let mut was_correct_output_even_if_error = false;
             let mut last_finish_reason = FinishReason::None;
+            let mut got_any_message = false;
+            let mut last_usage = serde_json::Value::Null;
+            let mut anthropic_converter = if endpoint_style == "anthropic" {
+                Some(crate::forward_to_anthropic_endpoint::AnthropicStreamConverter::new(&model_name))
+            } else {
+                None
+            };
             // let mut test_countdown = 250;
-            while let Some(event) = event_source.next().await {
+            'events: while let Some(event) = event_source.next().await {
                 match event {
                     Ok(Event::Open) => {},
                     Ok(Event::Message(message)) => {
                         // info!("Message: {:#?}", message);
+                        got_any_message = true;
                         if message.data.starts_with("[DONE]") {
                             break;
                         }
                         let json = serde_json::from_str::<serde_json::Value>(&message.data).unwrap();
-                        crate::global_context::look_for_piggyback_fields(gcx.clone(), &json).await;
-                        match _push_streaming_json_into_scratchpad(
-                            my_scratchpad,
-                            &json,
-                            &mut model_name,
-                            &mut was_correct_output_even_if_error,
-                        ) {
-                            Ok((mut value, finish_reason)) => {
-                                if finish_reason != FinishReason::None { // last event has service info(usage and other), there is no finish_reason
-                                    last_finish_reason = finish_reason;
+                        let jsons = match anthropic_converter.as_mut() {
+                            Some(converter) => {
+                                if crate::forward_to_anthropic_endpoint::AnthropicStreamConverter::is_message_stop(&json) {
+                                    break;
                                 }
-                                try_insert_usage(&mut value);
-                                value["created"] = json!(t1.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
-                                let value_str = format!("data: {}\n\n", serde_json::to_string(&value).unwrap());
-                                // let last_60_chars: String = crate::nicer_logs::first_n_chars(&value_str, 60);
-                                // info!("yield: {:?}", last_60_chars);
-                                yield Result::<_, String>::Ok(value_str);
+                                converter.convert(&json).unwrap_or_else(|e| vec![json!({"error": e})])
                             },
-                            Err(err_str) => {
-                                tracing::error!("unexpected error: {}", err_str);
-                                let value_str = format!("data: {}\n\n", serde_json::to_string(&json!({"detail": err_str})).unwrap());
-                                yield Result::<_, String>::Ok(value_str);
-                                // TODO: send telemetry
-                                break;
+                            None => vec![json],
+                        };
+                        for json in jsons {
+                            crate::global_context::look_for_piggyback_fields(gcx.clone(), &json).await;
+                            match _push_streaming_json_into_scratchpad(
+                                my_scratchpad,
+                                &json,
+                                &mut model_name,
+                                &mut was_correct_output_even_if_error,
+                            ) {
+                                Ok((mut value, finish_reason)) => {
+                                    if finish_reason != FinishReason::None { // last event has service info(usage and other), there is no finish_reason
+                                        last_finish_reason = finish_reason;
+                                    }
+                                    try_insert_usage(&mut value);
+                                    if !value["usage"].is_null() {
+                                        last_usage = value["usage"].clone();
+                                    }
+                                    record_retries_in_usage(&mut value, retry.retries_n);
+                                    value["created"] = json!(t1.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
+                                    let value_str = format!("data: {}\n\n", serde_json::to_string(&value).unwrap());
+                                    // let last_60_chars: String = crate::nicer_logs::first_n_chars(&value_str, 60);
+                                    // info!("yield: {:?}", last_60_chars);
+                                    yield Result::<_, String>::Ok(value_str);
+                                },
+                                Err(err_str) => {
+                                    tracing::error!("unexpected error: {}", err_str);
+                                    let value_str = format!("data: {}\n\n", serde_json::to_string(&json!({"detail": err_str})).unwrap());
+                                    yield Result::<_, String>::Ok(value_str);
+                                    // TODO: send telemetry
+                                    break 'events;
+                                }
                             }
                         }
 
@@ -483,9 +809,11 @@ pub async fn scratchpad_interaction_stream(
                             // "restream error: Stream ended"
                             break;
                         }
+                        // after the first token the scratchpad has state and the user has seen the text, never re-send
+                        let (retryable, retry_after) = if got_any_message { (false, None) } else { retry_info_from_stream_error(&err) };
                         let problem_str = match err {
-                            REError::InvalidStatusCode(err, resp) => {
-                                let text = resp.text().await.unwrap();
+                            StreamError::Status { status, text, .. } => {
+                                let err = StatusCode::from_u16(status).map(|s| s.to_string()).unwrap_or(status.to_string());
                                 let mut res = format!("{} with details = {:?}", err, text);
                                 if let Ok(value) = serde_json::from_str::<Value>(&text) {
                                     if let Some(detail) = value.get("detail") {
@@ -494,7 +822,7 @@ pub async fn scratchpad_interaction_stream(
                                 }
                                 res
                             }
-                            _ => {
+                            StreamError::Source(err) => {
                                 format!("{}", err)
                             }
                         };
@@ -507,6 +835,15 @@ pub async fn scratchpad_interaction_stream(
                                 problem_str.clone(),
                             ));
                         }
+                        if retryable {
+                            let tried_model = retry.current_model();
+                            if let Some(delay) = retry.next_attempt(retry_after) {
+                                tracing::warn!("{} failed, retry #{} with {} in {:?}", tried_model, retry.retries_n, retry.current_model(), delay);
+                                event_source.close();
+                                tokio::time::sleep(delay).await;
+                                continue 'attempts;
+                            }
+                        }
                         yield Result::<_, String>::Ok(serde_json::to_string(&json!({"detail": problem_str})).unwrap());
                         event_source.close();
                         return;
@@ -514,9 +851,14 @@ pub async fn scratchpad_interaction_stream(
                 }
             }
 
+            if scope.starts_with("chat") {
+                let chat_id = my_ccx.lock().await.chat_id.clone();
+                crate::agent_db::db_spend::spend_record_chat_usage(gcx.clone(), &chat_id, &model_name, &last_usage).await;
+            }
             let mut value = my_scratchpad.streaming_finished(last_finish_reason)?;
             value["created"] = json!(t1.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64());
             value["model"] = json!(model_name.clone());
+            record_retries_in_usage(&mut value, retry.retries_n);
             let value_str = format!("data: {}\n\n", serde_json::to_string(&value).unwrap());
             info!("yield final: {:?}", value_str);
             yield Result::<_, String>::Ok(value_str);
@@ -579,6 +921,19 @@ pub fn try_insert_usage(msg_value: &mut serde_json::Value) -> bool {
     return false;
 }
 
+// Reasoning goes apart from the content as "reasoning_content" (deepseek, litellm), openrouter and vllm call it "reasoning"
+fn normalize_reasoning_in_choices(value: &mut serde_json::Value, field: &str) {
+    if let Some(choices) = value.get_mut("choices").and_then(|c| c.as_array_mut()) {
+        for msg in choices.iter_mut().filter_map(|c| c.get_mut(field)).filter_map(|m| m.as_object_mut()) {
+            if let Some(reasoning) = msg.remove("reasoning") {
+                if reasoning.is_string() && !msg.contains_key("reasoning_content") {
+                    msg.insert("reasoning_content".to_string(), reasoning);
+                }
+            }
+        }
+    }
+}
+
 fn _push_streaming_json_into_scratchpad(
     scratch: &mut Box<dyn ScratchpadAbstract>,
     json: &serde_json::Value,
@@ -600,6 +955,8 @@ fn _push_streaming_json_into_scratchpad(
             FinishReason::None
         });
         if let Some(_delta) = choice0.get("delta") {
+            let mut json = json.clone();
+            normalize_reasoning_in_choices(&mut json, "delta");
             (value, finish_reason) = match scratch.response_message_streaming(&json, finish_reason.clone()) {
                 Ok(res) => Ok(res),
                 Err(err) => {
@@ -659,3 +1016,67 @@ pub async fn cached_stream(
        .unwrap();
     return Ok(response);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::cassette::UpstreamResponse;
+
+    #[test]
+    fn test_retry_state_backoff_then_fallback() {
+        let policy = crate::caps::RetryPolicy { max_retries: 2, backoff_initial_ms: 100, backoff_max_ms: 150 };
+        let mut retry = RetryState::new(policy, vec!["gpt-4o".to_string(), "gpt-4o-mini".to_string()]);
+        assert_eq!(retry.next_attempt(None), Some(Duration::from_millis(100)));
+        assert_eq!(retry.next_attempt(None), Some(Duration::from_millis(150)));
+        assert_eq!(retry.current_model(), "gpt-4o");
+        assert_eq!(retry.next_attempt(Some(Duration::from_secs(60))), Some(Duration::ZERO));
+        assert_eq!(retry.current_model(), "gpt-4o-mini");
+        assert_eq!(retry.next_attempt(Some(Duration::from_secs(60))), Some(Duration::from_millis(150)));
+        assert_eq!(retry.next_attempt(None), Some(Duration::from_millis(150)));
+        assert_eq!(retry.next_attempt(None), None);
+        assert_eq!(retry.retries_n, 5);
+    }
+
+    #[test]
+    fn test_normalize_reasoning_in_choices() {
+        let mut chunk = json!({"choices": [{"index": 0, "delta": {"reasoning": "hmm", "content": ""}}]});
+        normalize_reasoning_in_choices(&mut chunk, "delta");
+        assert_eq!(chunk["choices"][0]["delta"], json!({"reasoning_content": "hmm", "content": ""}));
+        let mut resp = json!({"choices": [{"message": {"reasoning_content": "kept", "reasoning": "dup", "content": "hi"}}]});
+        normalize_reasoning_in_choices(&mut resp, "message");
+        assert_eq!(resp["choices"][0]["message"], json!({"reasoning_content": "kept", "content": "hi"}));
+    }
+
+    #[test]
+    fn test_split_batched_choices() {
+        let model_says = json!({"choices": [
+            {"index": 2, "text": "b0", "finish_reason": "stop"},
+            {"index": 0, "text": "a0", "finish_reason": "length"},
+            {"index": 3, "text": "b1", "finish_reason": "stop"},
+            {"index": 1, "text": "a1", "finish_reason": "stop"},
+        ]});
+        let per_prompt = split_batched_choices(&model_says, 2).unwrap();
+        assert_eq!(per_prompt[0].0, vec!["a0".to_string(), "a1".to_string()]);
+        assert_eq!(per_prompt[1].0, vec!["b0".to_string(), "b1".to_string()]);
+        assert_eq!(per_prompt[0].1, vec![FinishReason::Length, FinishReason::Stop]);
+        assert!(split_batched_choices(&model_says, 3).is_err());
+        assert!(split_batched_choices(&json!({"error": "no such model"}), 2).is_err());
+    }
+
+    #[test]
+    fn test_retry_info_from_upstream_error() {
+        let rate_limited = UpstreamResponse { status: 429, retry_after: Some("2".to_string()), text: "{\"error\": \"rate limit\"}".to_string() };
+        let (retryable, after) = retry_info_from_upstream_error(&rate_limited.into_error("https://api.openai.com/v1/chat/completions"));
+        assert!(retryable);
+        assert_eq!(after, Some(Duration::from_secs(2)));
+        let unauthorized = UpstreamResponse { status: 401, retry_after: None, text: "connection refused by policy, timed out".to_string() };
+        assert_eq!(retry_info_from_upstream_error(&unauthorized.into_error("https://x/v1/chat/completions")), (false, None));
+        let overloaded = UpstreamResponse { status: 503, retry_after: None, text: "overloaded".to_string() };
+        assert_eq!(retry_info_from_upstream_error(&overloaded.into_error("https://x/v1/chat/completions")), (true, None));
+        let timed_out = UpstreamError { transport: true, message: "error sending request: operation timed out".to_string(), ..Default::default() };
+        assert_eq!(retry_info_from_upstream_error(&timed_out), (true, None));
+        let bad_json = UpstreamError::from("Failed to parse JSON response: connection EOF".to_string());
+        assert_eq!(retry_info_from_upstream_error(&bad_json), (false, None));
+    }
+}
@@ -13,7 +13,11 @@ use crate::call_validation::{ChatMessage, ChatPost, ContextFile, SamplingParamet
 use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
 use crate::scratchpads::chat_utils_deltadelta::DeltaDeltaChatStreamer;
 use crate::scratchpads::chat_utils_limit_history::fix_and_limit_messages_history;
+use crate::scratchpads::chat_utils_summarize_history::maybe_summarize_old_history;
+use crate::scratchpads::chat_utils_text_tools::{TOOL_RESULT_OPEN, messages_with_text_tools, text_tools_for_post, text_tools_system_prompt};
 use crate::scratchpads::scratchpad_utils::HasRagResults;
+use crate::tools::tools_description::tools_merged_and_filtered;
+use crate::tools::tools_execute::run_tools_locally;
 
 
 const DEBUG: bool = true;
@@ -34,6 +38,7 @@ pub struct GenericChatScratchpad {
     pub keyword_asst: String,
     pub has_rag_results: HasRagResults,
     pub allow_at: bool,
+    pub supports_tools: bool,
 }
 
 impl GenericChatScratchpad {
@@ -42,6 +47,7 @@ impl GenericChatScratchpad {
         post: &ChatPost,
         messages: &Vec<ChatMessage>,
         allow_at: bool,
+        supports_tools: bool,
     ) -> Self {
         GenericChatScratchpad {
             t: HasTokenizerAndEot::new(tokenizer),
@@ -55,6 +61,7 @@ impl GenericChatScratchpad {
             keyword_asst: "".to_string(),
             has_rag_results: HasRagResults::new(),
             allow_at,
+            supports_tools,
         }
     }
 }
@@ -103,10 +110,11 @@ impl ScratchpadAbstract for GenericChatScratchpad {
         } else {
             (self.messages.clone(), false)
         };
-        let limited_msgs: Vec<ChatMessage> = fix_and_limit_messages_history(&self.t, &messages, sampling_parameters_to_patch, n_ctx, None, self.post.model.as_str())?;
-        // if self.supports_tools {
-        // };
-        sampling_parameters_to_patch.stop = self.dd.stop_list.clone();
+        // no native function calling, tools are emulated in text, see chat_utils_text_tools
+        let (limited_msgs, text_tools) = text_tools_and_limited_history(
+            ccx.clone(), &self.t, &self.post, messages, self.supports_tools, &mut self.dd, &mut self.has_rag_results, sampling_parameters_to_patch, n_ctx
+        ).await?;
+        let limited_msgs = messages_with_text_tools(&limited_msgs, &text_tools);
         // adapted from https://huggingface.co/spaces/huggingface-projects/llama-2-13b-chat/blob/main/model.py#L24
         let mut prompt = self.token_bos.to_string();
         let mut last_role = "assistant".to_string();
@@ -187,3 +195,39 @@ impl ScratchpadAbstract for GenericChatScratchpad {
         self.dd.streaming_finished(finish_reason)
     }
 }
+
+// Shared by the scratchpads that emulate tools in text: runs the tools, sets the stop list, summarizes and limits
+// the history with the tools description taken into account. Returns the limited messages and the tools,
+// putting the tools into the messages (or into the template) is up to the scratchpad.
+pub async fn text_tools_and_limited_history(
+    ccx: Arc<AMutex<AtCommandsContext>>,
+    t: &HasTokenizerAndEot,
+    post: &ChatPost,
+    mut messages: Vec<ChatMessage>,
+    supports_tools: bool,
+    dd: &mut DeltaDeltaChatStreamer,
+    has_rag_results: &mut HasRagResults,
+    sampling_parameters_to_patch: &mut SamplingParameters,
+    n_ctx: usize,
+) -> Result<(Vec<ChatMessage>, Vec<Value>), String> {
+    let mut text_tools = vec![];
+    if supports_tools {
+        let gcx = ccx.lock().await.global_context.clone();
+        let mut at_tools = tools_merged_and_filtered(gcx.clone(), false).await?;
+        (messages, _) = run_tools_locally(ccx.clone(), &mut at_tools, t.tokenizer.clone(), sampling_parameters_to_patch.max_new_tokens, &messages, has_rag_results, &post.style).await?;
+        text_tools = text_tools_for_post(gcx, at_tools, post).await?;
+    }
+    dd.text_tool_calls = !text_tools.is_empty();
+    if dd.text_tool_calls && !dd.stop_list.contains(&TOOL_RESULT_OPEN.to_string()) {
+        dd.stop_list.push(TOOL_RESULT_OPEN.to_string());
+    }
+    let tools_description = if text_tools.is_empty() { None } else { Some(text_tools_system_prompt(&text_tools)) };
+    let messages = if post.history_summarization {
+        maybe_summarize_old_history(ccx.clone(), t, messages, sampling_parameters_to_patch, n_ctx, tools_description.clone(), post.model.as_str(), &post.meta.chat_id).await
+    } else {
+        messages
+    };
+    let limited_msgs: Vec<ChatMessage> = fix_and_limit_messages_history(t, &messages, sampling_parameters_to_patch, n_ctx, tools_description, post.model.as_str())?;
+    sampling_parameters_to_patch.stop = dd.stop_list.clone();
+    Ok((limited_msgs, text_tools))
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+use async_trait::async_trait;
+use minijinja::{context, Environment, Error as JinjaError, ErrorKind as JinjaErrorKind};
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+use tokio::sync::Mutex as AMutex;
+use tracing::{info, error};
+
+use crate::at_commands::execute_at::run_at_commands_locally;
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::cached_tokenizers::ChatTemplateConfig;
+use crate::call_validation::{ChatMessage, ChatPost, ContextFile, SamplingParameters};
+use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
+use crate::scratchpads::chat_generic::text_tools_and_limited_history;
+use crate::scratchpads::chat_utils_deltadelta::DeltaDeltaChatStreamer;
+use crate::scratchpads::chat_utils_text_tools::messages_with_text_tools;
+use crate::scratchpads::scratchpad_utils::HasRagResults;
+
+
+const DEBUG: bool = true;
+
+
+// Renders the prompt with the model's own jinja chat_template (from tokenizer_config.json or the model record),
+// so a new open-weights model needs no code, only a config
+pub struct ChatJinja {
+    pub t: HasTokenizerAndEot,
+    pub dd: DeltaDeltaChatStreamer,
+    pub post: ChatPost,
+    pub messages: Vec<ChatMessage>,
+    pub template: ChatTemplateConfig,
+    pub has_rag_results: HasRagResults,
+    pub allow_at: bool,
+    pub supports_tools: bool,
+}
+
+impl ChatJinja {
+    pub fn new(
+        tokenizer: Arc<StdRwLock<Tokenizer>>,
+        post: &ChatPost,
+        messages: &Vec<ChatMessage>,
+        template: ChatTemplateConfig,
+        allow_at: bool,
+        supports_tools: bool,
+    ) -> Self {
+        ChatJinja {
+            t: HasTokenizerAndEot::new(tokenizer),
+            dd: DeltaDeltaChatStreamer::new(),
+            post: post.clone(),
+            messages: messages.clone(),
+            template,
+            has_rag_results: HasRagResults::new(),
+            allow_at,
+            supports_tools,
+        }
+    }
+}
+
+#[async_trait]
+impl ScratchpadAbstract for ChatJinja {
+    async fn apply_model_adaptation_patch(
+        &mut self,
+        patch: &Value,
+        _exploration_tools: bool,
+        _agentic_tools: bool,
+    ) -> Result<(), String> {
+        if let Some(bos_token) = patch.get("bos_token").and_then(|x| x.as_str()) {
+            self.template.bos_token = bos_token.to_string();
+        }
+        if let Some(eos_token) = patch.get("eos_token").and_then(|x| x.as_str()) {
+            self.template.eos_token = eos_token.to_string();
+        }
+        self.t.eot = patch.get("eot").and_then(|x| x.as_str()).unwrap_or(&self.template.eos_token).to_string();
+        if self.t.eot.is_empty() {
+            // chat_template from the model record comes without tokenizer_config.json, the model would never stop
+            return Err("CHAT-JINJA needs eos_token or eot in the scratchpad patch, the chat_template has no eos_token to go with it".to_string());
+        }
+        self.dd.stop_list.clear();
+        self.dd.stop_list.push(self.t.eot.clone());
+        if let Some(stop_list) = patch.get("stop_list").and_then(|x| x.as_array()) {
+            self.dd.stop_list.extend(stop_list.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()));
+        }
+        self.dd.stop_list.retain(|x| !x.is_empty());
+        Ok(())
+    }
+
+    async fn prompt(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        sampling_parameters_to_patch: &mut SamplingParameters,
+    ) -> Result<String, String> {
+        let n_ctx = ccx.lock().await.n_ctx;
+        let (messages, _any_context_produced) = if self.allow_at {
+            run_at_commands_locally(ccx.clone(), self.t.tokenizer.clone(), sampling_parameters_to_patch.max_new_tokens, &self.messages, &mut self.has_rag_results).await
+        } else {
+            (self.messages.clone(), false)
+        };
+        let (limited_msgs, text_tools) = text_tools_and_limited_history(
+            ccx.clone(), &self.t, &self.post, messages, self.supports_tools, &mut self.dd, &mut self.has_rag_results, sampling_parameters_to_patch, n_ctx
+        ).await?;
+        let template = match &self.template.tool_use_template {
+            Some(tool_use_template) if !text_tools.is_empty() => tool_use_template.clone(),
+            _ => self.template.chat_template.clone(),
+        };
+        // templates that render tools get them as is, the others get the text emulation from chat_utils_text_tools,
+        // either way the model is expected to answer with <tool_call> blocks (hermes / qwen style)
+        let (limited_msgs, tools_for_template) = if !text_tools.is_empty() && template_renders_tools(&template, &self.template.bos_token, &self.template.eos_token) {
+            (limited_msgs, Some(text_tools))
+        } else {
+            (messages_with_text_tools(&limited_msgs, &text_tools), None)
+        };
+        let prompt = render_chat_template(
+            &template,
+            &messages_for_template(&limited_msgs)?,
+            tools_for_template.as_ref(),
+            &self.template.bos_token,
+            &self.template.eos_token,
+        )?;
+        self.dd.role = "assistant".to_string();
+        if DEBUG {
+            info!("jinja chat prompt\n{}", prompt);
+            info!("jinja chat re-encode whole prompt again gives {} tokens", self.t.count_tokens(prompt.as_str())?);
+        }
+        Ok(prompt)
+    }
+
+    fn response_n_choices(
+        &mut self,
+        choices: Vec<String>,
+        finish_reasons: Vec<FinishReason>,
+    ) -> Result<Value, String> {
+        self.dd.response_n_choices(choices, finish_reasons)
+    }
+
+    fn response_streaming(
+        &mut self,
+        delta: String,
+        finish_reason: FinishReason
+    ) -> Result<(Value, FinishReason), String> {
+        self.dd.response_streaming(delta, finish_reason)
+    }
+
+    fn response_message_streaming(
+        &mut self,
+        _delta: &Value,
+        _finish_reason: FinishReason,
+    ) -> Result<(Value, FinishReason), String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_spontaneous(&mut self) -> Result<Vec<Value>, String> {
+        self.has_rag_results.response_streaming()
+    }
+
+    fn streaming_finished(&mut self, finish_reason: FinishReason) -> Result<Value, String> {
+        self.dd.streaming_finished(finish_reason)
+    }
+}
+
+// Messages in the shape HuggingFace templates expect. Our own roles turn into user messages, and consecutive
+// user messages are merged because many templates raise an exception unless user and assistant alternate
+pub fn messages_for_template(messages: &Vec<ChatMessage>) -> Result<Vec<Value>, String> {
+    let mut result: Vec<Value> = vec![];
+    let mut tool_names: HashMap<String, String> = HashMap::new();
+    let push_user = |result: &mut Vec<Value>, text: String| {
+        if let Some(last) = result.last_mut() {
+            if last["role"] == "user" {
+                last["content"] = Value::String(format!("{}\n\n{}", last["content"].as_str().unwrap_or(""), text));
+                return;
+            }
+        }
+        result.push(json!({"role": "user", "content": text}));
+    };
+    for msg in messages {
+        let text = msg.content.content_text_only();
+        match msg.role.as_str() {
+            "system" => result.push(json!({"role": "system", "content": text})),
+            "user" | "cd_instruction" | "plain_text" => push_user(&mut result, text),
+            "assistant" => {
+                let mut m = json!({"role": "assistant", "content": text});
+                if let Some(tool_calls) = msg.tool_calls.as_ref().filter(|calls| !calls.is_empty()) {
+                    m["tool_calls"] = Value::Array(tool_calls.iter().map(|call| {
+                        tool_names.insert(call.id.clone(), call.function.name.clone());
+                        let arguments = serde_json::from_str::<Value>(&call.function.arguments).unwrap_or(Value::String(call.function.arguments.clone()));
+                        json!({"id": call.id, "type": "function", "function": {"name": call.function.name, "arguments": arguments}})
+                    }).collect());
+                }
+                result.push(m);
+            },
+            "tool" | "diff" => result.push(json!({
+                "role": "tool",
+                "content": text,
+                "tool_call_id": msg.tool_call_id,
+                "name": tool_names.get(&msg.tool_call_id).cloned().unwrap_or_default(),
+            })),
+            "context_file" => {
+                let vector_of_context_files: Vec<ContextFile> = serde_json::from_str(&text)
+                    .map_err(|e| error!("parsing context_files has failed: {}; content: {}", e, &text)).unwrap_or_default();
+                for context_file in vector_of_context_files {
+                    push_user(&mut result, format!("{}:{}-{}\n```\n{}```", context_file.file_name, context_file.line1, context_file.line2, context_file.file_content));
+                }
+            },
+            _ => return Err(format!("role \"{}\" not recognized", msg.role)),
+        }
+    }
+    Ok(result)
+}
+
+// Renders a made up tool to see if the template puts tools into the prompt, mentioning "tools" is not enough
+fn template_renders_tools(template: &str, bos_token: &str, eos_token: &str) -> bool {
+    const PROBE_TOOL_NAME: &str = "refact_probe_tool_4f1c";
+    let messages = vec![json!({"role": "user", "content": "hi"})];
+    let tools = vec![json!({"type": "function", "function": {"name": PROBE_TOOL_NAME, "description": "", "parameters": {"type": "object", "properties": {}}}})];
+    render_chat_template(template, &messages, Some(&tools), bos_token, eos_token)
+        .map(|prompt| prompt.contains(PROBE_TOOL_NAME))
+        .unwrap_or(false)
+}
+
+pub fn render_chat_template(
+    template: &str,
+    messages: &Vec<Value>,
+    tools: Option<&Vec<Value>>,
+    bos_token: &str,
+    eos_token: &str,
+) -> Result<String, String> {
+    // same settings as transformers uses for chat templates
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function("raise_exception", |msg: String| -> Result<String, JinjaError> {
+        Err(JinjaError::new(JinjaErrorKind::InvalidOperation, msg))
+    });
+    env.add_function("strftime_now", |format: String| chrono::Local::now().format(&format).to_string());
+    env.add_template("chat_template", template).map_err(|e| format!("chat_template doesn't compile: {}", e))?;
+    let tmpl = env.get_template("chat_template").map_err(|e| e.to_string())?;
+    tmpl.render(context! {
+        messages => messages,
+        tools => tools,
+        bos_token => bos_token,
+        eos_token => eos_token,
+        add_generation_prompt => true,
+    }).map_err(|e| format!("chat_template rendering failed: {}", e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_validation::{ChatContent, ChatToolCall, ChatToolFunction};
+
+    const CHATML_WITH_TOOLS: &str = r#"{%- if tools %}
+<|im_start|>system
+{{ messages[0]['content'] }}
+{%- for tool in tools %}
+{{ tool | tojson }}
+{%- endfor %}<|im_end|>
+{%- endif %}
+{%- for message in messages %}
+{%- if message.role == 'system' and tools %}
+{%- elif message.role == 'tool' %}
+<|im_start|>user
+<tool_response>{{ message.content.strip() }}</tool_response><|im_end|>
+{%- else %}
+<|im_start|>{{ message.role }}
+{{ message.content }}
+{%- for tool_call in message.tool_calls or [] %}
+<tool_call>{"name": "{{ tool_call.function.name }}", "arguments": {{ tool_call.function.arguments | tojson }}}</tool_call>
+{%- endfor %}<|im_end|>
+{%- endif %}
+{%- endfor %}
+{%- if add_generation_prompt %}
+<|im_start|>assistant
+{%- endif %}"#;
+
+    #[test]
+    fn test_render_chat_template() {
+        let messages = vec![
+            ChatMessage::new("system".to_string(), "Be brief.".to_string()),
+            ChatMessage::new("user".to_string(), "show a.rs".to_string()),
+            ChatMessage {
+                role: "assistant".to_string(),
+                tool_calls: Some(vec![ChatToolCall {
+                    id: "call_1".to_string(),
+                    function: ChatToolFunction { arguments: "{\"paths\":\"a.rs\"}".to_string(), name: "cat".to_string() },
+                    tool_type: "function".to_string(),
+                }]),
+                ..Default::default()
+            },
+            ChatMessage { role: "tool".to_string(), tool_call_id: "call_1".to_string(), content: ChatContent::SimpleText(" fn main() {} ".to_string()), ..Default::default() },
+        ];
+        let tools = vec![json!({"type": "function", "function": {"name": "cat"}})];
+        let prompt = render_chat_template(CHATML_WITH_TOOLS, &messages_for_template(&messages).unwrap(), Some(&tools), "", "<|im_end|>").unwrap();
+        assert!(prompt.starts_with("<|im_start|>system\nBe brief."), "{}", prompt);
+        assert!(prompt.contains("\"name\":\"cat\""), "{}", prompt);
+        assert!(prompt.contains("<tool_call>{\"name\": \"cat\", \"arguments\": {\"paths\":\"a.rs\"}}</tool_call>"), "{}", prompt);
+        assert!(prompt.contains("<tool_response>fn main() {}</tool_response>"), "{}", prompt);
+        assert!(prompt.ends_with("<|im_start|>assistant"), "{}", prompt);
+    }
+
+    #[test]
+    fn test_raise_exception_and_merging() {
+        let template = "{% for m in messages %}{% if loop.index0 % 2 == 0 and m.role != 'user' %}{{ raise_exception('roles must alternate') }}{% endif %}[{{ m.role }}] {{ m.content }}\n{% endfor %}";
+        let messages = vec![
+            ChatMessage::new("user".to_string(), "hi".to_string()),
+            ChatMessage::new("cd_instruction".to_string(), "be nice".to_string()),
+        ];
+        let prompt = render_chat_template(template, &messages_for_template(&messages).unwrap(), None, "", "").unwrap();
+        assert_eq!(prompt, "[user] hi\n\nbe nice\n");
+        let messages = vec![ChatMessage::new("assistant".to_string(), "hello".to_string())];
+        let err = render_chat_template(template, &messages_for_template(&messages).unwrap(), None, "", "").unwrap_err();
+        assert!(err.contains("roles must alternate"), "{}", err);
+    }
+
+    #[test]
+    fn test_template_renders_tools() {
+        assert!(template_renders_tools(CHATML_WITH_TOOLS, "", "<|im_end|>"));
+        // talks about tools, but never puts them into the prompt
+        let template = "{# tools are not supported #}{% for m in messages %}{{ m.content }}{% endfor %}";
+        assert!(!template_renders_tools(template, "", ""));
+    }
+}
@@ -11,7 +11,8 @@ use crate::at_commands::at_commands::AtCommandsContext;
 use crate::call_validation::{ChatMessage, ChatPost, ContextFile, SamplingParameters};
 use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
 use crate::scratchpads::chat_utils_deltadelta::DeltaDeltaChatStreamer;
-use crate::scratchpads::chat_utils_limit_history::fix_and_limit_messages_history;
+use crate::scratchpads::chat_generic::text_tools_and_limited_history;
+use crate::scratchpads::chat_utils_text_tools::messages_with_text_tools;
 use crate::scratchpads::scratchpad_utils::HasRagResults;
 
 
@@ -29,6 +30,7 @@ pub struct ChatLlama2 {
     pub keyword_slash_s: String,
     pub has_rag_results: HasRagResults,
     pub allow_at: bool,
+    pub supports_tools: bool,
 }
 
 
@@ -38,6 +40,7 @@ impl ChatLlama2 {
         post: &ChatPost,
         messages: &Vec<ChatMessage>,
         allow_at: bool,
+        supports_tools: bool,
     ) -> Self {
         ChatLlama2 {
             t: HasTokenizerAndEot::new(tokenizer),
@@ -49,6 +52,7 @@ impl ChatLlama2 {
             // default_system_message: "".to_string(),
             has_rag_results: HasRagResults::new(),
             allow_at,
+            supports_tools,
         }
     }
 }
@@ -83,8 +87,11 @@ impl ScratchpadAbstract for ChatLlama2 {
         } else {
             (self.messages.clone(), false)
         };
-        let limited_msgs: Vec<ChatMessage> = fix_and_limit_messages_history(&self.t, &messages, sampling_parameters_to_patch, n_ctx, None, self.post.model.as_str())?;
-        sampling_parameters_to_patch.stop = self.dd.stop_list.clone();
+        // no native function calling, tools are emulated in text, see chat_utils_text_tools
+        let (limited_msgs, text_tools) = text_tools_and_limited_history(
+            ccx.clone(), &self.t, &self.post, messages, self.supports_tools, &mut self.dd, &mut self.has_rag_results, sampling_parameters_to_patch, n_ctx
+        ).await?;
+        let limited_msgs = messages_with_text_tools(&limited_msgs, &text_tools);
         // loosely adapted from https://huggingface.co/spaces/huggingface-projects/llama-2-13b-chat/blob/main/model.py#L24
         let mut prompt = "".to_string();
         prompt.push_str(self.keyword_s.as_str());
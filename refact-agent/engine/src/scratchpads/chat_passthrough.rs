@@ -14,6 +14,7 @@ use crate::http::http_get_json;
 use crate::integrations::docker::docker_container_manager::docker_container_get_host_lsp_port_to_connect;
 use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
 use crate::scratchpads::chat_utils_limit_history::fix_and_limit_messages_history;
+use crate::scratchpads::chat_utils_summarize_history::maybe_summarize_old_history;
 use crate::scratchpads::scratchpad_utils::HasRagResults;
 use crate::scratchpads::chat_utils_prompts::prepend_the_right_system_prompt_and_maybe_more_initial_messages;
 use crate::scratchpads::passthrough_convert_messages::convert_messages_to_openai_format;
@@ -195,6 +196,20 @@ impl ScratchpadAbstract for ChatPassthrough {
         } else {
             messages
         };
+        let messages = if self.post.history_summarization {
+            maybe_summarize_old_history(
+                ccx.clone(),
+                &self.t,
+                messages,
+                sampling_parameters_to_patch,
+                n_ctx,
+                big_json.get("tools").map(|x| x.to_string()),
+                self.post.model.as_str(),
+                &self.post.meta.chat_id,
+            ).await
+        } else {
+            messages
+        };
         let limited_msgs = match fix_and_limit_messages_history(
             &self.t, 
             &messages,
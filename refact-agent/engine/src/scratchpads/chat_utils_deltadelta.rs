@@ -1,5 +1,6 @@
 use serde_json::Value;
 use crate::scratchpad_abstract::FinishReason;
+use crate::scratchpads::chat_utils_text_tools::{TOOL_CALL_OPEN, parse_text_tool_calls, tool_calls_to_json};
 
 #[derive(Debug)]
 pub struct DeltaDeltaChatStreamer {
@@ -11,6 +12,10 @@ pub struct DeltaDeltaChatStreamer {
     pub finished: bool,
     pub stop_list: Vec<String>,
     pub role: String,
+    // tool calling emulation: <tool_call> blocks are not streamed as content, they arrive as tool_calls at the end
+    pub text_tool_calls: bool,
+    pub held_back: String,
+    pub tool_calls_text: String,
 }
 
 impl DeltaDeltaChatStreamer {
@@ -21,9 +26,35 @@ impl DeltaDeltaChatStreamer {
             finished: false,
             stop_list: Vec::new(),
             role: String::new(),
+            text_tool_calls: false,
+            held_back: String::new(),
+            tool_calls_text: String::new(),
         }
     }
 
+    // Lets through the text that surely is not a part of a tool call
+    fn filter_tool_calls(&mut self, text: &str) -> String {
+        if !self.text_tool_calls {
+            return text.to_string();
+        }
+        if !self.tool_calls_text.is_empty() {
+            self.tool_calls_text.push_str(text);
+            return String::new();
+        }
+        let buf = std::mem::take(&mut self.held_back) + text;
+        if let Some(open_at) = buf.find(TOOL_CALL_OPEN) {
+            self.tool_calls_text = buf[open_at..].to_string();
+            return buf[..open_at].to_string();
+        }
+        for k in (1..TOOL_CALL_OPEN.len()).rev() {
+            if buf.ends_with(&TOOL_CALL_OPEN[..k]) {
+                self.held_back = buf[buf.len() - k..].to_string();
+                return buf[..buf.len() - k].to_string();
+            }
+        }
+        buf
+    }
+
     pub fn response_n_choices(
         &mut self,
         choices: Vec<String>,
@@ -33,12 +64,20 @@ impl DeltaDeltaChatStreamer {
         let mut json_choices = Vec::<Value>::new();
         for (i, x) in choices.iter().enumerate() {
             let s = cut_result(&x, &self.stop_list);
+            let mut message = serde_json::json!({
+                "role": self.role.clone(),
+                "content": s.clone()
+            });
+            if self.text_tool_calls {
+                let (content, tool_calls) = parse_text_tool_calls(&s);
+                message["content"] = Value::String(content);
+                if !tool_calls.is_empty() {
+                    message["tool_calls"] = tool_calls_to_json(&tool_calls);
+                }
+            }
             json_choices.push(serde_json::json!({
                 "index": i,
-                "message": {
-                    "role": self.role.clone(),
-                    "content": s.clone()
-                },
+                "message": message,
                 "finish_reason": finish_reasons[i].to_string(),
             }));
         }
@@ -54,13 +93,14 @@ impl DeltaDeltaChatStreamer {
         assert!(!self.finished, "already finished");
         self.delta2 = self.delta1.clone();
         self.delta1 = delta.clone();
+        let content = self.filter_tool_calls(&self.delta2.clone());
         let json_choices;
         if !delta.is_empty() {
             json_choices = serde_json::json!([{
                 "index": 0,
                 "delta": {
                     "role": self.role.clone(),
-                    "content": self.delta2
+                    "content": content
                 },
                 "finish_reason": finish_reason.to_json_val()
             }]);
@@ -69,7 +109,7 @@ impl DeltaDeltaChatStreamer {
                 "index": 0,
                 "delta": {
                     "role": self.role.clone(),
-                    "content": self.delta2
+                    "content": content
                 },
                 "finish_reason": finish_reason.to_json_val()
             }]);
@@ -82,13 +122,24 @@ impl DeltaDeltaChatStreamer {
         self.finished = true;
         self.delta2 = self.delta1.clone();
         let leftovers = self.delta2.clone();
+        let mut delta = serde_json::json!({
+            "role": self.role.clone(),
+            "content": cut_result(&leftovers, &self.stop_list),
+        });
+        if self.text_tool_calls {
+            let mut content = self.filter_tool_calls(&cut_result(&leftovers, &self.stop_list));
+            content.push_str(&std::mem::take(&mut self.held_back));
+            let (calls_content, tool_calls) = parse_text_tool_calls(&cut_result(&self.tool_calls_text, &self.stop_list));
+            content.push_str(&calls_content);
+            delta["content"] = Value::String(content);
+            if !tool_calls.is_empty() {
+                delta["tool_calls"] = tool_calls_to_json(&tool_calls);
+            }
+        }
         Ok(serde_json::json!({
             "choices": [{
                 "index": 0,
-                "delta": {
-                    "role": self.role.clone(),
-                    "content": cut_result(&leftovers, &self.stop_list),
-                },
+                "delta": delta,
                 "finish_reason": finish_reason.to_json_val()
             }],
         }))
@@ -109,3 +160,42 @@ fn cut_result(text: &str, local_stop_list: &Vec<String>) -> String {
     let ans = text.split_at(cut_at).0.to_string();
     ans.replace("\r", "")
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_tool_calls_streaming() {
+        let mut dd = DeltaDeltaChatStreamer::new();
+        dd.role = "assistant".to_string();
+        dd.text_tool_calls = true;
+        let mut streamed = String::new();
+        for token in ["Reading", " it", " <tool", "_call>", "{\"name\": ", "\"cat\", \"arguments\": {\"paths\": \"a.rs\"}}", "</tool_call>"] {
+            let (value, _) = dd.response_streaming(token.to_string(), FinishReason::None).unwrap();
+            streamed.push_str(value["choices"][0]["delta"]["content"].as_str().unwrap());
+        }
+        let last = dd.streaming_finished(FinishReason::Stop).unwrap();
+        streamed.push_str(last["choices"][0]["delta"]["content"].as_str().unwrap());
+        assert_eq!(streamed, "Reading it ");
+        let tool_calls = last["choices"][0]["delta"]["tool_calls"].as_array().unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0]["function"]["name"], "cat");
+    }
+
+    #[test]
+    fn test_text_tool_calls_false_alarm() {
+        let mut dd = DeltaDeltaChatStreamer::new();
+        dd.text_tool_calls = true;
+        let mut streamed = String::new();
+        for token in ["a <", "b", " <tool"] {
+            let (value, _) = dd.response_streaming(token.to_string(), FinishReason::None).unwrap();
+            streamed.push_str(value["choices"][0]["delta"]["content"].as_str().unwrap());
+        }
+        let last = dd.streaming_finished(FinishReason::Stop).unwrap();
+        streamed.push_str(last["choices"][0]["delta"]["content"].as_str().unwrap());
+        assert_eq!(streamed, "a <b <tool");
+        assert!(last["choices"][0]["delta"].get("tool_calls").is_none());
+    }
+}
@@ -45,7 +45,7 @@ pub fn get_model_token_params(model_name: &str) -> (i32, f32) {
     }
 }
 
-fn recalculate_token_limits(
+pub fn recalculate_token_limits(
     token_counts: &Vec<i32>,
     tools_description_tokens: i32,
     n_ctx: usize,
@@ -395,6 +395,17 @@ fn validate_chat_history(
     Ok(messages.clone())
 }
 
+/// Providers only need the reasoning of the current turn: anthropic checks the thinking blocks of the assistant
+/// messages after the last user message (tool use loop), and ignores the older ones without counting them.
+/// So the older reasoning is not sent and doesn't take any context, the current thinking blocks are counted as a part of the message.
+pub fn drop_reasoning_of_previous_turns(messages: &mut Vec<ChatMessage>) {
+    let last_user_msg_n = messages.iter().rposition(|msg| msg.role == "user").unwrap_or(0);
+    for msg in messages.iter_mut().take(last_user_msg_n) {
+        msg.reasoning_content = None;
+        msg.thinking_blocks = None;
+    }
+}
+
 pub fn fix_and_limit_messages_history(
     t: &HasTokenizerAndEot,
     messages: &Vec<ChatMessage>,
@@ -409,6 +420,7 @@ pub fn fix_and_limit_messages_history(
         return Err(format!("bad input, n_ctx={}, max_new_tokens={}", n_ctx, sampling_parameters_to_patch.max_new_tokens));
     }
     let mut mutable_messages = messages.clone();
+    drop_reasoning_of_previous_turns(&mut mutable_messages);
     
     // STAGE 0: Compress old and duplicated ContextFiles
     // This is done before token calculation to reduce the number of messages that need to be tokenized
@@ -832,6 +844,8 @@ mod compression_tests {
             tool_call_id: tool_call_id.unwrap_or_default(),
             usage: None,
             checkpoints: Vec::new(),
+            reasoning_content: None,
+            thinking_blocks: None,
         }
     }
 
@@ -1252,6 +1266,8 @@ mod tests {
             tool_call_id: tool_call_id_str,
             usage: None,
             checkpoints: Vec::new(),
+            reasoning_content: None,
+            thinking_blocks: None,
         }
     }
 
@@ -1699,4 +1715,22 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_reasoning_of_previous_turns_is_dropped() {
+        let (mut messages, _) = create_mock_chat_history();
+        messages.push(create_test_message("assistant", "block 3 assistant response", None, None));
+        let last_user_msg_n = messages.iter().rposition(|msg| msg.role == "user").unwrap();
+        for msg in messages.iter_mut().filter(|msg| msg.role == "assistant") {
+            msg.reasoning_content = Some("thinking about it".to_string());
+            msg.thinking_blocks = Some(vec![serde_json::json!({"type": "thinking", "thinking": "thinking about it", "signature": "sig"})]);
+        }
+        let mut sampling_params = SamplingParameters { max_new_tokens: 100, ..Default::default() };
+        let limited = fix_and_limit_messages_history(&HasTokenizerAndEot::mock(), &messages, &mut sampling_params, 10000, None, "default").unwrap();
+        assert_eq!(limited.len(), messages.len());
+        for (i, msg) in limited.iter().enumerate().filter(|(_, msg)| msg.role == "assistant") {
+            assert_eq!(msg.reasoning_content.is_some(), i > last_user_msg_n, "message {}", i);
+            assert_eq!(msg.thinking_blocks.is_some(), i > last_user_msg_n, "message {}", i);
+        }
+    }
 }
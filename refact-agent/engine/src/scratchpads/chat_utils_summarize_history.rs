@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::Mutex as AMutex;
+
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ChatContent, ChatMessage, SamplingParameters};
+use crate::scratchpad_abstract::HasTokenizerAndEot;
+use crate::scratchpads::chat_utils_limit_history::{get_model_token_params, recalculate_token_limits};
+use crate::scratchpads::token_count_cache::TokenCountCache;
+
+
+const SUMMARY_PREFIX: &str = "💿 The earlier part of this chat was summarized to fit the context. The goal, tool calls and decisions so far:\n";
+const SUMMARY_CACHE_MAX_CHATS: usize = 100;
+const KEEP_LAST_MESSAGES: usize = 6;   // of the current turn, when the turn alone doesn't fit
+
+#[derive(Debug, Clone)]
+pub struct HistorySummary {
+    covered_end: usize,     // messages[start..covered_end] are replaced by the summary
+    covered_hash: u64,
+    summary: String,
+    updated_ts: std::time::Instant,
+}
+
+pub type HistorySummaryCache = HashMap<String, HistorySummary>;  // chat_id -> summary
+
+fn hash_messages(messages: &[ChatMessage]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for m in messages {
+        m.role.hash(&mut hasher);
+        m.content.content_text_only().hash(&mut hasher);
+        m.tool_call_id.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn summary_message(summary: &str) -> ChatMessage {
+    ChatMessage {
+        role: "user".to_string(),
+        content: ChatContent::SimpleText(format!("{}{}", SUMMARY_PREFIX, summary)),
+        ..Default::default()
+    }
+}
+
+// Returns the range of messages that can be summarized: after the system prompt, up to the last user message.
+// An agent working on one goal has just one turn that keeps growing, then everything but the last
+// KEEP_LAST_MESSAGES is summarized, the goal included. The cut never separates tool results from the
+// assistant message that called the tools.
+fn summarizable_range(messages: &Vec<ChatMessage>) -> Option<(usize, usize)> {
+    let start = if messages.first().map(|m| m.role == "system").unwrap_or(false) { 1 } else { 0 };
+    let last_user = messages.iter().rposition(|m| m.role == "user")?;
+    let mut cut = messages.len().saturating_sub(KEEP_LAST_MESSAGES);
+    while cut > last_user && !["user", "assistant"].contains(&messages[cut].role.as_str()) {
+        cut -= 1;
+    }
+    let end = last_user.max(cut);
+    if end < start + 2 {
+        return None;
+    }
+    Some((start, end))
+}
+
+enum SummaryInput {
+    Cached(String),
+    ToSummarize(Vec<ChatMessage>),
+}
+
+// What to send to the summarization model: either the whole range, or the previous summary plus what happened since.
+fn messages_to_summarize(
+    messages: &Vec<ChatMessage>,
+    start: usize,
+    end: usize,
+    cached: Option<&HistorySummary>,
+) -> SummaryInput {
+    if let Some(cached) = cached {
+        if cached.covered_end <= end && cached.covered_hash == hash_messages(&messages[start..cached.covered_end]) {
+            if cached.covered_end == end {
+                return SummaryInput::Cached(cached.summary.clone());
+            }
+            let mut result = vec![summary_message(&cached.summary)];
+            result.extend(messages[cached.covered_end..end].iter().cloned());
+            return SummaryInput::ToSummarize(result);
+        }
+    }
+    SummaryInput::ToSummarize(messages[start..end].to_vec())
+}
+
+// Optional stage before fix_and_limit_messages_history(): if the history doesn't fit, older turns are replaced
+// with one message summarized by a cheap model (subchat_tool_parameters.summarize_history), using the same
+// prompt as compress_trajectory. Cached per chat_id, so the agent doesn't pay for it on every step.
+// Any failure leaves the messages as is, fix_and_limit_messages_history() will drop what doesn't fit.
+pub async fn maybe_summarize_old_history(
+    ccx: Arc<AMutex<AtCommandsContext>>,
+    t: &HasTokenizerAndEot,
+    messages: Vec<ChatMessage>,
+    sampling_parameters: &SamplingParameters,
+    n_ctx: usize,
+    tools_description: Option<String>,
+    model_name: &str,
+    chat_id: &String,
+) -> Vec<ChatMessage> {
+    if chat_id.is_empty() || n_ctx <= sampling_parameters.max_new_tokens {
+        return messages;
+    }
+    let (extra_tokens_per_message, _) = get_model_token_params(model_name);
+    let mut token_cache = TokenCountCache::new();
+    let mut token_counts = vec![];
+    for m in messages.iter() {
+        match token_cache.get_token_count(m, t.tokenizer.clone(), extra_tokens_per_message) {
+            Ok(count) => token_counts.push(count),
+            Err(_) => return messages,
+        }
+    }
+    let tools_description_tokens = tools_description.map(|d| t.count_tokens(&d).unwrap_or(0)).unwrap_or(0);
+    let (occupied_tokens, tokens_limit) = recalculate_token_limits(&token_counts, tools_description_tokens, n_ctx, sampling_parameters.max_new_tokens, model_name);
+    if occupied_tokens <= tokens_limit {
+        return messages;
+    }
+    let (start, end) = match summarizable_range(&messages) {
+        Some(x) => x,
+        None => return messages,
+    };
+
+    let gcx = ccx.lock().await.global_context.clone();
+    let cache_arc = gcx.read().await.history_summary_cache.clone();
+    let cached = cache_arc.lock().await.get(chat_id).cloned();
+    let summary = match messages_to_summarize(&messages, start, end, cached.as_ref()) {
+        SummaryInput::Cached(summary) => {
+            tracing::info!("history summary for {} is taken from cache, covers {} messages", chat_id, end - start);
+            summary
+        },
+        SummaryInput::ToSummarize(to_summarize) => {
+            let params = match crate::tools::tools_execute::unwrap_subchat_params(ccx.clone(), "summarize_history").await {
+                Ok(params) => params,
+                Err(e) => {
+                    tracing::warn!("cannot summarize history: {}", e);
+                    return messages;
+                }
+            };
+            let t0 = std::time::Instant::now();
+            match crate::agentic::compress_trajectory::compress_messages(gcx.clone(), &params.subchat_model, params.subchat_n_ctx, &to_summarize).await {
+                Ok(summary) => {
+                    tracing::info!("summarized {} messages of {} using {} in {:?}", to_summarize.len(), chat_id, params.subchat_model, t0.elapsed());
+                    summary
+                },
+                Err(e) => {
+                    tracing::warn!("history summarization failed, will drop messages instead: {}", e);
+                    return messages;
+                }
+            }
+        }
+    };
+
+    {
+        let mut cache_locked = cache_arc.lock().await;
+        cache_locked.insert(chat_id.clone(), HistorySummary {
+            covered_end: end,
+            covered_hash: hash_messages(&messages[start..end]),
+            summary: summary.clone(),
+            updated_ts: std::time::Instant::now(),
+        });
+        if cache_locked.len() > SUMMARY_CACHE_MAX_CHATS {
+            if let Some(oldest) = cache_locked.iter().min_by_key(|(_, v)| v.updated_ts).map(|(k, _)| k.clone()) {
+                cache_locked.remove(&oldest);
+            }
+        }
+    }
+
+    let mut result = messages[..start].to_vec();
+    result.push(summary_message(&summary));
+    result.extend(messages[end..].iter().cloned());
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, text: &str) -> ChatMessage {
+        ChatMessage { role: role.to_string(), content: ChatContent::SimpleText(text.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn test_summarizable_range() {
+        let messages = vec![
+            msg("system", "you are helpful"),
+            msg("user", "rename my_function1"),
+            msg("assistant", "done"),
+            msg("user", "now my_function2"),
+            msg("assistant", "working on it"),
+        ];
+        assert_eq!(summarizable_range(&messages), Some((1, 3)));
+        assert_eq!(summarizable_range(&messages[..3].to_vec()), None);
+    }
+
+    #[test]
+    fn test_summarizable_range_single_goal_agent() {
+        let mut messages = vec![msg("system", "you are helpful"), msg("user", "rename my_function everywhere")];
+        for i in 0..5 {
+            messages.push(msg("assistant", &format!("calling tools {}", i)));
+            messages.push(msg("tool", "result 1"));
+            messages.push(msg("tool", "result 2"));
+        }
+        messages.push(msg("assistant", "almost done"));
+        // keeping the last 6 of 18 would start at a tool result, the cut moves to the assistant message that called it
+        assert_eq!(messages[12].role, "tool");
+        assert_eq!(summarizable_range(&messages), Some((1, 11)));
+        assert_eq!(messages[11].role, "assistant");
+
+        // a short turn stays as is
+        assert_eq!(summarizable_range(&messages[..5].to_vec()), None);
+    }
+
+    fn cached_summary(result: SummaryInput) -> Option<String> {
+        match result {
+            SummaryInput::Cached(summary) => Some(summary),
+            SummaryInput::ToSummarize(_) => None,
+        }
+    }
+
+    fn to_summarize(result: SummaryInput) -> Vec<ChatMessage> {
+        match result {
+            SummaryInput::Cached(_) => panic!("expected messages to summarize"),
+            SummaryInput::ToSummarize(messages) => messages,
+        }
+    }
+
+    #[test]
+    fn test_messages_to_summarize_uses_cache() {
+        let messages = vec![
+            msg("system", "you are helpful"),
+            msg("user", "rename my_function1"),
+            msg("assistant", "done"),
+            msg("user", "now my_function2"),
+            msg("assistant", "done too"),
+            msg("user", "and my_function3"),
+        ];
+        let cached = HistorySummary {
+            covered_end: 3,
+            covered_hash: hash_messages(&messages[1..3]),
+            summary: "[[\"goal\", \"rename my_function1\"]]".to_string(),
+            updated_ts: std::time::Instant::now(),
+        };
+        assert_eq!(cached_summary(messages_to_summarize(&messages, 1, 3, Some(&cached))), Some(cached.summary.clone()));
+
+        // two more messages happened since, summarize them on top of the previous summary
+        let incremental = to_summarize(messages_to_summarize(&messages, 1, 5, Some(&cached)));
+        assert_eq!(incremental.len(), 3);
+        assert!(incremental[0].content.content_text_only().starts_with(SUMMARY_PREFIX));
+
+        // history was edited, the cache doesn't apply
+        let mut edited = messages.clone();
+        edited[2] = msg("assistant", "something else");
+        assert_eq!(to_summarize(messages_to_summarize(&edited, 1, 5, Some(&cached))).len(), 4);
+    }
+}
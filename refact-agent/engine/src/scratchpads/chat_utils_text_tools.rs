@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use indexmap::IndexMap;
+use serde_json::{json, Value};
+use tokio::sync::RwLock as ARwLock;
+use tracing::warn;
+
+use crate::call_validation::{ChatContent, ChatMessage, ChatPost, ChatToolCall, ChatToolFunction};
+use crate::global_context::GlobalContext;
+use crate::tools::tools_description::{tool_description_list_from_yaml, Tool};
+
+
+// Tool calling emulation for models without native function calling (CHAT-GENERIC, CHAT-LLAMA2).
+//
+// The tools go into the system prompt, the model is asked to call them like this:
+//
+//   <tool_call>
+//   {"name": "cat", "arguments": {"paths": "src/main.rs"}}
+//   </tool_call>
+//
+// Several calls in a row are allowed. Calls found in the output become ordinary ChatToolCall-s, so run_tools
+// executes them as usual, and the results go back to the model as user messages:
+//
+//   <tool_result name="cat" id="call_...">
+//   ...
+//   </tool_result>
+
+pub const TOOL_CALL_OPEN: &str = "<tool_call>";
+pub const TOOL_CALL_CLOSE: &str = "</tool_call>";
+pub const TOOL_RESULT_OPEN: &str = "<tool_result";
+pub const TOOL_RESULT_CLOSE: &str = "</tool_result>";
+
+
+// Descriptions in openai style for the tools turned on in the post, empty if there are none
+pub async fn text_tools_for_post(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    at_tools: IndexMap<String, Box<dyn Tool + Send>>,
+    post: &ChatPost,
+) -> Result<Vec<Value>, String> {
+    let turned_on = post.tools.as_ref().map(|tools| tools.iter()
+        .filter_map(|x| x.get("function").and_then(|f| f.get("name")).and_then(|n| n.as_str()).map(|s| s.to_string()))
+        .collect::<Vec<String>>()
+    ).unwrap_or_default();
+    if turned_on.is_empty() {
+        return Ok(vec![]);
+    }
+    let allow_experimental = gcx.read().await.cmdline.experimental;
+    let tool_descriptions = tool_description_list_from_yaml(at_tools, Some(&turned_on), allow_experimental).await?;
+    let mut tools = tool_descriptions.into_iter().filter(|x| x.is_supported_by(&post.model)).map(|x| x.into_openai_style()).collect::<Vec<_>>();
+    for tool in tools.iter_mut() {
+        if let Some(function) = tool.get_mut("function").and_then(|f| f.as_object_mut()) {
+            function.remove("agentic");
+        }
+    }
+    Ok(tools)
+}
+
+pub fn text_tools_system_prompt(tools: &Vec<Value>) -> String {
+    let mut prompt = String::from("You have access to the tools listed below. To call a tool, write a json object with \"name\" and \"arguments\" between ");
+    prompt.push_str(&format!("{} and {} tags, for example:\n\n", TOOL_CALL_OPEN, TOOL_CALL_CLOSE));
+    prompt.push_str(&format!("{}\n{{\"name\": \"tool_name\", \"arguments\": {{\"param\": \"value\"}}}}\n{}\n\n", TOOL_CALL_OPEN, TOOL_CALL_CLOSE));
+    prompt.push_str("You can make several calls in a row. After the calls stop writing and wait, the results will come back ");
+    prompt.push_str(&format!("in {}> tags. Never write tool results yourself.\n\nTools:\n", TOOL_RESULT_OPEN));
+    for tool in tools {
+        let function = tool.get("function").unwrap_or(tool);
+        prompt.push_str(&format!("{}\n", json!({
+            "name": function.get("name").cloned().unwrap_or_default(),
+            "description": function.get("description").cloned().unwrap_or_default(),
+            "parameters": function.get("parameters").cloned().unwrap_or_default(),
+        })));
+    }
+    prompt
+}
+
+fn render_tool_call(call: &ChatToolCall) -> String {
+    let arguments = serde_json::from_str::<Value>(&call.function.arguments).unwrap_or(Value::String(call.function.arguments.clone()));
+    format!("{}\n{}\n{}", TOOL_CALL_OPEN, json!({"name": call.function.name, "arguments": arguments}), TOOL_CALL_CLOSE)
+}
+
+// Rewrites native tool calls and results into plain text the model can read: assistant calls become tags inside
+// the assistant message, results (tool and diff roles) become user messages, the system prompt gets the tool list
+pub fn messages_with_text_tools(messages: &Vec<ChatMessage>, tools: &Vec<Value>) -> Vec<ChatMessage> {
+    let mut tool_names: HashMap<String, String> = HashMap::new();  // tool_call_id -> name
+    let mut result: Vec<ChatMessage> = vec![];
+    let mut results_merged_into_last = false;
+    for msg in messages {
+        if msg.role == "assistant" && msg.tool_calls.as_ref().map_or(false, |calls| !calls.is_empty()) {
+            let calls = msg.tool_calls.clone().unwrap_or_default();
+            let mut text = msg.content.content_text_only();
+            for call in calls.iter() {
+                tool_names.insert(call.id.clone(), call.function.name.clone());
+                if !text.is_empty() {
+                    text.push_str("\n");
+                }
+                text.push_str(&render_tool_call(call));
+            }
+            result.push(ChatMessage { role: "assistant".to_string(), content: ChatContent::SimpleText(text), ..Default::default() });
+            results_merged_into_last = false;
+        } else if msg.role == "tool" || msg.role == "diff" {
+            let name = tool_names.get(&msg.tool_call_id).cloned().unwrap_or_default();
+            let text = format!("{} name=\"{}\" id=\"{}\">\n{}\n{}", TOOL_RESULT_OPEN, name, msg.tool_call_id, msg.content.content_text_only(), TOOL_RESULT_CLOSE);
+            match result.last_mut() {
+                Some(last) if results_merged_into_last => {
+                    last.content = ChatContent::SimpleText(format!("{}\n{}", last.content.content_text_only(), text));
+                },
+                _ => result.push(ChatMessage::new("user".to_string(), text)),
+            }
+            results_merged_into_last = true;
+        } else {
+            result.push(msg.clone());
+            results_merged_into_last = false;
+        }
+    }
+    if !tools.is_empty() {
+        let tools_prompt = text_tools_system_prompt(tools);
+        match result.first_mut() {
+            Some(first) if first.role == "system" => {
+                first.content = ChatContent::SimpleText(format!("{}\n\n{}", first.content.content_text_only(), tools_prompt));
+            },
+            _ => result.insert(0, ChatMessage::new("system".to_string(), tools_prompt)),
+        }
+    }
+    result
+}
+
+// Splits the model output into plain content and tool calls, a call that doesn't parse stays in the content.
+// A missing closing tag at the very end is fine, the model might have stopped right there.
+pub fn parse_text_tool_calls(text: &str) -> (String, Vec<ChatToolCall>) {
+    let mut content = String::new();
+    let mut calls = vec![];
+    let mut rest = text;
+    while let Some(open_at) = rest.find(TOOL_CALL_OPEN) {
+        content.push_str(&rest[..open_at]);
+        let after_open = &rest[open_at + TOOL_CALL_OPEN.len()..];
+        let (inside, after) = match after_open.find(TOOL_CALL_CLOSE) {
+            Some(close_at) => (&after_open[..close_at], &after_open[close_at + TOOL_CALL_CLOSE.len()..]),
+            None => (after_open, ""),
+        };
+        match parse_one_call(inside) {
+            Some(call) => calls.push(call),
+            None => {
+                warn!("cannot parse a tool call from the model output: {:?}", inside);
+                content.push_str(&rest[open_at..rest.len() - after.len()]);
+            }
+        }
+        rest = after;
+    }
+    content.push_str(rest);
+    (content.trim().to_string(), calls)
+}
+
+fn parse_one_call(inside: &str) -> Option<ChatToolCall> {
+    let j: Value = serde_json::from_str(inside.trim()).ok()?;
+    let name = j.get("name")?.as_str()?.to_string();
+    let arguments = match j.get("arguments") {
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+        None => "{}".to_string(),
+    };
+    Some(ChatToolCall {
+        id: format!("call_{}", uuid::Uuid::new_v4().simple()),
+        function: ChatToolFunction { arguments, name },
+        tool_type: "function".to_string(),
+    })
+}
+
+// Tool calls in the shape an openai-style client accumulates from delta.tool_calls or message.tool_calls
+pub fn tool_calls_to_json(calls: &Vec<ChatToolCall>) -> Value {
+    Value::Array(calls.iter().enumerate().map(|(i, call)| json!({
+        "index": i,
+        "id": call.id,
+        "type": call.tool_type,
+        "function": {"name": call.function.name, "arguments": call.function.arguments},
+    })).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_text_tool_calls() {
+        let text = "Let me look.\n<tool_call>\n{\"name\": \"cat\", \"arguments\": {\"paths\": \"a.rs\"}}\n</tool_call>\n<tool_call>{\"name\": \"tree\"}";
+        let (content, calls) = parse_text_tool_calls(text);
+        assert_eq!(content, "Let me look.");
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].function.name, "cat");
+        assert_eq!(serde_json::from_str::<Value>(&calls[0].function.arguments).unwrap(), json!({"paths": "a.rs"}));
+        assert_eq!(calls[1].function.name, "tree");
+        assert_eq!(calls[1].function.arguments, "{}");
+
+        let (content, calls) = parse_text_tool_calls("<tool_call>not json</tool_call> done");
+        assert!(calls.is_empty());
+        assert_eq!(content, "<tool_call>not json</tool_call> done");
+    }
+
+    #[test]
+    fn test_messages_with_text_tools() {
+        let call = ChatToolCall {
+            id: "call_1".to_string(),
+            function: ChatToolFunction { arguments: "{\"paths\":\"a.rs\"}".to_string(), name: "cat".to_string() },
+            tool_type: "function".to_string(),
+        };
+        let messages = vec![
+            ChatMessage::new("system".to_string(), "You are a bot.".to_string()),
+            ChatMessage::new("user".to_string(), "show a.rs".to_string()),
+            ChatMessage { role: "assistant".to_string(), tool_calls: Some(vec![call]), ..Default::default() },
+            ChatMessage { role: "tool".to_string(), tool_call_id: "call_1".to_string(), content: ChatContent::SimpleText("fn main() {}".to_string()), ..Default::default() },
+            ChatMessage { role: "diff".to_string(), tool_call_id: "call_2".to_string(), content: ChatContent::SimpleText("[]".to_string()), ..Default::default() },
+        ];
+        let tools = vec![json!({"type": "function", "function": {"name": "cat", "description": "Show files", "parameters": {}}})];
+        let converted = messages_with_text_tools(&messages, &tools);
+        let roles = converted.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert!(converted[0].content.content_text_only().contains("\"name\":\"cat\""));
+        let (_, calls) = parse_text_tool_calls(&converted[2].content.content_text_only());
+        assert_eq!(calls[0].function.name, "cat");
+        assert!(converted[2].tool_calls.is_none());
+        let results = converted[3].content.content_text_only();
+        assert!(results.contains("<tool_result name=\"cat\" id=\"call_1\">\nfn main() {}\n</tool_result>"));
+        assert!(results.contains("id=\"call_2\""));
+    }
+}
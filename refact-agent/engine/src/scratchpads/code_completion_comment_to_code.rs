@@ -0,0 +1,395 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+use std::time::Instant;
+use async_trait::async_trait;
+use ropey::Rope;
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+use tokio::sync::Mutex as AMutex;
+use tokio::sync::RwLock as ARwLock;
+use tracing::{info, warn};
+
+use crate::ast::ast_db::doc_defs;
+use crate::ast::ast_indexer_thread::{ast_indexer_block_until_finished, ast_indexer_enqueue_files, AstIndexService};
+use crate::ast::ast_structs::AstDefinition;
+use crate::ast::treesitter::structs::SymbolType;
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ChatContent, ChatMessage, CodeCompletionPost, CursorPosition, SamplingParameters};
+use crate::completion_cache;
+use crate::global_context::GlobalContext;
+use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
+use crate::scratchpads::code_completion_replace::{prepare_cursor_file, retrieve_a_comment, unfence_the_last_code_block};
+use crate::scratchpads::completon_rag::retrieve_ast_based_extra_context;
+use crate::telemetry::snippets_collection;
+use crate::telemetry::telemetry_structs;
+
+
+// Comment-to-code: the user writes an instruction comment, for example "// refact: implement retry with backoff",
+// puts the cursor on the line below and asks for the implementation. Unlike FIM it goes to the chat model,
+// the prompt has the file skeleton, the file around the cursor and the AST context, the answer is multi-line.
+
+const DEBUG: bool = false;
+const SYSTEM_PROMPT: &str = r#"You are given a skeleton of a code file, the code around the <CURSOR> in that file, an extra context from other files, and an instruction.
+Write the code that implements the instruction, it will be inserted at the <CURSOR>.
+Use the functions and types that already exist in the file and in the context, follow the code style of the file.
+Output a single fenced code block that contains only the new code, do not repeat the instruction comment or the code around the <CURSOR>.
+Keep the indentation as it should be at the <CURSOR>.
+Instruction:
+<instruction>"#;
+const INSTRUCTION_PREFIX: &str = "refact:";
+const SKELETON_MAX_TOKENS: usize = 1024;
+const SUBBLOCK_MIN_TOKENS: usize = 128;
+const MAX_NEW_TOKENS: usize = 2048;
+const TEMPERATURE_INITIAL: f32 = 0.0;
+const TEMPERATURE_NOCACHE: f32 = 0.5;
+
+
+pub fn instruction_comment_above_cursor(source: &String, cpath: &PathBuf, cursor: &CursorPosition) -> Option<String> {
+    let comment = retrieve_a_comment(source, cpath, cursor)?;
+    let instruction = comment
+        .lines()
+        .map(|line| {
+            let mut line = line.trim();
+            for delimiter in ["<!--", "-->", "/*", "*/", "//", "\"\"\"", "'''", "--", "#", "*"] {
+                line = line.strip_prefix(delimiter).unwrap_or(line).trim();
+                line = line.strip_suffix(delimiter).unwrap_or(line).trim();
+            }
+            line.strip_prefix(INSTRUCTION_PREFIX).unwrap_or(line).trim().to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if instruction.is_empty() {
+        None
+    } else {
+        Some(instruction)
+    }
+}
+
+fn skeleton_from_defs(defs: &Vec<Arc<AstDefinition>>, text: &Rope) -> String {
+    let mut defs = defs
+        .iter()
+        .filter(|d| d.symbol_type == SymbolType::StructDeclaration || d.symbol_type == SymbolType::FunctionDeclaration)
+        .collect::<Vec<_>>();
+    defs.sort_by_key(|d| d.decl_line1);
+    let mut skeleton = String::new();
+    for d in defs {
+        let mut indent = String::new();
+        for idx in d.decl_line1.saturating_sub(1)..d.decl_line2.min(text.len_lines()) {
+            let line = text.line(idx).to_string().replace("\r", "");
+            if idx + 1 == d.decl_line1 {
+                indent = line.chars().take_while(|c| c.is_whitespace()).collect();
+            }
+            skeleton.push_str(line.trim_end());
+            skeleton.push('\n');
+        }
+        if d.symbol_type == SymbolType::FunctionDeclaration && d.body_line2 > d.decl_line2 {
+            skeleton.push_str(&format!("{}    ...\n", indent));
+        }
+    }
+    skeleton
+}
+
+// The answer is a fenced block, the instruction comment and the <CURSOR> marker the model sometimes repeats are removed,
+// the editor already has the indentation of the cursor line so the first line comes without it
+pub fn implementation_from_answer(answer: &String, comment_lines: &Vec<String>, text_before_cursor: &str) -> Option<String> {
+    let code = unfence_the_last_code_block(answer)?.replace("\r", "").replace("<CURSOR>", "");
+    let mut lines = code.lines().map(|x| x.to_string()).collect::<Vec<_>>();
+    while let Some(first) = lines.first() {
+        if first.trim().is_empty() || comment_lines.iter().any(|c| c.trim() == first.trim()) {
+            lines.remove(0);
+        } else {
+            break;
+        }
+    }
+    while lines.last().map(|x| x.trim().is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return None;
+    }
+    lines[0] = if text_before_cursor.trim().is_empty() && lines[0].starts_with(text_before_cursor) {
+        lines[0][text_before_cursor.len()..].to_string()
+    } else {
+        lines[0].trim_start().to_string()
+    };
+    Some(lines.join("\n"))
+}
+
+pub struct CodeCompletionCommentToCodeScratchpad {
+    pub t: HasTokenizerAndEot,
+    pub post: CodeCompletionPost,
+    pub comment_lines: Vec<String>,
+    pub context_used: Value,
+    pub data4cache: completion_cache::CompletionSaveToCache,
+    pub data4snippet: snippets_collection::SaveSnippet,
+    pub ast_service: Option<Arc<AMutex<AstIndexService>>>,
+    pub global_context: Arc<ARwLock<GlobalContext>>,
+}
+
+impl CodeCompletionCommentToCodeScratchpad {
+    pub fn new(
+        tokenizer: Arc<StdRwLock<Tokenizer>>,
+        post: &CodeCompletionPost,
+        cache_arc: Arc<StdRwLock<completion_cache::CompletionCache>>,
+        tele_storage: Arc<StdRwLock<telemetry_structs::Storage>>,
+        ast_service: Option<Arc<AMutex<AstIndexService>>>,
+        global_context: Arc<ARwLock<GlobalContext>>,
+    ) -> Self {
+        let data4cache = completion_cache::CompletionSaveToCache::new(cache_arc, &post);
+        let data4snippet = snippets_collection::SaveSnippet::new(tele_storage, &post);
+        CodeCompletionCommentToCodeScratchpad {
+            t: HasTokenizerAndEot::new(tokenizer),
+            post: post.clone(),
+            comment_lines: vec![],
+            context_used: json!({}),
+            data4cache,
+            data4snippet,
+            ast_service,
+            global_context,
+        }
+    }
+
+    async fn file_skeleton(&self, cpath: &PathBuf, text: &Rope) -> String {
+        let ast_service = match &self.ast_service {
+            Some(ast_service) => ast_service.clone(),
+            None => return String::new(),
+        };
+        let cpath_str = cpath.to_string_lossy().to_string();
+        ast_indexer_enqueue_files(ast_service.clone(), &vec![cpath_str.clone()], true).await;
+        ast_indexer_block_until_finished(ast_service.clone(), 20, true).await;
+        let ast_index = ast_service.lock().await.ast_index.clone();
+        skeleton_from_defs(&doc_defs(ast_index, &cpath_str).await, text)
+    }
+}
+
+#[async_trait]
+impl ScratchpadAbstract for CodeCompletionCommentToCodeScratchpad {
+    async fn apply_model_adaptation_patch(
+        &mut self,
+        patch: &Value,
+        _exploration_tools: bool,
+        _agentic_tools: bool,
+    ) -> Result<(), String> {
+        self.t.rag_ratio = patch
+            .get("rag_ratio")
+            .and_then(|x| x.as_f64())
+            .unwrap_or(0.5);
+        Ok(())
+    }
+
+    async fn prompt(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        sampling_parameters_to_patch: &mut SamplingParameters,
+    ) -> Result<String, String> {
+        let n_ctx = ccx.lock().await.n_ctx;
+        let completion_t0 = Instant::now();
+        let use_rag = self.t.rag_ratio > 0.0 && self.ast_service.is_some();
+        sampling_parameters_to_patch.max_new_tokens = MAX_NEW_TOKENS;
+        sampling_parameters_to_patch.temperature = if !self.post.no_cache { Some(TEMPERATURE_INITIAL) } else { Some(TEMPERATURE_NOCACHE) };
+        sampling_parameters_to_patch.stop = vec![];
+        let cpath = crate::files_correction::canonical_path(&self.post.inputs.cursor.file);
+        let source = self.post.inputs.sources.get(&self.post.inputs.cursor.file)
+            .ok_or("Cursor is in file not found in sources".to_string())?
+            .clone();
+        let instruction = instruction_comment_above_cursor(&source, &cpath, &self.post.inputs.cursor)
+            .ok_or("no instruction comment right above the cursor".to_string())?;
+        self.comment_lines = retrieve_a_comment(&source, &cpath, &self.post.inputs.cursor)
+            .unwrap_or_default()
+            .lines()
+            .map(|x| x.to_string())
+            .collect();
+
+        let mut messages = vec![ChatMessage {
+            role: "system".to_string(),
+            content: ChatContent::SimpleText(SYSTEM_PROMPT.replace("<instruction>", &instruction)),
+            ..Default::default()
+        }];
+        let mut available_tokens = n_ctx.saturating_sub(
+            self.t.count_tokens(&messages[0].content.content_text_only())? as usize + MAX_NEW_TOKENS,
+        );
+
+        let text = Rope::from_str(&source);
+        let skeleton = self.file_skeleton(&cpath, &text).await;
+        let skeleton_tokens = self.t.count_tokens(&skeleton).unwrap_or(0) as usize;
+        let skeleton = if !skeleton.is_empty() && skeleton_tokens <= SKELETON_MAX_TOKENS.min(available_tokens / 4) {
+            available_tokens = available_tokens.saturating_sub(skeleton_tokens);
+            skeleton
+        } else {
+            String::new()
+        };
+        let rag_tokens_n = if use_rag {
+            let rag_tokens_n = if self.post.rag_tokens_n > 0 {
+                self.post.rag_tokens_n
+            } else {
+                ((available_tokens as f64 * self.t.rag_ratio) as usize).max(50)
+            };
+            available_tokens = available_tokens.saturating_sub(rag_tokens_n);
+            rag_tokens_n
+        } else {
+            0
+        };
+        if available_tokens <= SUBBLOCK_MIN_TOKENS {
+            return Err(format!("not enough tokens for the cursor file: {available_tokens} <= {SUBBLOCK_MIN_TOKENS}"));
+        }
+
+        // the cursor file goes with the <CURSOR> marker in it, so the model sees where the code lands
+        let cursor = &self.post.inputs.cursor;
+        let cursor_char = text.line_to_char(cursor.line as usize) + cursor.character as usize;
+        let mut text_with_cursor = text.clone();
+        text_with_cursor.insert(cursor_char, "<CURSOR>");
+        let (file_content, _file_content_tokens_count, (line1, line2)) = prepare_cursor_file(
+            &self.t,
+            available_tokens,
+            &cpath,
+            &text_with_cursor,
+            cursor,
+        )?;
+        if use_rag {
+            let pp_settings = ccx.lock().await.postprocess_parameters.clone();
+            let extra_context = retrieve_ast_based_extra_context(
+                self.global_context.clone(),
+                self.ast_service.clone(),
+                &self.t,
+                &cpath,
+                cursor,
+                (line1 as i32, line2 as i32),
+                pp_settings,
+                rag_tokens_n,
+                vec![],
+                &mut self.context_used,
+            ).await;
+            if !extra_context.is_empty() {
+                messages.push(ChatMessage {
+                    role: "user".to_string(),
+                    content: ChatContent::SimpleText(extra_context),
+                    ..Default::default()
+                });
+            }
+        }
+        let mut user_message = String::new();
+        if !skeleton.is_empty() {
+            user_message.push_str(&format!("Skeleton of {}:\n```\n{}```\n", cpath.to_string_lossy(), skeleton));
+        }
+        user_message.push_str(&file_content);
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: ChatContent::SimpleText(user_message),
+            ..Default::default()
+        });
+
+        let json_messages = serde_json::to_string(&json!({
+            "messages": messages.iter().map(|x| x.into_value(&None)).collect::<Vec<_>>(),
+        })).unwrap();
+        let prompt = format!("PASSTHROUGH {json_messages}");
+
+        let completion_ms = completion_t0.elapsed().as_millis() as i32;
+        self.context_used["fim_ms"] = Value::from(completion_ms);
+        self.context_used["n_ctx"] = Value::from(n_ctx as i64);
+        self.context_used["rag_tokens_limit"] = Value::from(rag_tokens_n as i64);
+        self.context_used["skeleton_tokens"] = Value::from(if skeleton.is_empty() { 0 } else { skeleton_tokens as i64 });
+        info!(" -- /post comment-to-code {}ms-- ", completion_ms);
+        if DEBUG {
+            info!("comment-to-code prompt\n{}", prompt);
+        }
+        Ok(prompt)
+    }
+
+    fn response_n_choices(
+        &mut self,
+        _choices: Vec<String>,
+        _finish_reasons: Vec<FinishReason>,
+    ) -> Result<Value, String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_streaming(
+        &mut self,
+        _delta: String,
+        _finish_reason: FinishReason,
+    ) -> Result<(Value, FinishReason), String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_message_n_choices(
+        &mut self,
+        choices: Vec<String>,
+        finish_reasons: Vec<FinishReason>,
+    ) -> Result<Value, String> {
+        let text_before_cursor = self.post.inputs.sources.get(&self.post.inputs.cursor.file)
+            .and_then(|source| Rope::from_str(source).get_line(self.post.inputs.cursor.line as usize).map(|x| x.to_string()))
+            .map(|line| line.chars().take(self.post.inputs.cursor.character as usize).collect::<String>())
+            .unwrap_or_default();
+        let json_choices = choices.iter().enumerate().map(|(i, x)| {
+            let code = implementation_from_answer(x, &self.comment_lines, &text_before_cursor).unwrap_or_else(|| {
+                warn!("no code block found in the comment-to-code answer, return an empty completion");
+                String::new()
+            });
+            if i == 0 {
+                self.data4cache.completion0_text = code.clone();
+                self.data4cache.completion0_finish_reason = finish_reasons[i].to_string();
+            }
+            json!({
+                "index": i,
+                "code_completion": code,
+                "finish_reason": finish_reasons[i].to_json_val(),
+            })
+        }).collect::<Vec<_>>();
+        snippets_collection::snippet_register_from_data4cache(
+            &self.data4snippet,
+            &mut self.data4cache,
+            &self.context_used,
+        );
+        Ok(json!({
+            "choices": json_choices,
+            "snippet_telemetry_id": self.data4cache.completion0_snippet_telemetry_id,
+            "model": self.post.model.clone(),
+            "context": self.context_used,
+        }))
+    }
+
+    fn response_message_streaming(
+        &mut self,
+        _json: &Value,
+        _finish_reason: FinishReason,
+    ) -> Result<(Value, FinishReason), String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_spontaneous(&mut self) -> Result<Vec<Value>, String> {
+        Ok(vec![])
+    }
+
+    fn streaming_finished(&mut self, _finish_reason: FinishReason) -> Result<Value, String> {
+        Err("not implemented".to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_comment_above_cursor() {
+        let source = "fn main() {\n    // refact: implement retry\n    // with backoff\n    \n}\n".to_string();
+        let cursor = CursorPosition { file: "a.rs".to_string(), line: 3, character: 4 };
+        let instruction = instruction_comment_above_cursor(&source, &PathBuf::from("a.rs"), &cursor);
+        assert_eq!(instruction, Some("implement retry\nwith backoff".to_string()));
+        let cursor_far = CursorPosition { file: "a.rs".to_string(), line: 4, character: 0 };
+        assert_eq!(instruction_comment_above_cursor(&source, &PathBuf::from("a.rs"), &cursor_far), None);
+    }
+
+    #[test]
+    fn test_implementation_from_answer() {
+        let answer = "Here you go:\n```rust\n    // refact: sum the values\n    let s = a + b;\n    s\n```\n".to_string();
+        let comment_lines = vec!["// refact: sum the values".to_string()];
+        assert_eq!(
+            implementation_from_answer(&answer, &comment_lines, "    "),
+            Some("let s = a + b;\n    s".to_string())
+        );
+        assert_eq!(implementation_from_answer(&"no code".to_string(), &comment_lines, ""), None);
+    }
+}
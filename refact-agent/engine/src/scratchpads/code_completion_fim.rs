@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::RwLock as StdRwLock;
 use std::time::Instant;
@@ -11,11 +12,12 @@ use tokio::sync::RwLock as ARwLock;
 use tracing::info;
 use crate::ast::ast_indexer_thread::AstIndexService;
 use crate::at_commands::at_commands::AtCommandsContext;
-use crate::call_validation::{CodeCompletionPost, SamplingParameters};
+use crate::call_validation::{CodeCompletionPost, CursorPosition, SamplingParameters};
 use crate::global_context::GlobalContext;
 use crate::completion_cache;
 use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
-use crate::scratchpads::completon_rag::retrieve_ast_based_extra_context;
+use crate::scratchpads::completon_rag::{retrieve_ast_based_extra_context, repo_name_and_cursor_path, retrieve_repo_level_context, retrieve_vecdb_context_files, vecdb_query_around_cursor, vecdb_rag_enabled, VECDB_LATENCY_MS};
+use crate::scratchpads::syntax_truncation::truncate_by_syntax;
 use crate::telemetry::snippets_collection;
 use crate::telemetry::telemetry_structs;
 
@@ -30,6 +32,8 @@ pub struct FillInTheMiddleScratchpad {
     pub fim_suffix: String,
     pub fim_middle: String,
     pub extra_stop_tokens: Vec<String>,
+    pub vecdb_latency_ms: u64,
+    pub repo_level_tokens: Option<(String, String)>,  // FIM-REPO: (repo_name_token, file_sep_token)
     pub context_used: Value,
     pub data4cache: completion_cache::CompletionSaveToCache,
     pub data4snippet: snippets_collection::SaveSnippet,
@@ -57,6 +61,8 @@ impl FillInTheMiddleScratchpad {
             fim_suffix: String::new(),
             fim_middle: String::new(),
             extra_stop_tokens: vec![],
+            vecdb_latency_ms: VECDB_LATENCY_MS,
+            repo_level_tokens: None,
             context_used: json!({}),
             data4cache,
             data4snippet,
@@ -66,11 +72,15 @@ impl FillInTheMiddleScratchpad {
     }
 
     fn cleanup_prompt(&mut self, text: &String) -> String {
-        text.replace(&self.fim_prefix, "")
+        let mut text = text.replace(&self.fim_prefix, "")
             .replace(&self.fim_middle, "")
             .replace(&self.fim_suffix, "")
             .replace(&self.t.eos, "")
-            .replace(&self.t.eot, "")
+            .replace(&self.t.eot, "");
+        if let Some((repo_name_token, file_sep_token)) = &self.repo_level_tokens {
+            text = text.replace(repo_name_token, "").replace(file_sep_token, "");
+        }
+        text
     }
 }
 
@@ -91,6 +101,7 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
         self.t.eos = patch.get("eos").and_then(|x| x.as_str()).unwrap_or("").to_string();
         self.t.context_format = patch.get("context_format").and_then(|x| x.as_str()).unwrap_or_default().to_string();
         self.t.rag_ratio = patch.get("rag_ratio").and_then(|x| x.as_f64()).unwrap_or(0.5);
+        self.vecdb_latency_ms = patch.get("vecdb_latency_ms").and_then(|x| x.as_u64()).unwrap_or(VECDB_LATENCY_MS);
         self.t.assert_one_token(&self.fim_prefix.as_str())?;
         self.t.assert_one_token(&self.fim_suffix.as_str())?;
         self.t.assert_one_token(&self.fim_middle.as_str())?;
@@ -98,6 +109,10 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
         if !self.t.eos.is_empty() {
             self.t.assert_one_token(&self.t.eos.as_str())?;
         }
+        if let Some((repo_name_token, file_sep_token)) = &self.repo_level_tokens {
+            self.t.assert_one_token(repo_name_token.as_str())?;
+            self.t.assert_one_token(file_sep_token.as_str())?;
+        }
         Ok(())
     }
 
@@ -108,7 +123,11 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
     ) -> Result<String, String> {
         let n_ctx = ccx.lock().await.n_ctx;
         let fim_t0 = Instant::now();
-        let use_rag = !self.t.context_format.is_empty() && self.t.rag_ratio > 0.0 && self.post.use_ast && self.ast_service.is_some();
+        let use_ast = self.post.use_ast && self.ast_service.is_some();
+        let use_vecdb = vecdb_rag_enabled(self.global_context.clone(), self.post.use_vecdb).await;
+        // the repo-level format has its own way to show context files, context_format is for comments
+        let has_context_format = !self.t.context_format.is_empty() || self.repo_level_tokens.is_some();
+        let use_rag = has_context_format && self.t.rag_ratio > 0.0 && (use_ast || use_vecdb);
         let mut rag_tokens_n = if self.post.rag_tokens_n > 0 {
             self.post.rag_tokens_n.min(4096).max(50)
         } else {
@@ -118,7 +137,7 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
             rag_tokens_n = 0;
         }
         if !use_rag && self.post.use_ast {
-            tracing::warn!("will not use ast because {}{}{}{}", self.t.context_format.is_empty() as i32, self.post.use_ast as i32, (rag_tokens_n > 0) as i32, self.ast_service.is_some() as i32);
+            tracing::warn!("will not use ast because {}{}{}{}", !has_context_format as i32, self.post.use_ast as i32, (rag_tokens_n > 0) as i32, self.ast_service.is_some() as i32);
         }
 
         let limit: i32 = (n_ctx as i32) - (self.post.parameters.max_new_tokens as i32) - (rag_tokens_n as i32);
@@ -138,6 +157,10 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
                 stop_list.push("\n".to_string());  // This doesn't stop hf inference, only whole tokens do
             }
             stop_list.extend(self.extra_stop_tokens.clone());
+            if let Some((repo_name_token, file_sep_token)) = &self.repo_level_tokens {
+                stop_list.push(repo_name_token.clone());
+                stop_list.push(file_sep_token.clone());
+            }
             sampling_parameters_to_patch.stop = stop_list;
         }
         let mut source = self.post.inputs.sources.get(
@@ -241,23 +264,73 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
         info!(" -- /post fim {}ms-- ", fim_ms);
 
 
-        if use_rag && rag_tokens_n > 0 {
+        // cursors of a batch request share ccx, a cursor at the same place with the same FIM window doesn't
+        // run RAG again
+        let rag_memo_key = rag_memo_key(&cpath, pos, (fim_line1, fim_line2), rag_tokens_n);
+        let rag_memo = if use_rag && rag_tokens_n > 0 {
+            ccx.lock().await.completion_rag_memo.get(&rag_memo_key).cloned()
+        } else {
+            None
+        };
+        if let Some((extra_context, rag_context_used)) = rag_memo {
+            // attached_files and the rest of what RAG reported, the fields of this request stay as they are
+            for (k, v) in rag_context_used.as_object().into_iter().flatten() {
+                if self.context_used.get(k).is_none() {
+                    self.context_used[k] = v.clone();
+                }
+            }
+            self.context_used["rag_shared"] = Value::from(true);
+            prompt = format!("{extra_context}{prompt}");
+        } else if use_rag && rag_tokens_n > 0 {
             let pp_settings = {
                 let ccx_locked = ccx.lock().await;
                 ccx_locked.postprocess_parameters.clone()
             };
-            let extra_context = retrieve_ast_based_extra_context(
-                self.global_context.clone(),
-                self.ast_service.clone(),
-                &self.t,
-                &cpath,
-                &pos,
-                (fim_line1, fim_line2),
-                pp_settings,
-                rag_tokens_n,
-                &mut self.context_used
-            ).await;
+            let vecdb_context_files = if use_vecdb {
+                retrieve_vecdb_context_files(
+                    ccx.clone(),
+                    &vecdb_query_around_cursor(&text, &pos),
+                    &cpath,
+                    (fim_line1, fim_line2),
+                    self.vecdb_latency_ms,
+                    &mut self.context_used
+                ).await
+            } else {
+                vec![]
+            };
+            let extra_context = if let Some(repo_tokens) = &self.repo_level_tokens {
+                retrieve_repo_level_context(
+                    self.global_context.clone(),
+                    if use_ast { self.ast_service.clone() } else { None },
+                    &self.t,
+                    &cpath,
+                    &pos,
+                    pp_settings,
+                    rag_tokens_n,
+                    vecdb_context_files,
+                    repo_tokens,
+                    &mut self.context_used
+                ).await
+            } else {
+                retrieve_ast_based_extra_context(
+                    self.global_context.clone(),
+                    if use_ast { self.ast_service.clone() } else { None },
+                    &self.t,
+                    &cpath,
+                    &pos,
+                    (fim_line1, fim_line2),
+                    pp_settings,
+                    rag_tokens_n,
+                    vecdb_context_files,
+                    &mut self.context_used
+                ).await
+            };
+            ccx.lock().await.completion_rag_memo.insert(rag_memo_key, (extra_context.clone(), self.context_used.clone()));
             prompt = format!("{extra_context}{prompt}");
+        } else if let Some((repo_name_token, file_sep_token)) = &self.repo_level_tokens {
+            // no context files, but the model still expects to see the path of the file it completes
+            let (repo_name, cursor_filepath_stripped) = repo_name_and_cursor_path(self.global_context.clone(), &cpath).await;
+            prompt = format!("{repo_name_token}{repo_name}\n{file_sep_token}{cursor_filepath_stripped}\n{prompt}");
         }
 
         if DEBUG {
@@ -275,7 +348,12 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
         finish_reasons: Vec<FinishReason>
     ) -> Result<Value, String> {
         let json_choices = choices.iter().enumerate().map(|(i, x)| {
-            let cc = _cut_result(&x, self.t.eot.as_str(), self.post.inputs.multiline, &self.extra_stop_tokens);
+            let mut cc = _cut_result(&x, self.t.eot.as_str(), self.post.inputs.multiline, &self.extra_stop_tokens);
+            if self.post.inputs.multiline {
+                if let Some(source) = self.post.inputs.sources.get(&self.post.inputs.cursor.file) {
+                    cc = truncate_by_syntax(&PathBuf::from(&self.post.inputs.cursor.file), source, &self.post.inputs.cursor, &cc);
+                }
+            }
             if i==0 {
                 self.data4cache.completion0_text = cc.clone();
                 self.data4cache.completion0_finish_reason = finish_reasons[i].to_string();
@@ -289,7 +367,7 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
         if DEBUG {
             info!("response_n_choices\n{:?}", json_choices);
         }
-        snippets_collection::snippet_register_from_data4cache(&self.data4snippet, &mut self.data4cache, self.context_used != json!({}));
+        snippets_collection::snippet_register_from_data4cache(&self.data4snippet, &mut self.data4cache, &self.context_used);
         Ok(json!(
             {
                 "choices": json_choices,
@@ -325,7 +403,7 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
             }])
         };
         self.data4cache.completion0_finish_reason = finish_reason.to_string();
-        snippets_collection::snippet_register_from_data4cache(&self.data4snippet, &mut self.data4cache, self.context_used != json!({}));
+        snippets_collection::snippet_register_from_data4cache(&self.data4snippet, &mut self.data4cache, &self.context_used);
         Ok((json!({
             "choices": json_choices,
             "snippet_telemetry_id": self.data4cache.completion0_snippet_telemetry_id,
@@ -346,7 +424,7 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
 
     fn streaming_finished(&mut self, finish_reason: FinishReason) -> Result<Value, String> {
         self.data4cache.completion0_finish_reason = finish_reason.to_string();
-        snippets_collection::snippet_register_from_data4cache(&self.data4snippet, &mut self.data4cache, self.context_used != json!({}));
+        snippets_collection::snippet_register_from_data4cache(&self.data4snippet, &mut self.data4cache, &self.context_used);
         Ok(json!({
             "choices": [{
                 "index": 0,
@@ -358,6 +436,11 @@ impl ScratchpadAbstract for FillInTheMiddleScratchpad {
     }
 }
 
+// RAG depends on the cursor (AST symbols around it, the vecdb query) and on the FIM window that is excluded from it
+fn rag_memo_key(cpath: &PathBuf, pos: &CursorPosition, fim_range: (i32, i32), rag_tokens_n: usize) -> String {
+    format!("{}:{}:{}:{}-{}:{}", cpath.display(), pos.line, pos.character, fim_range.0, fim_range.1, rag_tokens_n)
+}
+
 fn _cut_result(text: &str, eot_token: &str, multiline: bool, extra_stop_tokens: &Vec<String>) -> String {
     let mut cut_at = vec![];
     if let Some(x) = text.find(eot_token) {
@@ -386,3 +469,21 @@ fn _cut_result(text: &str, eot_token: &str, multiline: bool, extra_stop_tokens:
     let ans = text.split_at(cut_at).0.to_string();
     ans.replace("\r", "")
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rag_memo_key_depends_on_cursor() {
+        let cpath = PathBuf::from("/p/a.py");
+        let cursor = |line: i32| CursorPosition { file: "/p/a.py".to_string(), line, character: 4 };
+        let top = rag_memo_key(&cpath, &cursor(10), (0, 40), 256);
+        let bottom = rag_memo_key(&cpath, &cursor(900), (860, 940), 256);
+        assert_ne!(top, bottom);
+        assert_eq!(top, rag_memo_key(&cpath, &cursor(10), (0, 40), 256));
+        assert_ne!(top, rag_memo_key(&cpath, &cursor(10), (0, 30), 256), "a different FIM window bans different lines");
+        assert_ne!(top, rag_memo_key(&cpath, &cursor(10), (0, 40), 512));
+    }
+}
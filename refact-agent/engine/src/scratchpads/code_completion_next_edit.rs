@@ -0,0 +1,364 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+use std::time::Instant;
+use async_trait::async_trait;
+use regex::Regex;
+use ropey::Rope;
+use serde_json::{json, Value};
+use tokenizers::Tokenizer;
+use tokio::sync::Mutex as AMutex;
+use tokio::sync::RwLock as ARwLock;
+use tracing::{info, warn};
+
+use crate::at_commands::at_commands::AtCommandsContext;
+use crate::call_validation::{ChatContent, ChatMessage, CodeCompletionPost, CursorPosition, SamplingParameters};
+use crate::edit_history::RecentEdit;
+use crate::global_context::GlobalContext;
+use crate::privacy::{check_file_privacy, load_privacy_if_needed, FilePrivacyLevel, PrivacySettings};
+use crate::scratchpad_abstract::{FinishReason, HasTokenizerAndEot, ScratchpadAbstract};
+use crate::scratchpads::code_completion_replace::unfence_the_last_code_block;
+
+
+// Next edit prediction: the model sees what the user changed recently (EditHistory, filled by on_did_change)
+// and a numbered window of the cursor file, and says which lines to replace next. The answer is a range
+// replacement, possibly far from the cursor, the client shows it and lets the user jump there:
+//
+//   "next_edit": {"file_name": "...", "line1": 10, "line2": 12, "text": "..."}
+//
+// line1 and line2 are 0-based like cursor.line, line2 is exclusive, line1 == line2 is an insertion before line1.
+
+const DEBUG: bool = false;
+const SYSTEM_PROMPT: &str = r#"You are given the recent edits the user made as diff hunks, oldest first, and a part of the file the user is editing now.
+Every line of the file starts with its number and "|", the cursor is marked with <CURSOR>.
+Predict the next edit the user is going to make. Usually it continues the recent edits: a renamed parameter gets renamed in the rest of the function, a new argument gets passed at the call sites, and so on.
+Reply with the range of lines to replace and the new lines without the numbers, like this:
+<EDIT line1=10 line2=12>
+```
+new code for lines 10 to 12
+```
+Both line1 and line2 are inclusive. To insert new lines without replacing anything use line2 = line1 - 1, the new lines go before line1.
+Keep indentation symbols unchanged. If there is nothing to edit, reply <NO_EDIT>."#;
+const MAX_EDITS_IN_PROMPT: usize = 10;
+const WINDOW_MAX_ROWS: usize = 60;
+const WINDOW_MIN_TOKENS: usize = 128;
+const EDITS_TOKENS_RATIO: f64 = 0.3;
+const MAX_NEW_TOKENS: usize = 512;
+const TEMPERATURE_INITIAL: f32 = 0.0;
+const TEMPERATURE_NOCACHE: f32 = 0.5;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextEdit {
+    pub line1: usize,
+    pub line2: usize,
+    pub text: String,
+}
+
+// Lines around the cursor, each prefixed with its 1-based number, returns the 0-based range shown (line2 exclusive)
+fn prepare_numbered_window(
+    tokenizer: &HasTokenizerAndEot,
+    max_tokens: usize,
+    file_text: &Rope,
+    cursor_pos: &CursorPosition,
+) -> Result<(String, (usize, usize)), String> {
+    let lines_n = file_text.len_lines();
+    let cursor_line = (cursor_pos.line.max(0) as usize).min(lines_n.saturating_sub(1));
+    let numbered = |idx: usize| -> String {
+        let line = file_text.line(idx).to_string().replace("\r\n", "\n");
+        let line = line.trim_end_matches('\n');
+        if idx == cursor_line {
+            let at = (cursor_pos.character.max(0) as usize).min(line.chars().count());
+            let (before, after): (String, String) = (line.chars().take(at).collect(), line.chars().skip(at).collect());
+            format!("{}|{}<CURSOR>{}\n", idx + 1, before, after)
+        } else {
+            format!("{}|{}\n", idx + 1, line)
+        }
+    };
+    let mut tokens_used = tokenizer.count_tokens(&numbered(cursor_line)).unwrap_or(0) as usize;
+    if tokens_used > max_tokens {
+        return Err("Tokens limit is too small to fit the cursor line".to_string());
+    }
+    let (mut line1, mut line2) = (cursor_line, cursor_line + 1);
+    while line2 - line1 < WINDOW_MAX_ROWS && (line1 > 0 || line2 < lines_n) {
+        let mut grown = false;
+        for take_above in [true, false] {
+            let idx = match take_above {
+                true if line1 > 0 => line1 - 1,
+                false if line2 < lines_n => line2,
+                _ => continue,
+            };
+            tokens_used += tokenizer.count_tokens(&numbered(idx)).unwrap_or(0) as usize;
+            if tokens_used > max_tokens {
+                break;
+            }
+            if take_above { line1 = idx; } else { line2 = idx + 1; }
+            grown = true;
+        }
+        if !grown || tokens_used > max_tokens {
+            break;
+        }
+    }
+    Ok(((line1..line2).map(&numbered).collect::<String>(), (line1, line2)))
+}
+
+// Finds <EDIT line1=N line2=M> and the code block after it, checks the range is inside the window the model saw
+pub fn parse_next_edit(answer: &str, window: (usize, usize), file_text: &Rope) -> Option<NextEdit> {
+    let re = Regex::new(r"<EDIT\s+line1=(\d+)\s+line2=(\d+)\s*>").unwrap();
+    let caps = re.captures_iter(answer).last()?;
+    let (line1_1based, line2_1based) = (caps[1].parse::<usize>().ok()?, caps[2].parse::<usize>().ok()?);
+    if line1_1based == 0 || line2_1based + 1 < line1_1based {
+        warn!("next edit: invalid range {}..{}", line1_1based, line2_1based);
+        return None;
+    }
+    let (line1, line2) = (line1_1based - 1, line2_1based);
+    if line1 < window.0 || line2 > window.1 {
+        warn!("next edit: range {}..{} is outside of the window {}..{}", line1, line2, window.0, window.1);
+        return None;
+    }
+    let after_tag = answer[caps.get(0)?.end()..].to_string();
+    let mut text = unfence_the_last_code_block(&after_tag)?.replace("\r", "");
+    if !text.is_empty() && !text.ends_with("\n") {
+        text.push('\n');
+    }
+    let line2_in_file = line2.min(file_text.len_lines());
+    let existing = file_text.slice(file_text.line_to_char(line1)..file_text.line_to_char(line2_in_file)).to_string().replace("\r", "");
+    if existing == text {
+        return None;
+    }
+    Some(NextEdit { line1, line2, text })
+}
+
+
+pub struct CodeCompletionNextEditScratchpad {
+    pub t: HasTokenizerAndEot,
+    pub post: CodeCompletionPost,
+    pub window: (usize, usize),
+    pub context_used: Value,
+    pub global_context: Arc<ARwLock<GlobalContext>>,
+}
+
+impl CodeCompletionNextEditScratchpad {
+    pub fn new(
+        tokenizer: Arc<StdRwLock<Tokenizer>>,
+        post: &CodeCompletionPost,
+        global_context: Arc<ARwLock<GlobalContext>>,
+    ) -> Self {
+        CodeCompletionNextEditScratchpad {
+            t: HasTokenizerAndEot::new(tokenizer),
+            post: post.clone(),
+            window: (0, 0),
+            context_used: json!({}),
+            global_context,
+        }
+    }
+
+    fn edits_prompt(&self, edits: &Vec<(PathBuf, RecentEdit)>, max_tokens: usize) -> String {
+        // the newest edits matter most, they go in first and the older ones fill the rest of the budget
+        let mut tokens_used = 0;
+        let mut hunks = vec![];
+        for (path, edit) in edits.iter().rev() {
+            let hunk = format!("File: {}\n{}", path.to_string_lossy(), edit.to_hunk());
+            tokens_used += self.t.count_tokens(&hunk).unwrap_or(0) as usize;
+            if tokens_used > max_tokens {
+                break;
+            }
+            hunks.push(hunk);
+        }
+        hunks.reverse();
+        hunks.join("\n")
+    }
+}
+
+#[async_trait]
+impl ScratchpadAbstract for CodeCompletionNextEditScratchpad {
+    async fn apply_model_adaptation_patch(
+        &mut self,
+        patch: &Value,
+        _exploration_tools: bool,
+        _agentic_tools: bool,
+    ) -> Result<(), String> {
+        self.t.context_format = patch
+            .get("context_format")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_string();
+        Ok(())
+    }
+
+    async fn prompt(
+        &mut self,
+        ccx: Arc<AMutex<AtCommandsContext>>,
+        sampling_parameters_to_patch: &mut SamplingParameters,
+    ) -> Result<String, String> {
+        let n_ctx = ccx.lock().await.n_ctx;
+        let completion_t0 = Instant::now();
+        sampling_parameters_to_patch.max_new_tokens = MAX_NEW_TOKENS;
+        sampling_parameters_to_patch.temperature = if !self.post.no_cache { Some(TEMPERATURE_INITIAL) } else { Some(TEMPERATURE_NOCACHE) };
+        sampling_parameters_to_patch.stop = vec![];
+        let cpath = crate::files_correction::canonical_path(&self.post.inputs.cursor.file);
+        let source = self
+            .post
+            .inputs
+            .sources
+            .get(&self.post.inputs.cursor.file)
+            .ok_or("Cursor is in file not found in sources".to_string())?
+            .clone();
+        let edit_history = self.global_context.read().await.documents_state.edit_history.clone();
+        let edits = edit_history.lock().unwrap().recent(MAX_EDITS_IN_PROMPT);
+        let edits = edits_allowed_by_privacy(load_privacy_if_needed(self.global_context.clone()).await, edits);
+
+        let mut messages = vec![ChatMessage {
+            role: "system".to_string(),
+            content: ChatContent::SimpleText(SYSTEM_PROMPT.to_string()),
+            ..Default::default()
+        }];
+        let mut available_tokens = n_ctx.saturating_sub(
+            self.t.count_tokens(SYSTEM_PROMPT)? as usize + MAX_NEW_TOKENS + 3,
+        );
+        let edits_text = self.edits_prompt(&edits, (available_tokens as f64 * EDITS_TOKENS_RATIO) as usize);
+        if !edits_text.is_empty() {
+            available_tokens = available_tokens.saturating_sub(self.t.count_tokens(&edits_text)? as usize);
+            messages.push(ChatMessage {
+                role: "user".to_string(),
+                content: ChatContent::SimpleText(format!("Recent edits:\n{}", edits_text)),
+                ..Default::default()
+            });
+        }
+        if available_tokens <= WINDOW_MIN_TOKENS {
+            return Err(format!("not enough tokens for the cursor file: {available_tokens} <= {WINDOW_MIN_TOKENS}"));
+        }
+        let text = Rope::from_str(&source);
+        let (window_text, window) = prepare_numbered_window(&self.t, available_tokens, &text, &self.post.inputs.cursor)?;
+        self.window = window;
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: ChatContent::SimpleText(format!("File name:\n{}\nContent:\n```\n{}```", cpath.to_string_lossy(), window_text)),
+            ..Default::default()
+        });
+
+        let json_messages = serde_json::to_string(&json!({
+            "messages": messages.iter().map(|x| { x.into_value(&None) }).collect::<Vec<_>>(),
+        })).unwrap();
+        let prompt = format!("PASSTHROUGH {json_messages}");
+
+        let completion_ms = completion_t0.elapsed().as_millis() as i32;
+        self.context_used["fim_ms"] = Value::from(completion_ms);
+        self.context_used["n_ctx"] = Value::from(n_ctx as i64);
+        self.context_used["recent_edits"] = Value::from(edits.len() as i64);
+        info!(" -- /post next edit {}ms, {} recent edits -- ", completion_ms, edits.len());
+        if DEBUG {
+            info!("next edit prompt:\n{}", prompt);
+        }
+        Ok(prompt)
+    }
+
+    fn response_n_choices(
+        &mut self,
+        _choices: Vec<String>,
+        _finish_reasons: Vec<FinishReason>,
+    ) -> Result<Value, String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_streaming(
+        &mut self,
+        _delta: String,
+        _finish_reason: FinishReason,
+    ) -> Result<(Value, FinishReason), String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_message_n_choices(
+        &mut self,
+        choices: Vec<String>,
+        finish_reasons: Vec<FinishReason>,
+    ) -> Result<Value, String> {
+        let text = Rope::from_str(self.post.inputs.sources.get(&self.post.inputs.cursor.file).map(|s| s.as_str()).unwrap_or(""));
+        let json_choices = choices.iter().enumerate().map(|(i, answer)| {
+            if DEBUG {
+                info!("unprocessed {i} next edit\n{}", answer);
+            }
+            let next_edit = parse_next_edit(answer, self.window, &text).map(|edit| json!({
+                "file_name": self.post.inputs.cursor.file,
+                "line1": edit.line1,
+                "line2": edit.line2,
+                "text": edit.text,
+            }));
+            // clients that don't know about next_edit see an empty completion
+            json!({
+                "index": i,
+                "code_completion": "",
+                "next_edit": next_edit,
+                "finish_reason": finish_reasons[i].to_json_val(),
+            })
+        }).collect::<Vec<_>>();
+        Ok(json!({
+            "choices": json_choices,
+            "model": self.post.model.clone(),
+            "context": self.context_used,
+        }))
+    }
+
+    fn response_message_streaming(
+        &mut self,
+        _json: &Value,
+        _finish_reason: FinishReason,
+    ) -> Result<(Value, FinishReason), String> {
+        Err("not implemented".to_string())
+    }
+
+    fn response_spontaneous(&mut self) -> Result<Vec<Value>, String> {
+        Ok(vec![])
+    }
+
+    fn streaming_finished(&mut self, _finish_reason: FinishReason) -> Result<Value, String> {
+        Err("not implemented".to_string())
+    }
+}
+
+
+// on_did_change doesn't record blocked files, but privacy.yaml might have changed since the edit was recorded
+fn edits_allowed_by_privacy(privacy: Arc<PrivacySettings>, edits: Vec<(PathBuf, RecentEdit)>) -> Vec<(PathBuf, RecentEdit)> {
+    edits.into_iter()
+        .filter(|(path, _)| check_file_privacy(privacy.clone(), path, &FilePrivacyLevel::OnlySendToServersIControl).is_ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privacy::FilePrivacySettings;
+
+    #[test]
+    fn test_parse_next_edit() {
+        let text = Rope::from_str("def f(xs):\n    for x in items:\n        print(x)\n");
+        let answer = "The loop should use the renamed parameter.\n<EDIT line1=2 line2=2>\n```\n    for x in xs:\n```";
+        let edit = parse_next_edit(answer, (0, 4), &text).unwrap();
+        assert_eq!(edit, NextEdit { line1: 1, line2: 2, text: "    for x in xs:\n".to_string() });
+
+        // insertion before line 3
+        let edit = parse_next_edit("<EDIT line1=3 line2=2>\n```\n        total += x\n```\n", (0, 4), &text).unwrap();
+        assert_eq!((edit.line1, edit.line2), (2, 2));
+
+        assert!(parse_next_edit("<NO_EDIT>", (0, 4), &text).is_none());
+        assert!(parse_next_edit("<EDIT line1=1 line2=1>\n```\ndef f(xs):\n```", (0, 4), &text).is_none(), "nothing changes");
+        assert!(parse_next_edit("<EDIT line1=2 line2=2>\n```\n    pass\n```", (2, 4), &text).is_none(), "outside of the window");
+    }
+
+    #[test]
+    fn test_blocked_edits_stay_out_of_prompt() {
+        let privacy = Arc::new(PrivacySettings {
+            privacy_rules: FilePrivacySettings {
+                only_send_to_servers_I_control: vec![],
+                blocked: vec!["*/secret/*".to_string()],
+            },
+            loaded_ts: 0,
+        });
+        let edit = RecentEdit { ts: 1.0, line1: 0, removed: vec![], added: vec!["API_KEY = 1".to_string()] };
+        let edits = vec![(PathBuf::from("/p/secret/a.py"), edit.clone()), (PathBuf::from("/p/a.py"), edit.clone())];
+        let allowed = edits_allowed_by_privacy(privacy, edits);
+        assert_eq!(allowed, vec![(PathBuf::from("/p/a.py"), edit)]);
+    }
+}
@@ -25,6 +25,7 @@ use tracing::{info, warn};
 use crate::ast::ast_db::doc_defs;
 use crate::ast::ast_structs::AstDefinition;
 use crate::scratchpads::completon_rag::retrieve_ast_based_extra_context;
+use crate::scratchpads::syntax_truncation::truncate_by_syntax;
 
 const DEBUG: bool = false;
 const SYSTEM_PROMPT: &str = r#"You are given a code file, <BLOCK_OF_CODE> from that file and an extra context from other files.
@@ -93,7 +94,7 @@ impl SubBlock {
     }
 }
 
-fn prepare_cursor_file(
+pub fn prepare_cursor_file(
     tokenizer: &HasTokenizerAndEot,
     max_tokens: usize,
     file_name: &PathBuf,
@@ -312,7 +313,7 @@ fn skip_similar_rows(pred_text: &Vec<String>, text_to_remove: &Vec<String>) -> V
     pred_text_trimmed
 }
 
-fn retrieve_a_comment(source: &String, cpath: &PathBuf, cursor: &CursorPosition) -> Option<String> {
+pub fn retrieve_a_comment(source: &String, cpath: &PathBuf, cursor: &CursorPosition) -> Option<String> {
     let mut has_a_comment_right_after_the_cursor: bool = false;
     let comments = parse_comments(
         &source,
@@ -334,15 +335,14 @@ fn retrieve_a_comment(source: &String, cpath: &PathBuf, cursor: &CursorPosition)
     if !has_a_comment_right_after_the_cursor {
         if let Some(c) = initial_comment.get(0) {
             let mut comments_to_combine = vec![c];
-            for idx in (0..c.end_line - 1).rev() {
-                if let Some(found_c) = comments
-                    .iter()
-                    .find(|x| x.end_line == idx as usize && !x.is_inline)
-                {
-                    comments_to_combine.push(found_c);
-                } else {
-                    break;
-                }
+            // single-line comments one after another make one comment
+            let mut line_above = c.start_line - 1;
+            while let Some(found_c) = comments
+                .iter()
+                .find(|x| x.end_line == line_above && !x.is_inline)
+            {
+                comments_to_combine.push(found_c);
+                line_above = found_c.start_line - 1;
             }
             let mut combined_text: String = "".to_string();
             for c in comments_to_combine.iter().rev() {
@@ -357,7 +357,7 @@ fn retrieve_a_comment(source: &String, cpath: &PathBuf, cursor: &CursorPosition)
     }
 }
 
-fn unfence_the_last_code_block(text: &String) -> Option<String> {
+pub fn unfence_the_last_code_block(text: &String) -> Option<String> {
     let mut blocks: Vec<String> = vec![];
     let mut current_block: Option<String> = None;
     for line in Rope::from_str(text).lines() {
@@ -386,12 +386,13 @@ fn process_n_choices(
     subblock: &mut Option<SubBlock>,
     choices: &Vec<String>,
     finish_reasons: &Vec<FinishReason>,
-    is_multiline: bool,
+    post: &CodeCompletionPost,
     data4cache: &mut completion_cache::CompletionSaveToCache,
 ) -> Vec<Value> {
     let subblock_ref = subblock
         .as_mut()
         .expect("cursor_subblock must be initialized in the prompt");
+    let is_multiline = post.inputs.multiline;
     let after_lines_str = subblock_ref.after_lines_str();
     let before_lines_str = subblock_ref.before_lines_str();
     let cursor_line = subblock_ref.cursor_line.trim_end().to_string();
@@ -502,6 +503,11 @@ fn process_n_choices(
                 }
             }
             cc = cc.replace("\r", "");
+            if is_multiline && !predicted_single_line {
+                if let Some(source) = post.inputs.sources.get(&post.inputs.cursor.file) {
+                    cc = truncate_by_syntax(&PathBuf::from(&post.inputs.cursor.file), source, &post.inputs.cursor, &cc);
+                }
+            }
 
             // Instruct-based models love to add weird comments
             // Trying to remove some of them with a simple heuristics
@@ -744,6 +750,7 @@ impl ScratchpadAbstract for CodeCompletionReplaceScratchpad {
                 (line1 as i32, line2 as i32),
                 pp_settings,
                 rag_tokens_n,
+                vec![],
                 &mut self.context_used
             ).await;
             prompt.push_str(self.keyword_user.as_str());
@@ -786,13 +793,13 @@ impl ScratchpadAbstract for CodeCompletionReplaceScratchpad {
             &mut self.cursor_subblock,
             &choices,
             &finish_reasons,
-            self.post.inputs.multiline,
+            &self.post,
             &mut self.data4cache,
         );
         snippets_collection::snippet_register_from_data4cache(
             &self.data4snippet,
             &mut self.data4cache,
-            self.context_used != json!({}),
+            &self.context_used,
         );
         Ok(json!(
             {
@@ -979,6 +986,7 @@ impl ScratchpadAbstract for CodeCompletionReplacePassthroughScratchpad {
                 (line1 as i32, line2 as i32),
                 pp_settings,
                 rag_tokens_n,
+                vec![],
                 &mut self.context_used
             ).await;
             if !extra_context.is_empty() {
@@ -1051,13 +1059,13 @@ impl ScratchpadAbstract for CodeCompletionReplacePassthroughScratchpad {
             &mut self.cursor_subblock,
             &choices,
             &finish_reasons,
-            self.post.inputs.multiline,
+            &self.post,
             &mut self.data4cache,
         );
         snippets_collection::snippet_register_from_data4cache(
             &self.data4snippet,
             &mut self.data4cache,
-            self.context_used != json!({}),
+            &self.context_used,
         );
         Ok(json!({
             "choices": json_choices,
@@ -1,14 +1,16 @@
 use crate::ast::ast_indexer_thread::AstIndexService;
 use crate::ast::ast_structs::{AstDB, AstDefinition};
+use crate::at_commands::at_commands::AtCommandsContext;
 use crate::call_validation::{ContextFile, CursorPosition, PostprocessSettings};
 use crate::global_context::GlobalContext;
 use crate::postprocessing::pp_context_files::postprocess_context_files;
 use crate::scratchpad_abstract::HasTokenizerAndEot;
+use ropey::Rope;
 use serde_json::{json, Value};
 use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::vec;
 use tokio::sync::Mutex as AMutex;
 use tokio::sync::RwLock as ARwLock;
@@ -17,6 +19,33 @@ use tracing::info;
 const DEBUG: bool = false;
 
 const TAKE_USAGES_AROUND_CURSOR: usize = 20;
+pub const VECDB_LATENCY_MS: u64 = 150;
+const VECDB_QUERY_LINES_BEFORE: usize = 10;
+const VECDB_QUERY_LINES_AFTER: usize = 3;
+const VECDB_QUERY_MAX_CHARS: usize = 2000;
+const VECDB_USEFULNESS_SCALE: f32 = 0.8;  // declarations of the symbols around the cursor are still better than similar code
+
+pub async fn repo_name_and_cursor_path(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    cursor_filepath: &PathBuf,
+) -> (String, String) {
+    if let Some(project_dir) = crate::files_correction::get_project_dirs(gcx).await.get(0) {
+        let repo_name = project_dir
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or("default_repo".to_string());
+        let cursor_filepath_stripped = cursor_filepath
+            .strip_prefix(project_dir)
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or(cursor_filepath.to_string_lossy().to_string());
+        (repo_name, cursor_filepath_stripped)
+    } else {
+        (
+            "default_repo".to_string(),
+            cursor_filepath.to_string_lossy().to_string(),
+        )
+    }
+}
 
 async fn _render_context_files(
     gcx: Arc<ARwLock<GlobalContext>>,
@@ -27,23 +56,7 @@ async fn _render_context_files(
     if postprocessed_messages.is_empty() {
         return "".to_string();
     }
-    let (repo_name, cursor_filepath_stripped) =
-        if let Some(project_dir) = crate::files_correction::get_project_dirs(gcx).await.get(0) {
-            let repo_name = project_dir
-                .file_name()
-                .map(|x| x.to_string_lossy().to_string())
-                .unwrap_or("default_repo".to_string());
-            let cursor_filepath_stripped = cursor_filepath
-                .strip_prefix(project_dir)
-                .map(|x| x.to_string_lossy().to_string())
-                .unwrap_or(cursor_filepath.to_string_lossy().to_string());
-            (repo_name, cursor_filepath_stripped)
-        } else {
-            (
-                "default_repo".to_string(),
-                cursor_filepath.to_string_lossy().to_string(),
-            )
-        };
+    let (repo_name, cursor_filepath_stripped) = repo_name_and_cursor_path(gcx, cursor_filepath).await;
     let mut context_files_prompt = String::new();
     match context_format.as_str() {
         "starcoder" => {
@@ -153,6 +166,91 @@ async fn _cursor_position_to_context_file(
     output
 }
 
+#[cfg(feature="vecdb")]
+pub async fn vecdb_rag_enabled(gcx: Arc<ARwLock<GlobalContext>>, use_vecdb: bool) -> bool {
+    use_vecdb && gcx.read().await.cmdline.vecdb
+}
+
+#[cfg(not(feature="vecdb"))]
+pub async fn vecdb_rag_enabled(_gcx: Arc<ARwLock<GlobalContext>>, _use_vecdb: bool) -> bool {
+    false
+}
+
+// Text around the cursor to look for similar code, mostly the lines above because that's what is being continued
+pub fn vecdb_query_around_cursor(text: &Rope, pos: &CursorPosition) -> String {
+    let cursor_line = (pos.line.max(0) as usize).min(text.len_lines().saturating_sub(1));
+    let line1 = cursor_line.saturating_sub(VECDB_QUERY_LINES_BEFORE);
+    let line2 = (cursor_line + 1 + VECDB_QUERY_LINES_AFTER).min(text.len_lines());
+    let query = text.slice(text.line_to_char(line1)..text.line_to_char(line2)).to_string();
+    let chars_n = query.chars().count();
+    query.chars().skip(chars_n.saturating_sub(VECDB_QUERY_MAX_CHARS)).collect()
+}
+
+#[cfg(feature="vecdb")]
+async fn _vecdb_search(ccx: Arc<AMutex<AtCommandsContext>>, query: &String) -> Result<Vec<ContextFile>, String> {
+    crate::at_commands::at_search::execute_at_search(ccx, query, None).await
+}
+
+#[cfg(not(feature="vecdb"))]
+async fn _vecdb_search(_ccx: Arc<AMutex<AtCommandsContext>>, _query: &String) -> Result<Vec<ContextFile>, String> {
+    Err("vecdb is not compiled in".to_string())
+}
+
+// Similar code from vecdb, or nothing at all if the search doesn't fit into latency_ms: a completion
+// that waits for the embedding model is worse than a completion without this context
+pub async fn retrieve_vecdb_context_files(
+    ccx: Arc<AMutex<AtCommandsContext>>,
+    query: &String,
+    cpath: &PathBuf,
+    subblock_to_ignore_range: (i32, i32),
+    latency_ms: u64,
+    context_used: &mut Value,
+) -> Vec<ContextFile> {
+    let vecdb_t0 = Instant::now();
+    let search_result = tokio::time::timeout(Duration::from_millis(latency_ms), _vecdb_search(ccx, query)).await;
+    context_used["vecdb_ms"] = Value::from(vecdb_t0.elapsed().as_millis() as i32);
+    let found = match search_result {
+        Ok(Ok(found)) => found,
+        Ok(Err(e)) => {
+            info!("vecdb rag skipped: {}", e);
+            context_used["vecdb_skipped"] = Value::from(e);
+            return vec![];
+        }
+        Err(_) => {
+            info!("vecdb rag skipped: slower than {}ms", latency_ms);
+            context_used["vecdb_skipped"] = Value::from(format!("slower than {}ms", latency_ms));
+            return vec![];
+        }
+    };
+    let cpath_str = cpath.to_string_lossy().to_string();
+    let has_range = subblock_to_ignore_range.0 != i32::MAX && subblock_to_ignore_range.1 != i32::MIN;
+    let context_files: Vec<ContextFile> = found
+        .into_iter()
+        .filter(|x| {
+            // the text around the cursor finds itself first, it's in the prompt already
+            !(has_range && x.file_name == cpath_str
+                && x.line1 as i32 <= subblock_to_ignore_range.1 + 1
+                && x.line2 as i32 >= subblock_to_ignore_range.0 + 1)
+        })
+        .map(|mut x| {
+            x.usefulness *= VECDB_USEFULNESS_SCALE;
+            x
+        })
+        .collect();
+    context_used["bucket_vecdb"] = Value::Array(
+        context_files
+            .iter()
+            .map(|x| json!({
+                "file_path": x.file_name,
+                "line1": x.line1,
+                "line2": x.line2,
+                "usefulness": x.usefulness,
+            }))
+            .collect(),
+    );
+    context_files
+}
+
 pub async fn retrieve_ast_based_extra_context(
     gcx: Arc<ARwLock<GlobalContext>>,
     ast_service: Option<Arc<AMutex<AstIndexService>>>,
@@ -162,6 +260,7 @@ pub async fn retrieve_ast_based_extra_context(
     subblock_to_ignore_range: (i32, i32),
     pp_settings: PostprocessSettings,
     rag_tokens_n: usize,
+    extra_context_files: Vec<ContextFile>,
     context_used: &mut Value,
 ) -> String {
     info!(" -- ast-based rag search starts --");
@@ -185,6 +284,8 @@ pub async fn retrieve_ast_based_extra_context(
     };
 
     let to_buckets_ms = rag_t0.elapsed().as_millis() as i32;
+    // vecdb results compete with the AST ones for the same rag_tokens_n
+    ast_context_file_vec.extend(extra_context_files);
     if subblock_to_ignore_range.0 != i32::MAX && subblock_to_ignore_range.1 != i32::MIN {
         // disable (usefulness==-1) the FIM region around the cursor from getting into the results
         let fim_ban = ContextFile {
@@ -233,13 +334,120 @@ pub async fn retrieve_ast_based_extra_context(
             .collect(),
     );
     context_used["rag_ms"] = Value::from(rag_ms);
-    _render_context_files(
+    let rendered = _render_context_files(
         gcx.clone(),
         &t.context_format,
         &postprocessed_messages,
         &cpath,
     )
-    .await
+    .await;
+    context_used["rag_tokens_used"] = Value::from(t.count_tokens(&rendered).unwrap_or(0));
+    rendered
+}
+
+// Token budget of each file for the repo-level format, proportional to the best usefulness in the file,
+// the most useful files first. Negative usefulness means "don't show these lines", it doesn't count.
+fn split_tokens_by_usefulness(
+    context_files: &Vec<ContextFile>,
+    tokens_n: usize,
+    max_files_n: usize,
+) -> Vec<(String, usize)> {
+    let mut best_in_file: Vec<(String, f32)> = vec![];
+    for x in context_files.iter().filter(|x| x.usefulness > 0.0) {
+        match best_in_file.iter_mut().find(|(file_name, _)| *file_name == x.file_name) {
+            Some((_, best)) => *best = best.max(x.usefulness),
+            None => best_in_file.push((x.file_name.clone(), x.usefulness)),
+        }
+    }
+    best_in_file.sort_by(|a, b| b.1.total_cmp(&a.1));
+    best_in_file.truncate(max_files_n);
+    let total: f32 = best_in_file.iter().map(|(_, u)| u).sum();
+    best_in_file
+        .into_iter()
+        .map(|(file_name, u)| (file_name, (tokens_n as f32 * u / total) as usize))
+        .collect()
+}
+
+// FIM-REPO: context files as separate segments, the way repo-level models saw code in training
+//   <|repo_name|>repo
+//   <|file_sep|>path/to/a.py
+//   ...
+//   <|file_sep|>path/to/cursor_file.py
+// The least useful file goes first, the most useful one is right before the cursor file.
+pub async fn retrieve_repo_level_context(
+    gcx: Arc<ARwLock<GlobalContext>>,
+    ast_service: Option<Arc<AMutex<AstIndexService>>>,
+    t: &HasTokenizerAndEot,
+    cpath: &PathBuf,
+    pos: &CursorPosition,
+    pp_settings: PostprocessSettings,
+    rag_tokens_n: usize,
+    extra_context_files: Vec<ContextFile>,
+    repo_tokens: &(String, String),
+    context_used: &mut Value,
+) -> String {
+    let mut pp_settings = pp_settings;
+    if pp_settings.max_files_n == 0 {
+        pp_settings.max_files_n = 5;
+    }
+    let rag_t0 = Instant::now();
+    let mut context_file_vec: Vec<ContextFile> = if let Some(ast) = &ast_service {
+        let ast_index = ast.lock().await.ast_index.clone();
+        _cursor_position_to_context_file(
+            ast_index.clone(),
+            cpath.to_string_lossy().to_string(),
+            pos.line,
+            context_used,
+        )
+        .await
+    } else {
+        vec![]
+    };
+    context_file_vec.extend(extra_context_files);
+    // the cursor file is the FIM part, it doesn't get a segment of its own
+    let cpath_str = cpath.to_string_lossy().to_string();
+    context_file_vec.retain(|x| x.file_name != cpath_str);
+
+    let mut segments = vec![];
+    for (file_name, file_tokens_n) in split_tokens_by_usefulness(&context_file_vec, rag_tokens_n, pp_settings.max_files_n) {
+        let mut file_context = context_file_vec.iter().filter(|x| x.file_name == file_name).cloned().collect::<Vec<_>>();
+        let postprocessed = postprocess_context_files(
+            gcx.clone(),
+            &mut file_context,
+            t.tokenizer.clone(),
+            file_tokens_n,
+            false,
+            &pp_settings,
+        )
+        .await;
+        segments.extend(postprocessed);
+    }
+    segments.reverse();
+
+    context_used["attached_files"] = Value::Array(
+        segments
+            .iter()
+            .map(|x| {
+                json!({
+                    "file_name": x.file_name,
+                    "file_content": x.file_content,
+                    "line1": x.line1,
+                    "line2": x.line2,
+                })
+            })
+            .collect(),
+    );
+    context_used["rag_ms"] = Value::from(rag_t0.elapsed().as_millis() as i32);
+
+    let (repo_name_token, file_sep_token) = repo_tokens;
+    let (repo_name, cursor_filepath_stripped) = repo_name_and_cursor_path(gcx.clone(), cpath).await;
+    let mut prompt = format!("{repo_name_token}{repo_name}\n");
+    for x in segments.iter() {
+        prompt.push_str(&format!("{file_sep_token}{}\n{}", x.file_name, x.file_content));
+    }
+    prompt.push_str(&format!("{file_sep_token}{cursor_filepath_stripped}\n"));
+    context_used["rag_tokens_used"] = Value::from(t.count_tokens(&prompt).unwrap_or(0));
+    prompt
 }
 
 //     // context["cursor_symbols"] = Value::Array(search_traces.cursor_symbols.iter()
@@ -247,3 +455,37 @@ pub async fn retrieve_ast_based_extra_context(
 //     // context["bucket_usage_of_same_stuff"] = Value::Array(search_traces.bucket_usage_of_same_stuff.iter()
 //     // context["bucket_high_overlap"] = Value::Array(search_traces.bucket_high_overlap.iter()
 //     // context["bucket_imports"] = Value::Array(search_traces.bucket_imports.iter()
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vecdb_query_around_cursor() {
+        let text = Rope::from_str(&(0..30).map(|i| format!("line{}\n", i)).collect::<String>());
+        let pos = CursorPosition { file: "a.py".to_string(), line: 15, character: 2 };
+        let query = vecdb_query_around_cursor(&text, &pos);
+        assert!(query.starts_with("line5\n"));
+        assert!(query.ends_with("line18\n"));
+
+        let pos = CursorPosition { file: "a.py".to_string(), line: 0, character: 0 };
+        assert_eq!(vecdb_query_around_cursor(&text, &pos), "line0\nline1\nline2\nline3\n");
+    }
+
+    #[test]
+    fn test_split_tokens_by_usefulness() {
+        let cf = |file_name: &str, usefulness: f32| ContextFile {
+            file_name: file_name.to_string(),
+            file_content: "".to_string(),
+            line1: 1,
+            line2: 10,
+            symbols: vec![],
+            gradient_type: -1,
+            usefulness,
+        };
+        let files = vec![cf("a.py", 50.0), cf("b.py", 100.0), cf("a.py", 100.0), cf("c.py", 50.0), cf("d.py", -1.0), cf("e.py", 10.0)];
+        let split = split_tokens_by_usefulness(&files, 1000, 3);
+        assert_eq!(split, vec![("b.py".to_string(), 400), ("a.py".to_string(), 400), ("c.py".to_string(), 200)]);
+    }
+}
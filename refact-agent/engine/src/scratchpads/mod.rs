@@ -5,18 +5,24 @@ use tokenizers::Tokenizer;
 
 pub mod code_completion_fim;
 pub mod chat_generic;
+pub mod chat_jinja;
 pub mod chat_llama2;
 pub mod chat_passthrough;
 pub mod chat_utils_deltadelta;
 pub mod chat_utils_limit_history;
 pub mod chat_utils_prompts;
+pub mod chat_utils_summarize_history;
+pub mod chat_utils_text_tools;
 pub mod token_count_cache;
 pub mod scratchpad_utils;
 pub mod code_completion_replace;
+pub mod code_completion_next_edit;
+pub mod code_completion_comment_to_code;
 pub mod multimodality;
 mod comments_parser;
 mod passthrough_convert_messages;
 mod completon_rag;
+mod syntax_truncation;
 
 use crate::ast::ast_indexer_thread::AstIndexService;
 use crate::call_validation::{ChatMessage, CodeCompletionPost};
@@ -44,7 +50,7 @@ pub async fn create_code_completion_scratchpad(
     ast_module: Option<Arc<AMutex<AstIndexService>>>,
 ) -> Result<Box<dyn ScratchpadAbstract>, String> {
     let mut result: Box<dyn ScratchpadAbstract>;
-    let tokenizer_arc: Arc<StdRwLock<Tokenizer>> = cached_tokenizers::cached_tokenizer(caps, global_context.clone(), model_name_for_tokenizer).await?;
+    let tokenizer_arc: Arc<StdRwLock<Tokenizer>> = cached_tokenizers::cached_tokenizer(caps.clone(), global_context.clone(), model_name_for_tokenizer.clone()).await?;
     if scratchpad_name == "FIM-PSM" {
         result = Box::new(code_completion_fim::FillInTheMiddleScratchpad::new(
             tokenizer_arc, &post, "PSM".to_string(), cache_arc, tele_storage, ast_module, global_context.clone()
@@ -53,6 +59,19 @@ pub async fn create_code_completion_scratchpad(
         result = Box::new(code_completion_fim::FillInTheMiddleScratchpad::new(
             tokenizer_arc, &post, "SPM".to_string(), cache_arc, tele_storage, ast_module, global_context.clone()
         ))
+    } else if scratchpad_name == "FIM-REPO" {
+        let (repo_name_token, file_sep_token) = match caps.read().unwrap().code_completion_models.get(&model_name_for_tokenizer) {
+            Some(rec) => (rec.repo_name_token.clone(), rec.file_sep_token.clone()),
+            None => (String::new(), String::new()),
+        };
+        if repo_name_token.is_empty() || file_sep_token.is_empty() {
+            return Err(format!("model {} has no repo_name_token or file_sep_token, required for FIM-REPO", model_name_for_tokenizer));
+        }
+        let mut fim = code_completion_fim::FillInTheMiddleScratchpad::new(
+            tokenizer_arc, &post, "PSM".to_string(), cache_arc, tele_storage, ast_module, global_context.clone()
+        );
+        fim.repo_level_tokens = Some((repo_name_token, file_sep_token));
+        result = Box::new(fim)
     } else if scratchpad_name == "REPLACE" {
         result = Box::new(code_completion_replace::CodeCompletionReplaceScratchpad::new(
             tokenizer_arc, &post, cache_arc, tele_storage, ast_module, global_context.clone()
@@ -61,6 +80,14 @@ pub async fn create_code_completion_scratchpad(
         result = Box::new(code_completion_replace::CodeCompletionReplacePassthroughScratchpad::new(
             tokenizer_arc, &post, cache_arc, tele_storage, ast_module, global_context.clone()
         ))
+    } else if scratchpad_name == "COMMENT-TO-CODE" {
+        result = Box::new(code_completion_comment_to_code::CodeCompletionCommentToCodeScratchpad::new(
+            tokenizer_arc, &post, cache_arc, tele_storage, ast_module, global_context.clone()
+        ))
+    } else if scratchpad_name == "NEXT_EDIT" {
+        result = Box::new(code_completion_next_edit::CodeCompletionNextEditScratchpad::new(
+            tokenizer_arc, &post, global_context.clone()
+        ))
     } else {
         return Err(format!("This rust binary doesn't have code completion scratchpad \"{}\" compiled in", scratchpad_name));
     }
@@ -83,14 +110,26 @@ pub async fn create_chat_scratchpad(
     supports_clicks: bool,
 ) -> Result<Box<dyn ScratchpadAbstract>, String> {
     let mut result: Box<dyn ScratchpadAbstract>;
-    let tokenizer_arc = cached_tokenizers::cached_tokenizer(caps, global_context.clone(), model_name_for_tokenizer).await?;
+    let tokenizer_arc = cached_tokenizers::cached_tokenizer(caps.clone(), global_context.clone(), model_name_for_tokenizer.clone()).await?;
     if scratchpad_name == "CHAT-GENERIC" {
         result = Box::new(chat_generic::GenericChatScratchpad::new(
-            tokenizer_arc.clone(), post, messages, allow_at
+            tokenizer_arc.clone(), post, messages, allow_at, supports_tools
         ));
     } else if scratchpad_name == "CHAT-LLAMA2" {
         result = Box::new(chat_llama2::ChatLlama2::new(
-            tokenizer_arc.clone(), post, messages, allow_at
+            tokenizer_arc.clone(), post, messages, allow_at, supports_tools
+        ));
+    } else if scratchpad_name == "CHAT-JINJA" {
+        let model_chat_template = caps.read().unwrap().code_chat_models.get(&model_name_for_tokenizer)
+            .map(|rec| rec.chat_template.clone()).unwrap_or_default();
+        let template = if !model_chat_template.is_empty() {
+            cached_tokenizers::ChatTemplateConfig { chat_template: model_chat_template, ..Default::default() }
+        } else {
+            cached_tokenizers::cached_chat_template(caps.clone(), global_context.clone(), model_name_for_tokenizer.clone()).await
+                .ok_or(format!("model {} has no chat_template, set it in the model record or provide tokenizer_config.json", model_name_for_tokenizer))?
+        };
+        result = Box::new(chat_jinja::ChatJinja::new(
+            tokenizer_arc.clone(), post, messages, template, allow_at, supports_tools
         ));
     } else if scratchpad_name == "PASSTHROUGH" {
         result = Box::new(chat_passthrough::ChatPassthrough::new(
@@ -262,6 +262,13 @@ impl ChatMessage {
         dict.insert("content".to_string(), json!(chat_content_raw));
         dict.insert("tool_calls".to_string(), json!(self.tool_calls.clone()));
         dict.insert("tool_call_id".to_string(), Value::String(self.tool_call_id.clone()));
+        // the endpoint decides what to send back, see passthrough_messages_to_json and convert_messages_to_anthropic
+        if let Some(reasoning_content) = &self.reasoning_content {
+            dict.insert("reasoning_content".to_string(), Value::String(reasoning_content.clone()));
+        }
+        if let Some(thinking_blocks) = &self.thinking_blocks {
+            dict.insert("thinking_blocks".to_string(), json!(thinking_blocks));
+        }
 
         Value::Object(dict)
     }
@@ -292,6 +299,10 @@ impl<'de> Deserialize<'de> for ChatMessage {
             .transpose()?;
         let tool_call_id: Option<String> = value.get("tool_call_id")
             .and_then(|s| s.as_str()).map(|s| s.to_string());
+        let reasoning_content: Option<String> = value.get("reasoning_content")
+            .and_then(|s| s.as_str()).map(|s| s.to_string());
+        let thinking_blocks: Option<Vec<Value>> = value.get("thinking_blocks")
+            .and_then(|v| v.as_array()).cloned();
 
         Ok(ChatMessage {
             role,
@@ -299,6 +310,8 @@ impl<'de> Deserialize<'de> for ChatMessage {
             finish_reason,
             tool_calls,
             tool_call_id: tool_call_id.unwrap_or_default(),
+            reasoning_content,
+            thinking_blocks,
             ..Default::default()
         })
     }
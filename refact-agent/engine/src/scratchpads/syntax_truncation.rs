@@ -0,0 +1,181 @@
+use std::path::PathBuf;
+use ropey::Rope;
+use tracing::info;
+use tree_sitter::{Node, Tree};
+
+use crate::ast::treesitter::parsers::{get_ast_parser_by_filename, AstLanguageParser};
+use crate::call_validation::CursorPosition;
+
+
+// Multiline completions are cut using the file's grammar. The file is parsed as prefix + completion + suffix,
+// the completion keeps as many lines as possible without adding ERROR (or MISSING) nodes compared to
+// prefix + suffix alone, then it stops where the block around the cursor ends, so finishing a function
+// doesn't go on to write the next one. Files without a parser get the completion as is.
+// Only a window around the cursor gets parsed, from the last top-level line above it to the next top-level line
+// below it, so the number of parses per completion doesn't get multiplied by the size of the file.
+
+const MAX_LINES_TO_TRY: usize = 64;
+const MAX_WINDOW_LINES: usize = 300;   // each way from the cursor
+const BLOCK_KINDS_END_WITH: [&str; 4] = ["block", "body", "compound_statement", "declaration_list"];
+
+
+fn count_errors(node: Node) -> usize {
+    if !node.has_error() {
+        return 0;
+    }
+    let mut errors = if node.is_error() || node.is_missing() { 1 } else { 0 };
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        errors += count_errors(child);
+    }
+    errors
+}
+
+// The smallest block-like node around the cursor: function body, class body, compound statement
+fn enclosing_block_end(tree: &Tree, cursor_byte: usize) -> Option<usize> {
+    let mut node = tree.root_node().descendant_for_byte_range(cursor_byte, cursor_byte)?;
+    loop {
+        if node.start_byte() <= cursor_byte && BLOCK_KINDS_END_WITH.iter().any(|k| node.kind().ends_with(k)) {
+            return Some(node.end_byte());
+        }
+        node = node.parent()?;
+    }
+}
+
+fn longest_without_new_errors(
+    parser: &mut Box<dyn AstLanguageParser>,
+    prefix: &str,
+    completion: &str,
+    suffix: &str,
+    baseline_errors: usize,
+) -> Option<(String, Tree)> {
+    let lines = completion.split('\n').collect::<Vec<_>>();
+    for lines_n in (1..=lines.len()).rev().take(MAX_LINES_TO_TRY) {
+        let candidate = lines[..lines_n].join("\n");
+        if candidate.trim().is_empty() {
+            break;
+        }
+        let tree = parser.parse_tree(&format!("{prefix}{candidate}{suffix}"))?;
+        if count_errors(tree.root_node()) <= baseline_errors {
+            return Some((candidate, tree));
+        }
+    }
+    None
+}
+
+fn is_top_level_line(line: &str) -> bool {
+    match line.chars().next() {
+        Some(c) => !c.is_whitespace() && !")]}".contains(c),
+        None => false,
+    }
+}
+
+// Lines [line1, line2) to parse for a cursor on the given line
+fn parse_window(text: &Rope, line: usize) -> (usize, usize) {
+    let lowest = line.saturating_sub(MAX_WINDOW_LINES);
+    let line1 = (lowest..=line).rev()
+        .find(|&l| is_top_level_line(&text.line(l).to_string()))
+        .unwrap_or(lowest);
+    let highest = (line + 1 + MAX_WINDOW_LINES).min(text.len_lines());
+    let line2 = (line + 1..highest)
+        .find(|&l| is_top_level_line(&text.line(l).to_string()))
+        .unwrap_or(highest);
+    (line1, line2)
+}
+
+pub fn truncate_by_syntax(cpath: &PathBuf, source: &str, cursor: &CursorPosition, completion: &str) -> String {
+    if !completion.contains('\n') {
+        return completion.to_string();
+    }
+    let mut parser = match get_ast_parser_by_filename(cpath) {
+        Ok((parser, _language_id)) => parser,
+        Err(_) => return completion.to_string(),
+    };
+    let text = Rope::from_str(source);
+    let line = cursor.line.max(0) as usize;
+    if line >= text.len_lines() {
+        return completion.to_string();
+    }
+    let line_text = text.line(line).to_string();
+    let line_len = line_text.trim_end_matches(|c: char| c == '\n' || c == '\r').chars().count();
+    let (window_line1, window_line2) = parse_window(&text, line);
+    let window_start = text.line_to_byte(window_line1);
+    let window_end = text.line_to_byte(window_line2);
+    let cursor_byte = text.char_to_byte(text.line_to_char(line) + (cursor.character.max(0) as usize).min(line_len)) - window_start;
+    let (prefix, suffix) = source[window_start..window_end].split_at(cursor_byte);
+
+    let baseline_errors = match parser.parse_tree(&format!("{prefix}{suffix}")) {
+        Some(tree) => count_errors(tree.root_node()),
+        None => return completion.to_string(),
+    };
+    let (mut result, tree) = match longest_without_new_errors(&mut parser, prefix, completion, suffix, baseline_errors) {
+        Some(found) => found,
+        None => return completion.to_string(),
+    };
+    if let Some(block_end) = enclosing_block_end(&tree, cursor_byte) {
+        let block_end_in_completion = block_end.saturating_sub(cursor_byte);
+        if block_end >= cursor_byte && block_end_in_completion < result.len() && result.is_char_boundary(block_end_in_completion) {
+            result.truncate(block_end_in_completion);
+            result.truncate(result.trim_end().len());
+            // the block might end with the closing bracket the suffix already has
+            if let Some((shorter, _)) = longest_without_new_errors(&mut parser, prefix, &result, suffix, baseline_errors) {
+                result = shorter;
+            }
+        }
+    }
+    if result != completion {
+        info!("syntax truncation: {} -> {} chars", completion.len(), result.len());
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(line: i32, character: i32) -> CursorPosition {
+        CursorPosition { file: "".to_string(), line, character }
+    }
+
+    #[test]
+    fn test_stops_at_the_end_of_the_block() {
+        let source = "def f(a):\n    \n\ndef h():\n    pass\n";
+        let completion = "b = a + 1\n    return b\n\ndef g():\n    return 2";
+        let result = truncate_by_syntax(&PathBuf::from("a.py"), source, &cursor(1, 4), completion);
+        assert_eq!(result, "b = a + 1\n    return b");
+
+        let source = "fn f() -> i32 {\n    \n}\n";
+        let completion = "let x = 1;\n    x + 1\n}\n\nfn g() {";
+        let result = truncate_by_syntax(&PathBuf::from("a.rs"), source, &cursor(1, 4), completion);
+        assert_eq!(result, "let x = 1;\n    x + 1");
+    }
+
+    #[test]
+    fn test_cuts_unfinished_statements() {
+        let source = "fn f() -> i32 {\n    \n}\n";
+        let completion = "let x = 1;\n    let y = (x";
+        let result = truncate_by_syntax(&PathBuf::from("a.rs"), source, &cursor(1, 4), completion);
+        assert_eq!(result, "let x = 1;");
+
+        // no parser for this language, nothing changes
+        let result = truncate_by_syntax(&PathBuf::from("a.txt"), source, &cursor(1, 4), completion);
+        assert_eq!(result, completion);
+    }
+
+    #[test]
+    fn test_large_file_is_not_parsed_as_a_whole() {
+        let mut source = (0..20000).map(|i| format!("def f{i}(a):\n    return a + {i}\n\n")).collect::<String>();
+        let cursor_line = source.lines().count() as i32;
+        source.push_str("def g(a):\n    \n    return a\n");
+        // every line opens a bracket, the longest candidate without new errors is found only at the end of the search
+        let completion = (0..80).map(|i| format!("x{i} = (a")).collect::<Vec<_>>().join("\n    ");
+        let t0 = std::time::Instant::now();
+        let result = truncate_by_syntax(&PathBuf::from("a.py"), &source, &cursor(cursor_line + 1, 4), &completion);
+        assert_eq!(result, completion, "no candidate parses, the completion stays as is");
+        assert!(t0.elapsed().as_secs_f64() < 1.0, "took {:.3}s", t0.elapsed().as_secs_f64());
+
+        let text = Rope::from_str(&source);
+        assert_eq!(parse_window(&text, cursor_line as usize + 1), (cursor_line as usize, text.len_lines()));
+    }
+}
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::RwLock;
 use tokenizers::Tokenizer;
-use crate::call_validation::ChatMessage;
+use crate::call_validation::{ChatContent, ChatMessage};
 
 pub struct TokenCountCache {
     cache: HashMap<String, i32>,
@@ -20,9 +20,18 @@ impl TokenCountCache {
     }
     
     fn cache_key(msg: &ChatMessage) -> String {
-        // Use role and content as the key
+        // Use role, content and thinking as the key
         // This is sufficient because we only care about the tokenization of the text
-        format!("{}:{}", msg.role, msg.content.content_text_only())
+        format!("{}:{}:{}", msg.role, msg.content.content_text_only(), Self::thinking_text(msg))
+    }
+
+    // reasoning_content is never sent back (forward_to_openai_endpoint strips it, anthropic doesn't read it),
+    // the thinking blocks are: anthropic checks them, litellm passes them on, so only they take context
+    fn thinking_text(msg: &ChatMessage) -> String {
+        msg.thinking_blocks.iter().flatten()
+            .filter_map(|block| block.get("thinking").and_then(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n")
     }
     
     pub fn get_token_count(
@@ -41,8 +50,14 @@ impl TokenCountCache {
         
         // Cache miss - compute the token count
         self.misses += 1;
-        let content_tokens = msg.content.count_tokens(tokenizer, &None)?;
-        let total_tokens = extra_tokens_per_message + content_tokens;
+        let content_tokens = msg.content.count_tokens(tokenizer.clone(), &None)?;
+        let thinking = Self::thinking_text(msg);
+        let thinking_tokens = if thinking.is_empty() {
+            0
+        } else {
+            ChatContent::SimpleText(thinking).count_tokens(tokenizer, &None)?
+        };
+        let total_tokens = extra_tokens_per_message + content_tokens + thinking_tokens;
         
         // Cache the result
         self.cache.insert(key, total_tokens);
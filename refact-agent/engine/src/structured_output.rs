@@ -0,0 +1,198 @@
+use serde_json::Value;
+
+use crate::agentic::generate_commit_message::remove_fencing;
+use crate::call_validation::{ChatContent, ChatMessage};
+use crate::caps::CodeAssistantCaps;
+
+
+// How many times the model gets to answer before we give up, including the first answer
+pub const STRUCTURED_OUTPUT_ATTEMPTS: usize = 3;
+const MAX_ERRORS_TO_SHOW: usize = 5;
+
+
+// Checks the request itself: {"type": "text"}, {"type": "json_object"} or {"type": "json_schema", "json_schema": {"schema": {...}}}
+pub fn check_response_format(response_format: &Value) -> Result<(), String> {
+    match response_format.get("type").and_then(|t| t.as_str()) {
+        Some("text") | Some("json_object") => Ok(()),
+        Some("json_schema") => {
+            let schema = response_format_schema(response_format)
+                .ok_or("response_format.json_schema.schema is missing".to_string())?;
+            jsonschema::validator_for(&schema).map_err(|e| format!("response_format.json_schema.schema is not a valid json schema: {}", e))?;
+            Ok(())
+        },
+        Some(other) => Err(format!("response_format.type {:?} is not supported, use text, json_object or json_schema", other)),
+        None => Err("response_format.type is missing".to_string()),
+    }
+}
+
+fn response_format_schema(response_format: &Value) -> Option<Value> {
+    response_format.get("json_schema").and_then(|j| j.get("schema")).cloned()
+}
+
+// Ok(parsed json) if the answer matches the response_format, otherwise a human (and model) readable list of problems
+pub fn validate_structured_output(response_format: &Value, answer: &str) -> Result<Value, String> {
+    let kind = response_format.get("type").and_then(|t| t.as_str()).unwrap_or("text");
+    if kind == "text" {
+        return Ok(Value::String(answer.to_string()));
+    }
+    // models without native support like to wrap json in ```json fences
+    let parsed: Value = serde_json::from_str(&remove_fencing(&answer.to_string()))
+        .map_err(|e| format!("the answer is not valid json: {}", e))?;
+    if kind == "json_object" {
+        return if parsed.is_object() { Ok(parsed) } else { Err("the answer must be a json object".to_string()) };
+    }
+    let schema = response_format_schema(response_format).ok_or("response_format.json_schema.schema is missing".to_string())?;
+    let validator = jsonschema::validator_for(&schema).map_err(|e| format!("invalid json schema: {}", e))?;
+    let errors = validator.iter_errors(&parsed)
+        .take(MAX_ERRORS_TO_SHOW)
+        .map(|e| format!("at {:?}: {}", e.instance_path.to_string(), e))
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(format!("the answer doesn't match the schema:\n{}", errors.join("\n")))
+    }
+}
+
+// Finds the first choice whose last assistant message doesn't match, returns that message and the problems
+pub fn first_invalid_choice(response_format: &Value, choices: &Vec<Vec<ChatMessage>>) -> Option<(ChatMessage, String)> {
+    for choice in choices {
+        let answer = match choice.iter().rev().find(|m| m.role == "assistant") {
+            Some(answer) => answer,
+            None => return Some((ChatMessage::new("assistant".to_string(), "".to_string()), "no answer from the model".to_string())),
+        };
+        if let Err(problems) = validate_structured_output(response_format, &answer.content.content_text_only()) {
+            return Some((answer.clone(), problems));
+        }
+    }
+    None
+}
+
+// Same for /v1/chat/completions non-streaming response: {"choices": [{"message": {"content": "..."}}]}
+pub fn first_invalid_choice_in_response(response_format: &Value, response: &Value) -> Option<(String, String)> {
+    let choices = match response.get("choices").and_then(|c| c.as_array()) {
+        Some(choices) if !choices.is_empty() => choices,
+        _ => return Some(("".to_string(), "no choices in the model response".to_string())),
+    };
+    for choice in choices {
+        let content = choice.get("message").and_then(|m| m.get("content")).and_then(|c| c.as_str()).unwrap_or("");
+        if let Err(problems) = validate_structured_output(response_format, content) {
+            return Some((content.to_string(), problems));
+        }
+    }
+    None
+}
+
+// For models that don't support response_format natively, the format has to be explained in the prompt
+// Only openai-style passthrough endpoints get response_format as is, everything else needs the instruction message
+pub fn has_native_support(caps: &CodeAssistantCaps, model_name: &String, scratchpad_name: &str) -> bool {
+    scratchpad_name == "PASSTHROUGH" && crate::caps::chat_endpoint_style(caps, model_name) == "openai"
+}
+
+pub fn format_instruction_message(response_format: &Value) -> Option<ChatMessage> {
+    let instruction = match response_format.get("type").and_then(|t| t.as_str()) {
+        Some("json_object") => "Reply with a single json object only, no explanations and no markdown.".to_string(),
+        Some("json_schema") => format!(
+            "Reply with json only, no explanations and no markdown. The json must match this schema:\n{}",
+            serde_json::to_string_pretty(&response_format_schema(response_format)?).unwrap_or_default()
+        ),
+        _ => return None,
+    };
+    Some(ChatMessage::new("user".to_string(), instruction))
+}
+
+pub fn reask_message(problems: &str) -> ChatMessage {
+    ChatMessage {
+        role: "user".to_string(),
+        content: ChatContent::SimpleText(format!(
+            "{}\n\nReply again with json only, no explanations and no markdown, and make sure it matches the required format.",
+            problems
+        )),
+        ..Default::default()
+    }
+}
+
+// Rejected answers cost tokens too, their usage is added to the usage of the answer that is returned
+pub fn add_usage(total: &mut Value, usage: &Value) {
+    let usage = match usage.as_object() {
+        Some(x) => x,
+        None => return,
+    };
+    if !total.is_object() {
+        *total = Value::Object(serde_json::Map::new());
+    }
+    let total = total.as_object_mut().unwrap();
+    for (k, v) in usage {
+        if let Some(n) = v.as_u64() {
+            let before = total.get(k).and_then(|x| x.as_u64()).unwrap_or(0);
+            total.insert(k.clone(), Value::from(before + n));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person_format() -> Value {
+        json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "person",
+                "schema": {
+                    "type": "object",
+                    "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+                    "required": ["name", "age"],
+                    "additionalProperties": false
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_check_response_format() {
+        assert!(check_response_format(&person_format()).is_ok());
+        assert!(check_response_format(&json!({"type": "json_object"})).is_ok());
+        assert!(check_response_format(&json!({"type": "xml"})).is_err());
+        assert!(check_response_format(&json!({"type": "json_schema", "json_schema": {"name": "x"}})).is_err());
+    }
+
+    #[test]
+    fn test_validate_structured_output() {
+        let rf = person_format();
+        assert_eq!(validate_structured_output(&rf, "{\"name\": \"Ann\", \"age\": 7}").unwrap(), json!({"name": "Ann", "age": 7}));
+        assert!(validate_structured_output(&rf, "```json\n{\"name\": \"Ann\", \"age\": 7}\n```").is_ok());
+        let problems = validate_structured_output(&rf, "{\"name\": \"Ann\"}").unwrap_err();
+        assert!(problems.contains("age"), "{}", problems);
+        assert!(validate_structured_output(&rf, "Sure! Here is the json").unwrap_err().contains("not valid json"));
+        assert!(validate_structured_output(&json!({"type": "json_object"}), "[1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_first_invalid_choice_in_response() {
+        let rf = person_format();
+        let good = json!({"choices": [{"message": {"role": "assistant", "content": "{\"name\": \"Ann\", \"age\": 7}"}}]});
+        assert!(first_invalid_choice_in_response(&rf, &good).is_none());
+        let bad = json!({"choices": [{"message": {"role": "assistant", "content": "{\"name\": 7}"}}]});
+        let (content, _problems) = first_invalid_choice_in_response(&rf, &bad).unwrap();
+        assert_eq!(content, "{\"name\": 7}");
+    }
+
+    #[test]
+    fn test_format_instruction_message() {
+        let msg = format_instruction_message(&person_format()).unwrap();
+        assert!(msg.content.content_text_only().contains("\"required\""));
+        assert!(format_instruction_message(&json!({"type": "text"})).is_none());
+    }
+
+    #[test]
+    fn test_add_usage() {
+        let mut total = Value::Null;
+        add_usage(&mut total, &json!({"prompt_tokens": 100, "completion_tokens": 20, "total_tokens": 120}));
+        add_usage(&mut total, &json!({"prompt_tokens": 150, "completion_tokens": 10, "total_tokens": 160, "prompt_tokens_details": {}}));
+        add_usage(&mut total, &Value::Null);
+        assert_eq!(total, json!({"prompt_tokens": 250, "completion_tokens": 30, "total_tokens": 280}));
+    }
+}
@@ -12,6 +12,7 @@ use crate::global_context::{GlobalContext, try_load_caps_quickly_if_not_present,
 use crate::http::routers::v1::chat::lookup_chat_scratchpad;
 use crate::scratchpad_abstract::ScratchpadAbstract;
 use crate::scratchpads::multimodality::chat_content_raw_from_value;
+use crate::structured_output::{STRUCTURED_OUTPUT_ATTEMPTS, first_invalid_choice, format_instruction_message, has_native_support, reask_message};
 use crate::yaml_configs::customization_loader::load_customization;
 
 
@@ -59,6 +60,7 @@ pub async fn create_chat_post_and_scratchpad(
             stop: vec![],
             n: Some(n),
             reasoning_effort,
+            response_format: None,
         },
         model: model_name.to_string(),
         scratchpad: "".to_string(),
@@ -209,6 +211,8 @@ async fn chat_interaction_non_stream(
 
         let content = chat_content_raw_from_value(content_value).and_then(|c|c.to_internal_format())
             .map_err(|e| format!("error parsing model's output: {}", e))?;
+        let reasoning_content = message.get("reasoning_content").and_then(|v| v.as_str()).map(|s| s.to_string());
+        let thinking_blocks = message.get("thinking_blocks").and_then(|v| v.as_array()).cloned();
 
         let mut ch_results = vec![];
         let msg = ChatMessage {
@@ -217,6 +221,8 @@ async fn chat_interaction_non_stream(
             tool_calls,
             tool_call_id,
             usage: usage_mb.clone(),
+            reasoning_content,
+            thinking_blocks,
             ..Default::default()
         };
         ch_results.extend(det_messages.clone());
@@ -257,6 +263,9 @@ fn update_usage_from_messages(usage: &mut ChatUsage, messages: &Vec<Vec<ChatMess
                 usage.total_tokens += u.total_tokens;
                 usage.completion_tokens += u.completion_tokens;
                 usage.prompt_tokens += u.prompt_tokens;
+                usage.cache_read_tokens += u.cache_read_tokens;
+                usage.cache_creation_tokens += u.cache_creation_tokens;
+                usage.retries_n += u.retries_n;
             }
         }
     }
@@ -274,9 +283,10 @@ pub async fn subchat_single(
     n: usize,
     reasoning_effort: Option<ReasoningEffort>,
     prepend_system_prompt: bool,
-    usage_collector_mb: Option<&mut ChatUsage>,
+    mut usage_collector_mb: Option<&mut ChatUsage>,
     tx_toolid_mb: Option<String>,
     tx_chatid_mb: Option<String>,
+    response_format: Option<Value>,
 ) -> Result<Vec<Vec<ChatMessage>>, String> {
     let (gcx, should_execute_remotely) = {
         let ccx_locked = ccx.lock().await;
@@ -301,23 +311,56 @@ pub async fn subchat_single(
     info!("tools_on_intersection {:?}", tools_on_intersection);
 
     let max_new_tokens = max_new_tokens.unwrap_or(MAX_NEW_TOKENS);
-    let (mut chat_post, spad) = create_chat_post_and_scratchpad(
-        gcx.clone(),
-        ccx.clone(),
-        model_name,
-        messages.iter().collect::<Vec<_>>(),
-        temperature,
-        max_new_tokens,
-        n,
-        reasoning_effort,
-        prepend_system_prompt,
-        Some(tools),
-        tool_choice.clone(),
-        only_deterministic_messages,
-        should_execute_remotely,
-    ).await?;
-
-    let chat_response_msgs = chat_interaction(ccx.clone(), spad, &mut chat_post).await?;
+    // with response_format, a bad answer goes back to the model together with the problems found
+    let mut messages_to_send = messages.clone();
+    if let Some(rf) = &response_format {
+        let caps = try_load_caps_quickly_if_not_present(gcx.clone(), 0).await.map_err(|e| format!("no caps: {:?}", e))?;
+        let probe_post = ChatPost { model: model_name.to_string(), ..Default::default() };
+        let (resolved_model, scratchpad_name, _, _, _, _, _) = lookup_chat_scratchpad(caps.clone(), &probe_post).await?;
+        let native_support = has_native_support(&caps.read().unwrap(), &resolved_model, &scratchpad_name);
+        if !native_support {
+            messages_to_send.extend(format_instruction_message(rf));
+        }
+    }
+    let mut attempt_n = 0;
+    let chat_response_msgs = loop {
+        attempt_n += 1;
+        let (mut chat_post, spad) = create_chat_post_and_scratchpad(
+            gcx.clone(),
+            ccx.clone(),
+            model_name,
+            messages_to_send.iter().collect::<Vec<_>>(),
+            temperature,
+            max_new_tokens,
+            n,
+            reasoning_effort.clone(),
+            prepend_system_prompt,
+            Some(tools.clone()),
+            tool_choice.clone(),
+            only_deterministic_messages,
+            should_execute_remotely,
+        ).await?;
+        chat_post.parameters.response_format = response_format.clone();
+
+        let chat_response_msgs = chat_interaction(ccx.clone(), spad, &mut chat_post).await?;
+        let rf = match &response_format {
+            Some(rf) => rf,
+            None => break chat_response_msgs,
+        };
+        let (bad_answer, problems) = match first_invalid_choice(rf, &chat_response_msgs) {
+            Some(x) => x,
+            None => break chat_response_msgs,
+        };
+        if let Some(usage_collector) = usage_collector_mb.as_deref_mut() {
+            update_usage_from_messages(usage_collector, &chat_response_msgs);
+        }
+        if attempt_n >= STRUCTURED_OUTPUT_ATTEMPTS {
+            return Err(format!("model {} didn't produce a valid structured output after {} attempts: {}", model_name, attempt_n, problems));
+        }
+        warn!("subchat structured output attempt {} failed: {}", attempt_n, problems);
+        messages_to_send.push(bad_answer);
+        messages_to_send.push(reask_message(&problems));
+    };
 
     let old_messages = messages.clone();
     // no need to remove user from old_messages here, because allow_at is false
@@ -405,6 +448,7 @@ pub async fn subchat(
                 Some(&mut usage_collector),
                 tx_toolid_mb.clone(),
                 tx_chatid_mb.clone(),
+                None,
             ).await?[0].clone();
             step_n += 1;
         }
@@ -428,6 +472,7 @@ pub async fn subchat(
                 Some(&mut usage_collector),
                 tx_toolid_mb.clone(),
                 tx_chatid_mb.clone(),
+                None,
             ).await?[0].clone();
         }
     }
@@ -447,6 +492,7 @@ pub async fn subchat(
         Some(&mut usage_collector),
         tx_toolid_mb.clone(),
         tx_chatid_mb.clone(),
+        None,
     ).await?;
     for messages in choices.iter() {
         let last_message = messages.last().unwrap();
@@ -467,6 +513,7 @@ pub async fn subchat(
                     Some(&mut usage_collector),
                     tx_toolid_mb.clone(),
                     tx_chatid_mb.clone(),
+                    None,
                 ).await?[0].clone();
             }
         }
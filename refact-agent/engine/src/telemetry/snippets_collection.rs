@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::sync::Arc;
 use std::sync::RwLock as StdRwLock;
+use std::time::Instant;
 
 use tokio::sync::RwLock as ARwLock;
 use tracing::debug;
@@ -27,6 +29,7 @@ pub struct SaveSnippet {
     // Purpose is to aggregate this struct to a scratchpad
     pub storage_arc: Arc<StdRwLock<telemetry_structs::Storage>>,
     pub post: CodeCompletionPost,
+    pub created: Instant,
 }
 
 impl SaveSnippet {
@@ -37,6 +40,7 @@ impl SaveSnippet {
         SaveSnippet {
             storage_arc,
             post: post.clone(),
+            created: Instant::now(),
         }
     }
 }
@@ -44,12 +48,14 @@ impl SaveSnippet {
 fn snippet_register(
     ss: &SaveSnippet,
     grey_text: String,
-    context_used: bool,
+    context_used: &Value,
 ) -> u64 {
     let mut storage_locked = ss.storage_arc.write().unwrap();
     let snippet_telemetry_id = storage_locked.tele_snippet_next_id;
     let mut model = ss.post.model.clone();
-    if context_used {
+    // context_used always has the rag fields, only files actually attached to the prompt count as ast
+    let has_attached_files = context_used.get("attached_files").and_then(|x| x.as_array()).map_or(false, |x| !x.is_empty());
+    if has_attached_files {
         model = format!("{}+ast", model);
     }
     let snip = SnippetTracker {
@@ -62,6 +68,10 @@ fn snippet_register(
         created_ts: chrono::Local::now().timestamp(),
         accepted_ts: 0,
         finished_ts: 0,
+        scratchpad: ss.post.scratchpad.clone(),
+        ab_arm: ss.post.ab_arm.clone(),
+        latency_ms: ss.created.elapsed().as_millis() as i64,
+        rag_tokens: context_used.get("rag_tokens_used").and_then(|x| x.as_i64()).unwrap_or(0),
     };
     storage_locked.tele_snippet_next_id += 1;
     storage_locked.tele_snippets.push(snip);
@@ -71,7 +81,7 @@ fn snippet_register(
 pub fn snippet_register_from_data4cache(
     ss: &SaveSnippet,
     data4cache: &mut completion_cache::CompletionSaveToCache,
-    context_used: bool,
+    context_used: &Value,
 ) {
     // Convenience function: snippet_telemetry_id should be returned inside a cached answer as well, so there's
     // typically a combination of the two
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 use tokio::sync::RwLock as ARwLock;
 
+use crate::agent_db::db_completion_events::{completion_event_record, fim_example_record};
 use crate::global_context;
 
 
@@ -10,13 +11,15 @@ const SNIP_ACCEPTED_NOT_FINISHED_TIMEOUT_AFTER: i64 = 600;
 
 pub async fn send_finished_snippets(gcx: Arc<ARwLock<global_context::GlobalContext>>) {
     let tele_storage;
+    let chore_db;
     let now = chrono::Local::now().timestamp();
     {
         let cx = gcx.read().await;
         tele_storage = cx.telemetry.clone();
+        chore_db = cx.chore_db.clone();
     }
 
-    {
+    let removed = {
         let mut to_remove: Vec<usize> = vec![];
         let mut storage_locked = tele_storage.write().unwrap();
         for (idx, snip) in &mut storage_locked.tele_snippets.iter().enumerate() {
@@ -36,8 +39,22 @@ pub async fn send_finished_snippets(gcx: Arc<ARwLock<global_context::GlobalConte
         // Sort in reverse order to remove from the end
         to_remove.sort_by(|a, b| b.cmp(a));
         to_remove.dedup();
-        for idx in to_remove {
-            storage_locked.tele_snippets.remove(idx);
+        to_remove.into_iter().map(|idx| storage_locked.tele_snippets.remove(idx)).collect::<Vec<_>>()
+    };
+
+    // Local analytics, see /v1/completion-stats
+    if !removed.is_empty() {
+        let lite = chore_db.lock().lite.clone();
+        let mut conn = lite.lock();
+        let result = conn.transaction().map_err(|e| e.to_string()).and_then(|tx| {
+            for snip in removed.iter() {
+                completion_event_record(&tx, snip)?;
+                fim_example_record(&tx, snip)?;
+            }
+            tx.commit().map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            tracing::error!("cannot save completion events: {}", e);
         }
     }
 
@@ -63,6 +63,11 @@ pub struct SnippetTracker {
     pub created_ts: i64,
     pub accepted_ts: i64,
     pub finished_ts: i64,
+    // for the local completion_events table
+    pub scratchpad: String,
+    pub ab_arm: String,
+    pub latency_ms: i64,
+    pub rag_tokens: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -157,6 +157,7 @@ impl Tool for ToolDeepThinking {
             Some(&mut usage_collector),
             Some(tool_call_id.clone()),
             Some(format!("{log_prefix}-deep-thinking")),
+            None,
         ).await?[0].clone();
 
         let final_message = model_says.last()
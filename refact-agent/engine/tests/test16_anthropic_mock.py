@@ -0,0 +1,119 @@
+# Checks the anthropic chat_endpoint_style against a local mock of the Messages API, no real key needed.
+#
+# python tests/test16_anthropic_mock.py --write-caps /tmp/anthropic-mock.yaml
+# target/debug/refact-lsp --address-url /tmp/anthropic-mock.yaml --http-port 8001 --logs-stderr
+# python tests/test16_anthropic_mock.py
+
+import argparse, json, threading, time
+import requests
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+
+MOCK_PORT = 8011
+LSP_URL = "http://127.0.0.1:8001/v1/chat/completions"
+MODEL = "claude-3-7-sonnet"
+
+CAPS_YAML = f"""
+cloud_name: Anthropic mock
+chat_endpoint_style: "anthropic"
+chat_endpoint: "http://127.0.0.1:{MOCK_PORT}/v1/messages"
+chat_apikey: "mock-key"
+chat_model: {MODEL}
+running_models:
+  - {MODEL}
+"""
+
+USAGE = {"input_tokens": 12, "output_tokens": 1, "cache_read_input_tokens": 900, "cache_creation_input_tokens": 100}
+SSE_EVENTS = [
+    {"type": "message_start", "message": {"id": "msg_mock", "model": MODEL, "usage": USAGE}},
+    {"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}},
+    {"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Frogs "}},
+    {"type": "ping"},
+    {"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "are amphibians."}},
+    {"type": "content_block_stop", "index": 0},
+    {"type": "message_delta", "delta": {"stop_reason": "end_turn"}, "usage": {"output_tokens": 5}},
+    {"type": "message_stop"},
+]
+
+received_requests = []
+
+
+class MockAnthropic(BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = json.loads(self.rfile.read(int(self.headers["Content-Length"])))
+        received_requests.append((dict(self.headers), body))
+        if body.get("stream"):
+            self.send_response(200)
+            self.send_header("Content-Type", "text/event-stream")
+            self.end_headers()
+            for ev in SSE_EVENTS:
+                self.wfile.write(f"event: {ev['type']}\ndata: {json.dumps(ev)}\n\n".encode())
+                self.wfile.flush()
+        else:
+            resp = {
+                "id": "msg_mock", "type": "message", "role": "assistant", "model": MODEL,
+                "content": [{"type": "text", "text": "Frogs are amphibians."}],
+                "stop_reason": "end_turn", "usage": {**USAGE, "output_tokens": 5},
+            }
+            data = json.dumps(resp).encode()
+            self.send_response(200)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(data)))
+            self.end_headers()
+            self.wfile.write(data)
+
+    def log_message(self, *args):
+        pass
+
+
+def check_request():
+    headers, body = received_requests[-1]
+    assert headers.get("x-api-key") == "mock-key", headers
+    assert headers.get("anthropic-version"), headers
+    assert body["system"][-1]["cache_control"] == {"type": "ephemeral"}, body["system"]
+    assert all(m["role"] in ("user", "assistant") for m in body["messages"]), body["messages"]
+    assert body["messages"][-1]["content"][-1]["cache_control"] == {"type": "ephemeral"}, body["messages"]
+
+
+def test_chat(stream: bool):
+    messages = [
+        {"role": "system", "content": "You are a biologist."},
+        {"role": "user", "content": "What are frogs?"},
+    ]
+    r = requests.post(LSP_URL, json={"model": MODEL, "messages": messages, "stream": stream, "max_tokens": 100}, stream=stream)
+    assert r.status_code == 200, r.text
+    content, usage = "", None
+    if stream:
+        for line in r.iter_lines():
+            if not line.startswith(b"data: ") or line == b"data: [DONE]":
+                continue
+            j = json.loads(line[6:])
+            for choice in j.get("choices", []):
+                content += (choice.get("delta") or {}).get("content") or ""
+            usage = j.get("usage") or usage
+    else:
+        j = r.json()
+        content = j["choices"][0]["message"]["content"]
+        usage = j["usage"]
+    check_request()
+    assert content == "Frogs are amphibians.", content
+    assert usage["cache_read_tokens"] == 900 and usage["cache_creation_tokens"] == 100, usage
+    assert usage["prompt_tokens"] == 1012 and usage["completion_tokens"] == 5, usage
+    print("stream=%s OK, usage %s" % (stream, usage))
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser()
+    parser.add_argument("--write-caps", type=str, default="", help="write a caps file pointing to the mock and exit")
+    args = parser.parse_args()
+    if args.write_caps:
+        with open(args.write_caps, "w") as f:
+            f.write(CAPS_YAML)
+        print("caps written to %s" % args.write_caps)
+        exit(0)
+    server = ThreadingHTTPServer(("127.0.0.1", MOCK_PORT), MockAnthropic)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    time.sleep(0.2)
+    test_chat(stream=True)
+    test_chat(stream=False)
+    server.shutdown()